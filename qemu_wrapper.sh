@@ -2,6 +2,8 @@
 
 NOGRAPHIC=${NOGRAPHIC:-0}
 DUMP_NETWORK=${DUMP_NETWORK:-0}
+CAPTURE=${CAPTURE:-0}
+CAPTURE_PORT=${CAPTURE_PORT:-5555}
 TAP_IF=${TAP_IF:-tap0}
 GDB=${GDB:-0}
 NUM_CORES=${NUM_CORES:-4}
@@ -18,6 +20,18 @@ else
   DUMP_NET_CMD="-object filter-dump,id=n0,netdev=n0,file=network.dump"
 fi
 
+if [ "$CAPTURE" == "0" ]; then
+  CAPTURE_CMD=""
+else
+  # In kernel pcap-ng capture on COM2, waits for e.g.
+  # nc localhost $CAPTURE_PORT | wireshark -k -i -
+  CAPTURE_CMD="-serial tcp::$CAPTURE_PORT,server=on,wait=on"
+  if [ "$NOGRAPHIC" != "0" ]; then
+    # Otherwise COM1 would be the capture port
+    STDIO_CMD="$STDIO_CMD -serial mon:stdio"
+  fi
+fi
+
 if [ "$GDB" == "0" ]; then
   GDB_CMD=""
 else
@@ -31,6 +45,6 @@ cp "$KERNEL" isodir/boot/myos.bin
 cp grub.cfg isodir/boot/grub/grub.cfg
 grub-mkrescue -o myos.iso isodir 2> /dev/null
 
-qemu-system-i386 $GDB_CMD $STDIO_CMD $DUMP_NET_CMD -netdev tap,id=n0,ifname=$TAP_IF,script=no,downscript=no -device rtl8139,netdev=n0,bus=pci.0,addr=4,mac=12:34:56:78:9a:bc -device isa-debug-exit,iobase=0xf4,iosize=0x01 -cdrom myos.iso -smp $NUM_CORES -enable-kvm -cpu host -usb -device usb-mouse,bus=usb-bus.0,port=2
+qemu-system-i386 $GDB_CMD $STDIO_CMD $CAPTURE_CMD $DUMP_NET_CMD -netdev tap,id=n0,ifname=$TAP_IF,script=no,downscript=no -device rtl8139,netdev=n0,bus=pci.0,addr=4,mac=12:34:56:78:9a:bc -device isa-debug-exit,iobase=0xf4,iosize=0x01 -cdrom myos.iso -smp $NUM_CORES -enable-kvm -cpu host -usb -device usb-mouse,bus=usb-bus.0,port=2
 
 exit $(($? >> 1))
@@ -5,7 +5,7 @@ use crate::{
 
 use core::{
     alloc::{GlobalAlloc, Layout},
-    sync::atomic::{AtomicPtr, Ordering},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
 };
 
 #[global_allocator]
@@ -59,16 +59,52 @@ impl UsedSegment {
 
 pub struct Allocator {
     pub first_free: AtomicPtr<FreeSegment>,
+    heap_size: AtomicUsize,
     lock: SpinLock<()>,
 }
 
+#[derive(Debug)]
+pub struct AllocatorStats {
+    pub heap_size: usize,
+    pub free: usize,
+    pub free_segments: usize,
+    /// Upper bound for a single allocation
+    pub largest_free: usize,
+}
+
 impl Allocator {
     pub const fn new() -> Allocator {
         Allocator {
             first_free: AtomicPtr::new(core::ptr::null_mut()),
+            heap_size: AtomicUsize::new(0),
             lock: SpinLock::new(()),
         }
     }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let _guard1 = InterruptGuarded::new(());
+        let _guard1 = _guard1.lock();
+        let _guard2 = self.lock.lock();
+
+        let mut stats = AllocatorStats {
+            heap_size: self.heap_size.load(Ordering::Relaxed),
+            free: 0,
+            free_segments: 0,
+            largest_free: 0,
+        };
+
+        let mut it = self.first_free.load(Ordering::Relaxed);
+        while !it.is_null() {
+            // Packed struct, copy the fields out before using them
+            let (size, next) = unsafe { ((*it).size, (*it).next_segment) };
+            stats.free += size;
+            stats.free_segments += 1;
+            stats.largest_free = stats.largest_free.max(size);
+            it = next;
+        }
+
+        stats
+    }
 }
 
 pub unsafe fn init(info: &Multiboot2) {
@@ -97,6 +133,7 @@ pub unsafe fn init(info: &Multiboot2) {
         next_segment: core::ptr::null_mut(),
     };
 
+    ALLOC.heap_size.store(segment_size, Ordering::Relaxed);
     ALLOC.first_free.store(segment, Ordering::Relaxed);
 }
 
@@ -9,7 +9,7 @@ use crate::{
 use core::{
     future::Future,
     pin::Pin,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     task::{Context, Poll},
 };
 
@@ -36,16 +36,48 @@ struct Task<'a> {
 #[derive(Clone, Debug, Copy, Hash, Eq, PartialEq)]
 pub struct TaskId(u64);
 
+/// Counters that tasks can read while the executor runs
+#[derive(Debug, Default)]
+pub struct ExecutorStats {
+    pub spawned: AtomicUsize,
+    pub finished: AtomicUsize,
+    pub polls: AtomicUsize,
+}
+
+impl core::fmt::Display for ExecutorStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let spawned = self.spawned.load(Ordering::Relaxed);
+        let finished = self.finished.load(Ordering::Relaxed);
+        write!(
+            f,
+            "{} running, {} finished, {} polls",
+            spawned.saturating_sub(finished),
+            finished,
+            self.polls.load(Ordering::Relaxed)
+        )
+    }
+}
+
 pub struct Executor<'a> {
     cpu_dispatcher: Option<&'a CpuFnDispatcher>,
     id: TaskId,
     tasks: Arc<SpinLock<HashMap<TaskId, Task<'a>>>>,
     to_run: Receiver<TaskId>,
     queue_to_run: Sender<TaskId>,
+    stats: Arc<ExecutorStats>,
 }
 
 impl<'a> Executor<'a> {
+    #[allow(unused)]
     pub fn new(dispatcher: Option<&'a CpuFnDispatcher>) -> Executor<'a> {
+        Executor::with_stats(dispatcher, Default::default())
+    }
+
+    /// Counts into stats, which can be shared with the tasks that are spawned later
+    pub fn with_stats(
+        dispatcher: Option<&'a CpuFnDispatcher>,
+        stats: Arc<ExecutorStats>,
+    ) -> Executor<'a> {
         let (queue_to_run, to_run) = lock_free_queue::channel(1024);
         Executor {
             cpu_dispatcher: dispatcher,
@@ -53,6 +85,7 @@ impl<'a> Executor<'a> {
             tasks: Arc::new(SpinLock::new(Default::default())),
             to_run,
             queue_to_run,
+            stats,
         }
     }
 
@@ -71,6 +104,7 @@ impl<'a> Executor<'a> {
         };
 
         self.tasks.lock().insert(id, task);
+        self.stats.spawned.fetch_add(1, Ordering::Relaxed);
         self.queue_to_run
             .push(id)
             .expect("Failed to queue task on executor");
@@ -124,11 +158,16 @@ impl<'a> Executor<'a> {
                     >(Arc::clone(&self.tasks))
                 };
 
+                let stats = Arc::clone(&self.stats);
+
                 let poll_fn = move || {
                     let context_waker = Arc::clone(&task.waker).into();
                     let mut context = core::task::Context::from_waker(&context_waker);
+                    stats.polls.fetch_add(1, Ordering::Relaxed);
                     if task.future.as_mut().poll(&mut context).is_pending() {
                         tasks.lock().insert(task_id, task);
+                    } else {
+                        stats.finished.fetch_add(1, Ordering::Relaxed);
                     }
                 };
 
@@ -257,3 +296,56 @@ where
 pub fn poll_immediate<R, F: Future<Output = R>>(f: F) -> impl Future<Output = Option<R>> {
     PollImmediate { f: Some(f) }
 }
+
+/// Futures driven by a single task, for when a task has to run a varying number of sub-tasks,
+/// e.g. one per connection, without access to the executor
+#[derive(Default)]
+pub struct FutureSet<'a> {
+    futures: Vec<Pin<Box<dyn Future<Output = ()> + Send + 'a>>>,
+}
+
+impl<'a> FutureSet<'a> {
+    pub fn new() -> FutureSet<'a> {
+        FutureSet {
+            futures: Vec::new(),
+        }
+    }
+
+    pub fn push<F: Future<Output = ()> + Send + 'a>(&mut self, fut: F) {
+        self.futures.push(Box::pin(fut));
+    }
+
+    pub fn len(&self) -> usize {
+        self.futures.len()
+    }
+
+    #[allow(unused)]
+    pub fn is_empty(&self) -> bool {
+        self.futures.is_empty()
+    }
+
+    /// Polls every future, resolves when one of them has finished. Never resolves while empty
+    pub fn next(&mut self) -> FutureSetNext<'_, 'a> {
+        FutureSetNext { set: self }
+    }
+}
+
+pub struct FutureSetNext<'b, 'a> {
+    set: &'b mut FutureSet<'a>,
+}
+
+impl Future for FutureSetNext<'_, '_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let futures = &mut self.set.futures;
+        for i in 0..futures.len() {
+            if futures[i].as_mut().poll(cx).is_ready() {
+                drop(futures.swap_remove(i));
+                return Poll::Ready(());
+            }
+        }
+
+        Poll::Pending
+    }
+}
@@ -396,6 +396,22 @@ pub fn load_idt() {
     debug!("{:?}", read_idtr());
 }
 
+/// Loads an empty IDT and raises an exception. With no handler for it or for the double fault
+/// that follows the cpu triple faults, which resets the machine
+pub unsafe fn reboot() -> ! {
+    let idt = Idt { size: 0, offset: 0 };
+    asm!(r#"
+         lidt ({idt})
+         int3
+         "#,
+         idt = in (reg) &idt,
+         options(att_syntax));
+
+    loop {
+        asm!("hlt");
+    }
+}
+
 pub fn init(
     io_allocator: &mut IoAllocator,
 ) -> Result<&'static InterruptHandlerData, InitInterruptError> {
@@ -183,7 +183,7 @@ pub struct MmapRange {
     pub length: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PciInterfaceId {
     pub class: u8,
     pub subclass: u8,
@@ -191,6 +191,35 @@ pub struct PciInterfaceId {
     pub revision: u8,
 }
 
+/// What was found at an address during enumeration, kept around for listing devices
+#[derive(Debug, Clone)]
+pub struct PciDeviceInfo {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor: u16,
+    pub device: u16,
+    pub interface_id: PciInterfaceId,
+}
+
+/// Formatted like `lspci -n`
+impl core::fmt::Display for PciDeviceInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:02x}{:02x}: {:04x}:{:04x} (rev {:02x})",
+            self.bus,
+            self.slot,
+            self.function,
+            self.interface_id.class,
+            self.interface_id.subclass,
+            self.vendor,
+            self.device,
+            self.interface_id.revision
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct InvalidIrq;
 
@@ -207,6 +236,18 @@ impl GeneralPciDevice {
         (vendor, device)
     }
 
+    pub fn info(&mut self, pci: &mut Pci) -> PciDeviceInfo {
+        let (vendor, device) = self.id(pci);
+        PciDeviceInfo {
+            bus: self.addr.bus,
+            slot: self.addr.slot,
+            function: self.addr.function,
+            vendor,
+            device,
+            interface_id: self.interface_id(pci),
+        }
+    }
+
     pub fn interface_id(&mut self, pci: &mut Pci) -> PciInterfaceId {
         let reg = pci.config_read(self.addr.bus, self.addr.slot, self.addr.function, 0x8);
         PciInterfaceId {
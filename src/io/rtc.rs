@@ -35,7 +35,7 @@ pub enum WriteError {
     Century(OffsetOutOfRange),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Eq, PartialEq)]
 pub struct DateTime {
     pub seconds: u8,
     pub minutes: u8,
@@ -47,6 +47,74 @@ pub struct DateTime {
     pub century: u8,
 }
 
+impl DateTime {
+    const SECONDS_PER_DAY: u64 = 86400;
+    // Days from 0000-03-01 to 1970-01-01 in the proleptic gregorian calendar
+    const UNIX_EPOCH_DAYS: u64 = 719468;
+
+    /// Interprets the date as UTC. Dates before 1970 are clamped to the epoch
+    pub fn to_unix(&self) -> u64 {
+        // Some emulated CMOS chips leave the century register empty
+        let century = match self.century {
+            0 => 20,
+            century => century as u64,
+        };
+        let (month, day) = (self.month as u64, self.day as u64);
+
+        // Counting years from march puts the leap day at the end of the year. See
+        // http://howardhinnant.github.io/date_algorithms.html
+        let year = century * 100 + self.year as u64 - (month <= 2) as u64;
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * 146097 + day_of_era).saturating_sub(Self::UNIX_EPOCH_DAYS);
+
+        days * Self::SECONDS_PER_DAY
+            + self.hours as u64 * 3600
+            + self.minutes as u64 * 60
+            + self.seconds as u64
+    }
+
+    pub fn from_unix(timestamp: u64) -> DateTime {
+        let days = timestamp / Self::SECONDS_PER_DAY;
+        let seconds_of_day = timestamp % Self::SECONDS_PER_DAY;
+
+        let shifted_days = days + Self::UNIX_EPOCH_DAYS;
+        let era = shifted_days / 146097;
+        let day_of_era = shifted_days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = (shifted_month + 2) % 12 + 1;
+        let year = era * 400 + year_of_era + (month <= 2) as u64;
+
+        DateTime {
+            seconds: (seconds_of_day % 60) as u8,
+            minutes: (seconds_of_day / 60 % 60) as u8,
+            hours: (seconds_of_day / 3600) as u8,
+            // The epoch was a thursday, the RTC counts from sunday = 1
+            weekday: ((days + 4) % 7 + 1) as u8,
+            day: day as u8,
+            month: month as u8,
+            year: (year % 100) as u8,
+            century: (year / 100) as u8,
+        }
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02}{:02}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.century, self.year, self.month, self.day, self.hours, self.minutes, self.seconds
+        )
+    }
+}
+
 fn get_nmi_mask(nmi_enable: bool) -> u8 {
     if nmi_enable {
         0
@@ -180,7 +248,7 @@ impl Rtc {
         Ok(Rtc { cmos_io })
     }
 
-    pub fn write(&mut self, date_time: &DateTime) -> Result<(), WriteError> {
+    pub fn write(&self, date_time: &DateTime) -> Result<(), WriteError> {
         update_guarded_op(&mut self.cmos_io.lock(), |cmos_io| {
             write_cmos_reg(cmos_io, NMI_ENABLE, 0x00, date_time.seconds)
                 .map_err(WriteError::Seconds)?;
@@ -200,7 +268,7 @@ impl Rtc {
         })
     }
 
-    pub fn read(&mut self) -> Result<DateTime, ReadError> {
+    pub fn read(&self) -> Result<DateTime, ReadError> {
         update_guarded_op(&mut self.cmos_io.lock(), |cmos_io| {
             let seconds = read_cmos_reg(cmos_io, NMI_ENABLE, 0x00).map_err(ReadError::Seconds)?;
             let minutes = read_cmos_reg(cmos_io, NMI_ENABLE, 0x02).map_err(ReadError::Minutes)?;
@@ -240,4 +308,34 @@ mod test {
         test_true!(in_progress_set((1 << 7) | 0x34));
         Ok(())
     });
+
+    create_test!(test_date_time_unix_conversion, {
+        let date = DateTime {
+            seconds: 56,
+            minutes: 34,
+            hours: 12,
+            weekday: 5,
+            day: 29,
+            month: 2,
+            year: 24,
+            century: 20,
+        };
+        test_eq!(date.to_unix(), 1709210096);
+        test_eq!(DateTime::from_unix(1709210096), date);
+        test_eq!(
+            alloc::format!("{}", DateTime::from_unix(1709210096)),
+            "2024-02-29 12:34:56"
+        );
+
+        test_eq!(DateTime::from_unix(0).to_unix(), 0);
+        test_eq!(DateTime::from_unix(0).weekday, 5);
+        // Turn of the century, which is not a leap year
+        test_eq!(DateTime::from_unix(4107542400).month, 3);
+        test_eq!(DateTime::from_unix(4107542400).day, 1);
+        for timestamp in [951782400, 951868799, 1234567890, 4102444800] {
+            test_eq!(DateTime::from_unix(timestamp).to_unix(), timestamp);
+        }
+
+        Ok(())
+    });
 }
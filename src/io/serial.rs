@@ -2,7 +2,8 @@ use crate::io::io_allocator::{IoAllocator, IoOffset, IoRange, OffsetOutOfRange};
 
 use core::cell::UnsafeCell;
 
-const BASE_ADDR: u16 = 0x3f8;
+pub const COM1: u16 = 0x3f8;
+pub const COM2: u16 = 0x2f8;
 const DATA_OFFSET: IoOffset = IoOffset::new(0);
 const ENABLE_INTERRUPT_OFFSET: IoOffset = IoOffset::new(1);
 const INTERRUPT_ID_FIFO_CONTROL_OFFSET: IoOffset = IoOffset::new(2);
@@ -28,10 +29,17 @@ pub struct Serial {
 
 impl Serial {
     pub fn new(io_allocator: &mut IoAllocator) -> Result<Serial, SerialInitError> {
+        Self::with_port(io_allocator, COM1)
+    }
+
+    pub fn with_port(
+        io_allocator: &mut IoAllocator,
+        base_addr: u16,
+    ) -> Result<Serial, SerialInitError> {
         use SerialInitError::*;
 
         let mut serial_io = io_allocator
-            .request_io_range(BASE_ADDR, 8)
+            .request_io_range(base_addr, 8)
             .ok_or(IoRangeReserved)?;
 
         (|| -> Result<(), OffsetOutOfRange> {
@@ -76,7 +84,11 @@ impl Serial {
     }
 
     pub fn write_str(&self, s: &str) {
-        for b in s.as_bytes() {
+        self.write_bytes(s.as_bytes());
+    }
+
+    pub fn write_bytes(&self, data: &[u8]) {
+        for b in data {
             self.write_byte(*b).expect("failed to write to serial");
         }
     }
@@ -1,9 +1,15 @@
 use crate::util::{
+    async_channel,
     async_mutex::Mutex,
     atomic_cell::AtomicCell,
+    interrupt_guard::InterruptGuarded,
     lock_free_queue::{self, Receiver, Sender},
+    spinlock::SpinLock,
+};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
 };
-use alloc::string::String;
 use core::{
     cell::UnsafeCell,
     ops::Deref,
@@ -115,6 +121,24 @@ pub enum LogLevel {
     Error,
 }
 
+#[derive(Debug)]
+pub struct InvalidLogLevel;
+
+impl core::str::FromStr for LogLevel {
+    type Err = InvalidLogLevel;
+
+    fn from_str(s: &str) -> Result<LogLevel, InvalidLogLevel> {
+        let ret = match s.to_ascii_lowercase().as_str() {
+            "debug" => LogLevel::Debug,
+            "info" => LogLevel::Info,
+            "warn" | "warning" => LogLevel::Warning,
+            "error" => LogLevel::Error,
+            _ => return Err(InvalidLogLevel),
+        };
+        Ok(ret)
+    }
+}
+
 impl core::fmt::Display for LogLevel {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
@@ -157,10 +181,12 @@ impl core::future::Future for LogWaiter<'_> {
 }
 
 pub struct Logger {
-    levels: HashMap<String, LogLevel>,
+    // Logging can happen in interrupt handlers, so interrupts are disabled while this is held
+    levels: SpinLock<HashMap<String, LogLevel>>,
     log_tx: Sender<Log>,
     log_rx: Mutex<Receiver<Log>>,
     waker: AtomicCell<Waker>,
+    subscribers: Mutex<Vec<async_channel::Sender<String>>>,
 }
 
 impl Logger {
@@ -169,15 +195,25 @@ impl Logger {
         let log_rx = Mutex::new(log_rx);
         let waker = AtomicCell::new();
         Logger {
-            levels,
+            levels: SpinLock::new(levels),
             log_tx,
             log_rx,
             waker,
+            subscribers: Mutex::new(Vec::new()),
         }
     }
 
     pub fn get_level(&self, module: &str) -> LogLevel {
-        *self.levels.get(module).unwrap_or(&LogLevel::Info)
+        let _guard1 = InterruptGuarded::new(());
+        let _guard1 = _guard1.lock();
+        *self.levels.lock().get(module).unwrap_or(&LogLevel::Info)
+    }
+
+    /// module is a full module path, e.g. kernel::net::tcp
+    pub fn set_level(&self, module: &str, level: LogLevel) {
+        let _guard1 = InterruptGuarded::new(());
+        let _guard1 = _guard1.lock();
+        self.levels.lock().insert(module.into(), level);
     }
 
     pub fn push_log(&self, log: Log) {
@@ -198,8 +234,23 @@ impl Logger {
             }
             .await;
             println!("{}", log);
+
+            let subscribers = self.subscribers.lock().await;
+            if !subscribers.is_empty() {
+                let line = log.to_string();
+                for subscriber in subscribers.iter() {
+                    subscriber.send(line.clone()).await;
+                }
+            }
         }
     }
+
+    /// Every log printed from now on is also sent to the returned receiver
+    pub async fn subscribe(&self) -> async_channel::Receiver<String> {
+        let (tx, rx) = async_channel::channel();
+        self.subscribers.lock().await.push(tx);
+        rx
+    }
 }
 
 pub fn init(log_levels: HashMap<String, LogLevel>) {
@@ -211,3 +262,7 @@ pub fn init(log_levels: HashMap<String, LogLevel>) {
 pub async fn service() {
     LOGGER.service().await;
 }
+
+pub async fn subscribe() -> async_channel::Receiver<String> {
+    LOGGER.subscribe().await
+}
@@ -37,20 +37,14 @@ mod multiprocessing;
 mod net;
 mod rng;
 mod rtl8139;
+mod shell;
 mod sleep;
 mod time;
 mod usb;
 mod util;
 
 use acpi::MadtEntry;
-use alloc::{
-    boxed::Box,
-    format,
-    string::{String, ToString},
-    sync::Arc,
-    vec,
-    vec::Vec,
-};
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec, vec::Vec};
 use multiboot2::Multiboot2;
 use multiprocessing::Apic;
 
@@ -58,6 +52,7 @@ use core::{
     arch::global_asm,
     panic::PanicInfo,
     pin::Pin,
+    sync::atomic::Ordering,
     task::{Context, Poll},
 };
 use hashbrown::HashMap;
@@ -66,28 +61,53 @@ use crate::{
     acpi::AcpiTable,
     cursor::Cursor,
     framebuffer::FrameBuffer,
-    future::{Either, Executor},
+    future::{Either, Executor, ExecutorStats},
     interrupts::{InitInterruptError, InterruptHandlerData},
     io::{
         io_allocator::IoAllocator,
-        pci::{Pci, PciDevice},
+        pci::{Pci, PciDevice, PciDeviceInfo},
         ps2::Ps2Keyboard,
-        rtc::Rtc,
-        serial::Serial,
+        rtc::{DateTime, Rtc},
+        serial::{self, Serial},
     },
     mouse::Mouse,
     multiprocessing::CpuFnDispatcher,
     net::{
-        tcp::Tcp, ArpFrame, ArpFrameParams, ArpOperation, EtherType, EthernetFrameParams,
-        ParsedIpv4Frame, ParsedPacket, UnknownArpOperation,
+        capture::{Capture, CaptureFilter, CaptureInterface, Direction},
+        dhcp::{self, Dhcp},
+        dns::Dns,
+        firewall::{Action, Firewall, Interface, InvalidRuleIndex, IpPrefix, PacketInfo, Rule},
+        http::{
+            client::Client,
+            server::{Router, Server},
+            Request, Response, StatusCode,
+        },
+        icmp::{self, Icmp, UnreachableCode},
+        icmpv6::Icmpv6,
+        ipv4::Ipv4,
+        ipv6::{self, Ipv6Config, Ipv6Frame},
+        loopback::{self, Loopback},
+        sntp::Sntp,
+        stats::{DropReason, NetStats, Netstat, SocketTable, TrafficStats},
+        tcp::{Tcp, TcpFrame},
+        tftp::Tftp,
+        udp::{Udp, UdpDeliveryError},
+        vlan::{VlanInterface, VlanTag},
+        ArpFrame, ArpFrameParams, ArpOperation, EtherType, EthernetFrame, EthernetFrameParams,
+        IpAddr, Ipv4Config, Ipv4Frame, NetDevice, ParseIpv4Error, ParseIpv6Error, ParsePacketError,
+        ParsedIpv4Frame, ParsedIpv6Frame, ParsedPacket, UnknownArpOperation,
     },
     rng::Rng,
     rtl8139::Rtl8139,
+    shell::Shell,
     sleep::{WakeupRequester, WakeupService},
-    time::MonotonicTime,
-    usb::{uhci::Uhci, Usb, UsbDescriptor},
+    time::{MonotonicTime, WallClock},
+    usb::{uhci::Uhci, Usb, UsbDescriptor, UsbDeviceInfo},
+    util::async_io::AsyncWrite,
     util::async_mutex::Mutex,
     util::interrupt_guard::InterruptGuarded,
+    util::pubsub::PubSub,
+    util::updated_val::UpdatedVal,
 };
 
 // Include boot.s which defines _start as inline assembly in main. This allows us to do more fine
@@ -95,7 +115,40 @@ use crate::{
 // naked function + some inline asm, but this seems much more straight forward.
 global_asm!(include_str!("boot.s"), options(att_syntax));
 
+// Sending "exit\n" to this port shuts down the vm
+const EXIT_PORT: u16 = 6000;
+// Command shell, telnet or netcat both work as clients
+const SHELL_PORT: u16 = 23;
+// Fallback address for when DHCP fails
 const STATIC_IP: [u8; 4] = [192, 168, 2, 2];
+const STATIC_NETMASK: [u8; 4] = [255, 255, 255, 0];
+const STATIC_DNS_SERVER: [u8; 4] = [192, 168, 2, 1];
+// The wall clock is kept in sync with this server, anything the resolver accepts works
+const NTP_SERVER: &str = "pool.ntp.org";
+const NTP_POLL_INTERVAL_S: f32 = 1024.0;
+// Only these networks and the loopback interface may reach the http server, the shell and the
+// exit hook
+const TRUSTED_PREFIXES: [IpPrefix; 2] = [
+    IpPrefix {
+        ip: IpAddr::V4([192, 168, 2, 0]),
+        len: 24,
+    },
+    IpPrefix {
+        ip: IpAddr::V6([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        len: 10,
+    },
+];
+// VLAN sub-interfaces of the rtl8139 with their tag, address and netmask
+const STATIC_VLANS: [(VlanTag, Ipv4Addr, Ipv4Addr); 1] = [(
+    VlanTag {
+        id: 10,
+        priority: 0,
+    },
+    [192, 168, 10, 2],
+    [255, 255, 255, 0],
+)];
+// Packet capture is streamed to COM2 if the VM has one, otherwise over UDP to this address
+const CAPTURE_UDP_DEST: Option<(Ipv4Addr, u16)> = None;
 
 extern "C" {
     static KERNEL_START: u32;
@@ -146,15 +199,16 @@ unsafe fn interrupt_guarded_init(
 }
 
 // FIXME: Ip address should be strong typed
-type IpAddr = [u8; 4];
+type Ipv4Addr = [u8; 4];
+type Ipv6Addr = [u8; 16];
 type MacAddr = [u8; 6];
 
-struct ArpReadyFuture<'a> {
-    ip: &'a IpAddr,
-    table: &'a Mutex<HashMap<IpAddr, MacAddr>>,
+struct NeighborReadyFuture<'a, A> {
+    ip: &'a A,
+    table: &'a Mutex<HashMap<A, MacAddr>>,
 }
 
-impl<'a> core::future::Future for ArpReadyFuture<'a> {
+impl<'a, A: Eq + core::hash::Hash> core::future::Future for NeighborReadyFuture<'a, A> {
     type Output = MacAddr;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
@@ -173,23 +227,31 @@ impl<'a> core::future::Future for ArpReadyFuture<'a> {
     }
 }
 
-struct ArpTable {
-    table: Mutex<HashMap<IpAddr, MacAddr>>,
+/// IP to MAC mappings, filled by ARP for ipv4 and neighbor discovery for ipv6
+struct NeighborTable<A> {
+    table: Mutex<HashMap<A, MacAddr>>,
 }
 
-impl ArpTable {
-    fn new() -> ArpTable {
+type ArpTable = NeighborTable<Ipv4Addr>;
+type NdpTable = NeighborTable<Ipv6Addr>;
+
+impl<A: Copy + Eq + core::hash::Hash> NeighborTable<A> {
+    fn new() -> NeighborTable<A> {
         let table = Mutex::new(HashMap::new());
-        ArpTable { table }
+        NeighborTable { table }
     }
 
-    async fn write_mac(&self, ip: &IpAddr, mac: &MacAddr) {
+    async fn write_mac(&self, ip: &A, mac: &MacAddr) {
         let mut table = self.table.lock().await;
         table.insert(*ip, *mac);
     }
 
-    async fn wait_for(&self, ip: &[u8; 4]) -> [u8; 6] {
-        ArpReadyFuture {
+    async fn get(&self, ip: &A) -> Option<MacAddr> {
+        self.table.lock().await.get(ip).copied()
+    }
+
+    async fn wait_for(&self, ip: &A) -> MacAddr {
+        NeighborReadyFuture {
             ip,
             table: &self.table,
         }
@@ -197,6 +259,333 @@ impl ArpTable {
     }
 }
 
+struct Ipv4Sender<'a> {
+    ipv4: &'a Ipv4,
+    rtl8139: &'a Rtl8139,
+    loopback: &'a Loopback,
+    ipv4_config: &'a UpdatedVal<Ipv4Config>,
+    vlans: &'a [VlanInterface],
+    arp_table: &'a ArpTable,
+    stats: &'a NetStats,
+    monotonic_time: &'a MonotonicTime,
+    wakeup_requester: &'a WakeupRequester,
+}
+
+impl Ipv4Sender<'_> {
+    /// VLAN sub-interfaces are used for their own subnets, everything else goes out untagged
+    async fn route(&self, remote_ip: &Ipv4Addr) -> (Option<VlanTag>, Ipv4Config) {
+        for vlan in self.vlans {
+            let ipv4_config = vlan.ipv4_config.read().await;
+            if ipv4_config.is_local(remote_ip) {
+                return (Some(vlan.tag), ipv4_config);
+            }
+        }
+
+        (None, self.ipv4_config.read().await)
+    }
+
+    async fn local_ip_for(&self, remote_ip: &Ipv4Addr) -> Ipv4Addr {
+        let (_, ipv4_config) = self.route(remote_ip).await;
+        ipv4_config.local_ip_for(remote_ip)
+    }
+
+    async fn resolve_mac(
+        &self,
+        local_ip: &Ipv4Addr,
+        remote_ip: &Ipv4Addr,
+        vlan: Option<VlanTag>,
+        ipv4_config: &Ipv4Config,
+    ) -> Option<MacAddr> {
+        if *remote_ip == [255; 4] {
+            return Some([0xff; 6]);
+        }
+
+        let next_hop = ipv4_config.next_hop(remote_ip);
+        if let Some(mac) = self.arp_table.get(&next_hop).await {
+            return Some(mac);
+        }
+
+        let mac = self.rtl8139.get_mac();
+        let arp_frame = net::generate_arp_request(&next_hop, local_ip, &mac);
+        let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
+            dest_mac: [0xff; 6],
+            source_mac: mac,
+            ether_type: EtherType::Arp,
+            vlan,
+            payload: &arp_frame,
+        });
+        self.rtl8139.write(&ethernet_frame).await.unwrap();
+        self.stats.arp.record_tx(arp_frame.len());
+
+        let sleep_fut = sleep::sleep(1.0, self.monotonic_time, self.wakeup_requester);
+        let sleep_fut = core::pin::pin!(sleep_fut);
+        let arp_lookup = core::pin::pin!(self.arp_table.wait_for(&next_hop));
+
+        match crate::future::select(arp_lookup, sleep_fut).await {
+            Either::Left((mac, _)) => Some(mac),
+            Either::Right(_) => None,
+        }
+    }
+
+    async fn send(
+        &self,
+        payload: &[u8],
+        protocol: net::Ipv4Protocol,
+        local_ip: &Ipv4Addr,
+        remote_ip: &Ipv4Addr,
+    ) {
+        let is_loopback = loopback::is_loopback_ip(remote_ip);
+        let mtu = if is_loopback {
+            Loopback::MTU
+        } else {
+            Rtl8139::MTU
+        };
+        let ipv4_frames = match self.ipv4.generate_frames(
+            payload,
+            protocol,
+            local_ip,
+            remote_ip,
+            mtu,
+            dont_fragment(protocol),
+        ) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Dropping datagram to {:?}: {:?}", remote_ip, e);
+                return;
+            }
+        };
+
+        if is_loopback {
+            for ipv4_frame in &ipv4_frames {
+                let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
+                    dest_mac: Loopback::MAC,
+                    source_mac: Loopback::MAC,
+                    ether_type: EtherType::Ipv4,
+                    vlan: None,
+                    payload: ipv4_frame,
+                });
+
+                self.loopback.write(&ethernet_frame).await.unwrap();
+                self.stats.ipv4.record_tx(ipv4_frame.len());
+            }
+            self.stats.record_protocol_tx(protocol, payload.len());
+            return;
+        }
+
+        let (vlan, ipv4_config) = self.route(remote_ip).await;
+        let dest_mac = match self
+            .resolve_mac(local_ip, remote_ip, vlan, &ipv4_config)
+            .await
+        {
+            Some(v) => v,
+            None => {
+                warn!("ARP lookup for {:?} failed, dropping packet", remote_ip);
+                self.stats.drops.record(DropReason::ArpMiss);
+                return;
+            }
+        };
+
+        for ipv4_frame in &ipv4_frames {
+            let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
+                dest_mac,
+                source_mac: self.rtl8139.get_mac(),
+                ether_type: EtherType::Ipv4,
+                vlan,
+                payload: ipv4_frame,
+            });
+
+            self.rtl8139.write(&ethernet_frame).await.unwrap();
+            self.stats.ipv4.record_tx(ipv4_frame.len());
+        }
+        self.stats.record_protocol_tx(protocol, payload.len());
+    }
+}
+
+struct Ipv6Sender<'a> {
+    rtl8139: &'a Rtl8139,
+    loopback: &'a Loopback,
+    ipv6_config: &'a UpdatedVal<Ipv6Config>,
+    icmpv6: &'a Icmpv6,
+    ndp_table: &'a NdpTable,
+    stats: &'a NetStats,
+    monotonic_time: &'a MonotonicTime,
+    wakeup_requester: &'a WakeupRequester,
+}
+
+impl Ipv6Sender<'_> {
+    async fn local_ip_for(&self, remote_ip: &Ipv6Addr) -> Ipv6Addr {
+        self.ipv6_config.read().await.local_ip_for(remote_ip)
+    }
+
+    async fn resolve_mac(&self, local_ip: &Ipv6Addr, remote_ip: &Ipv6Addr) -> Option<MacAddr> {
+        if ipv6::is_multicast(remote_ip) {
+            return Some(ipv6::multicast_mac(remote_ip));
+        }
+
+        let next_hop = self.ipv6_config.read().await.next_hop(remote_ip)?;
+        if let Some(mac) = self.ndp_table.get(&next_hop).await {
+            return Some(mac);
+        }
+
+        let solicitation = self
+            .icmpv6
+            .generate_neighbor_solicitation(&next_hop, local_ip);
+        let ipv6_frame = ipv6::generate_ipv6_frame(
+            &solicitation.payload,
+            net::Ipv4Protocol::Icmpv6,
+            &solicitation.local_ip,
+            &solicitation.remote_ip,
+            solicitation.hop_limit,
+        );
+        let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
+            dest_mac: ipv6::multicast_mac(&solicitation.remote_ip),
+            source_mac: self.rtl8139.get_mac(),
+            ether_type: EtherType::Ipv6,
+            vlan: None,
+            payload: &ipv6_frame,
+        });
+        self.rtl8139.write(&ethernet_frame).await.unwrap();
+        self.stats.ipv6.record_tx(ipv6_frame.len());
+        self.stats
+            .record_protocol_tx(net::Ipv4Protocol::Icmpv6, solicitation.payload.len());
+
+        let sleep_fut = sleep::sleep(1.0, self.monotonic_time, self.wakeup_requester);
+        let sleep_fut = core::pin::pin!(sleep_fut);
+        let ndp_lookup = core::pin::pin!(self.ndp_table.wait_for(&next_hop));
+
+        match crate::future::select(ndp_lookup, sleep_fut).await {
+            Either::Left((mac, _)) => Some(mac),
+            Either::Right(_) => None,
+        }
+    }
+
+    /// There is no ipv6 fragmentation, datagrams larger than the MTU are dropped
+    async fn send(
+        &self,
+        payload: &[u8],
+        next_header: net::Ipv4Protocol,
+        local_ip: &Ipv6Addr,
+        remote_ip: &Ipv6Addr,
+        hop_limit: u8,
+    ) {
+        let ipv6_frame =
+            ipv6::generate_ipv6_frame(payload, next_header, local_ip, remote_ip, hop_limit);
+        if ipv6_frame.len() > Rtl8139::MTU {
+            warn!("Dropping oversized datagram to {:02x?}", remote_ip);
+            return;
+        }
+
+        if *remote_ip == ipv6::LOOPBACK {
+            let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
+                dest_mac: Loopback::MAC,
+                source_mac: Loopback::MAC,
+                ether_type: EtherType::Ipv6,
+                vlan: None,
+                payload: &ipv6_frame,
+            });
+
+            self.loopback.write(&ethernet_frame).await.unwrap();
+            self.stats.ipv6.record_tx(ipv6_frame.len());
+            self.stats.record_protocol_tx(next_header, payload.len());
+            return;
+        }
+
+        let dest_mac = match self.resolve_mac(local_ip, remote_ip).await {
+            Some(v) => v,
+            None => {
+                warn!(
+                    "Neighbor lookup for {:02x?} failed, dropping packet",
+                    remote_ip
+                );
+                self.stats.drops.record(DropReason::ArpMiss);
+                return;
+            }
+        };
+
+        let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
+            dest_mac,
+            source_mac: self.rtl8139.get_mac(),
+            ether_type: EtherType::Ipv6,
+            vlan: None,
+            payload: &ipv6_frame,
+        });
+
+        self.rtl8139.write(&ethernet_frame).await.unwrap();
+        self.stats.ipv6.record_tx(ipv6_frame.len());
+        self.stats.record_protocol_tx(next_header, payload.len());
+    }
+}
+
+/// Picks the sender for the address family of a transport protocol's addresses
+struct IpSender<'a> {
+    ipv4: &'a Ipv4Sender<'a>,
+    ipv6: &'a Ipv6Sender<'a>,
+}
+
+impl IpSender<'_> {
+    async fn local_ip_for(&self, remote_ip: &IpAddr) -> IpAddr {
+        match remote_ip {
+            IpAddr::V4(ip) => IpAddr::V4(self.ipv4.local_ip_for(ip).await),
+            IpAddr::V6(ip) => IpAddr::V6(self.ipv6.local_ip_for(ip).await),
+        }
+    }
+
+    async fn send(
+        &self,
+        payload: &[u8],
+        protocol: net::Ipv4Protocol,
+        local_ip: &IpAddr,
+        remote_ip: &IpAddr,
+    ) {
+        match (local_ip, remote_ip) {
+            (IpAddr::V4(local_ip), IpAddr::V4(remote_ip)) => {
+                self.ipv4.send(payload, protocol, local_ip, remote_ip).await
+            }
+            (IpAddr::V6(local_ip), IpAddr::V6(remote_ip)) => {
+                self.ipv6
+                    .send(
+                        payload,
+                        protocol,
+                        local_ip,
+                        remote_ip,
+                        ipv6::DEFAULT_HOP_LIMIT,
+                    )
+                    .await
+            }
+            _ => error!(
+                "Mismatched address families {:?} -> {:?}",
+                local_ip, remote_ip
+            ),
+        }
+    }
+}
+
+/// TCP segments are sized by the MSS, so like Linux they are sent with DF set
+fn dont_fragment(protocol: net::Ipv4Protocol) -> bool {
+    protocol == net::Ipv4Protocol::Tcp
+}
+
+struct NetStack<'a> {
+    arp_table: &'a ArpTable,
+    ndp_table: &'a NdpTable,
+    ipv4: &'a Ipv4,
+    tcp: &'a Tcp,
+    icmp: &'a Icmp,
+    icmpv6: &'a Icmpv6,
+    udp: &'a Udp,
+    // Simulates packet loss, received tcp segments are dropped when this returns true
+    tcp_drop_hook: Option<&'a (dyn Fn(&TcpFrame<'_>) -> bool + Sync)>,
+    capture: Option<&'a Capture>,
+    stats: &'a NetStats,
+    firewall: &'a Firewall,
+}
+
+/// Where pcap-ng records are streamed to
+enum CaptureOutput {
+    Serial(Serial),
+    Udp(Ipv4Addr, u16),
+}
+
 #[allow(unused)]
 struct Kernel {
     cpu_dispatcher: CpuFnDispatcher,
@@ -205,15 +594,34 @@ struct Kernel {
     rng: Mutex<Rng>,
     rtc: Rtc,
     pci: Pci,
+    /// Found during init, devices can't be enumerated again once drivers own them
+    pci_devices: Vec<PciDeviceInfo>,
     ps2: Ps2Keyboard,
     rtl8139: Rtl8139,
+    loopback: Loopback,
     usb: Usb,
     arp_table: ArpTable,
+    ndp_table: NdpTable,
+    ipv4: Ipv4,
     serial: Arc<Serial>,
     framebuffer: FrameBuffer,
     cursor: Cursor,
     tcp: Tcp,
+    icmp: Icmp,
+    icmpv6: Icmpv6,
+    udp: Arc<Udp>,
+    dns: Dns,
+    ipv4_config: UpdatedVal<Ipv4Config>,
+    ipv6_config: UpdatedVal<Ipv6Config>,
+    vlans: Vec<VlanInterface>,
+    capture: Option<(Arc<Capture>, CaptureOutput)>,
+    net_stats: NetStats,
+    firewall: Firewall,
+    /// Live data for the web page
+    channels: PubSub,
+    dhcp: Dhcp,
     monotonic_time: Arc<MonotonicTime>,
+    wall_clock: WallClock,
     wakeup_requester: WakeupRequester,
     wakeup_service: WakeupService,
 }
@@ -249,8 +657,11 @@ impl Kernel {
             )
             .expect("Failed to register empty interrupt handler");
 
-        let mut rtc = io::rtc::Rtc::new(&mut io_allocator, interrupt_handlers, on_tick)
+        let rtc = io::rtc::Rtc::new(&mut io_allocator, interrupt_handlers, on_tick)
             .expect("Failed to construct rtc");
+        // The RTC is assumed to run on UTC, as it does in qemu by default
+        let boot_time = rtc.read().expect("Failed to read rtc").to_unix();
+        let wall_clock = WallClock::new(Arc::clone(&monotonic_time), boot_time as f64);
 
         let mut pci = Pci::new(&mut io_allocator).expect("Failed to initialize pci");
 
@@ -264,10 +675,12 @@ impl Kernel {
 
         let mut rtl8139 = None;
         let mut uhci = None;
+        let mut pci_device_infos = Vec::new();
 
         for mut device in pci_devices {
             let id = device.id(&mut pci);
             let interface_id = device.interface_id(&mut pci);
+            pci_device_infos.push(device.info(&mut pci));
 
             debug!(
                 "PCI device: {:?} with id {:#x}, {:#x} and interface: {:?}",
@@ -294,14 +707,75 @@ impl Kernel {
             }
         }
 
-        let rtl8139 = rtl8139.expect("Failed to find pci device id for rtl8139");
+        let mut rtl8139 = rtl8139.expect("Failed to find pci device id for rtl8139");
+
+        // See CAPTURE in qemu_wrapper.sh for attaching COM2
+        let capture_output = match Serial::with_port(&mut io_allocator, serial::COM2) {
+            Ok(serial) => Some(CaptureOutput::Serial(serial)),
+            Err(_) => CAPTURE_UDP_DEST.map(|(ip, port)| CaptureOutput::Udp(ip, port)),
+        };
+        let capture = capture_output.map(|output| {
+            let ignored_udp_port = match output {
+                CaptureOutput::Udp(_, port) => Some(port),
+                CaptureOutput::Serial(_) => None,
+            };
+            let capture = Arc::new(Capture::new(Arc::clone(&monotonic_time), ignored_udp_port));
+            rtl8139.set_capture(Arc::clone(&capture));
+            (capture, output)
+        });
         let uhci = uhci.expect("Failed to find uhci controller");
 
         let usb = Usb::new(uhci);
+        let loopback = Loopback::new();
 
         let arp_table = ArpTable::new();
-        let rng = Mutex::new(Rng::new(rtc.read().unwrap().seconds as u64));
-        let tcp = Tcp::new(Arc::clone(&monotonic_time), wakeup_requester.clone());
+        let ndp_table = NdpTable::new();
+        let mut rng = Rng::new(rtc.read().unwrap().seconds as u64);
+        let dhcp_xid = rng.u64() as u32;
+        let dns_id = rng.u64() as u16;
+        let isn_secret = [rng.u64() ^ rng::tsc(), rng.u64() ^ rng::tsc()];
+        let rng = Mutex::new(rng);
+
+        let mut fallback_ipv4_config = Ipv4Config::new(STATIC_IP, STATIC_NETMASK);
+        fallback_ipv4_config.dns_servers[0] = Some(STATIC_DNS_SERVER);
+        let ipv4_config = UpdatedVal::new(fallback_ipv4_config);
+        let ipv6_config = UpdatedVal::new(Ipv6Config::new(ipv6::link_local_address(
+            &rtl8139.get_mac(),
+        )));
+
+        let vlans = STATIC_VLANS
+            .iter()
+            .map(|(tag, address, netmask)| {
+                VlanInterface::new(*tag, Ipv4Config::new(*address, *netmask))
+            })
+            .collect();
+
+        let ipv4 = Ipv4::new(Arc::clone(&monotonic_time));
+        let tcp = Tcp::new(
+            ipv4_config.clone(),
+            ipv6_config.clone(),
+            Arc::clone(&monotonic_time),
+            wakeup_requester.clone(),
+            isn_secret,
+        );
+        let icmp = Icmp::new(Arc::clone(&monotonic_time), wakeup_requester.clone());
+        let icmpv6 = Icmpv6::new(rtl8139.get_mac(), ipv6_config.clone());
+        let udp = Arc::new(Udp::new());
+        let dns = Dns::new(
+            Arc::clone(&udp),
+            ipv4_config.clone(),
+            dns_id,
+            Arc::clone(&monotonic_time),
+            wakeup_requester.clone(),
+        );
+        let dhcp = Dhcp::new(
+            rtl8139.get_mac(),
+            dhcp_xid,
+            ipv4_config.clone(),
+            fallback_ipv4_config,
+            Arc::clone(&monotonic_time),
+            wakeup_requester.clone(),
+        );
 
         let framebuffer_info = info
             .get_framebuffer_info()
@@ -345,15 +819,32 @@ impl Kernel {
             interrupt_handlers,
             io_allocator,
             rtc,
+            wall_clock,
             rng,
             pci,
+            pci_devices: pci_device_infos,
             ps2,
             arp_table,
+            ndp_table,
+            ipv4,
             rtl8139,
+            loopback,
             cursor,
             usb,
             serial,
             tcp,
+            icmp,
+            icmpv6,
+            ipv4_config,
+            ipv6_config,
+            vlans,
+            capture,
+            net_stats: NetStats::default(),
+            firewall: Firewall::new(firewall_rules(), Action::Accept),
+            channels: PubSub::new(),
+            udp,
+            dns,
+            dhcp,
             framebuffer,
             monotonic_time,
             wakeup_service,
@@ -380,107 +871,458 @@ impl Kernel {
         };
 
         let send_udp = async {
-            let mac = self.rtl8139.get_mac();
             const REMOTE_IP: [u8; 4] = [192, 168, 2, 1];
-            let arp_frame: Vec<u8> = net::generate_arp_request(&REMOTE_IP, &STATIC_IP, &mac);
-            let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
-                dest_mac: [0xff; 6],
-                source_mac: mac,
-                ether_type: EtherType::Arp,
-                payload: &arp_frame,
-            });
-            self.rtl8139.write(&ethernet_frame).await.unwrap();
-
-            let sleep_fut = sleep::sleep(1.0, &self.monotonic_time, &self.wakeup_requester);
-            let sleep_fut = core::pin::pin!(sleep_fut);
-            let arp_lookup = self.arp_table.wait_for(&REMOTE_IP);
-            let arp_lookup = core::pin::pin!(arp_lookup);
+            match self.udp.bind(0).await {
+                Ok(socket) => {
+                    socket
+                        .send_to(b"hello from inside the os\n", REMOTE_IP, 6000)
+                        .await
+                }
+                Err(e) => warn!("Failed to bind udp socket: {:?}", e),
+            }
 
-            let mac = match crate::future::select(arp_lookup, sleep_fut).await {
-                Either::Left((mac, _)) => mac,
-                Either::Right(_) => {
-                    warn!("ARP lookup for {:?} failed", REMOTE_IP);
-                    return;
+            for ip in [Loopback::IP, REMOTE_IP] {
+                match self.icmp.ping(ip, 1.0).await {
+                    Ok(rtt) => info!("Ping {:?}: {}ms", ip, rtt * 1000.0),
+                    Err(e) => warn!("Ping {:?} failed: {:?}", ip, e),
                 }
-            };
+            }
+
+            match self.dns.resolve("example.com").await {
+                Ok(addrs) => info!("Resolved example.com: {:?}", addrs),
+                Err(e) => warn!("Failed to resolve example.com: {}", e),
+            }
 
-            info!("Resolved mac address!: {:?}", mac);
+            match self.tcp.connect(REMOTE_IP, 6000).await {
+                Ok(connection) => {
+                    if let Err(e) = connection.write_all(b"hello over tcp\n").await {
+                        warn!("Failed to write to {:?}: {:?}", REMOTE_IP, e);
+                    }
+                    connection.close().await;
+                }
+                Err(e) => warn!("Failed to connect to {:?}: {:?}", REMOTE_IP, e),
+            }
 
-            let udp_frame = net::generate_udp_frame(6000, b"hello from inside the os\n");
-            let ipv4_frame = net::generate_ipv4_frame(
-                &udp_frame,
-                net::Ipv4Protocol::Udp,
-                &STATIC_IP,
-                &REMOTE_IP,
+            let client = Client::new(
+                &self.tcp,
+                &self.dns,
+                &self.monotonic_time,
+                &self.wakeup_requester,
             );
-            let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
-                dest_mac: mac,
-                source_mac: self.rtl8139.get_mac(),
-                ether_type: EtherType::Ipv4,
-                payload: &ipv4_frame,
-            });
+            match client.get("http://192.168.2.1:8000/").await {
+                Ok(response) => info!(
+                    "GET http://192.168.2.1:8000/: {} ({} bytes)",
+                    response.status.0,
+                    response.body.len()
+                ),
+                Err(e) => warn!("GET http://192.168.2.1:8000/ failed: {}", e),
+            }
 
-            self.rtl8139.write(&ethernet_frame).await.unwrap();
+            let tftp = Tftp::new(&self.udp, &self.monotonic_time, &self.wakeup_requester);
+            let progress = |len| debug!("TFTP index.html: {} bytes so far", len);
+            match tftp.get(REMOTE_IP, "index.html", progress).await {
+                Ok(data) => info!("TFTP index.html: {} bytes", data.len()),
+                Err(e) => warn!("TFTP index.html failed: {}", e),
+            }
+
+            let interfaces = [("eth0", &self.rtl8139.stats), ("lo", &self.loopback.stats)];
+            info!(
+                "{}",
+                net_summary(&interfaces, &self.net_stats, &self.ipv4, &self.tcp).await
+            );
 
             info!("Sleeping for 5 seconds to wait for incoming connections");
         };
 
-        let echo_tcp = async {
-            let listener = self.tcp.listen(STATIC_IP, 80).await;
-            loop {
-                let connection = listener.connection().await;
-                let data = connection.read().await;
+        let http_server = async {
+            let router = Router::new()
+                .get("/", |_| async {
+                    Response::html(include_str!("../res/index.html"))
+                })
+                .get("/form", |request: Request| async move {
+                    match request.param("data") {
+                        Some(data) => Response::text(format!("Got form data: {}", data)),
+                        None => Response::error(StatusCode::BAD_REQUEST),
+                    }
+                })
+                .websocket("/channels", &self.channels);
+            let listener = self.tcp.listen(ipv6::UNSPECIFIED, 80).await;
+            Server::new(router, &self.monotonic_time, &self.wakeup_requester)
+                .serve(&listener)
+                .await;
+        };
 
-                info!(
-                    "Received TCP data: \"{}\" on cpu {}",
-                    core::str::from_utf8_unchecked(&data),
-                    multiprocessing::cpuid()
-                );
+        let ipv4_sender = Ipv4Sender {
+            ipv4: &self.ipv4,
+            rtl8139: &self.rtl8139,
+            loopback: &self.loopback,
+            ipv4_config: &self.ipv4_config,
+            vlans: &self.vlans,
+            arp_table: &self.arp_table,
+            stats: &self.net_stats,
+            monotonic_time: &self.monotonic_time,
+            wakeup_requester: &self.wakeup_requester,
+        };
 
-                match handle_http_request(&data) {
-                    Ok(response) => {
-                        connection.write(response.to_string().into_bytes()).await;
-                    }
-                    Err(_) => {
-                        connection
-                            .write(
-                                "HTTP/1.1 500 Internal servrer error\r\n\
-                            Content-Length: 0
-                            \r\n\
-                            \r\n"
-                                    .to_string()
-                                    .into_bytes(),
-                            )
-                            .await;
-                    }
+        let ipv6_sender = Ipv6Sender {
+            rtl8139: &self.rtl8139,
+            loopback: &self.loopback,
+            ipv6_config: &self.ipv6_config,
+            icmpv6: &self.icmpv6,
+            ndp_table: &self.ndp_table,
+            stats: &self.net_stats,
+            monotonic_time: &self.monotonic_time,
+            wakeup_requester: &self.wakeup_requester,
+        };
+
+        let ip_sender = IpSender {
+            ipv4: &ipv4_sender,
+            ipv6: &ipv6_sender,
+        };
+
+        // RFC 4861 section 6.3.7, a few solicitations in case the first one is lost
+        let router_solicitation = async {
+            for _ in 0..3 {
+                let ipv6_config = self.ipv6_config.read().await;
+                if ipv6_config.router.is_some() {
+                    break;
                 }
+
+                let solicitation = self
+                    .icmpv6
+                    .generate_router_solicitation(&ipv6_config.link_local);
+                ipv6_sender
+                    .send(
+                        &solicitation.payload,
+                        net::Ipv4Protocol::Icmpv6,
+                        &solicitation.local_ip,
+                        &solicitation.remote_ip,
+                        solicitation.hop_limit,
+                    )
+                    .await;
+                sleep::sleep(4.0, &self.monotonic_time, &self.wakeup_requester).await;
             }
         };
 
         let tcp_service = async {
             loop {
                 let outgoing_data = self.tcp.service().await;
-                let ipv4_frame = net::generate_ipv4_frame(
-                    &outgoing_data.payload,
-                    net::Ipv4Protocol::Tcp,
-                    &outgoing_data.local_ip,
+                ip_sender
+                    .send(
+                        &outgoing_data.payload,
+                        net::Ipv4Protocol::Tcp,
+                        &outgoing_data.local_ip,
+                        &outgoing_data.remote_ip,
+                    )
+                    .await;
+            }
+        };
+
+        let icmp_service = async {
+            loop {
+                let outgoing_data = self.icmp.service().await;
+                ipv4_sender
+                    .send(
+                        &outgoing_data.payload,
+                        net::Ipv4Protocol::Icmp,
+                        &ipv4_sender.local_ip_for(&outgoing_data.remote_ip).await,
+                        &outgoing_data.remote_ip,
+                    )
+                    .await;
+            }
+        };
+
+        let udp_service = async {
+            loop {
+                let outgoing_data = self.udp.service().await;
+                let local_ip = ip_sender.local_ip_for(&outgoing_data.remote_ip).await;
+                let udp_frame = net::generate_udp_frame(
+                    &local_ip,
                     &outgoing_data.remote_ip,
+                    outgoing_data.local_port,
+                    outgoing_data.remote_port,
+                    &outgoing_data.data,
                 );
+                ip_sender
+                    .send(
+                        &udp_frame,
+                        net::Ipv4Protocol::Udp,
+                        &local_ip,
+                        &outgoing_data.remote_ip,
+                    )
+                    .await;
+            }
+        };
 
-                // FIXME: Generate arp request if needed?
-                let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
-                    dest_mac: self.arp_table.wait_for(&outgoing_data.remote_ip).await,
-                    source_mac: self.rtl8139.get_mac(),
-                    ether_type: EtherType::Ipv4,
-                    payload: &ipv4_frame,
-                });
+        // The DHCP client has to pick its own source address while unconfigured, so it only
+        // receives through the UDP stack
+        let dhcp_service = async {
+            loop {
+                let outgoing_data = self.dhcp.service().await;
+                ipv4_sender
+                    .send(
+                        &outgoing_data.payload,
+                        net::Ipv4Protocol::Udp,
+                        &outgoing_data.local_ip,
+                        &outgoing_data.remote_ip,
+                    )
+                    .await;
+            }
+        };
 
-                self.rtl8139.write(&ethernet_frame).await.unwrap();
+        let dhcp_client = async {
+            let socket = self
+                .udp
+                .bind(dhcp::CLIENT_PORT)
+                .await
+                .expect("DHCP client port already bound");
+            let recv = async {
+                loop {
+                    let (data, _, _) = socket.recv_from().await;
+                    self.dhcp.handle_frame(&data).await;
+                }
+            };
+
+            crate::future::select(core::pin::pin!(self.dhcp.run()), core::pin::pin!(recv)).await;
+        };
+
+        let capture_service = async {
+            let Some((capture, output)) = &self.capture else {
+                return;
+            };
+
+            match output {
+                CaptureOutput::Serial(serial) => {
+                    capture
+                        .service(|record| async move { serial.write_bytes(&record) })
+                        .await
+                }
+                CaptureOutput::Udp(ip, port) => {
+                    let socket = match self.udp.bind(0).await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!("Failed to bind capture socket: {:?}", e);
+                            return;
+                        }
+                    };
+                    let socket = &socket;
+                    capture
+                        .service(|record| async move { socket.send_to(&record, *ip, *port).await })
+                        .await
+                }
             }
         };
 
+        let exit_service = async {
+            let socket = self
+                .udp
+                .bind(EXIT_PORT)
+                .await
+                .expect("Exit port already bound");
+            loop {
+                let (data, _, _) = socket.recv_from().await;
+                if data == b"exit\n" {
+                    unsafe {
+                        io::exit(0);
+                    }
+                }
+            }
+        };
+
+        let net_stack = NetStack {
+            arp_table: &self.arp_table,
+            ndp_table: &self.ndp_table,
+            ipv4: &self.ipv4,
+            tcp: &self.tcp,
+            icmp: &self.icmp,
+            icmpv6: &self.icmpv6,
+            udp: &self.udp,
+            tcp_drop_hook: None,
+            capture: self.capture.as_ref().map(|(capture, _)| capture.as_ref()),
+            stats: &self.net_stats,
+            firewall: &self.firewall,
+        };
+
         let recv = async {
-            recv_loop(&self.rtl8139, &self.arp_table, &self.tcp, &self.rng).await;
+            recv_loop(
+                NetDevice::Rtl8139(&self.rtl8139),
+                &self.ipv4_config,
+                &self.ipv6_config,
+                &self.vlans,
+                &net_stack,
+            )
+            .await;
+        };
+
+        let loopback_config = UpdatedVal::new(Loopback::config());
+        let loopback_ipv6_config = UpdatedVal::new(Ipv6Config::new(ipv6::LOOPBACK));
+        let loopback_recv = async {
+            recv_loop(
+                NetDevice::Loopback(&self.loopback),
+                &loopback_config,
+                &loopback_ipv6_config,
+                &[],
+                &net_stack,
+            )
+            .await;
+        };
+
+        let executor_stats = Arc::new(ExecutorStats::default());
+        let usb_devices = Mutex::new(Vec::new());
+        let shell_interfaces = [("eth0", &self.rtl8139.stats), ("lo", &self.loopback.stats)];
+        let shell_server = async {
+            let mut shell = Shell::new();
+
+            let stats = &executor_stats;
+            shell.register("tasks", "Executor task counters", move |_| async move {
+                format!("{}\n", stats)
+            });
+
+            let (interfaces, net_stats, ipv4, tcp) =
+                (&shell_interfaces, &self.net_stats, &self.ipv4, &self.tcp);
+            shell.register(
+                "net",
+                "Interface, protocol and socket stats",
+                move |_| async move { net_summary(interfaces, net_stats, ipv4, tcp).await },
+            );
+
+            let pci_devices = &self.pci_devices;
+            shell.register("lspci", "List PCI devices", move |_| async move {
+                pci_devices
+                    .iter()
+                    .map(|device| format!("{}\n", device))
+                    .collect()
+            });
+
+            let usb_devices = &usb_devices;
+            shell.register("lsusb", "List USB devices", move |_| async move {
+                usb_devices
+                    .lock()
+                    .await
+                    .iter()
+                    .map(|device| format!("{}\n", device))
+                    .collect()
+            });
+
+            let (dns, udp, time, wakeup_list) = (
+                &self.dns,
+                &self.udp,
+                &self.monotonic_time,
+                &self.wakeup_requester,
+            );
+            shell.register(
+                "tftp <host> <file>",
+                "Download a file and show its size",
+                move |args| async move {
+                    let [host, file] = args.as_slice() else {
+                        return "Usage: tftp <host> <file>\n".into();
+                    };
+                    let server = match dns.resolve(host).await {
+                        Ok(addrs) => addrs[0],
+                        Err(e) => return format!("Failed to resolve {}: {}\n", host, e),
+                    };
+
+                    let tftp = Tftp::new(udp, time, wakeup_list);
+                    match tftp.get(server, file, |_| ()).await {
+                        Ok(data) => format!("Received {} bytes\n", data.len()),
+                        Err(e) => format!("Failed to get {}: {}\n", file, e),
+                    }
+                },
+            );
+
+            let capture = self.capture.as_ref().map(|(capture, _)| capture.as_ref());
+            shell.register(
+                "capture filter <none|filter [or filter]...>",
+                "Limit which frames are captured",
+                move |args| async move { capture_command(capture, &args).await },
+            );
+
+            let firewall = &self.firewall;
+            shell.register(
+                "fw [list|add <rule>|insert <index> <rule>|del <index>|default <action>]",
+                "Show or change the firewall rules",
+                move |args| async move { firewall_command(firewall, &args).await },
+            );
+
+            let wall_clock = &self.wall_clock;
+            shell.register("date", "Current UTC time", move |_| async move {
+                format!("{} UTC\n", DateTime::from_unix(wall_clock.now() as u64))
+            });
+
+            let listener = self.tcp.listen(ipv6::UNSPECIFIED, SHELL_PORT).await;
+            shell.serve(&listener).await;
+        };
+
+        let clock_sync = async {
+            let sntp = Sntp::new(
+                &self.udp,
+                &self.wall_clock,
+                &self.monotonic_time,
+                &self.wakeup_requester,
+            );
+            loop {
+                let server = match self.dns.resolve(NTP_SERVER).await {
+                    Ok(addrs) => Some(addrs[0]),
+                    Err(e) => {
+                        warn!("Failed to resolve {}: {}", NTP_SERVER, e);
+                        None
+                    }
+                };
+
+                if let Some(server) = server {
+                    match sntp.sync(server).await {
+                        Ok((measurement, adjustment)) => {
+                            let now = self.wall_clock.now() as u64;
+                            info!(
+                                "Wall clock {:?} by {:.3}s, delay {:.3}s, stratum {}, now {}",
+                                adjustment,
+                                measurement.offset_s,
+                                measurement.delay_s,
+                                measurement.stratum,
+                                DateTime::from_unix(now)
+                            );
+
+                            // Only worth writing once it is off by more than the RTC's
+                            // resolution
+                            match self.rtc.read() {
+                                Ok(date) if date.to_unix().abs_diff(now) > 1 => {
+                                    info!("Correcting RTC from {}", date);
+                                    if let Err(e) = self.rtc.write(&DateTime::from_unix(now)) {
+                                        warn!("Failed to write rtc: {:?}", e);
+                                    }
+                                }
+                                Ok(_) => (),
+                                Err(e) => warn!("Failed to read rtc: {:?}", e),
+                            }
+                        }
+                        Err(e) => warn!("SNTP sync with {:?} failed: {}", server, e),
+                    }
+                }
+
+                sleep::sleep(
+                    NTP_POLL_INTERVAL_S,
+                    &self.monotonic_time,
+                    &self.wakeup_requester,
+                )
+                .await;
+            }
+        };
+
+        let publish_logs = async {
+            let logs = logger::subscribe().await;
+            loop {
+                let line = logs.recv().await;
+                self.channels.publish("logs", &line);
+            }
+        };
+
+        let cursor_pos = self.cursor.get_pos_reader();
+        let publish_cursor = async {
+            loop {
+                let pos = cursor_pos.wait().await;
+                self.channels
+                    .publish("cursor", &format!("{:.3} {:.3}", pos.x, pos.y));
+            }
         };
 
         let mouse_move_tx = self.cursor.get_movement_writer();
@@ -497,6 +1339,12 @@ impl Kernel {
         let usb_driver_dispatch = async {
             loop {
                 let device = device_rx.recv().await;
+                let descriptor = usb_handle.get_device_descriptor(device.address).await;
+                usb_devices
+                    .lock()
+                    .await
+                    .push(UsbDeviceInfo::new(device.address, &descriptor));
+
                 let descriptors = usb_handle
                     .get_configuration_descriptors(device.address)
                     .await;
@@ -518,12 +1366,21 @@ impl Kernel {
             }
         };
 
-        let mut executor = Executor::new(Some(&self.cpu_dispatcher));
+        let mut executor =
+            Executor::with_stats(Some(&self.cpu_dispatcher), Arc::clone(&executor_stats));
         executor.spawn(logger::service());
         executor.spawn(init_demo);
         executor.spawn(recv);
-        executor.spawn(echo_tcp);
+        executor.spawn(loopback_recv);
+        executor.spawn(http_server);
         executor.spawn(tcp_service);
+        executor.spawn(icmp_service);
+        executor.spawn(udp_service);
+        executor.spawn(dhcp_service);
+        executor.spawn(dhcp_client);
+        executor.spawn(router_solicitation);
+        executor.spawn(capture_service);
+        executor.spawn(exit_service);
         executor.spawn(send_udp);
         executor.spawn(game.run());
         executor.spawn(self.wakeup_service.service());
@@ -532,87 +1389,161 @@ impl Kernel {
         executor.spawn(self.usb.service());
         executor.spawn(usb_driver_dispatch);
         executor.spawn(self.cursor.service());
+        executor.spawn(publish_logs);
+        executor.spawn(publish_cursor);
+        executor.spawn(shell_server);
+        executor.spawn(clock_sync);
+        executor.spawn(publish_task_stats(
+            &executor_stats,
+            &self.channels,
+            &self.monotonic_time,
+            &self.wakeup_requester,
+        ));
         executor.run();
 
         info!("And now we exit/halt");
     }
 }
 
-struct IncompleteHttpRequest;
-
-struct HttpResponse {
-    headers: HashMap<String, String>,
-    body: String,
-}
-
-impl HttpResponse {
-    fn new(body: String, content_type: String) -> HttpResponse {
-        let mut headers = HashMap::new();
-        headers.insert("Content-Length".to_string(), body.len().to_string());
-        headers.insert("Content-Type".to_string(), content_type);
-        headers.insert("Connection".to_string(), "close".into());
-
-        HttpResponse { headers, body }
-    }
+/// netstat and ss style summary of the whole stack
+async fn net_summary(
+    interfaces: &[(&str, &TrafficStats)],
+    net: &NetStats,
+    ipv4: &Ipv4,
+    tcp: &Tcp,
+) -> String {
+    let connections = tcp.connections().await;
+    let netstat = Netstat {
+        interfaces,
+        net,
+        ipv4: &ipv4.stats,
+        tcp: &tcp.stats,
+        tcp_connections: connections.len(),
+    };
+    format!("{}{}", netstat, SocketTable(&connections))
 }
 
-impl core::fmt::Display for HttpResponse {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "HTTP/1.1 200 OK\r\n")?;
-        for (key, value) in &self.headers {
-            write!(f, "{}: {}\r\n", key, value)?;
+/// Rules are written as `accept|drop [on <interface>] [from <prefix>] [to <prefix>]
+/// [proto <protocol>] [port <port>] [log]`
+async fn firewall_command(firewall: &Firewall, args: &[String]) -> String {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] | ["list"] => {
+            let mut ret = String::new();
+            for (index, info) in firewall.rules().await.iter().enumerate() {
+                ret += &format!(
+                    "{}: {} ({} packets, {} bytes)\n",
+                    index, info.rule, info.packets, info.bytes
+                );
+            }
+            ret += &format!("default: {}\n", firewall.default_action().await);
+            ret
+        }
+        ["add", rule @ ..] => {
+            let Ok(rule) = rule.join(" ").parse::<Rule>() else {
+                return "Invalid rule\n".into();
+            };
+            let ret = format!("Added {}\n", rule);
+            firewall.push(rule).await;
+            ret
+        }
+        ["insert", index, rule @ ..] => {
+            let Ok(index) = index.parse() else {
+                return format!("Invalid index {}\n", index);
+            };
+            let Ok(rule) = rule.join(" ").parse::<Rule>() else {
+                return "Invalid rule\n".into();
+            };
+            let ret = format!("Inserted {} at {}\n", rule, index);
+            match firewall.insert(index, rule).await {
+                Ok(()) => ret,
+                Err(InvalidRuleIndex) => format!("No rule slot {}\n", index),
+            }
+        }
+        ["del", index] => {
+            let Ok(index) = index.parse() else {
+                return format!("Invalid index {}\n", index);
+            };
+            match firewall.remove(index).await {
+                Ok(rule) => format!("Removed {}\n", rule),
+                Err(InvalidRuleIndex) => format!("No rule {}\n", index),
+            }
+        }
+        ["default", action] => {
+            let Ok(action) = action.parse() else {
+                return format!("Invalid action {}, expected accept or drop\n", action);
+            };
+            firewall.set_default_action(action).await;
+            format!("Default action is now {}\n", action)
         }
-        write!(f, "\r\n{}", self.body)?;
-        Ok(())
+        _ => "Usage: fw [list|add <rule>|insert <index> <rule>|del <index>|default <action>]\n"
+            .into(),
     }
 }
 
-fn handle_http_request(data: &[u8]) -> Result<HttpResponse, IncompleteHttpRequest> {
-    let first_line_end = data
-        .windows(2)
-        .position(|d| d == b"\r\n")
-        .ok_or(IncompleteHttpRequest)?;
-
-    let request_line = &data[..first_line_end];
-
-    let uri = request_line
-        .split(|b| *b == b' ')
-        .nth(1)
-        .ok_or(IncompleteHttpRequest)?;
-
-    let mut uri_items = uri.splitn(2, |b| *b == b'?');
+/// Filters are written as `[in|out] [vlan <id>] [ether <type>] [host <ipv4>]
+/// [proto <protocol>] [port <port>]`, frames matching any of them are captured
+async fn capture_command(capture: Option<&Capture>, args: &[String]) -> String {
+    let Some(capture) = capture else {
+        return "Capture is not enabled\n".into();
+    };
+    let Some((subcommand, filters)) = args.split_first().filter(|(_, f)| !f.is_empty()) else {
+        return "Usage: capture filter <none|filter [or filter]...>\n".into();
+    };
+    if subcommand != "filter" {
+        return format!("Unknown capture subcommand {}\n", subcommand);
+    }
 
-    let path = uri_items
-        .next()
-        .expect("Should always get at least 1 element");
-    let params = uri_items.next();
+    let filters = filters.join(" ");
+    if filters == "none" {
+        capture.set_filters(Vec::new()).await;
+        return "Capturing every frame\n".into();
+    }
 
-    let ret = match path {
-        b"/" => HttpResponse::new(
-            include_str!("../res/index.html").to_string(),
-            "text/html".into(),
-        ),
-        b"/form" => unsafe {
-            HttpResponse::new(
-                format!(
-                    "Got form request with params: {}",
-                    core::str::from_utf8_unchecked(params.unwrap())
-                ),
-                "text/html".into(),
-            )
-        },
-        _ => HttpResponse::new("Uh oh".to_string(), "text/html".into()),
+    let Ok(filters) = filters
+        .split(" or ")
+        .map(str::parse)
+        .collect::<Result<Vec<CaptureFilter>, _>>()
+    else {
+        return "Invalid filter\n".into();
     };
+    let ret = format!("Capturing frames matching {} filters\n", filters.len());
+    capture.set_filters(filters).await;
+    ret
+}
 
-    Ok(ret)
+async fn publish_task_stats(
+    stats: &ExecutorStats,
+    channels: &PubSub,
+    monotonic_time: &MonotonicTime,
+    wakeup_requester: &WakeupRequester,
+) {
+    let mut last_polls = 0;
+    loop {
+        sleep::sleep(1.0, monotonic_time, wakeup_requester).await;
+
+        let spawned = stats.spawned.load(Ordering::Relaxed);
+        let finished = stats.finished.load(Ordering::Relaxed);
+        let polls = stats.polls.load(Ordering::Relaxed);
+        let message = format!(
+            "{} running, {} finished, {} polls/s",
+            spawned.saturating_sub(finished),
+            finished,
+            polls.wrapping_sub(last_polls)
+        );
+        last_polls = polls;
+        channels.publish("tasks", &message);
+    }
 }
 
 async fn handle_arp_frame(
     arp_frame: &ArpFrame<'_>,
-    rtl8139: &Rtl8139,
-    mac: &[u8; 6],
-    arp_table: &ArpTable,
+    device: NetDevice<'_>,
+    local_ip: &Ipv4Addr,
+    net_stack: &NetStack<'_>,
 ) {
+    let mac = &device.get_mac();
+
     debug!("Received arp frame: {:?}", arp_frame);
 
     match arp_frame.operation() {
@@ -626,7 +1557,7 @@ async fn handle_arp_frame(
                 .sender_protocol_address()
                 .try_into()
                 .expect("Arp ip address not the right size");
-            arp_table.write_mac(&ip, &mac).await;
+            net_stack.arp_table.write_mac(&ip, &mac).await;
             return;
         }
         Err(UnknownArpOperation(v)) => {
@@ -638,8 +1569,7 @@ async fn handle_arp_frame(
         return;
     }
 
-    if arp_frame.target_hardware_address() != mac
-        && arp_frame.target_protocol_address() != STATIC_IP
+    if arp_frame.target_hardware_address() != mac && arp_frame.target_protocol_address() != local_ip
     {
         return;
     }
@@ -657,7 +1587,7 @@ async fn handle_arp_frame(
     );
     params.operation = ArpOperation::Reply;
     params.sender_hardware_address = *mac;
-    params.sender_protocol_address = STATIC_IP;
+    params.sender_protocol_address = *local_ip;
 
     let response = net::generate_arp_frame(&params);
 
@@ -668,39 +1598,374 @@ async fn handle_arp_frame(
             .expect("Invalid length for dest mac"),
         source_mac: *mac,
         ether_type: EtherType::Arp,
+        vlan: device.vlan_tag(),
         payload: &response,
     });
 
-    rtl8139.write(&response_frame).await.unwrap();
+    device.write(&response_frame).await.unwrap();
+    net_stack.stats.arp.record_tx(response.len());
+}
+
+fn firewall_rules() -> Vec<Rule> {
+    let mut rules = vec![Rule {
+        interface: Some(Interface::Loopback),
+        ..Rule::accept()
+    }];
+
+    for (protocol, port) in [
+        (net::Ipv4Protocol::Tcp, 80),
+        (net::Ipv4Protocol::Tcp, SHELL_PORT),
+        (net::Ipv4Protocol::Udp, EXIT_PORT),
+    ] {
+        for prefix in TRUSTED_PREFIXES {
+            rules.push(Rule {
+                source: Some(prefix),
+                protocol: Some(protocol),
+                port: Some(port),
+                ..Rule::accept()
+            });
+        }
+        rules.push(Rule {
+            protocol: Some(protocol),
+            port: Some(port),
+            log: true,
+            ..Rule::drop()
+        });
+    }
+
+    rules
+}
+
+/// Runs received packets through the firewall, dropped packets are counted
+async fn firewall_accepts(net_stack: &NetStack<'_>, packet: &PacketInfo) -> bool {
+    match net_stack.firewall.evaluate(packet).await {
+        Action::Accept => true,
+        Action::Drop => {
+            net_stack.stats.drops.record(DropReason::Filtered);
+            false
+        }
+    }
+}
+
+async fn send_ipv4_reply(
+    net_stack: &NetStack<'_>,
+    device: NetDevice<'_>,
+    ethernet_frame: &EthernetFrame<'_>,
+    payload: &[u8],
+    protocol: net::Ipv4Protocol,
+    local_ip: &Ipv4Addr,
+    remote_ip: &Ipv4Addr,
+) {
+    let ipv4_frames = match net_stack.ipv4.generate_frames(
+        payload,
+        protocol,
+        local_ip,
+        remote_ip,
+        device.mtu(),
+        dont_fragment(protocol),
+    ) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Dropping reply to {:?}: {:?}", remote_ip, e);
+            return;
+        }
+    };
+
+    for ipv4_frame in &ipv4_frames {
+        let response_ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
+            dest_mac: ethernet_frame
+                .source_mac()
+                .try_into()
+                .expect("invalid source mac length"),
+            source_mac: device.get_mac(),
+            ether_type: EtherType::Ipv4,
+            vlan: device.vlan_tag(),
+            payload: ipv4_frame,
+        });
+
+        device.write(&response_ethernet_frame).await.unwrap();
+        net_stack.stats.ipv4.record_tx(ipv4_frame.len());
+    }
+    net_stack.stats.record_protocol_tx(protocol, payload.len());
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_ipv6_reply(
+    net_stack: &NetStack<'_>,
+    device: NetDevice<'_>,
+    ethernet_frame: &EthernetFrame<'_>,
+    payload: &[u8],
+    next_header: net::Ipv4Protocol,
+    local_ip: &Ipv6Addr,
+    remote_ip: &Ipv6Addr,
+    hop_limit: u8,
+) {
+    let ipv6_frame =
+        ipv6::generate_ipv6_frame(payload, next_header, local_ip, remote_ip, hop_limit);
+    if ipv6_frame.len() > device.mtu() {
+        warn!("Dropping oversized reply to {:02x?}", remote_ip);
+        return;
+    }
+
+    let dest_mac = if ipv6::is_multicast(remote_ip) {
+        ipv6::multicast_mac(remote_ip)
+    } else {
+        ethernet_frame
+            .source_mac()
+            .try_into()
+            .expect("invalid source mac length")
+    };
+
+    let response_ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
+        dest_mac,
+        source_mac: device.get_mac(),
+        ether_type: EtherType::Ipv6,
+        vlan: device.vlan_tag(),
+        payload: &ipv6_frame,
+    });
+
+    device.write(&response_ethernet_frame).await.unwrap();
+    net_stack.stats.ipv6.record_tx(ipv6_frame.len());
+    net_stack
+        .stats
+        .record_protocol_tx(next_header, payload.len());
+}
+
+async fn handle_ipv6_frame(
+    ipv6_frame: &Ipv6Frame<'_>,
+    device: NetDevice<'_>,
+    ethernet_frame: &EthernetFrame<'_>,
+    ipv6_config: &Ipv6Config,
+    net_stack: &NetStack<'_>,
+) {
+    let source_ip = ipv6_frame.source_ip();
+    let dest_ip = ipv6_frame.dest_ip();
+    if !ipv6_config.accepts(&dest_ip) {
+        return;
+    }
+
+    let stats = net_stack.stats;
+    stats.record_protocol_rx(ipv6_frame.next_header(), ipv6_frame.payload().len());
+
+    let frame = net::parse_ipv6(ipv6_frame);
+    let filter_packet = PacketInfo {
+        interface: Interface::from(device),
+        source_ip: IpAddr::V6(source_ip),
+        dest_ip: IpAddr::V6(dest_ip),
+        protocol: ipv6_frame.next_header(),
+        dest_port: match &frame {
+            Ok(ParsedIpv6Frame::Udp(udp_frame)) => Some(udp_frame.dest_port()),
+            Ok(ParsedIpv6Frame::Tcp(tcp_frame)) => Some(tcp_frame.dest_port()),
+            _ => None,
+        },
+        len: ipv6_frame.payload().len(),
+    };
+    if !firewall_accepts(net_stack, &filter_packet).await {
+        return;
+    }
+
+    match frame {
+        Ok(ParsedIpv6Frame::Icmpv6(icmpv6_frame)) => {
+            if !icmpv6_frame.checksum_valid(&source_ip, &dest_ip) {
+                debug!("Dropping ICMPv6 frame with invalid checksum");
+                stats.drops.record(DropReason::BadChecksum);
+                return;
+            }
+
+            let response = net_stack
+                .icmpv6
+                .handle_frame(&icmpv6_frame, &source_ip, &dest_ip, ipv6_frame.hop_limit())
+                .await;
+            if let Some((ip, mac)) = response.neighbor {
+                net_stack.ndp_table.write_mac(&ip, &mac).await;
+            }
+            if let Some(reply) = response.reply {
+                send_ipv6_reply(
+                    net_stack,
+                    device,
+                    ethernet_frame,
+                    &reply.payload,
+                    net::Ipv4Protocol::Icmpv6,
+                    &reply.local_ip,
+                    &reply.remote_ip,
+                    reply.hop_limit,
+                )
+                .await;
+            }
+        }
+        Ok(ParsedIpv6Frame::Udp(udp_frame)) => {
+            let delivery = net_stack
+                .udp
+                .handle_frame(&udp_frame, &IpAddr::V6(source_ip), &IpAddr::V6(dest_ip))
+                .await;
+            match delivery {
+                Ok(()) => (),
+                Err(e) => {
+                    debug!("Dropping UDP frame: {:?}", e);
+                    stats.drops.record(match e {
+                        UdpDeliveryError::InvalidChecksum => DropReason::BadChecksum,
+                        UdpDeliveryError::PortUnreachable => DropReason::NoListener,
+                    });
+                }
+            }
+        }
+        Ok(ParsedIpv6Frame::Tcp(tcp_frame)) => {
+            if matches!(net_stack.tcp_drop_hook, Some(hook) if hook(&tcp_frame)) {
+                info!("Dropping packet");
+                return;
+            }
+            let response_tcp_frame = net_stack
+                .tcp
+                .handle_frame(&tcp_frame, &IpAddr::V6(source_ip), &IpAddr::V6(dest_ip))
+                .await;
+            if let Some(response_tcp_frame) = response_tcp_frame {
+                send_ipv6_reply(
+                    net_stack,
+                    device,
+                    ethernet_frame,
+                    &response_tcp_frame,
+                    net::Ipv4Protocol::Tcp,
+                    &dest_ip,
+                    &source_ip,
+                    ipv6::DEFAULT_HOP_LIMIT,
+                )
+                .await;
+            }
+        }
+        Ok(ParsedIpv6Frame::Unknown(p)) => {
+            debug!("Unknown ipv6 next header {:?}", p);
+            stats.drops.record(DropReason::UnknownProtocol);
+        }
+        Err(e) => {
+            match e {
+                ParseIpv6Error::Icmpv6(e) => debug!("Invalid icmpv6 frame: {:?}", e),
+                ParseIpv6Error::Udp(e) => debug!("Invalid udp frame: {:?}", e),
+            }
+            stats.drops.record(DropReason::Malformed);
+        }
+    }
 }
 
 // FIXME: Where does this belong?
 async fn handle_packet(
     packet: Vec<u8>,
-    rtl8139: &Rtl8139,
-    mac: &[u8; 6],
-    arp_table: &ArpTable,
-    tcp: &Tcp,
-    rng: &Mutex<Rng>,
+    device: NetDevice<'_>,
+    local_ip: &Ipv4Addr,
+    ipv6_config: &Ipv6Config,
+    vlan_ips: &[(VlanTag, Ipv4Addr)],
+    net_stack: &NetStack<'_>,
 ) {
     let packet = net::parse_packet(&packet);
 
     let packet = match packet {
         Ok(v) => v,
         Err(e) => {
-            debug!("Received invalid packet: {:?}", e);
+            match e {
+                ParsePacketError::Ethernet(e) => debug!("Invalid ethernet frame: {:?}", e),
+                ParsePacketError::Arp(e) => debug!("Invalid arp frame: {:?}", e),
+                ParsePacketError::Ipv4(e) => {
+                    net_stack.ipv4.record_invalid_frame();
+                    debug!("Invalid ipv4 frame: {:?}", e);
+                }
+                ParsePacketError::Ipv6(e) => debug!("Invalid ipv6 frame: {:?}", e),
+            }
+            net_stack.stats.drops.record(DropReason::Malformed);
             return;
         }
     };
 
+    // Tagged frames belong to the sub-interface with their VLAN ID, which has its own address
+    let (device, local_ip) = match packet.ethernet.vlan_tag() {
+        None => (device, *local_ip),
+        Some(tag) => {
+            let interface = vlan_ips
+                .iter()
+                .find(|(vlan, _)| vlan.id == tag.id)
+                .and_then(|(vlan, ip)| Some((device.with_vlan_tag(*vlan)?, *ip)));
+            match interface {
+                Some(v) => v,
+                None => {
+                    debug!("Dropping frame for unknown VLAN {}", tag.id);
+                    net_stack.stats.drops.record(DropReason::UnknownVlan);
+                    return;
+                }
+            }
+        }
+    };
+    let local_ip = &local_ip;
+    let stats = net_stack.stats;
+
     match packet.inner {
         ParsedPacket::Arp(arp_frame) => {
-            handle_arp_frame(&arp_frame, rtl8139, mac, arp_table).await;
+            stats.arp.record_rx(packet.ethernet.payload().len());
+            handle_arp_frame(&arp_frame, device, local_ip, net_stack).await;
         }
         ParsedPacket::Ipv4(ipv4_frame) => {
             debug!("Received IPV4 frame");
+            stats.ipv4.record_rx(ipv4_frame.datagram().len());
+            let datagram = match net_stack.ipv4.reassemble(&ipv4_frame).await {
+                Some(v) => v,
+                None => return,
+            };
+            let ipv4_frame =
+                Ipv4Frame::new(&datagram).expect("Reassembled datagrams have valid headers");
+            stats.record_protocol_rx(ipv4_frame.protocol(), ipv4_frame.payload().len());
             let frame = net::parse_ipv4(&ipv4_frame);
+            let filter_packet = PacketInfo {
+                interface: Interface::from(device),
+                source_ip: IpAddr::V4(ipv4_frame.source_ip()),
+                dest_ip: IpAddr::V4(ipv4_frame.dest_ip()),
+                protocol: ipv4_frame.protocol(),
+                dest_port: match &frame {
+                    Ok(ParsedIpv4Frame::Udp(udp_frame)) => Some(udp_frame.dest_port()),
+                    Ok(ParsedIpv4Frame::Tcp(tcp_frame)) => Some(tcp_frame.dest_port()),
+                    _ => None,
+                },
+                len: ipv4_frame.datagram().len(),
+            };
+            if !firewall_accepts(net_stack, &filter_packet).await {
+                return;
+            }
+
             match frame {
+                Ok(ParsedIpv4Frame::Icmp(icmp_frame)) => {
+                    match icmp_frame.unreachable_datagram() {
+                        Some(datagram)
+                            if icmp_frame.checksum_valid()
+                                && datagram.protocol == net::Ipv4Protocol::Tcp =>
+                        {
+                            net_stack
+                                .tcp
+                                .handle_unreachable(
+                                    icmp_frame.code(),
+                                    &IpAddr::V4(datagram.source_ip),
+                                    datagram.source_port,
+                                    &IpAddr::V4(datagram.dest_ip),
+                                    datagram.dest_port,
+                                    &datagram.transport_header,
+                                )
+                                .await;
+                        }
+                        _ => (),
+                    }
+
+                    if let Some(response) = net_stack
+                        .icmp
+                        .handle_frame(&icmp_frame, &ipv4_frame.source_ip())
+                        .await
+                    {
+                        send_ipv4_reply(
+                            net_stack,
+                            device,
+                            &packet.ethernet,
+                            &response,
+                            net::Ipv4Protocol::Icmp,
+                            local_ip,
+                            &ipv4_frame.source_ip(),
+                        )
+                        .await;
+                    }
+                }
                 Ok(ParsedIpv4Frame::Udp(udp_frame)) => {
                     unsafe {
                         debug!(
@@ -708,66 +1973,147 @@ async fn handle_packet(
                             core::str::from_utf8_unchecked(udp_frame.data())
                         );
                     }
-                    if udp_frame.data() == b"exit\n" {
-                        unsafe {
-                            io::exit(0);
+
+                    let delivery = net_stack
+                        .udp
+                        .handle_frame(
+                            &udp_frame,
+                            &IpAddr::V4(ipv4_frame.source_ip()),
+                            &IpAddr::V4(ipv4_frame.dest_ip()),
+                        )
+                        .await;
+
+                    match delivery {
+                        Ok(()) => return,
+                        Err(UdpDeliveryError::InvalidChecksum) => {
+                            debug!("Dropping UDP frame with invalid checksum");
+                            stats.drops.record(DropReason::BadChecksum);
+                            return;
+                        }
+                        Err(UdpDeliveryError::PortUnreachable) => {
+                            stats.drops.record(DropReason::NoListener);
                         }
                     }
+
+                    // ICMP errors must never be sent in response to broadcast/multicast traffic
+                    // (RFC 1122)
+                    let is_multicast = packet.ethernet.destination_mac()[0] & 1 == 1;
+                    if is_multicast {
+                        return;
+                    }
+
+                    let response =
+                        icmp::generate_destination_unreachable(UnreachableCode::Port, &ipv4_frame);
+                    send_ipv4_reply(
+                        net_stack,
+                        device,
+                        &packet.ethernet,
+                        &response,
+                        net::Ipv4Protocol::Icmp,
+                        local_ip,
+                        &ipv4_frame.source_ip(),
+                    )
+                    .await;
                 }
                 Ok(ParsedIpv4Frame::Tcp(tcp_frame)) => {
-                    //if rng.lock().await.normalized() < 0.1 {
-                    //    info!("Dropping packet");
-                    //    return
-                    //}
-                    let response_tcp_frame = tcp
-                        .handle_frame(&tcp_frame, &ipv4_frame.source_ip(), &STATIC_IP, rng)
+                    if matches!(net_stack.tcp_drop_hook, Some(hook) if hook(&tcp_frame)) {
+                        info!("Dropping packet");
+                        return;
+                    }
+                    let response_tcp_frame = net_stack
+                        .tcp
+                        .handle_frame(
+                            &tcp_frame,
+                            &IpAddr::V4(ipv4_frame.source_ip()),
+                            &IpAddr::V4(*local_ip),
+                        )
                         .await;
                     if let Some(response_tcp_frame) = response_tcp_frame {
-                        let response_ipv4_frame = net::generate_ipv4_frame(
+                        send_ipv4_reply(
+                            net_stack,
+                            device,
+                            &packet.ethernet,
                             &response_tcp_frame,
                             net::Ipv4Protocol::Tcp,
-                            &STATIC_IP,
+                            local_ip,
                             &ipv4_frame.source_ip(),
-                        );
-
-                        let response_ethernet_frame =
-                            net::generate_ethernet_frame(&EthernetFrameParams {
-                                dest_mac: packet
-                                    .ethernet
-                                    .source_mac()
-                                    .try_into()
-                                    .expect("invalid source mac length"),
-                                source_mac: rtl8139.get_mac(),
-                                ether_type: EtherType::Ipv4,
-                                payload: &response_ipv4_frame,
-                            });
-
-                        rtl8139.write(&response_ethernet_frame).await.unwrap();
+                        )
+                        .await;
                     }
                 }
                 Ok(ParsedIpv4Frame::Unknown(p)) => {
                     debug!("Unknown ipv4 protocol {:?}", p);
+                    stats.drops.record(DropReason::UnknownProtocol);
                 }
                 Err(e) => {
-                    debug!("Invalid ipv4 packet: {:?}", e);
+                    match e {
+                        ParseIpv4Error::Icmp(e) => debug!("Invalid icmp frame: {:?}", e),
+                        ParseIpv4Error::Udp(e) => debug!("Invalid udp frame: {:?}", e),
+                    }
+                    stats.drops.record(DropReason::Malformed);
                 }
             }
         }
+        ParsedPacket::Ipv6(_) if device.vlan_tag().is_some() => {
+            debug!("Dropping ipv6 frame on VLAN sub-interface");
+        }
+        ParsedPacket::Ipv6(ipv6_frame) => {
+            stats.ipv6.record_rx(packet.ethernet.payload().len());
+            handle_ipv6_frame(
+                &ipv6_frame,
+                device,
+                &packet.ethernet,
+                ipv6_config,
+                net_stack,
+            )
+            .await;
+        }
         ParsedPacket::Unknown(t) => {
             debug!("Found unknown packet type: {:#06x}", t);
+            stats.drops.record(DropReason::UnknownEtherType);
         }
     }
 }
 
-async fn recv_loop(rtl8139: &Rtl8139, arp_table: &ArpTable, tcp: &Tcp, rng: &Mutex<Rng>) {
-    let mac = rtl8139.get_mac();
-
+async fn recv_loop(
+    device: NetDevice<'_>,
+    ipv4_config: &UpdatedVal<Ipv4Config>,
+    ipv6_config: &UpdatedVal<Ipv6Config>,
+    vlans: &[VlanInterface],
+    net_stack: &NetStack<'_>,
+) {
     loop {
         debug!("Waiting for a packet");
-        rtl8139
+        let local_ip = ipv4_config.read().await.address;
+        let local_ipv6_config = ipv6_config.read().await;
+        let mut vlan_ips = Vec::with_capacity(vlans.len());
+        for vlan in vlans {
+            vlan_ips.push((vlan.tag, vlan.ipv4_config.read().await.address));
+        }
+        let (local_ip, local_ipv6_config, vlan_ips) = (&local_ip, &local_ipv6_config, &vlan_ips);
+        device
             .read(|packet| {
                 // FIXME: Avoid copying but types are hard
-                handle_packet(packet.to_vec(), rtl8139, &mac, arp_table, tcp, rng)
+                let packet = packet.to_vec();
+                async move {
+                    if let Some(capture) = net_stack.capture {
+                        // Received frames still have the FCS
+                        let frame = &packet[..packet.len().saturating_sub(4)];
+                        capture
+                            .record(CaptureInterface::from(device), Direction::Inbound, frame)
+                            .await;
+                    }
+
+                    handle_packet(
+                        packet,
+                        device,
+                        local_ip,
+                        local_ipv6_config,
+                        vlan_ips,
+                        net_stack,
+                    )
+                    .await
+                }
             })
             .await;
     }
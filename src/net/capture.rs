@@ -0,0 +1,510 @@
+use crate::{
+    net::{dns::parse_ipv4_literal, vlan::VlanTag, IpAddr, Ipv4Protocol, NetDevice},
+    time::MonotonicTime,
+    util::{
+        async_channel::{self, Receiver, Sender},
+        async_mutex::Mutex,
+    },
+};
+
+use alloc::{sync::Arc, vec::Vec};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Interface ids as described in the section header, VLAN sub-interfaces share their parent's
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u32)]
+pub enum CaptureInterface {
+    Ethernet = 0,
+    Loopback = 1,
+}
+
+impl CaptureInterface {
+    const ALL: [CaptureInterface; 2] = [CaptureInterface::Ethernet, CaptureInterface::Loopback];
+
+    fn name(&self) -> &'static str {
+        match self {
+            CaptureInterface::Ethernet => "eth0",
+            CaptureInterface::Loopback => "lo",
+        }
+    }
+}
+
+impl From<NetDevice<'_>> for CaptureInterface {
+    fn from(device: NetDevice<'_>) -> CaptureInterface {
+        match device {
+            NetDevice::Rtl8139(_) | NetDevice::Vlan(_, _) => CaptureInterface::Ethernet,
+            NetDevice::Loopback(_) => CaptureInterface::Loopback,
+        }
+    }
+}
+
+/// A frame matches when every field that is set matches. Addresses and ports match either
+/// the source or the destination
+#[derive(Debug, Default, Clone)]
+pub struct CaptureFilter {
+    pub direction: Option<Direction>,
+    pub vlan: Option<u16>,
+    pub ether_type: Option<u16>,
+    pub ip: Option<IpAddr>,
+    pub protocol: Option<Ipv4Protocol>,
+    pub port: Option<u16>,
+}
+
+impl CaptureFilter {
+    fn matches(&self, direction: Direction, frame: &FrameSummary) -> bool {
+        fn field_matches<T: PartialEq>(filter: &Option<T>, val: Option<T>) -> bool {
+            filter.is_none() || *filter == val
+        }
+
+        field_matches(&self.direction, Some(direction))
+            && field_matches(&self.vlan, frame.vlan)
+            && field_matches(&self.ether_type, Some(frame.ether_type))
+            && field_matches(&self.protocol, frame.protocol)
+            && (self.ip.is_none() || self.ip == frame.source_ip || self.ip == frame.dest_ip)
+            && (self.port.is_none()
+                || self.port == frame.source_port
+                || self.port == frame.dest_port)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidCaptureFilter;
+
+impl CaptureFilter {
+    fn set_field(&mut self, name: &str, value: &str) -> Result<(), InvalidCaptureFilter> {
+        match name {
+            "vlan" => self.vlan = Some(value.parse().map_err(|_| InvalidCaptureFilter)?),
+            "ether" => {
+                let ether_type = match value.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16),
+                    None => value.parse(),
+                };
+                self.ether_type = Some(ether_type.map_err(|_| InvalidCaptureFilter)?);
+            }
+            "host" => {
+                let ip = parse_ipv4_literal(value).ok_or(InvalidCaptureFilter)?;
+                self.ip = Some(ip.into());
+            }
+            "proto" => self.protocol = Some(value.parse().map_err(|_| InvalidCaptureFilter)?),
+            "port" => self.port = Some(value.parse().map_err(|_| InvalidCaptureFilter)?),
+            _ => return Err(InvalidCaptureFilter),
+        }
+        Ok(())
+    }
+}
+
+/// tcpdump like words: `[in|out] [vlan <id>] [ether <type>] [host <ipv4>] [proto <protocol>]
+/// [port <port>]`, the ether type may be given in hex with a 0x prefix
+impl core::str::FromStr for CaptureFilter {
+    type Err = InvalidCaptureFilter;
+
+    fn from_str(s: &str) -> Result<CaptureFilter, InvalidCaptureFilter> {
+        let mut ret = CaptureFilter::default();
+        let mut words = s.split_whitespace();
+        while let Some(word) = words.next() {
+            match word {
+                "in" => ret.direction = Some(Direction::Inbound),
+                "out" => ret.direction = Some(Direction::Outbound),
+                _ => ret.set_field(word, words.next().ok_or(InvalidCaptureFilter)?)?,
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+/// Fields filters look at. This is parsed by hand as transmitted frames have no FCS and
+/// fragments or truncated frames should still be captured
+#[derive(Default)]
+struct FrameSummary {
+    vlan: Option<u16>,
+    ether_type: u16,
+    source_ip: Option<IpAddr>,
+    dest_ip: Option<IpAddr>,
+    protocol: Option<Ipv4Protocol>,
+    source_port: Option<u16>,
+    dest_port: Option<u16>,
+}
+
+impl FrameSummary {
+    fn new(frame: &[u8]) -> FrameSummary {
+        let mut ret = FrameSummary::default();
+        let read_u16 = |offset: usize| -> Option<u16> {
+            Some(u16::from_be_bytes(
+                frame.get(offset..offset + 2)?.try_into().ok()?,
+            ))
+        };
+
+        let mut ether_type_offset = 12;
+        let Some(mut ether_type) = read_u16(ether_type_offset) else {
+            return ret;
+        };
+        if ether_type == VlanTag::TPID {
+            ret.vlan = read_u16(14).map(|tci| VlanTag::from_tci(tci).id);
+            ether_type_offset = 16;
+            ether_type = read_u16(ether_type_offset).unwrap_or(0);
+        }
+        ret.ether_type = ether_type;
+
+        let ip = &frame[(ether_type_offset + 2).min(frame.len())..];
+        let transport_offset = match ether_type {
+            0x0800 if ip.len() >= 20 => {
+                let source_ip: [u8; 4] = ip[12..16].try_into().expect("slice is 4 bytes");
+                let dest_ip: [u8; 4] = ip[16..20].try_into().expect("slice is 4 bytes");
+                ret.source_ip = Some(source_ip.into());
+                ret.dest_ip = Some(dest_ip.into());
+                ret.protocol = Some(ip[9].into());
+
+                // Only the first fragment has the transport header
+                let fragment_offset = u16::from_be_bytes([ip[6], ip[7]]) & 0x1fff;
+                (fragment_offset == 0).then_some((ip[0] & 0xf) as usize * 4)
+            }
+            0x86DD if ip.len() >= 40 => {
+                let source_ip: [u8; 16] = ip[8..24].try_into().expect("slice is 16 bytes");
+                let dest_ip: [u8; 16] = ip[24..40].try_into().expect("slice is 16 bytes");
+                ret.source_ip = Some(source_ip.into());
+                ret.dest_ip = Some(dest_ip.into());
+                ret.protocol = Some(ip[6].into());
+                Some(40)
+            }
+            _ => None,
+        };
+
+        if let (Some(offset), Some(Ipv4Protocol::Tcp | Ipv4Protocol::Udp)) =
+            (transport_offset, ret.protocol)
+        {
+            // TCP and UDP both start with the source and destination port
+            if let Some(ports) = ip.get(offset..offset + 4) {
+                ret.source_port = Some(u16::from_be_bytes([ports[0], ports[1]]));
+                ret.dest_port = Some(u16::from_be_bytes([ports[2], ports[3]]));
+            }
+        }
+
+        ret
+    }
+}
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x00000001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x00000006;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_ENDOFOPT: u16 = 0;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+
+fn pad_to_u32(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn write_option(buf: &mut Vec<u8>, code: u16, val: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(val.len() as u16).to_le_bytes());
+    buf.extend_from_slice(val);
+    pad_to_u32(buf);
+}
+
+/// Blocks are written in our (little endian) byte order, readers detect it from the section
+/// header
+fn generate_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    debug_assert!(body.len().is_multiple_of(4));
+    let total_length = (body.len() + 12) as u32;
+
+    let mut ret = Vec::with_capacity(total_length as usize);
+    ret.extend_from_slice(&block_type.to_le_bytes());
+    ret.extend_from_slice(&total_length.to_le_bytes());
+    ret.extend_from_slice(body);
+    ret.extend_from_slice(&total_length.to_le_bytes());
+    ret
+}
+
+/// Section header followed by a description of every interface, has to be at the start of a
+/// stream for it to be readable
+pub fn generate_section_header() -> Vec<u8> {
+    let mut body = Vec::new();
+    const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    // Version 1.0
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // Section length is unknown when streaming
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    let mut ret = generate_block(SECTION_HEADER_BLOCK, &body);
+
+    for interface in CaptureInterface::ALL {
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // No snap length limit
+        body.extend_from_slice(&0u32.to_le_bytes());
+        write_option(&mut body, IF_NAME, interface.name().as_bytes());
+        write_option(&mut body, OPT_ENDOFOPT, &[]);
+        ret.extend_from_slice(&generate_block(INTERFACE_DESCRIPTION_BLOCK, &body));
+    }
+
+    ret
+}
+
+/// Timestamps use the default resolution of microseconds
+pub fn generate_enhanced_packet_block(
+    interface: CaptureInterface,
+    timestamp_us: u64,
+    direction: Direction,
+    frame: &[u8],
+) -> Vec<u8> {
+    let mut body = Vec::with_capacity(frame.len() + 40);
+    body.extend_from_slice(&(interface as u32).to_le_bytes());
+    body.extend_from_slice(&((timestamp_us >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp_us as u32).to_le_bytes());
+    // Captured and original length
+    body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    body.extend_from_slice(frame);
+    pad_to_u32(&mut body);
+
+    let flags: u32 = match direction {
+        Direction::Inbound => 0b01,
+        Direction::Outbound => 0b10,
+    };
+    write_option(&mut body, EPB_FLAGS, &flags.to_le_bytes());
+    write_option(&mut body, OPT_ENDOFOPT, &[]);
+
+    generate_block(ENHANCED_PACKET_BLOCK, &body)
+}
+
+/// Turns frames into pcap-ng records for the service to stream out. With no filters set every
+/// frame is captured, otherwise frames have to match at least one filter
+pub struct Capture {
+    monotonic_time: Arc<MonotonicTime>,
+    filters: Mutex<Vec<CaptureFilter>>,
+    // The port records are sent to when streaming over UDP, capturing those would feed back
+    ignored_udp_port: Option<u16>,
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl Capture {
+    pub fn new(monotonic_time: Arc<MonotonicTime>, ignored_udp_port: Option<u16>) -> Capture {
+        let (tx, rx) = async_channel::channel();
+        Capture {
+            monotonic_time,
+            filters: Mutex::new(Vec::new()),
+            ignored_udp_port,
+            tx,
+            rx,
+        }
+    }
+
+    pub async fn set_filters(&self, filters: Vec<CaptureFilter>) {
+        *self.filters.lock().await = filters;
+    }
+
+    async fn should_capture(&self, direction: Direction, frame: &[u8]) -> bool {
+        let summary = FrameSummary::new(frame);
+        if summary.protocol == Some(Ipv4Protocol::Udp)
+            && self.ignored_udp_port.is_some()
+            && summary.dest_port == self.ignored_udp_port
+        {
+            return false;
+        }
+
+        let filters = self.filters.lock().await;
+        filters.is_empty() || filters.iter().any(|f| f.matches(direction, &summary))
+    }
+
+    /// Frames are expected without an FCS
+    pub async fn record(&self, interface: CaptureInterface, direction: Direction, frame: &[u8]) {
+        if !self.should_capture(direction, frame).await {
+            return;
+        }
+
+        let timestamp_us = (self.monotonic_time.get() as f64
+            / self.monotonic_time.tick_freq() as f64
+            * 1e6) as u64;
+        let record = generate_enhanced_packet_block(interface, timestamp_us, direction, frame);
+        self.tx.send(record).await;
+    }
+
+    /// Writes the section header and then every record as it comes in
+    pub async fn service<F, Fut>(&self, write: F)
+    where
+        F: Fn(Vec<u8>) -> Fut,
+        Fut: core::future::Future<Output = ()>,
+    {
+        write(generate_section_header()).await;
+        loop {
+            let record = self.rx.recv().await;
+            write(record).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        net::{self, EtherType, EthernetFrameParams},
+        testing::*,
+    };
+    use alloc::string::ToString;
+
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn udp_frame(vlan: Option<VlanTag>, dest_port: u16) -> Vec<u8> {
+        let source_ip = [192, 168, 2, 2];
+        let dest_ip = [192, 168, 2, 1];
+        let udp = net::generate_udp_frame(
+            &source_ip.into(),
+            &dest_ip.into(),
+            4000,
+            dest_port,
+            b"hello",
+        );
+        let ip = net::generate_ipv4_frame(&udp, Ipv4Protocol::Udp, &source_ip, &dest_ip);
+        net::generate_ethernet_frame(&EthernetFrameParams {
+            dest_mac: [0xff; 6],
+            source_mac: [1; 6],
+            ether_type: EtherType::Ipv4,
+            vlan,
+            payload: &ip,
+        })
+    }
+
+    create_test!(test_pcapng_blocks, {
+        let header = generate_section_header();
+        test_eq!(read_u32(&header, 0), SECTION_HEADER_BLOCK);
+        test_eq!(read_u32(&header, 8), 0x1a2b3c4d);
+        let shb_len = read_u32(&header, 4) as usize;
+        test_eq!(shb_len, 28);
+        test_eq!(read_u32(&header, shb_len - 4) as usize, shb_len);
+
+        // eth0 padded to 4 bytes, then lo
+        let idb = &header[shb_len..];
+        test_eq!(read_u32(idb, 0), INTERFACE_DESCRIPTION_BLOCK);
+        test_eq!(read_u32(idb, 4), 32);
+        test_eq!(&idb[16..24], b"\x02\x00\x04\x00eth0");
+        test_eq!(header.len(), 28 + 32 + 32);
+
+        let frame = [0xaa; 61];
+        let epb = generate_enhanced_packet_block(
+            CaptureInterface::Loopback,
+            0x1_0000_0002,
+            Direction::Outbound,
+            &frame,
+        );
+        // Header, 64 bytes of padded frame, flags option, end of options, trailing length
+        test_eq!(epb.len(), 28 + 64 + 8 + 4 + 4);
+        test_eq!(read_u32(&epb, 0), ENHANCED_PACKET_BLOCK);
+        test_eq!(read_u32(&epb, 4) as usize, epb.len());
+        test_eq!(read_u32(&epb, 8), 1);
+        test_eq!(read_u32(&epb, 12), 1);
+        test_eq!(read_u32(&epb, 16), 2);
+        test_eq!(read_u32(&epb, 20), 61);
+        test_eq!(&epb[28..89], &frame);
+        test_eq!(&epb[89..92], &[0, 0, 0]);
+        test_eq!(read_u32(&epb, 96), 0b10);
+
+        Ok(())
+    });
+
+    create_test!(test_capture_filter, {
+        let frame = udp_frame(None, 6000);
+        let summary = FrameSummary::new(&frame);
+        test_eq!(summary.ether_type, 0x0800);
+        test_eq!(summary.protocol, Some(Ipv4Protocol::Udp));
+        test_eq!(summary.source_port, Some(4000));
+        test_eq!(summary.dest_port, Some(6000));
+
+        test_true!(CaptureFilter::default().matches(Direction::Inbound, &summary));
+        let filter = CaptureFilter {
+            ip: Some([192, 168, 2, 1].into()),
+            port: Some(4000),
+            ..Default::default()
+        };
+        test_true!(filter.matches(Direction::Inbound, &summary));
+        let filter = CaptureFilter {
+            direction: Some(Direction::Outbound),
+            ..Default::default()
+        };
+        test_false!(filter.matches(Direction::Inbound, &summary));
+        let filter = CaptureFilter {
+            protocol: Some(Ipv4Protocol::Tcp),
+            ..Default::default()
+        };
+        test_false!(filter.matches(Direction::Inbound, &summary));
+
+        let tagged = FrameSummary::new(&udp_frame(Some(VlanTag { id: 7, priority: 0 }), 6000));
+        test_eq!(tagged.vlan, Some(7));
+        test_eq!(tagged.dest_port, Some(6000));
+        let filter = CaptureFilter {
+            vlan: Some(7),
+            ..Default::default()
+        };
+        test_true!(filter.matches(Direction::Inbound, &tagged));
+        test_false!(filter.matches(Direction::Inbound, &summary));
+
+        // Truncated frames are still summarized as far as possible
+        let truncated = FrameSummary::new(&frame[..20]);
+        test_eq!(truncated.ether_type, 0x0800);
+        test_true!(truncated.source_ip.is_none());
+
+        Ok(())
+    });
+
+    create_test!(test_capture_filter_parsing, {
+        let filter = "in vlan 7 ether 0x0800 host 192.168.2.1 proto udp port 4000"
+            .parse::<CaptureFilter>()
+            .map_err(|_| "Filter not parsed".to_string())?;
+        test_eq!(filter.direction, Some(Direction::Inbound));
+        test_eq!(filter.vlan, Some(7));
+        test_eq!(filter.ether_type, Some(0x0800));
+        test_eq!(filter.ip, Some(IpAddr::V4([192, 168, 2, 1])));
+        test_eq!(filter.protocol, Some(Ipv4Protocol::Udp));
+        test_eq!(filter.port, Some(4000));
+
+        test_true!("ether 2054"
+            .parse::<CaptureFilter>()
+            .is_ok_and(|filter| filter.ether_type == Some(0x0806)));
+        test_err!("port".parse::<CaptureFilter>());
+        test_err!("host example.com".parse::<CaptureFilter>());
+        test_err!("size 100".parse::<CaptureFilter>());
+
+        Ok(())
+    });
+
+    create_test!(test_capture_ignores_sink_port, {
+        let capture = Capture::new(Arc::new(MonotonicTime::new(10.0)), Some(5555));
+        test_false!(
+            capture
+                .should_capture(Direction::Outbound, &udp_frame(None, 5555))
+                .await
+        );
+        test_true!(
+            capture
+                .should_capture(Direction::Outbound, &udp_frame(None, 6000))
+                .await
+        );
+
+        capture
+            .set_filters(alloc::vec![CaptureFilter {
+                port: Some(80),
+                ..Default::default()
+            }])
+            .await;
+        test_false!(
+            capture
+                .should_capture(Direction::Outbound, &udp_frame(None, 6000))
+                .await
+        );
+
+        Ok(())
+    });
+}
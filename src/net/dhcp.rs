@@ -0,0 +1,886 @@
+use crate::{
+    net::{self, IpAddr, Ipv4Config},
+    sleep::WakeupRequester,
+    time::MonotonicTime,
+    util::{
+        async_channel::{self, Receiver, Sender},
+        updated_val::UpdatedVal,
+    },
+    Ipv4Addr, MacAddr,
+};
+
+use alloc::{sync::Arc, vec::Vec};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_OFFSET: usize = 240;
+
+const OP_BOOTREQUEST: u8 = 1;
+const OP_BOOTREPLY: u8 = 2;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+/// RFC 2132 9.2, a lease that never has to be renewed
+const INFINITE_LEASE_S: u32 = 0xffffffff;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DhcpMessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Unknown(u8),
+}
+
+impl From<u8> for DhcpMessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => DhcpMessageType::Discover,
+            2 => DhcpMessageType::Offer,
+            3 => DhcpMessageType::Request,
+            4 => DhcpMessageType::Decline,
+            5 => DhcpMessageType::Ack,
+            6 => DhcpMessageType::Nak,
+            7 => DhcpMessageType::Release,
+            v => DhcpMessageType::Unknown(v),
+        }
+    }
+}
+
+impl From<DhcpMessageType> for u8 {
+    fn from(value: DhcpMessageType) -> Self {
+        match value {
+            DhcpMessageType::Discover => 1,
+            DhcpMessageType::Offer => 2,
+            DhcpMessageType::Request => 3,
+            DhcpMessageType::Decline => 4,
+            DhcpMessageType::Ack => 5,
+            DhcpMessageType::Nak => 6,
+            DhcpMessageType::Release => 7,
+            DhcpMessageType::Unknown(v) => v,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InvalidDhcpFrame {
+    TooShort(usize),
+    InvalidMagicCookie,
+}
+
+pub struct DhcpFrame<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> DhcpFrame<'a> {
+    pub fn new(data: &[u8]) -> Result<DhcpFrame<'_>, InvalidDhcpFrame> {
+        if data.len() < OPTIONS_OFFSET {
+            return Err(InvalidDhcpFrame::TooShort(data.len()));
+        }
+
+        if data[236..240] != MAGIC_COOKIE {
+            return Err(InvalidDhcpFrame::InvalidMagicCookie);
+        }
+
+        Ok(DhcpFrame { data })
+    }
+
+    pub fn op(&self) -> u8 {
+        self.data[0]
+    }
+
+    pub fn xid(&self) -> u32 {
+        u32::from_be_bytes(self.data[4..8].try_into().expect("xid length wrong"))
+    }
+
+    pub fn yiaddr(&self) -> Ipv4Addr {
+        self.data[16..20].try_into().expect("yiaddr length wrong")
+    }
+
+    pub fn chaddr(&self) -> &[u8] {
+        &self.data[28..34]
+    }
+
+    pub fn option(&self, code: u8) -> Option<&'a [u8]> {
+        let mut options = &self.data[OPTIONS_OFFSET..];
+        while let Some(&option_code) = options.first() {
+            match option_code {
+                OPTION_PAD => {
+                    options = &options[1..];
+                    continue;
+                }
+                OPTION_END => return None,
+                _ => (),
+            }
+
+            let len = *options.get(1)? as usize;
+            let value = options.get(2..2 + len)?;
+            if option_code == code {
+                return Some(value);
+            }
+            options = &options[2 + len..];
+        }
+
+        None
+    }
+
+    pub fn message_type(&self) -> Option<DhcpMessageType> {
+        self.option(OPTION_MESSAGE_TYPE)
+            .and_then(|v| v.first())
+            .map(|v| (*v).into())
+    }
+
+    fn ip_option(&self, code: u8) -> Option<Ipv4Addr> {
+        self.option(code)?.get(0..4)?.try_into().ok()
+    }
+
+    fn u32_option(&self, code: u8) -> Option<u32> {
+        Some(u32::from_be_bytes(
+            self.option(code)?.get(0..4)?.try_into().ok()?,
+        ))
+    }
+
+    pub fn server_id(&self) -> Option<Ipv4Addr> {
+        self.ip_option(OPTION_SERVER_ID)
+    }
+
+    pub fn subnet_mask(&self) -> Option<Ipv4Addr> {
+        self.ip_option(OPTION_SUBNET_MASK)
+    }
+
+    pub fn router(&self) -> Option<Ipv4Addr> {
+        self.ip_option(OPTION_ROUTER)
+    }
+
+    pub fn dns_servers(&self) -> impl Iterator<Item = Ipv4Addr> + 'a {
+        self.option(OPTION_DNS_SERVERS)
+            .unwrap_or(&[])
+            .chunks_exact(4)
+            .map(|ip| ip.try_into().expect("chunk should be 4 bytes"))
+    }
+
+    pub fn lease_time(&self) -> Option<u32> {
+        self.u32_option(OPTION_LEASE_TIME)
+    }
+
+    pub fn renewal_time(&self) -> Option<u32> {
+        self.u32_option(OPTION_RENEWAL_TIME)
+    }
+
+    pub fn rebinding_time(&self) -> Option<u32> {
+        self.u32_option(OPTION_REBINDING_TIME)
+    }
+}
+
+impl core::fmt::Debug for DhcpFrame<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "op: {}", self.op())?;
+        writeln!(f, "xid: {:#x}", self.xid())?;
+        writeln!(f, "yiaddr: {:?}", self.yiaddr())?;
+        writeln!(f, "message_type: {:?}", self.message_type())?;
+        writeln!(f, "server_id: {:?}", self.server_id())?;
+        writeln!(f, "lease_time: {:?}", self.lease_time())?;
+        Ok(())
+    }
+}
+
+// Server side options are only generated by tests
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum DhcpOption<'a> {
+    MessageType(DhcpMessageType),
+    SubnetMask(Ipv4Addr),
+    Router(Ipv4Addr),
+    DnsServers(&'a [Ipv4Addr]),
+    RequestedIp(Ipv4Addr),
+    LeaseTime(u32),
+    ServerId(Ipv4Addr),
+    ParameterRequestList(&'a [u8]),
+    RenewalTime(u32),
+    RebindingTime(u32),
+}
+
+impl DhcpOption<'_> {
+    fn write(&self, buf: &mut Vec<u8>) {
+        let mut write_option = |code: u8, data: &[u8]| {
+            buf.push(code);
+            buf.push(data.len() as u8);
+            buf.extend_from_slice(data);
+        };
+
+        match self {
+            DhcpOption::MessageType(t) => write_option(OPTION_MESSAGE_TYPE, &[(*t).into()]),
+            DhcpOption::SubnetMask(ip) => write_option(OPTION_SUBNET_MASK, ip),
+            DhcpOption::Router(ip) => write_option(OPTION_ROUTER, ip),
+            DhcpOption::DnsServers(ips) => write_option(OPTION_DNS_SERVERS, &ips.concat()),
+            DhcpOption::RequestedIp(ip) => write_option(OPTION_REQUESTED_IP, ip),
+            DhcpOption::LeaseTime(t) => write_option(OPTION_LEASE_TIME, &t.to_be_bytes()),
+            DhcpOption::ServerId(ip) => write_option(OPTION_SERVER_ID, ip),
+            DhcpOption::ParameterRequestList(list) => {
+                write_option(OPTION_PARAMETER_REQUEST_LIST, list)
+            }
+            DhcpOption::RenewalTime(t) => write_option(OPTION_RENEWAL_TIME, &t.to_be_bytes()),
+            DhcpOption::RebindingTime(t) => write_option(OPTION_REBINDING_TIME, &t.to_be_bytes()),
+        }
+    }
+}
+
+pub struct DhcpMessageParams<'a> {
+    pub op: u8,
+    pub xid: u32,
+    pub broadcast: bool,
+    pub ciaddr: Ipv4Addr,
+    pub yiaddr: Ipv4Addr,
+    pub siaddr: Ipv4Addr,
+    pub chaddr: MacAddr,
+    pub options: &'a [DhcpOption<'a>],
+}
+
+pub fn generate_dhcp_message(params: &DhcpMessageParams<'_>) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(OPTIONS_OFFSET + 64);
+    ret.push(params.op);
+    // htype: ethernet
+    ret.push(1);
+    // hlen
+    ret.push(6);
+    // hops
+    ret.push(0);
+    ret.extend_from_slice(&params.xid.to_be_bytes());
+    // secs
+    ret.extend_from_slice(&0u16.to_be_bytes());
+    let flags: u16 = if params.broadcast { 0x8000 } else { 0 };
+    ret.extend_from_slice(&flags.to_be_bytes());
+    ret.extend_from_slice(&params.ciaddr);
+    ret.extend_from_slice(&params.yiaddr);
+    ret.extend_from_slice(&params.siaddr);
+    // giaddr
+    ret.extend_from_slice(&[0; 4]);
+    ret.extend_from_slice(&params.chaddr);
+    // chaddr padding + sname + file
+    ret.resize(OPTIONS_OFFSET - MAGIC_COOKIE.len(), 0);
+    ret.extend_from_slice(&MAGIC_COOKIE);
+
+    for option in params.options {
+        option.write(&mut ret);
+    }
+    ret.push(OPTION_END);
+
+    ret
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DhcpLease {
+    pub config: Ipv4Config,
+    pub server_id: Ipv4Addr,
+    pub lease_time_s: u32,
+    pub renewal_time_s: u32,
+    pub rebinding_time_s: u32,
+}
+
+impl DhcpLease {
+    fn from_ack(frame: &DhcpFrame<'_>) -> Option<DhcpLease> {
+        let mut dns_servers = [None; Ipv4Config::MAX_DNS_SERVERS];
+        for (slot, ip) in dns_servers.iter_mut().zip(frame.dns_servers()) {
+            *slot = Some(ip);
+        }
+
+        let lease_time_s = frame.lease_time()?;
+        Some(DhcpLease {
+            config: Ipv4Config {
+                address: frame.yiaddr(),
+                netmask: frame.subnet_mask().unwrap_or([255, 255, 255, 0]),
+                router: frame.router(),
+                dns_servers,
+            },
+            server_id: frame.server_id()?,
+            lease_time_s,
+            // RFC 2131 4.4.5 defaults
+            renewal_time_s: frame.renewal_time().unwrap_or(lease_time_s / 2),
+            rebinding_time_s: frame
+                .rebinding_time()
+                .unwrap_or((lease_time_s as u64 * 7 / 8) as u32),
+        })
+    }
+}
+
+pub struct OutgoingDhcpPacket {
+    pub local_ip: Ipv4Addr,
+    pub remote_ip: Ipv4Addr,
+    /// UDP frame
+    pub payload: Vec<u8>,
+}
+
+enum LeaseEnd {
+    Renewed(DhcpLease),
+    Expired,
+}
+
+pub struct Dhcp {
+    mac: MacAddr,
+    xid: u32,
+    ipv4_config: UpdatedVal<Ipv4Config>,
+    fallback_config: Ipv4Config,
+    incoming_tx: Sender<Vec<u8>>,
+    incoming_rx: Receiver<Vec<u8>>,
+    outgoing_tx: Sender<OutgoingDhcpPacket>,
+    outgoing_rx: Receiver<OutgoingDhcpPacket>,
+    time: Arc<MonotonicTime>,
+    wakeup_list: WakeupRequester,
+}
+
+impl Dhcp {
+    const REPLY_TIMEOUT_S: f32 = 4.0;
+    const DISCOVER_ATTEMPTS: usize = 4;
+    const REQUEST_ATTEMPTS: usize = 3;
+    const RETRY_DELAY_S: f32 = 60.0;
+    const PARAMETER_REQUEST_LIST: &'static [u8] = &[
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS_SERVERS,
+        OPTION_LEASE_TIME,
+        OPTION_RENEWAL_TIME,
+        OPTION_REBINDING_TIME,
+    ];
+
+    /// ipv4_config is expected to hold the fallback configuration used whenever we do not hold a
+    /// lease
+    pub fn new(
+        mac: MacAddr,
+        xid: u32,
+        ipv4_config: UpdatedVal<Ipv4Config>,
+        fallback_config: Ipv4Config,
+        time: Arc<MonotonicTime>,
+        wakeup_list: WakeupRequester,
+    ) -> Dhcp {
+        let (incoming_tx, incoming_rx) = async_channel::channel();
+        let (outgoing_tx, outgoing_rx) = async_channel::channel();
+        Dhcp {
+            mac,
+            xid,
+            ipv4_config,
+            fallback_config,
+            incoming_tx,
+            incoming_rx,
+            outgoing_tx,
+            outgoing_rx,
+            time,
+            wakeup_list,
+        }
+    }
+
+    pub async fn handle_frame(&self, data: &[u8]) {
+        let frame = match DhcpFrame::new(data) {
+            Ok(v) => v,
+            Err(InvalidDhcpFrame::TooShort(len)) => {
+                debug!("Dhcp frame too short: {} bytes", len);
+                return;
+            }
+            Err(InvalidDhcpFrame::InvalidMagicCookie) => {
+                debug!("Dhcp frame has an invalid magic cookie");
+                return;
+            }
+        };
+
+        if frame.op() != OP_BOOTREPLY || frame.xid() != self.xid || frame.chaddr() != self.mac {
+            return;
+        }
+
+        self.incoming_tx.send(data.to_vec()).await;
+    }
+
+    pub async fn service(&self) -> OutgoingDhcpPacket {
+        self.outgoing_rx.recv().await
+    }
+
+    pub async fn run(&self) {
+        loop {
+            let lease = match self.acquire_lease().await {
+                Some(v) => v,
+                None => {
+                    warn!(
+                        "DHCP failed, using fallback address {:?}",
+                        self.fallback_config.address
+                    );
+                    self.ipv4_config.write(self.fallback_config).await;
+                    self.sleep(Self::RETRY_DELAY_S).await;
+                    continue;
+                }
+            };
+
+            let mut lease = lease;
+            loop {
+                info!("DHCP lease acquired: {:?}", lease);
+                self.ipv4_config.write(lease.config).await;
+
+                match self.maintain_lease(&lease).await {
+                    LeaseEnd::Renewed(renewed) => lease = renewed,
+                    LeaseEnd::Expired => {
+                        warn!("DHCP lease for {:?} expired", lease.config.address);
+                        self.ipv4_config.write(self.fallback_config).await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// INIT -> SELECTING -> REQUESTING -> BOUND
+    async fn acquire_lease(&self) -> Option<DhcpLease> {
+        for _ in 0..Self::DISCOVER_ATTEMPTS {
+            self.send(
+                DhcpMessageType::Discover,
+                &[0; 4],
+                &[255; 4],
+                &[DhcpOption::ParameterRequestList(
+                    Self::PARAMETER_REQUEST_LIST,
+                )],
+            )
+            .await;
+
+            let offer = match self
+                .wait_for_reply(Self::REPLY_TIMEOUT_S, &[DhcpMessageType::Offer])
+                .await
+            {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let offer = DhcpFrame::new(&offer).expect("Offer validated in handle_frame");
+            let server_id = match offer.server_id() {
+                Some(v) => v,
+                None => {
+                    debug!("DHCP offer without server id");
+                    continue;
+                }
+            };
+            let requested_ip = offer.yiaddr();
+
+            for _ in 0..Self::REQUEST_ATTEMPTS {
+                self.send(
+                    DhcpMessageType::Request,
+                    &[0; 4],
+                    &[255; 4],
+                    &[
+                        DhcpOption::RequestedIp(requested_ip),
+                        DhcpOption::ServerId(server_id),
+                        DhcpOption::ParameterRequestList(Self::PARAMETER_REQUEST_LIST),
+                    ],
+                )
+                .await;
+
+                let reply = self
+                    .wait_for_reply(
+                        Self::REPLY_TIMEOUT_S,
+                        &[DhcpMessageType::Ack, DhcpMessageType::Nak],
+                    )
+                    .await;
+
+                let reply = match reply {
+                    Some(v) => v,
+                    None => continue,
+                };
+
+                let reply = DhcpFrame::new(&reply).expect("Reply validated in handle_frame");
+                if reply.message_type() == Some(DhcpMessageType::Nak) {
+                    debug!("DHCP request was nak'd");
+                    break;
+                }
+
+                return DhcpLease::from_ack(&reply);
+            }
+        }
+
+        None
+    }
+
+    /// BOUND -> RENEWING -> REBINDING
+    async fn maintain_lease(&self, lease: &DhcpLease) -> LeaseEnd {
+        if lease.lease_time_s == INFINITE_LEASE_S {
+            return core::future::pending().await;
+        }
+
+        let acquired = self.time.get();
+        let tick_freq = self.time.tick_freq();
+        let elapsed_s = || (self.time.get() - acquired) as f32 / tick_freq;
+
+        self.sleep(lease.renewal_time_s as f32).await;
+
+        // RENEWING: unicast to the server that gave us the lease
+        while elapsed_s() < lease.rebinding_time_s as f32 {
+            if let Some(end) = self
+                .request_renewal(
+                    lease,
+                    &lease.server_id,
+                    lease.rebinding_time_s as f32 - elapsed_s(),
+                )
+                .await
+            {
+                return end;
+            }
+        }
+
+        // REBINDING: any server may extend the lease
+        while elapsed_s() < lease.lease_time_s as f32 {
+            if let Some(end) = self
+                .request_renewal(lease, &[255; 4], lease.lease_time_s as f32 - elapsed_s())
+                .await
+            {
+                return end;
+            }
+        }
+
+        LeaseEnd::Expired
+    }
+
+    async fn request_renewal(
+        &self,
+        lease: &DhcpLease,
+        server: &Ipv4Addr,
+        remaining_s: f32,
+    ) -> Option<LeaseEnd> {
+        self.send(
+            DhcpMessageType::Request,
+            &lease.config.address,
+            server,
+            &[DhcpOption::ParameterRequestList(
+                Self::PARAMETER_REQUEST_LIST,
+            )],
+        )
+        .await;
+
+        // RFC 2131 4.4.5: wait half of the remaining time, down to a minimum of 60 seconds
+        let timeout = (remaining_s / 2.0).max(Self::RETRY_DELAY_S.min(remaining_s));
+        let reply = self
+            .wait_for_reply(timeout, &[DhcpMessageType::Ack, DhcpMessageType::Nak])
+            .await?;
+        let reply = DhcpFrame::new(&reply).expect("Reply validated in handle_frame");
+        match reply.message_type() {
+            Some(DhcpMessageType::Ack) => DhcpLease::from_ack(&reply).map(LeaseEnd::Renewed),
+            // RFC 2131 4.4.5, the lease cannot be extended and must be given up
+            Some(DhcpMessageType::Nak) => {
+                debug!("DHCP renewal was nak'd");
+                Some(LeaseEnd::Expired)
+            }
+            _ => None,
+        }
+    }
+
+    async fn send(
+        &self,
+        message_type: DhcpMessageType,
+        ciaddr: &Ipv4Addr,
+        remote_ip: &Ipv4Addr,
+        extra_options: &[DhcpOption<'_>],
+    ) {
+        let mut options = Vec::with_capacity(extra_options.len() + 1);
+        options.push(DhcpOption::MessageType(message_type));
+        options.extend_from_slice(extra_options);
+
+        let message = generate_dhcp_message(&DhcpMessageParams {
+            op: OP_BOOTREQUEST,
+            xid: self.xid,
+            // We cannot receive unicast to an address we do not own yet
+            broadcast: *ciaddr == [0; 4],
+            ciaddr: *ciaddr,
+            yiaddr: [0; 4],
+            siaddr: [0; 4],
+            chaddr: self.mac,
+            options: &options,
+        });
+
+        self.outgoing_tx
+            .send(OutgoingDhcpPacket {
+                local_ip: *ciaddr,
+                remote_ip: *remote_ip,
+                payload: net::generate_udp_frame(
+                    &IpAddr::V4(*ciaddr),
+                    &IpAddr::V4(*remote_ip),
+                    CLIENT_PORT,
+                    SERVER_PORT,
+                    &message,
+                ),
+            })
+            .await;
+    }
+
+    async fn wait_for_reply(
+        &self,
+        timeout_s: f32,
+        expected: &[DhcpMessageType],
+    ) -> Option<Vec<u8>> {
+        let reply = async {
+            loop {
+                let message = self.incoming_rx.recv().await;
+                let frame = DhcpFrame::new(&message).expect("Message validated in handle_frame");
+                match frame.message_type() {
+                    Some(t) if expected.contains(&t) => return message,
+                    t => debug!("Ignoring unexpected dhcp message {:?}", t),
+                }
+            }
+        };
+        crate::sleep::timeout(timeout_s, &self.time, &self.wakeup_list, reply).await
+    }
+
+    async fn sleep(&self, time_s: f32) {
+        crate::sleep::sleep(time_s, &self.time, &self.wakeup_list).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::string::ToString;
+
+    const CLIENT_MAC: MacAddr = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+    const SERVER_IP: Ipv4Addr = [192, 168, 2, 1];
+    const OFFERED_IP: Ipv4Addr = [192, 168, 2, 50];
+    const XID: u32 = 0xdeadbeef;
+
+    fn server_reply(message_type: DhcpMessageType) -> Vec<u8> {
+        server_reply_with_lease(message_type, 3600)
+    }
+
+    fn server_reply_with_lease(message_type: DhcpMessageType, lease_time_s: u32) -> Vec<u8> {
+        generate_dhcp_message(&DhcpMessageParams {
+            op: OP_BOOTREPLY,
+            xid: XID,
+            broadcast: true,
+            ciaddr: [0; 4],
+            yiaddr: OFFERED_IP,
+            siaddr: SERVER_IP,
+            chaddr: CLIENT_MAC,
+            options: &[
+                DhcpOption::MessageType(message_type),
+                DhcpOption::ServerId(SERVER_IP),
+                DhcpOption::SubnetMask([255, 255, 255, 0]),
+                DhcpOption::Router(SERVER_IP),
+                DhcpOption::DnsServers(&[SERVER_IP, [8, 8, 8, 8]]),
+                DhcpOption::LeaseTime(lease_time_s),
+            ],
+        })
+    }
+
+    fn sent_message(packet: &OutgoingDhcpPacket) -> Vec<u8> {
+        // Strip the udp header
+        packet.payload[8..].to_vec()
+    }
+
+    create_test!(test_dhcp_option_parsing, {
+        let reply = server_reply(DhcpMessageType::Offer);
+        let frame = DhcpFrame::new(&reply).map_err(|_| "Invalid dhcp frame".to_string())?;
+
+        test_eq!(frame.op(), OP_BOOTREPLY);
+        test_eq!(frame.xid(), XID);
+        test_eq!(frame.yiaddr(), OFFERED_IP);
+        test_eq!(frame.chaddr(), &CLIENT_MAC);
+        test_eq!(frame.message_type(), Some(DhcpMessageType::Offer));
+        test_eq!(frame.server_id(), Some(SERVER_IP));
+        test_eq!(frame.subnet_mask(), Some([255, 255, 255, 0]));
+        test_eq!(frame.router(), Some(SERVER_IP));
+        test_eq!(
+            frame.dns_servers().collect::<Vec<_>>(),
+            [SERVER_IP, [8, 8, 8, 8]]
+        );
+        test_eq!(frame.lease_time(), Some(3600));
+        test_true!(frame.renewal_time().is_none());
+        test_true!(frame.option(OPTION_PARAMETER_REQUEST_LIST).is_none());
+
+        let mut corrupted = reply.clone();
+        corrupted[236] = 0;
+        test_err!(DhcpFrame::new(&corrupted));
+        test_err!(DhcpFrame::new(&reply[..239]));
+
+        Ok(())
+    });
+
+    create_test!(test_dhcp_lease_acquisition, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let fallback = Ipv4Config::new([192, 168, 2, 2], [255, 255, 255, 0]);
+        let ipv4_config = UpdatedVal::new(fallback);
+        let dhcp = Dhcp::new(
+            CLIENT_MAC,
+            XID,
+            ipv4_config.clone(),
+            fallback,
+            Arc::clone(&time),
+            wakeup_list,
+        );
+
+        let mut run = core::pin::pin!(dhcp.run());
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+
+        let discover = crate::future::poll_immediate(dhcp.service())
+            .await
+            .ok_or("No dhcp discover".to_string())?;
+        test_eq!(discover.local_ip, [0; 4]);
+        test_eq!(discover.remote_ip, [255; 4]);
+        let discover = sent_message(&discover);
+        let discover = DhcpFrame::new(&discover).map_err(|_| "Invalid discover".to_string())?;
+        test_eq!(discover.message_type(), Some(DhcpMessageType::Discover));
+        test_eq!(discover.xid(), XID);
+
+        // Replies for other clients should be ignored
+        let mut other_client = server_reply(DhcpMessageType::Offer);
+        other_client[4] = 0;
+        dhcp.handle_frame(&other_client).await;
+        dhcp.handle_frame(&server_reply(DhcpMessageType::Offer))
+            .await;
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+
+        let request = crate::future::poll_immediate(dhcp.service())
+            .await
+            .ok_or("No dhcp request".to_string())?;
+        let request = sent_message(&request);
+        let request = DhcpFrame::new(&request).map_err(|_| "Invalid request".to_string())?;
+        test_eq!(request.message_type(), Some(DhcpMessageType::Request));
+        test_eq!(request.ip_option(OPTION_REQUESTED_IP), Some(OFFERED_IP));
+        test_eq!(request.server_id(), Some(SERVER_IP));
+
+        dhcp.handle_frame(&server_reply(DhcpMessageType::Ack)).await;
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+
+        let config = ipv4_config.read().await;
+        test_eq!(config.address, OFFERED_IP);
+        test_eq!(config.router, Some(SERVER_IP));
+        test_eq!(
+            config.dns_servers().collect::<Vec<_>>(),
+            [SERVER_IP, [8, 8, 8, 8]]
+        );
+
+        // Nothing more to send until T1
+        test_true!(crate::future::poll_immediate(dhcp.service())
+            .await
+            .is_none());
+
+        time.set_tick((1801.0 * time.tick_freq()) as usize);
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+
+        let renewal = crate::future::poll_immediate(dhcp.service())
+            .await
+            .ok_or("No dhcp renewal".to_string())?;
+        test_eq!(renewal.local_ip, OFFERED_IP);
+        test_eq!(renewal.remote_ip, SERVER_IP);
+
+        // A nak ends the lease instead of retrying until it runs out
+        dhcp.handle_frame(&server_reply(DhcpMessageType::Nak)).await;
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+        test_eq!(ipv4_config.read().await.address, fallback.address);
+
+        let discover = crate::future::poll_immediate(dhcp.service())
+            .await
+            .ok_or("No dhcp discover after nak".to_string())?;
+        let discover = sent_message(&discover);
+        let discover = DhcpFrame::new(&discover).map_err(|_| "Invalid discover".to_string())?;
+        test_eq!(discover.message_type(), Some(DhcpMessageType::Discover));
+
+        Ok(())
+    });
+
+    create_test!(test_dhcp_offer_without_server_id, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let fallback = Ipv4Config::new([192, 168, 2, 2], [255, 255, 255, 0]);
+        let dhcp = Dhcp::new(
+            CLIENT_MAC,
+            XID,
+            UpdatedVal::new(fallback),
+            fallback,
+            Arc::clone(&time),
+            wakeup_list,
+        );
+
+        let mut run = core::pin::pin!(dhcp.run());
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+        crate::future::poll_immediate(dhcp.service())
+            .await
+            .ok_or("No dhcp discover".to_string())?;
+
+        let offer = generate_dhcp_message(&DhcpMessageParams {
+            op: OP_BOOTREPLY,
+            xid: XID,
+            broadcast: true,
+            ciaddr: [0; 4],
+            yiaddr: OFFERED_IP,
+            siaddr: SERVER_IP,
+            chaddr: CLIENT_MAC,
+            options: &[DhcpOption::MessageType(DhcpMessageType::Offer)],
+        });
+        dhcp.handle_frame(&offer).await;
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+
+        // Discovery carries on rather than giving up on the first bad offer
+        let discover = crate::future::poll_immediate(dhcp.service())
+            .await
+            .ok_or("No second dhcp discover".to_string())?;
+        let discover = sent_message(&discover);
+        let discover = DhcpFrame::new(&discover).map_err(|_| "Invalid discover".to_string())?;
+        test_eq!(discover.message_type(), Some(DhcpMessageType::Discover));
+
+        Ok(())
+    });
+
+    create_test!(test_dhcp_infinite_lease, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let fallback = Ipv4Config::new([192, 168, 2, 2], [255, 255, 255, 0]);
+        let ipv4_config = UpdatedVal::new(fallback);
+        let dhcp = Dhcp::new(
+            CLIENT_MAC,
+            XID,
+            ipv4_config.clone(),
+            fallback,
+            Arc::clone(&time),
+            wakeup_list,
+        );
+
+        // Timeouts this close to the end of the tick range must not overflow
+        time.set_tick(usize::MAX - 1);
+
+        let mut run = core::pin::pin!(dhcp.run());
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+        crate::future::poll_immediate(dhcp.service())
+            .await
+            .ok_or("No dhcp discover".to_string())?;
+
+        dhcp.handle_frame(&server_reply(DhcpMessageType::Offer))
+            .await;
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+        crate::future::poll_immediate(dhcp.service())
+            .await
+            .ok_or("No dhcp request".to_string())?;
+
+        dhcp.handle_frame(&server_reply_with_lease(
+            DhcpMessageType::Ack,
+            INFINITE_LEASE_S,
+        ))
+        .await;
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+        test_eq!(ipv4_config.read().await.address, OFFERED_IP);
+
+        time.set_tick(usize::MAX);
+        test_true!(crate::future::poll_immediate(run.as_mut()).await.is_none());
+        test_true!(crate::future::poll_immediate(dhcp.service())
+            .await
+            .is_none());
+
+        Ok(())
+    });
+}
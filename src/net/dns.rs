@@ -0,0 +1,694 @@
+use crate::{
+    net::{
+        udp::{BindError, Udp, UdpSocket},
+        IpAddr, Ipv4Config,
+    },
+    sleep::WakeupRequester,
+    time::MonotonicTime,
+    util::{async_mutex::Mutex, updated_val::UpdatedVal},
+    Ipv4Addr,
+};
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU16, Ordering};
+use hashbrown::HashMap;
+
+pub const SERVER_PORT: u16 = 53;
+
+const HEADER_LENGTH: usize = 12;
+
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+
+const RCODE_NO_ERROR: u8 = 0;
+const RCODE_NAME_ERROR: u8 = 3;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const CLASS_IN: u16 = 1;
+
+const MAX_LABEL_LENGTH: usize = 63;
+const MAX_NAME_LENGTH: usize = 253;
+// Compression pointers can form loops, bound how many we are willing to follow
+const MAX_COMPRESSION_JUMPS: usize = 16;
+
+#[derive(Debug)]
+pub enum InvalidDnsMessage {
+    TooShort(usize),
+    InvalidLabel,
+    NameTooLong,
+    PointerLoop,
+}
+
+impl core::fmt::Display for InvalidDnsMessage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::TooShort(len) => f.write_fmt(format_args!("message too short: {} bytes", len)),
+            Self::InvalidLabel => f.write_str("invalid label"),
+            Self::NameTooLong => f.write_str("name too long"),
+            Self::PointerLoop => f.write_str("compression pointer loop"),
+        }?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Cname(String),
+    Other(u16),
+}
+
+#[derive(Debug)]
+pub struct DnsRecord {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+pub struct DnsMessage<'a> {
+    data: &'a [u8],
+}
+
+impl DnsMessage<'_> {
+    pub fn new(data: &[u8]) -> Result<DnsMessage<'_>, InvalidDnsMessage> {
+        if data.len() < HEADER_LENGTH {
+            return Err(InvalidDnsMessage::TooShort(data.len()));
+        }
+
+        Ok(DnsMessage { data })
+    }
+
+    pub fn id(&self) -> u16 {
+        u16::from_be_bytes(self.data[0..2].try_into().expect("u16 size incorrect"))
+    }
+
+    fn flags(&self) -> u16 {
+        u16::from_be_bytes(self.data[2..4].try_into().expect("u16 size incorrect"))
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags() & FLAG_RESPONSE != 0
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags() & 0xf) as u8
+    }
+
+    pub fn question_count(&self) -> u16 {
+        u16::from_be_bytes(self.data[4..6].try_into().expect("u16 size incorrect"))
+    }
+
+    pub fn answer_count(&self) -> u16 {
+        u16::from_be_bytes(self.data[6..8].try_into().expect("u16 size incorrect"))
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], InvalidDnsMessage> {
+        self.data
+            .get(offset..offset + len)
+            .ok_or(InvalidDnsMessage::TooShort(self.data.len()))
+    }
+
+    fn u16_at(&self, offset: usize) -> Result<u16, InvalidDnsMessage> {
+        Ok(u16::from_be_bytes(
+            self.bytes(offset, 2)?
+                .try_into()
+                .expect("u16 size incorrect"),
+        ))
+    }
+
+    fn u32_at(&self, offset: usize) -> Result<u32, InvalidDnsMessage> {
+        Ok(u32::from_be_bytes(
+            self.bytes(offset, 4)?
+                .try_into()
+                .expect("u32 size incorrect"),
+        ))
+    }
+
+    /// Returns the lowercased name and the offset of the data following it
+    fn read_name(&self, mut offset: usize) -> Result<(String, usize), InvalidDnsMessage> {
+        let mut name = String::new();
+        let mut end = None;
+        let mut jumps = 0;
+
+        loop {
+            let len = self.bytes(offset, 1)?[0] as usize;
+            match len & 0xc0 {
+                0xc0 => {
+                    let pointer = u16::from_be_bytes(
+                        self.bytes(offset, 2)?
+                            .try_into()
+                            .expect("u16 size incorrect"),
+                    );
+                    end.get_or_insert(offset + 2);
+
+                    jumps += 1;
+                    if jumps > MAX_COMPRESSION_JUMPS {
+                        return Err(InvalidDnsMessage::PointerLoop);
+                    }
+
+                    offset = (pointer & 0x3fff) as usize;
+                }
+                0x00 => {
+                    if len == 0 {
+                        return Ok((name, end.unwrap_or(offset + 1)));
+                    }
+
+                    let label = self.bytes(offset + 1, len)?;
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    name.extend(label.iter().map(|c| c.to_ascii_lowercase() as char));
+
+                    if name.len() > MAX_NAME_LENGTH {
+                        return Err(InvalidDnsMessage::NameTooLong);
+                    }
+
+                    offset += 1 + len;
+                }
+                // 0x40 and 0x80 are reserved label types
+                _ => return Err(InvalidDnsMessage::InvalidLabel),
+            }
+        }
+    }
+
+    pub fn answers(&self) -> Result<Vec<DnsRecord>, InvalidDnsMessage> {
+        let mut offset = HEADER_LENGTH;
+
+        for _ in 0..self.question_count() {
+            let (_, name_end) = self.read_name(offset)?;
+            // qtype + qclass
+            offset = name_end + 4;
+        }
+
+        let mut ret = Vec::new();
+        for _ in 0..self.answer_count() {
+            let (name, name_end) = self.read_name(offset)?;
+            let record_type = self.u16_at(name_end)?;
+            let class = self.u16_at(name_end + 2)?;
+            let ttl = self.u32_at(name_end + 4)?;
+            let data_length = self.u16_at(name_end + 8)? as usize;
+            let data_offset = name_end + 10;
+            let record_data = self.bytes(data_offset, data_length)?;
+
+            let data = match (record_type, class) {
+                (TYPE_A, CLASS_IN) if data_length == 4 => {
+                    RecordData::A(record_data.try_into().expect("ip size incorrect"))
+                }
+                (TYPE_CNAME, CLASS_IN) => RecordData::Cname(self.read_name(data_offset)?.0),
+                _ => RecordData::Other(record_type),
+            };
+
+            ret.push(DnsRecord { name, ttl, data });
+            offset = data_offset + data_length;
+        }
+
+        Ok(ret)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidName;
+
+/// Lowercases the name and strips the root label so that it can be used as a cache key
+fn normalize_name(name: &str) -> Result<String, InvalidName> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(InvalidName);
+    }
+
+    if name
+        .split('.')
+        .any(|label| label.is_empty() || label.len() > MAX_LABEL_LENGTH)
+    {
+        return Err(InvalidName);
+    }
+
+    Ok(name.to_ascii_lowercase())
+}
+
+pub fn generate_dns_query(id: u16, name: &str) -> Result<Vec<u8>, InvalidName> {
+    let name = normalize_name(name)?;
+
+    let mut ret = Vec::with_capacity(HEADER_LENGTH + name.len() + 6);
+    ret.extend_from_slice(&id.to_be_bytes());
+    ret.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // qdcount
+    ret.extend_from_slice(&1u16.to_be_bytes());
+    // ancount, nscount, arcount
+    ret.extend_from_slice(&[0; 6]);
+
+    for label in name.split('.') {
+        ret.push(label.len() as u8);
+        ret.extend_from_slice(label.as_bytes());
+    }
+    ret.push(0);
+
+    ret.extend_from_slice(&TYPE_A.to_be_bytes());
+    ret.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(ret)
+}
+
+pub fn parse_ipv4_literal(name: &str) -> Option<Ipv4Addr> {
+    let mut ret = [0; 4];
+    let mut octets = name.split('.');
+    for octet in &mut ret {
+        *octet = octets.next()?.parse().ok()?;
+    }
+
+    match octets.next() {
+        Some(_) => None,
+        None => Some(ret),
+    }
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+    InvalidName,
+    NoServers,
+    Bind(BindError),
+    Timeout,
+    InvalidResponse(InvalidDnsMessage),
+    NameNotFound,
+    ServerFailure(u8),
+    NoAddresses,
+    TooManyCnames,
+}
+
+impl core::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidName => f.write_str("invalid name"),
+            Self::NoServers => f.write_str("no dns servers configured"),
+            Self::Bind(e) => f.write_fmt(format_args!("failed to bind socket: {:?}", e)),
+            Self::Timeout => f.write_str("timed out"),
+            Self::InvalidResponse(e) => f.write_fmt(format_args!("invalid response: {}", e)),
+            Self::NameNotFound => f.write_str("name not found"),
+            Self::ServerFailure(rcode) => {
+                f.write_fmt(format_args!("server failure: rcode {}", rcode))
+            }
+            Self::NoAddresses => f.write_str("no addresses"),
+            Self::TooManyCnames => f.write_str("too many cnames"),
+        }?;
+        Ok(())
+    }
+}
+
+enum Answer {
+    Addresses(Vec<Ipv4Addr>, u32),
+    Cname(String, u32),
+}
+
+fn parse_answer(message: &DnsMessage<'_>, name: &str) -> Result<Answer, ResolveError> {
+    match message.rcode() {
+        RCODE_NO_ERROR => (),
+        RCODE_NAME_ERROR => return Err(ResolveError::NameNotFound),
+        rcode => return Err(ResolveError::ServerFailure(rcode)),
+    }
+
+    let records = message.answers().map_err(ResolveError::InvalidResponse)?;
+
+    // Servers usually include the whole CNAME chain in the answer section, follow it as far as
+    // the response allows
+    let mut current = name;
+    let mut ttl = u32::MAX;
+    for _ in 0..=Dns::MAX_CNAME_DEPTH {
+        let mut addrs = Vec::new();
+        for record in records.iter().filter(|r| r.name == current) {
+            if let RecordData::A(ip) = record.data {
+                addrs.push(ip);
+                ttl = ttl.min(record.ttl);
+            }
+        }
+
+        if !addrs.is_empty() {
+            return Ok(Answer::Addresses(addrs, ttl));
+        }
+
+        let cname = records
+            .iter()
+            .filter(|r| r.name == current)
+            .find_map(|r| match &r.data {
+                RecordData::Cname(target) => Some((target, r.ttl)),
+                _ => None,
+            });
+
+        match cname {
+            Some((target, cname_ttl)) => {
+                current = target;
+                ttl = ttl.min(cname_ttl);
+            }
+            None if current != name => return Ok(Answer::Cname(current.to_string(), ttl)),
+            None => return Err(ResolveError::NoAddresses),
+        }
+    }
+
+    Err(ResolveError::TooManyCnames)
+}
+
+struct CacheEntry {
+    addrs: Vec<Ipv4Addr>,
+    expiry: usize,
+}
+
+pub struct Dns {
+    udp: Arc<Udp>,
+    ipv4_config: UpdatedVal<Ipv4Config>,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    next_id: AtomicU16,
+    time: Arc<MonotonicTime>,
+    wakeup_list: WakeupRequester,
+}
+
+impl Dns {
+    const QUERY_TIMEOUT_S: f32 = 2.0;
+    const QUERY_ATTEMPTS: usize = 3;
+    const MAX_CNAME_DEPTH: usize = 8;
+    // Do not let a bogus TTL pin an entry forever
+    const MAX_CACHE_TTL_S: u32 = 86400;
+
+    /// Servers are taken from ipv4_config at query time, so addresses handed out by DHCP are
+    /// picked up automatically
+    pub fn new(
+        udp: Arc<Udp>,
+        ipv4_config: UpdatedVal<Ipv4Config>,
+        initial_id: u16,
+        time: Arc<MonotonicTime>,
+        wakeup_list: WakeupRequester,
+    ) -> Dns {
+        Dns {
+            udp,
+            ipv4_config,
+            cache: Mutex::new(Default::default()),
+            next_id: AtomicU16::new(initial_id),
+            time,
+            wakeup_list,
+        }
+    }
+
+    pub async fn resolve(&self, name: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        let addrs = self.resolve_ipv4(name).await?;
+        Ok(addrs.into_iter().map(IpAddr::V4).collect())
+    }
+
+    async fn resolve_ipv4(&self, name: &str) -> Result<Vec<Ipv4Addr>, ResolveError> {
+        if let Some(ip) = parse_ipv4_literal(name) {
+            return Ok(vec![ip]);
+        }
+
+        let name = normalize_name(name).map_err(|_| ResolveError::InvalidName)?;
+
+        let mut chain = vec![name];
+        let mut ttl = u32::MAX;
+        for _ in 0..=Self::MAX_CNAME_DEPTH {
+            let current = chain.last().expect("chain is never empty");
+            if let Some(addrs) = self.cached(current).await {
+                return Ok(addrs);
+            }
+
+            match self.query(current).await? {
+                Answer::Addresses(addrs, addrs_ttl) => {
+                    ttl = ttl.min(addrs_ttl);
+                    self.insert_cache(&chain, &addrs, ttl).await;
+                    return Ok(addrs);
+                }
+                Answer::Cname(target, cname_ttl) => {
+                    ttl = ttl.min(cname_ttl);
+                    chain.push(target);
+                }
+            }
+        }
+
+        Err(ResolveError::TooManyCnames)
+    }
+
+    async fn cached(&self, name: &str) -> Option<Vec<Ipv4Addr>> {
+        let mut cache = self.cache.lock().await;
+        let entry = cache.get(name)?;
+        if self.time.get() < entry.expiry {
+            return Some(entry.addrs.clone());
+        }
+
+        cache.remove(name);
+        None
+    }
+
+    async fn insert_cache(&self, names: &[String], addrs: &[Ipv4Addr], ttl_s: u32) {
+        let ttl_s = ttl_s.min(Self::MAX_CACHE_TTL_S);
+        let expiry = self.time.get() + (ttl_s as f32 * self.time.tick_freq()) as usize;
+
+        let mut cache = self.cache.lock().await;
+        for name in names {
+            cache.insert(
+                name.clone(),
+                CacheEntry {
+                    addrs: addrs.to_vec(),
+                    expiry,
+                },
+            );
+        }
+    }
+
+    async fn query(&self, name: &str) -> Result<Answer, ResolveError> {
+        let servers: Vec<Ipv4Addr> = self.ipv4_config.read().await.dns_servers().collect();
+        if servers.is_empty() {
+            return Err(ResolveError::NoServers);
+        }
+
+        let socket = self.udp.bind(0).await.map_err(ResolveError::Bind)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let query = generate_dns_query(id, name).map_err(|_| ResolveError::InvalidName)?;
+
+        for _ in 0..Self::QUERY_ATTEMPTS {
+            for server in &servers {
+                socket.send_to(&query, *server, SERVER_PORT).await;
+
+                if let Some(response) = self.wait_for_response(&socket, server, id).await {
+                    let message = DnsMessage::new(&response)
+                        .expect("Response validated in wait_for_response");
+                    return parse_answer(&message, name);
+                }
+
+                debug!("DNS query for {} to {:?} timed out", name, server);
+            }
+        }
+
+        Err(ResolveError::Timeout)
+    }
+
+    async fn wait_for_response(
+        &self,
+        socket: &UdpSocket,
+        server: &Ipv4Addr,
+        id: u16,
+    ) -> Option<Vec<u8>> {
+        let response = async {
+            loop {
+                let (data, remote_ip, remote_port) = socket.recv_from().await;
+                if remote_ip != IpAddr::V4(*server) || remote_port != SERVER_PORT {
+                    debug!(
+                        "Ignoring DNS response from unexpected source {:?}",
+                        remote_ip
+                    );
+                    continue;
+                }
+
+                match DnsMessage::new(&data) {
+                    Ok(message) if message.is_response() && message.id() == id => return data,
+                    Ok(_) => debug!("Ignoring unexpected DNS message"),
+                    Err(e) => debug!("Invalid DNS message: {}", e),
+                }
+            }
+        };
+        crate::sleep::timeout(
+            Self::QUERY_TIMEOUT_S,
+            &self.time,
+            &self.wakeup_list,
+            response,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::{self, UdpFrame};
+    use crate::testing::*;
+    use alloc::format;
+
+    const LOCAL_IP: Ipv4Addr = [192, 168, 2, 2];
+    const SERVER_IP: Ipv4Addr = [192, 168, 2, 1];
+    const RESOLVED_IP: Ipv4Addr = [93, 184, 216, 34];
+
+    // www.example.com CNAME example.com (TTL 3600), example.com A 93.184.216.34 (TTL 60). Both
+    // answers refer back to the question through compression pointers
+    fn cname_response(id: u16) -> Vec<u8> {
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&id.to_be_bytes());
+        ret.extend_from_slice(&[0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00]);
+        ret.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+        ret.extend_from_slice(&[
+            0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x02, 0xc0, 0x10,
+        ]);
+        ret.extend_from_slice(&[
+            0xc0, 0x10, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04,
+        ]);
+        ret.extend_from_slice(&RESOLVED_IP);
+        ret
+    }
+
+    fn gen_dns() -> (Dns, Arc<Udp>, Arc<MonotonicTime>) {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let udp = Arc::new(Udp::new());
+
+        let mut config = Ipv4Config::new(LOCAL_IP, [255, 255, 255, 0]);
+        config.dns_servers[0] = Some(SERVER_IP);
+
+        let dns = Dns::new(
+            Arc::clone(&udp),
+            UpdatedVal::new(config),
+            0x1234,
+            Arc::clone(&time),
+            wakeup_list,
+        );
+        (dns, udp, time)
+    }
+
+    async fn reply(udp: &Udp, local_port: u16, response: &[u8]) -> Result<(), String> {
+        let (server_ip, local_ip) = (IpAddr::V4(SERVER_IP), IpAddr::V4(LOCAL_IP));
+        let frame =
+            net::generate_udp_frame(&server_ip, &local_ip, SERVER_PORT, local_port, response);
+        let frame = UdpFrame::new(&frame).map_err(|_| "Invalid udp frame".to_string())?;
+        udp.handle_frame(&frame, &server_ip, &local_ip)
+            .await
+            .map_err(|_| "Failed to deliver dns response".to_string())
+    }
+
+    create_test!(test_dns_query_generation, {
+        let query = generate_dns_query(0xabcd, "WWW.Example.com.")
+            .map_err(|_| "Failed to generate query".to_string())?;
+        test_eq!(
+            query,
+            b"\xab\xcd\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x03www\x07example\x03com\x00\x00\x01\x00\x01"
+        );
+
+        test_err!(generate_dns_query(0, "a..b"));
+        test_err!(generate_dns_query(0, &"a".repeat(64)));
+        Ok(())
+    });
+
+    create_test!(test_dns_message_parsing, {
+        let response = cname_response(0x1234);
+        let message = DnsMessage::new(&response).map_err(|_| "Invalid dns message".to_string())?;
+        test_eq!(message.id(), 0x1234);
+        test_true!(message.is_response());
+        test_eq!(message.rcode(), RCODE_NO_ERROR);
+
+        let answers = message
+            .answers()
+            .map_err(|_| "Failed to parse answers".to_string())?;
+        test_eq!(answers.len(), 2);
+        test_eq!(answers[0].name, "www.example.com");
+        test_eq!(answers[0].ttl, 3600);
+        test_eq!(
+            answers[0].data,
+            RecordData::Cname("example.com".to_string())
+        );
+        test_eq!(answers[1].name, "example.com");
+        test_eq!(answers[1].data, RecordData::A(RESOLVED_IP));
+
+        // A name that points at itself must not hang the parser
+        let mut looped = response.clone();
+        looped[12..14].copy_from_slice(&[0xc0, 0x0c]);
+        let message = DnsMessage::new(&looped).map_err(|_| "Invalid dns message".to_string())?;
+        test_err!(message.answers());
+
+        let message = DnsMessage::new(&response[..response.len() - 1])
+            .map_err(|_| "Invalid dns message".to_string())?;
+        test_err!(message.answers());
+        test_true!(DnsMessage::new(&response[..11]).is_err());
+
+        Ok(())
+    });
+
+    create_test!(test_dns_resolve_and_cache, {
+        let (dns, udp, time) = gen_dns();
+
+        let mut resolve = core::pin::pin!(dns.resolve("www.example.com"));
+        test_true!(crate::future::poll_immediate(resolve.as_mut())
+            .await
+            .is_none());
+
+        let query = crate::future::poll_immediate(udp.service())
+            .await
+            .ok_or("No dns query".to_string())?;
+        test_eq!(query.remote_ip, IpAddr::V4(SERVER_IP));
+        test_eq!(query.remote_port, SERVER_PORT);
+        let id = DnsMessage::new(&query.data)
+            .map_err(|_| "Invalid dns query".to_string())?
+            .id();
+
+        // Responses with the wrong id are ignored
+        reply(&udp, query.local_port, &cname_response(id.wrapping_add(1))).await?;
+        test_true!(crate::future::poll_immediate(resolve.as_mut())
+            .await
+            .is_none());
+
+        reply(&udp, query.local_port, &cname_response(id)).await?;
+        let addrs = crate::future::poll_immediate(resolve.as_mut())
+            .await
+            .ok_or("Resolve did not complete".to_string())?
+            .map_err(|e| format!("Resolve failed: {:?}", e))?;
+        test_eq!(addrs, [IpAddr::V4(RESOLVED_IP)]);
+
+        // Cached for the smallest TTL in the chain
+        time.set_tick((59.0 * time.tick_freq()) as usize);
+        let addrs = crate::future::poll_immediate(dns.resolve("WWW.example.com"))
+            .await
+            .ok_or("Cached resolve did not complete".to_string())?
+            .map_err(|e| format!("Resolve failed: {:?}", e))?;
+        test_eq!(addrs, [IpAddr::V4(RESOLVED_IP)]);
+        test_true!(crate::future::poll_immediate(udp.service()).await.is_none());
+
+        time.set_tick((61.0 * time.tick_freq()) as usize);
+        test_true!(crate::future::poll_immediate(dns.resolve("example.com"))
+            .await
+            .is_none());
+        test_true!(crate::future::poll_immediate(udp.service()).await.is_some());
+
+        Ok(())
+    });
+
+    create_test!(test_dns_retries, {
+        let (dns, udp, time) = gen_dns();
+
+        let mut resolve = core::pin::pin!(dns.resolve("example.com"));
+        for attempt in 0..Dns::QUERY_ATTEMPTS {
+            test_true!(crate::future::poll_immediate(resolve.as_mut())
+                .await
+                .is_none());
+            test_true!(crate::future::poll_immediate(udp.service()).await.is_some());
+
+            let next_timeout = (attempt + 1) as f32 * Dns::QUERY_TIMEOUT_S;
+            time.set_tick((next_timeout * time.tick_freq()) as usize);
+        }
+
+        let result = crate::future::poll_immediate(resolve.as_mut())
+            .await
+            .ok_or("Resolve did not time out".to_string())?;
+        test_true!(matches!(result, Err(ResolveError::Timeout)));
+
+        test_eq!(
+            dns.resolve("10.0.2.2").await.ok(),
+            Some(vec![IpAddr::V4([10, 0, 2, 2])])
+        );
+        Ok(())
+    });
+}
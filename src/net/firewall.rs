@@ -0,0 +1,505 @@
+use crate::{
+    net::{dns::parse_ipv4_literal, stats::FmtIp, IpAddr, Ipv4Protocol, NetDevice},
+    util::async_mutex::Mutex,
+};
+
+use alloc::vec::Vec;
+use core::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+/// Interface a packet was received on, VLAN sub-interfaces are matched by their ID
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Interface {
+    Ethernet,
+    Loopback,
+    Vlan(u16),
+}
+
+impl FromStr for Interface {
+    type Err = InvalidRule;
+
+    fn from_str(s: &str) -> Result<Interface, InvalidRule> {
+        match s {
+            "eth0" => Ok(Interface::Ethernet),
+            "lo" => Ok(Interface::Loopback),
+            s => {
+                let id = s.strip_prefix("vlan").ok_or(InvalidRule)?;
+                Ok(Interface::Vlan(id.parse().map_err(|_| InvalidRule)?))
+            }
+        }
+    }
+}
+
+impl Display for Interface {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Interface::Ethernet => write!(f, "eth0"),
+            Interface::Loopback => write!(f, "lo"),
+            Interface::Vlan(id) => write!(f, "vlan{}", id),
+        }
+    }
+}
+
+impl From<NetDevice<'_>> for Interface {
+    fn from(device: NetDevice<'_>) -> Interface {
+        match device {
+            NetDevice::Rtl8139(_) => Interface::Ethernet,
+            NetDevice::Loopback(_) => Interface::Loopback,
+            NetDevice::Vlan(_, tag) => Interface::Vlan(tag.id),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Action {
+    Accept,
+    Drop,
+}
+
+impl FromStr for Action {
+    type Err = InvalidRule;
+
+    fn from_str(s: &str) -> Result<Action, InvalidRule> {
+        match s {
+            "accept" => Ok(Action::Accept),
+            "drop" => Ok(Action::Drop),
+            _ => Err(InvalidRule),
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Accept => write!(f, "accept"),
+            Action::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IpPrefix {
+    pub ip: IpAddr,
+    /// Number of leading bits that have to match
+    pub len: u8,
+}
+
+impl IpPrefix {
+    /// Addresses of the other family never match
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        if self.ip.is_ipv4() != ip.is_ipv4() {
+            return false;
+        }
+
+        let mut remaining = self.len as usize;
+        for (a, b) in self.ip.octets().iter().zip(ip.octets()) {
+            if remaining == 0 {
+                break;
+            }
+            let bits = remaining.min(8);
+            let mask = 0xffu8 << (8 - bits);
+            if a & mask != b & mask {
+                return false;
+            }
+            remaining -= bits;
+        }
+
+        true
+    }
+}
+
+/// Only ipv4 prefixes can be parsed, e.g. 192.168.2.0/24. A plain address is a /32
+impl FromStr for IpPrefix {
+    type Err = InvalidRule;
+
+    fn from_str(s: &str) -> Result<IpPrefix, InvalidRule> {
+        let (ip, len) = match s.split_once('/') {
+            Some((ip, len)) => (ip, len.parse().map_err(|_| InvalidRule)?),
+            None => (s, 32),
+        };
+        let ip = parse_ipv4_literal(ip).ok_or(InvalidRule)?;
+        if len > 32 {
+            return Err(InvalidRule);
+        }
+
+        Ok(IpPrefix { ip: ip.into(), len })
+    }
+}
+
+impl Display for IpPrefix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", FmtIp(&self.ip), self.len)
+    }
+}
+
+/// Fields of a received packet that rules look at
+#[derive(Debug)]
+pub struct PacketInfo {
+    pub interface: Interface,
+    pub source_ip: IpAddr,
+    pub dest_ip: IpAddr,
+    pub protocol: Ipv4Protocol,
+    /// Only set for UDP and TCP
+    pub dest_port: Option<u16>,
+    pub len: usize,
+}
+
+/// Fields left as `None` match every packet
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Rule {
+    pub interface: Option<Interface>,
+    pub source: Option<IpPrefix>,
+    pub dest: Option<IpPrefix>,
+    pub protocol: Option<Ipv4Protocol>,
+    /// Destination port, rules with a port never match protocols without ports
+    pub port: Option<u16>,
+    pub action: Action,
+    /// Log every packet the rule matches
+    pub log: bool,
+}
+
+impl Rule {
+    /// Rule that accepts everything, narrowed down with struct update syntax
+    pub const fn accept() -> Rule {
+        Rule {
+            interface: None,
+            source: None,
+            dest: None,
+            protocol: None,
+            port: None,
+            action: Action::Accept,
+            log: false,
+        }
+    }
+
+    pub const fn drop() -> Rule {
+        Rule {
+            action: Action::Drop,
+            ..Rule::accept()
+        }
+    }
+
+    fn matches(&self, packet: &PacketInfo) -> bool {
+        fn field_matches<T: PartialEq>(filter: &Option<T>, val: Option<T>) -> bool {
+            filter.is_none() || *filter == val
+        }
+
+        fn prefix_matches(prefix: &Option<IpPrefix>, ip: &IpAddr) -> bool {
+            match prefix {
+                Some(prefix) => prefix.contains(ip),
+                None => true,
+            }
+        }
+
+        field_matches(&self.interface, Some(packet.interface))
+            && field_matches(&self.protocol, Some(packet.protocol))
+            && field_matches(&self.port, packet.dest_port)
+            && prefix_matches(&self.source, &packet.source_ip)
+            && prefix_matches(&self.dest, &packet.dest_ip)
+    }
+}
+
+/// The action followed by the fields to match, in the same order as they are displayed:
+/// `accept|drop [on <interface>] [from <prefix>] [to <prefix>] [proto <protocol>]
+/// [port <port>] [log]`
+impl FromStr for Rule {
+    type Err = InvalidRule;
+
+    fn from_str(s: &str) -> Result<Rule, InvalidRule> {
+        let mut words = s.split_whitespace();
+        let mut rule = Rule {
+            action: words.next().ok_or(InvalidRule)?.parse()?,
+            ..Rule::accept()
+        };
+
+        while let Some(word) = words.next() {
+            if word == "log" {
+                rule.log = true;
+                continue;
+            }
+
+            let value = words.next().ok_or(InvalidRule)?;
+            match word {
+                "on" => rule.interface = Some(value.parse()?),
+                "from" => rule.source = Some(value.parse()?),
+                "to" => rule.dest = Some(value.parse()?),
+                "proto" => rule.protocol = Some(value.parse().map_err(|_| InvalidRule)?),
+                "port" => rule.port = Some(value.parse().map_err(|_| InvalidRule)?),
+                _ => return Err(InvalidRule),
+            }
+        }
+
+        Ok(rule)
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.action)?;
+        if let Some(interface) = self.interface {
+            write!(f, " on {}", interface)?;
+        }
+        if let Some(source) = self.source {
+            write!(f, " from {}", source)?;
+        }
+        if let Some(dest) = self.dest {
+            write!(f, " to {}", dest)?;
+        }
+        if let Some(protocol) = self.protocol {
+            write!(f, " proto {}", protocol)?;
+        }
+        if let Some(port) = self.port {
+            write!(f, " port {}", port)?;
+        }
+        if self.log {
+            write!(f, " log")?;
+        }
+        Ok(())
+    }
+}
+
+/// A rule and how much traffic it matched
+#[derive(Debug, Clone)]
+pub struct RuleInfo {
+    pub rule: Rule,
+    pub packets: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug)]
+pub struct InvalidRuleIndex;
+
+#[derive(Debug)]
+pub struct InvalidRule;
+
+struct RuleTable {
+    rules: Vec<RuleInfo>,
+    default_action: Action,
+}
+
+/// Stateless filter for received ip packets. Rules are checked in order and the first match
+/// decides, packets no rule matches get the default action
+pub struct Firewall {
+    table: Mutex<RuleTable>,
+}
+
+impl Firewall {
+    pub fn new(rules: Vec<Rule>, default_action: Action) -> Firewall {
+        let rules = rules
+            .into_iter()
+            .map(|rule| RuleInfo {
+                rule,
+                packets: 0,
+                bytes: 0,
+            })
+            .collect();
+
+        Firewall {
+            table: Mutex::new(RuleTable {
+                rules,
+                default_action,
+            }),
+        }
+    }
+
+    pub async fn default_action(&self) -> Action {
+        self.table.lock().await.default_action
+    }
+
+    pub async fn set_default_action(&self, action: Action) {
+        self.table.lock().await.default_action = action;
+    }
+
+    pub async fn push(&self, rule: Rule) {
+        self.table.lock().await.rules.push(RuleInfo {
+            rule,
+            packets: 0,
+            bytes: 0,
+        });
+    }
+
+    pub async fn insert(&self, index: usize, rule: Rule) -> Result<(), InvalidRuleIndex> {
+        let mut table = self.table.lock().await;
+        if index > table.rules.len() {
+            return Err(InvalidRuleIndex);
+        }
+
+        table.rules.insert(
+            index,
+            RuleInfo {
+                rule,
+                packets: 0,
+                bytes: 0,
+            },
+        );
+        Ok(())
+    }
+
+    pub async fn remove(&self, index: usize) -> Result<Rule, InvalidRuleIndex> {
+        let mut table = self.table.lock().await;
+        if index >= table.rules.len() {
+            return Err(InvalidRuleIndex);
+        }
+
+        Ok(table.rules.remove(index).rule)
+    }
+
+    pub async fn rules(&self) -> Vec<RuleInfo> {
+        self.table.lock().await.rules.clone()
+    }
+
+    pub async fn evaluate(&self, packet: &PacketInfo) -> Action {
+        let mut table = self.table.lock().await;
+        let matched = table
+            .rules
+            .iter_mut()
+            .enumerate()
+            .find(|(_, info)| info.rule.matches(packet));
+
+        let Some((index, info)) = matched else {
+            return table.default_action;
+        };
+
+        info.packets += 1;
+        info.bytes += packet.len;
+        if info.rule.log {
+            info!(
+                "Firewall rule {}: {:?} {:?}",
+                index, info.rule.action, packet
+            );
+        }
+
+        info.rule.action
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::{string::ToString, vec};
+
+    create_test!(test_prefix_contains, {
+        let prefix = IpPrefix {
+            ip: IpAddr::V4([192, 168, 2, 0]),
+            len: 23,
+        };
+        test_true!(prefix.contains(&IpAddr::V4([192, 168, 3, 10])));
+        test_false!(prefix.contains(&IpAddr::V4([192, 168, 4, 10])));
+        test_false!(prefix.contains(&IpAddr::V6([0; 16])));
+
+        let mut link_local = [0; 16];
+        link_local[0] = 0xfe;
+        link_local[1] = 0x80;
+        let prefix = IpPrefix {
+            ip: IpAddr::V6(link_local),
+            len: 10,
+        };
+        link_local[1] = 0xbf;
+        link_local[15] = 1;
+        test_true!(prefix.contains(&IpAddr::V6(link_local)));
+        link_local[1] = 0xc0;
+        test_false!(prefix.contains(&IpAddr::V6(link_local)));
+
+        let any = IpPrefix {
+            ip: IpAddr::V4([0; 4]),
+            len: 0,
+        };
+        test_true!(any.contains(&IpAddr::V4([10, 0, 0, 1])));
+
+        Ok(())
+    });
+
+    create_test!(test_firewall_first_match, {
+        let local = IpPrefix {
+            ip: IpAddr::V4([192, 168, 2, 0]),
+            len: 24,
+        };
+        let firewall = Firewall::new(
+            vec![
+                Rule {
+                    source: Some(local),
+                    ..Rule::accept()
+                },
+                Rule {
+                    protocol: Some(Ipv4Protocol::Tcp),
+                    port: Some(80),
+                    ..Rule::drop()
+                },
+            ],
+            Action::Accept,
+        );
+
+        let mut packet = PacketInfo {
+            interface: Interface::Ethernet,
+            source_ip: IpAddr::V4([10, 0, 0, 1]),
+            dest_ip: IpAddr::V4([192, 168, 2, 2]),
+            protocol: Ipv4Protocol::Tcp,
+            dest_port: Some(80),
+            len: 60,
+        };
+        test_eq!(firewall.evaluate(&packet).await, Action::Drop);
+
+        packet.source_ip = IpAddr::V4([192, 168, 2, 1]);
+        test_eq!(firewall.evaluate(&packet).await, Action::Accept);
+
+        // Falls through to the default action
+        packet.source_ip = IpAddr::V4([10, 0, 0, 1]);
+        packet.dest_port = Some(81);
+        test_eq!(firewall.evaluate(&packet).await, Action::Accept);
+
+        let rules = firewall.rules().await;
+        test_eq!(rules[0].packets, 1);
+        test_eq!(rules[1].packets, 1);
+        test_eq!(rules[1].bytes, 60);
+
+        // Rules are runtime configurable
+        test_ok!(firewall.insert(0, Rule::drop()).await);
+        test_eq!(firewall.evaluate(&packet).await, Action::Drop);
+        let removed = firewall.remove(0).await.ok();
+        test_eq!(removed, Some(Rule::drop()));
+        test_err!(firewall.remove(2).await);
+        test_err!(firewall.insert(3, Rule::drop()).await);
+
+        firewall.set_default_action(Action::Drop).await;
+        test_eq!(firewall.evaluate(&packet).await, Action::Drop);
+
+        Ok(())
+    });
+
+    create_test!(test_rule_parsing, {
+        let text = "drop on vlan10 from 10.0.0.0/8 to 192.168.2.2/32 proto tcp port 22 log";
+        let rule = text
+            .parse::<Rule>()
+            .map_err(|_| "Rule not parsed".to_string())?;
+        test_eq!(
+            rule,
+            Rule {
+                interface: Some(Interface::Vlan(10)),
+                source: Some(IpPrefix {
+                    ip: IpAddr::V4([10, 0, 0, 0]),
+                    len: 8,
+                }),
+                dest: Some(IpPrefix {
+                    ip: IpAddr::V4([192, 168, 2, 2]),
+                    len: 32,
+                }),
+                protocol: Some(Ipv4Protocol::Tcp),
+                port: Some(22),
+                log: true,
+                ..Rule::drop()
+            }
+        );
+        test_eq!(rule.to_string(), text);
+
+        test_eq!("accept".parse::<Rule>().ok(), Some(Rule::accept()));
+        test_err!("reject".parse::<Rule>());
+        test_err!("drop port".parse::<Rule>());
+        test_err!("drop from 10.0.0.0/33".parse::<Rule>());
+        test_err!("drop on eth1".parse::<Rule>());
+
+        Ok(())
+    });
+}
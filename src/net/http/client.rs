@@ -0,0 +1,351 @@
+use crate::{
+    net::{
+        dns::{Dns, ResolveError},
+        http::{
+            parser::{ParseError, ResponseParser},
+            Headers, Method, Response,
+        },
+        tcp::{ConnectError, Tcp},
+    },
+    sleep::WakeupRequester,
+    time::MonotonicTime,
+    util::async_io::{AsyncRead, AsyncWrite, IoError},
+};
+
+use alloc::{format, string::String, vec::Vec};
+
+#[derive(Debug)]
+pub enum HttpError {
+    InvalidUrl,
+    /// Only plain http is supported
+    UnsupportedScheme,
+    Resolve(ResolveError),
+    Connect(ConnectError),
+    Io(IoError),
+    InvalidResponse(ParseError),
+    /// The server closed the connection before the response was complete
+    ConnectionClosed,
+    Timeout,
+    TooManyRedirects,
+}
+
+impl core::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidUrl => f.write_str("invalid url"),
+            Self::UnsupportedScheme => f.write_str("unsupported scheme"),
+            Self::Resolve(e) => f.write_fmt(format_args!("failed to resolve host: {}", e)),
+            Self::Connect(e) => f.write_fmt(format_args!("failed to connect: {:?}", e)),
+            Self::Io(e) => f.write_fmt(format_args!("io error: {:?}", e)),
+            Self::InvalidResponse(e) => f.write_fmt(format_args!("invalid response: {:?}", e)),
+            Self::ConnectionClosed => f.write_str("connection closed"),
+            Self::Timeout => f.write_str("timed out"),
+            Self::TooManyRedirects => f.write_str("too many redirects"),
+        }?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// Path and query, always starts with a slash
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, HttpError> {
+        let rest = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+            Some(_) => return Err(HttpError::UnsupportedScheme),
+            None => return Err(HttpError::InvalidUrl),
+        };
+
+        // Fragments are never sent
+        let rest = rest.split('#').next().unwrap_or(rest);
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].into()),
+            None => (rest, "/".into()),
+        };
+
+        // User info and ipv6 literals are not supported
+        if authority.contains(['@', '[']) {
+            return Err(HttpError::InvalidUrl);
+        }
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| HttpError::InvalidUrl)?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(HttpError::InvalidUrl);
+        }
+
+        Ok(Url {
+            host: host.into(),
+            port,
+            path,
+        })
+    }
+
+    /// Resolves a Location header relative to this url
+    pub fn join(&self, location: &str) -> Result<Url, HttpError> {
+        if location.contains("://") {
+            return Url::parse(location);
+        }
+        if location.starts_with("//") {
+            return Url::parse(&format!("http:{}", location));
+        }
+
+        let path = if location.starts_with('/') {
+            location.into()
+        } else {
+            let path = self.path.split('?').next().unwrap_or(&self.path);
+            let dir = &path[..path.rfind('/').expect("Paths start with a slash") + 1];
+            format!("{}{}", dir, location)
+        };
+
+        Ok(Url {
+            host: self.host.clone(),
+            port: self.port,
+            path,
+        })
+    }
+
+    fn host_header(&self) -> String {
+        match self.port {
+            80 => self.host.clone(),
+            port => format!("{}:{}", self.host, port),
+        }
+    }
+}
+
+fn generate_request(method: Method, url: &Url, headers: &Headers, body: &[u8]) -> Vec<u8> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\n",
+        method.as_str(),
+        url.path,
+        url.host_header()
+    );
+    for (name, value) in headers.iter() {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    if !body.is_empty() || method == Method::Post {
+        head += &format!("Content-Length: {}\r\n", body.len());
+    }
+    // One request per connection keeps the response framing simple
+    head += "Connection: close\r\n\r\n";
+
+    let mut ret = head.into_bytes();
+    ret.extend_from_slice(body);
+    ret
+}
+
+/// Writes a request and reads the response
+async fn exchange<S: AsyncRead + AsyncWrite>(
+    stream: &S,
+    request: &[u8],
+    max_body_len: usize,
+) -> Result<Response, HttpError> {
+    stream.write_all(request).await.map_err(HttpError::Io)?;
+
+    let mut parser = ResponseParser::new(max_body_len);
+    let mut buf = [0; 1024];
+    loop {
+        if let Some(response) = parser.next_response().map_err(HttpError::InvalidResponse)? {
+            return Ok(response);
+        }
+
+        let len = stream.read(&mut buf).await;
+        if len == 0 {
+            return parser.finish().ok_or(HttpError::ConnectionClosed);
+        }
+        parser.push(&buf[..len]);
+    }
+}
+
+/// HTTP/1.1 client over ipv4, host names are looked up with the stub resolver
+pub struct Client<'a> {
+    tcp: &'a Tcp,
+    dns: &'a Dns,
+    time: &'a MonotonicTime,
+    wakeup_list: &'a WakeupRequester,
+    /// How long to wait for each response once connected
+    pub timeout_s: f32,
+    pub max_body_len: usize,
+    pub max_redirects: usize,
+}
+
+impl<'a> Client<'a> {
+    pub fn new(
+        tcp: &'a Tcp,
+        dns: &'a Dns,
+        time: &'a MonotonicTime,
+        wakeup_list: &'a WakeupRequester,
+    ) -> Client<'a> {
+        Client {
+            tcp,
+            dns,
+            time,
+            wakeup_list,
+            timeout_s: 10.0,
+            max_body_len: 1024 * 1024,
+            max_redirects: 5,
+        }
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Response, HttpError> {
+        self.request(Method::Get, url, &Headers::new(), Vec::new())
+            .await
+    }
+
+    #[allow(unused)]
+    pub async fn post(
+        &self,
+        url: &str,
+        content_type: &str,
+        body: impl Into<Vec<u8>>,
+    ) -> Result<Response, HttpError> {
+        let mut headers = Headers::new();
+        headers.push("Content-Type", content_type);
+        self.request(Method::Post, url, &headers, body.into()).await
+    }
+
+    /// Follows redirects. 301, 302 and 303 turn into GETs without a body like browsers do,
+    /// 307 and 308 repeat the request
+    pub async fn request(
+        &self,
+        mut method: Method,
+        url: &str,
+        headers: &Headers,
+        mut body: Vec<u8>,
+    ) -> Result<Response, HttpError> {
+        let mut url = Url::parse(url)?;
+        for _ in 0..=self.max_redirects {
+            let response = self.send(method, &url, headers, &body).await?;
+            let location = match response.status.0 {
+                301 | 302 | 303 | 307 | 308 => response.headers.get("Location"),
+                _ => None,
+            };
+            let Some(location) = location else {
+                return Ok(response);
+            };
+
+            debug!("Redirected from {:?} to {}", url, location);
+            url = url.join(location)?;
+            if matches!(response.status.0, 301..=303) && method != Method::Head {
+                method = Method::Get;
+                body.clear();
+            }
+        }
+
+        Err(HttpError::TooManyRedirects)
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: &Url,
+        headers: &Headers,
+        body: &[u8],
+    ) -> Result<Response, HttpError> {
+        let ips = self
+            .dns
+            .resolve(&url.host)
+            .await
+            .map_err(HttpError::Resolve)?;
+        let connection = self
+            .tcp
+            .connect(ips[0], url.port)
+            .await
+            .map_err(HttpError::Connect)?;
+
+        let request = generate_request(method, url, headers, body);
+        let exchange = exchange(&connection, &request, self.max_body_len);
+        let result = crate::sleep::timeout(self.timeout_s, self.time, self.wakeup_list, exchange)
+            .await
+            .unwrap_or(Err(HttpError::Timeout));
+
+        connection.close().await;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::http::{test::MockStream, StatusCode};
+    use crate::testing::*;
+    use alloc::string::ToString;
+
+    create_test!(test_url, {
+        let url = Url::parse("http://192.168.2.1:8000/files/kernel.bin?x=1#top").unwrap();
+        test_eq!(url.host, "192.168.2.1");
+        test_eq!(url.port, 8000);
+        test_eq!(url.path, "/files/kernel.bin?x=1");
+        test_eq!(url.host_header(), "192.168.2.1:8000");
+
+        let url = Url::parse("HTTP://example.com?q").unwrap();
+        test_eq!(url.path, "/?q");
+        test_eq!(url.host_header(), "example.com");
+
+        test_true!(matches!(
+            Url::parse("https://example.com/"),
+            Err(HttpError::UnsupportedScheme)
+        ));
+        test_true!(matches!(
+            Url::parse("example.com/"),
+            Err(HttpError::InvalidUrl)
+        ));
+        test_true!(matches!(
+            Url::parse("http://example.com:99999/"),
+            Err(HttpError::InvalidUrl)
+        ));
+
+        let base = Url::parse("http://example.com:8000/a/b?c").unwrap();
+        test_eq!(base.join("d").unwrap().path, "/a/d");
+        test_eq!(base.join("/e?f").unwrap().path, "/e?f");
+        let other = base.join("//other.com/g").unwrap();
+        test_eq!(other.host, "other.com");
+        test_eq!(other.port, 80);
+
+        Ok(())
+    });
+
+    create_test!(test_exchange, {
+        let url = Url::parse("http://192.168.2.1:8000/results").unwrap();
+        let mut headers = Headers::new();
+        headers.push("Content-Type", "text/plain");
+        let request = generate_request(Method::Post, &url, &headers, b"ok");
+        test_eq!(
+            String::from_utf8(request.clone()).unwrap(),
+            "POST /results HTTP/1.1\r\n\
+             Host: 192.168.2.1:8000\r\n\
+             Content-Type: text/plain\r\n\
+             Content-Length: 2\r\n\
+             Connection: close\r\n\r\nok"
+                .to_string()
+        );
+
+        let stream = MockStream::new(
+            b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nsaved\r\n0\r\n\r\n",
+        );
+        let response = exchange(&stream, &request, 1024).await.unwrap();
+        test_eq!(response.status, StatusCode(201));
+        test_eq!(response.body.as_slice(), b"saved");
+        test_eq!(*stream.output.lock(), request);
+
+        // Truncated responses are errors, unless delimited by the close
+        let stream = MockStream::new(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort");
+        let result = exchange(&stream, &request, 1024).await;
+        test_true!(matches!(result, Err(HttpError::ConnectionClosed)));
+
+        let stream = MockStream::new(b"HTTP/1.0 200 OK\r\n\r\nuntil close");
+        let response = exchange(&stream, &request, 1024).await.unwrap();
+        test_eq!(response.body.as_slice(), b"until close");
+
+        Ok(())
+    });
+}
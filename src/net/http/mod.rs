@@ -0,0 +1,333 @@
+pub mod client;
+pub mod parser;
+pub mod server;
+pub mod websocket;
+
+use alloc::{format, string::String, vec::Vec};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+}
+
+impl Method {
+    fn from_bytes(method: &[u8]) -> Option<Method> {
+        let ret = match method {
+            b"GET" => Method::Get,
+            b"HEAD" => Method::Head,
+            b"POST" => Method::Post,
+            b"PUT" => Method::Put,
+            b"DELETE" => Method::Delete,
+            b"OPTIONS" => Method::Options,
+            _ => return None,
+        };
+        Some(ret)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StatusCode(pub u16);
+
+impl StatusCode {
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+    pub const OK: StatusCode = StatusCode(200);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const UPGRADE_REQUIRED: StatusCode = StatusCode(426);
+    pub const HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const VERSION_NOT_SUPPORTED: StatusCode = StatusCode(505);
+
+    pub fn reason(&self) -> &'static str {
+        match self.0 {
+            101 => "Switching Protocols",
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            426 => "Upgrade Required",
+            431 => "Request Header Fields Too Large",
+            501 => "Not Implemented",
+            505 => "HTTP Version Not Supported",
+            _ => "",
+        }
+    }
+}
+
+/// Header fields in the order they were received, names compare case insensitively
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers(Vec::new())
+    }
+
+    /// The first value for name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the comma separated list in name contains token
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.0
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn push(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct InvalidPercentEncoding;
+
+/// Decodes %XX escapes, and + as a space in query strings
+pub fn percent_decode(data: &[u8], plus_as_space: bool) -> Result<String, InvalidPercentEncoding> {
+    fn hex_val(c: u8) -> Option<u8> {
+        (c as char).to_digit(16).map(|v| v as u8)
+    }
+
+    let mut ret = Vec::with_capacity(data.len());
+    let mut it = data.iter();
+    while let Some(&c) = it.next() {
+        let decoded = match c {
+            b'%' => {
+                let high = it.next().and_then(|c| hex_val(*c));
+                let low = it.next().and_then(|c| hex_val(*c));
+                match (high, low) {
+                    (Some(high), Some(low)) => high << 4 | low,
+                    _ => return Err(InvalidPercentEncoding),
+                }
+            }
+            b'+' if plus_as_space => b' ',
+            c => c,
+        };
+        ret.push(decoded);
+    }
+
+    String::from_utf8(ret).map_err(|_| InvalidPercentEncoding)
+}
+
+/// Splits an application/x-www-form-urlencoded string into decoded key value pairs
+pub fn parse_query(query: &[u8]) -> Result<Vec<(String, String)>, InvalidPercentEncoding> {
+    query
+        .split(|b| *b == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut it = pair.splitn(2, |b| *b == b'=');
+            let key = it.next().expect("splitn always returns one item");
+            let value = it.next().unwrap_or(&[]);
+            Ok((percent_decode(key, true)?, percent_decode(value, true)?))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    /// Percent decoded, without the query
+    pub path: String,
+    pub params: Vec<(String, String)>,
+    /// Minor version, 0 or 1
+    pub version: u8,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// HTTP/1.1 connections persist unless closed, HTTP/1.0 ones have to ask
+    pub fn keep_alive(&self) -> bool {
+        if self.version == 0 {
+            self.headers.contains_token("Connection", "keep-alive")
+        } else {
+            !self.headers.contains_token("Connection", "close")
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    /// Content-Length and Connection are added when serializing
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn with_body(status: StatusCode, content_type: &str, body: impl Into<Vec<u8>>) -> Response {
+        let mut headers = Headers::new();
+        headers.push("Content-Type", content_type);
+        Response {
+            status,
+            headers,
+            body: body.into(),
+        }
+    }
+
+    pub fn html(body: impl Into<Vec<u8>>) -> Response {
+        Response::with_body(StatusCode::OK, "text/html; charset=utf-8", body)
+    }
+
+    pub fn text(body: impl Into<Vec<u8>>) -> Response {
+        Response::with_body(StatusCode::OK, "text/plain; charset=utf-8", body)
+    }
+
+    /// Error responses carry their reason phrase as the body
+    pub fn error(status: StatusCode) -> Response {
+        Response::with_body(status, "text/plain; charset=utf-8", status.reason())
+    }
+
+    /// Responses to HEAD requests keep the Content-Length of the body they leave out.
+    /// Informational responses never have a body and set their own Connection header
+    pub fn serialize(&self, keep_alive: bool, include_body: bool) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status.0, self.status.reason());
+        for (name, value) in self.headers.iter() {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        if self.status.0 >= 200 {
+            head += &format!("Content-Length: {}\r\n", self.body.len());
+            let connection = if keep_alive { "keep-alive" } else { "close" };
+            head += &format!("Connection: {}\r\n", connection);
+        }
+        head += "\r\n";
+
+        let mut ret = head.into_bytes();
+        if include_body {
+            ret.extend_from_slice(&self.body);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::testing::*;
+    use crate::util::{
+        async_io::{AsyncRead, AsyncWrite, BoxFuture, IoError},
+        spinlock::SpinLock,
+    };
+    use alloc::{boxed::Box, collections::VecDeque, string::ToString};
+
+    /// Hands out input a few bytes at a time and collects output
+    pub struct MockStream {
+        input: SpinLock<VecDeque<u8>>,
+        pub output: SpinLock<Vec<u8>>,
+    }
+
+    impl MockStream {
+        pub fn new(input: &[u8]) -> MockStream {
+            MockStream {
+                input: SpinLock::new(input.iter().copied().collect()),
+                output: SpinLock::new(Vec::new()),
+            }
+        }
+    }
+
+    impl AsyncRead for MockStream {
+        fn read<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, usize> {
+            Box::pin(async move {
+                let mut input = self.input.lock();
+                let len = buf.len().min(input.len()).min(7);
+                for (dst, src) in buf.iter_mut().zip(input.drain(..len)) {
+                    *dst = src;
+                }
+                len
+            })
+        }
+    }
+
+    impl AsyncWrite for MockStream {
+        fn write<'a>(&'a self, buf: &'a [u8]) -> BoxFuture<'a, Result<usize, IoError>> {
+            Box::pin(async move {
+                self.output.lock().extend_from_slice(buf);
+                Ok(buf.len())
+            })
+        }
+    }
+
+    create_test!(test_percent_decode, {
+        let decode = |data: &[u8], plus_as_space| percent_decode(data, plus_as_space).ok();
+        test_eq!(decode(b"a%20b+c%2Bd", true), Some("a b c+d".to_string()));
+        test_eq!(decode(b"a+b", false), Some("a+b".to_string()));
+        test_eq!(decode(b"%e2%9c%93", false), Some("\u{2713}".to_string()));
+        test_err!(percent_decode(b"%4", false));
+        test_err!(percent_decode(b"%zz", false));
+        // Not utf-8
+        test_err!(percent_decode(b"%ff", false));
+
+        let params = parse_query(b"data=hello+world&empty=&flag&&x=%3D").ok();
+        test_eq!(
+            params,
+            Some(alloc::vec![
+                ("data".to_string(), "hello world".to_string()),
+                ("empty".to_string(), String::new()),
+                ("flag".to_string(), String::new()),
+                ("x".to_string(), "=".to_string()),
+            ])
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_response_serialize, {
+        let response = Response::text("hi");
+        test_eq!(
+            response.serialize(true, true),
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/plain; charset=utf-8\r\n\
+              Content-Length: 2\r\n\
+              Connection: keep-alive\r\n\r\nhi"
+                .to_vec()
+        );
+
+        let serialized = Response::error(StatusCode::NOT_FOUND).serialize(false, false);
+        test_eq!(
+            serialized,
+            b"HTTP/1.1 404 Not Found\r\n\
+              Content-Type: text/plain; charset=utf-8\r\n\
+              Content-Length: 9\r\n\
+              Connection: close\r\n\r\n"
+                .to_vec()
+        );
+
+        Ok(())
+    });
+}
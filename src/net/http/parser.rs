@@ -0,0 +1,563 @@
+use crate::net::http::{self, Headers, Method, Request, Response, StatusCode};
+
+use alloc::vec::Vec;
+
+/// Request line and headers
+const MAX_HEAD_LEN: usize = 8192;
+const MAX_REQUEST_BODY_LEN: usize = 64 * 1024;
+// Chunk size lines with extensions, and trailer lines
+const MAX_LINE_LEN: usize = 1024;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseError {
+    InvalidRequestLine,
+    InvalidStatusLine,
+    UnknownMethod,
+    UnsupportedVersion,
+    InvalidHeader,
+    HeadTooLarge,
+    InvalidContentLength,
+    /// Both Content-Length and Transfer-Encoding, which is how requests get smuggled
+    ConflictingLength,
+    UnsupportedTransferEncoding,
+    InvalidChunk,
+    BodyTooLarge,
+    InvalidPercentEncoding,
+}
+
+impl ParseError {
+    /// What the server answers before closing the connection
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ParseError::UnknownMethod | ParseError::UnsupportedTransferEncoding => {
+                StatusCode::NOT_IMPLEMENTED
+            }
+            ParseError::UnsupportedVersion => StatusCode::VERSION_NOT_SUPPORTED,
+            ParseError::HeadTooLarge => StatusCode::HEADER_FIELDS_TOO_LARGE,
+            ParseError::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl From<http::InvalidPercentEncoding> for ParseError {
+    fn from(_: http::InvalidPercentEncoding) -> ParseError {
+        ParseError::InvalidPercentEncoding
+    }
+}
+
+fn find_crlf(data: &[u8]) -> Option<usize> {
+    data.windows(2).position(|w| w == b"\r\n")
+}
+
+fn is_token_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+/// Header lines without the terminating empty line
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a [u8]>) -> Result<Headers, ParseError> {
+    let mut headers = Headers::new();
+    for line in lines {
+        let colon = line
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or(ParseError::InvalidHeader)?;
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+        // Also rejects obsolete line folding, where continuation lines start with whitespace
+        if name.is_empty() || !name.iter().all(|c| is_token_char(*c)) {
+            return Err(ParseError::InvalidHeader);
+        }
+
+        let value = core::str::from_utf8(value).map_err(|_| ParseError::InvalidHeader)?;
+        if value.contains(['\r', '\n', '\0']) {
+            return Err(ParseError::InvalidHeader);
+        }
+
+        let name = core::str::from_utf8(name).expect("Token characters are ascii");
+        headers.push(name, value.trim_matches([' ', '\t']));
+    }
+
+    Ok(headers)
+}
+
+/// Position of the empty line that ends the head
+fn find_head_end(buf: &[u8]) -> Result<Option<usize>, ParseError> {
+    match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(v) if v > MAX_HEAD_LEN => Err(ParseError::HeadTooLarge),
+        Some(v) => Ok(Some(v)),
+        None if buf.len() > MAX_HEAD_LEN => Err(ParseError::HeadTooLarge),
+        None => Ok(None),
+    }
+}
+
+/// Bare line feeds are accepted as line endings
+fn head_lines(head: &[u8]) -> impl Iterator<Item = &[u8]> {
+    head.split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+fn parse_request_head(head: &[u8]) -> Result<Request, ParseError> {
+    let mut lines = head_lines(head);
+    let request_line = lines.next().expect("split always returns one item");
+
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::InvalidRequestLine);
+    };
+
+    let method = Method::from_bytes(method).ok_or(ParseError::UnknownMethod)?;
+    let version = match version {
+        b"HTTP/1.1" => 1,
+        b"HTTP/1.0" => 0,
+        v if v.starts_with(b"HTTP/") => return Err(ParseError::UnsupportedVersion),
+        _ => return Err(ParseError::InvalidRequestLine),
+    };
+
+    // Only origin-form targets, proxies are not supported
+    if !target.starts_with(b"/") {
+        return Err(ParseError::InvalidRequestLine);
+    }
+    let mut target = target.splitn(2, |b| *b == b'?');
+    let path = target.next().expect("splitn always returns one item");
+    let path = http::percent_decode(path, false)?;
+    let params = match target.next() {
+        Some(query) => http::parse_query(query)?,
+        None => Vec::new(),
+    };
+
+    Ok(Request {
+        method,
+        path,
+        params,
+        version,
+        headers: parse_headers(lines)?,
+        body: Vec::new(),
+    })
+}
+
+fn parse_response_head(head: &[u8]) -> Result<Response, ParseError> {
+    let mut lines = head_lines(head);
+    let status_line = lines.next().expect("split always returns one item");
+
+    // The reason phrase is ignored
+    let mut parts = status_line.splitn(3, |b| *b == b' ');
+    let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+        return Err(ParseError::InvalidStatusLine);
+    };
+
+    match version {
+        b"HTTP/1.1" | b"HTTP/1.0" => (),
+        v if v.starts_with(b"HTTP/") => return Err(ParseError::UnsupportedVersion),
+        _ => return Err(ParseError::InvalidStatusLine),
+    }
+
+    if status.len() != 3 || !status.iter().all(|c| c.is_ascii_digit()) {
+        return Err(ParseError::InvalidStatusLine);
+    }
+    let status = core::str::from_utf8(status)
+        .expect("Digits are ascii")
+        .parse()
+        .expect("Three digits fit a u16");
+
+    Ok(Response {
+        status: StatusCode(status),
+        headers: parse_headers(lines)?,
+        body: Vec::new(),
+    })
+}
+
+/// Where in the body the parser is
+#[derive(Debug)]
+enum BodyState {
+    Fixed(usize),
+    ChunkSize,
+    ChunkData(usize),
+    /// CRLF after the chunk data
+    ChunkDataEnd,
+    Trailers,
+    /// Responses without Content-Length or Transfer-Encoding end when the connection closes
+    UntilClose,
+}
+
+impl BodyState {
+    /// None without Content-Length or Transfer-Encoding
+    fn from_headers(
+        headers: &Headers,
+        max_body_len: usize,
+    ) -> Result<Option<BodyState>, ParseError> {
+        let transfer_encoding = headers.get("Transfer-Encoding");
+        let content_length = headers.get("Content-Length");
+        match (transfer_encoding, content_length) {
+            (Some(_), Some(_)) => Err(ParseError::ConflictingLength),
+            (Some(encoding), None) => {
+                if !encoding.trim().eq_ignore_ascii_case("chunked") {
+                    return Err(ParseError::UnsupportedTransferEncoding);
+                }
+                Ok(Some(BodyState::ChunkSize))
+            }
+            (None, Some(length)) => {
+                if length.is_empty() || !length.bytes().all(|c| c.is_ascii_digit()) {
+                    return Err(ParseError::InvalidContentLength);
+                }
+                match length.parse::<usize>() {
+                    Ok(v) if v <= max_body_len => Ok(Some(BodyState::Fixed(v))),
+                    _ => Err(ParseError::BodyTooLarge),
+                }
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Moves body bytes out of buf, returns true once the body is complete
+    fn advance(
+        &mut self,
+        buf: &mut Vec<u8>,
+        body: &mut Vec<u8>,
+        max_body_len: usize,
+    ) -> Result<bool, ParseError> {
+        let mut pos = 0;
+        let done = loop {
+            let available = &buf[pos..];
+            match *self {
+                BodyState::Fixed(remaining) | BodyState::ChunkData(remaining) => {
+                    let len = remaining.min(available.len());
+                    body.extend_from_slice(&available[..len]);
+                    pos += len;
+
+                    let remaining = remaining - len;
+                    match self {
+                        BodyState::Fixed(v) | BodyState::ChunkData(v) if remaining != 0 => {
+                            *v = remaining;
+                            break false;
+                        }
+                        BodyState::Fixed(_) => break true,
+                        _ => *self = BodyState::ChunkDataEnd,
+                    }
+                }
+                BodyState::ChunkSize => {
+                    let Some(line_end) = find_crlf(available) else {
+                        if available.len() > MAX_LINE_LEN {
+                            return Err(ParseError::InvalidChunk);
+                        }
+                        break false;
+                    };
+
+                    // Chunk extensions are ignored
+                    let line = &available[..line_end];
+                    let size = line.split(|b| *b == b';').next().unwrap_or(line);
+                    let size = core::str::from_utf8(size)
+                        .ok()
+                        .map(|s| s.trim_matches([' ', '\t']))
+                        .filter(|s| {
+                            !s.is_empty()
+                                && s.len() <= 8
+                                && s.bytes().all(|c| c.is_ascii_hexdigit())
+                        })
+                        .and_then(|s| usize::from_str_radix(s, 16).ok())
+                        .ok_or(ParseError::InvalidChunk)?;
+                    pos += line_end + 2;
+
+                    if size == 0 {
+                        *self = BodyState::Trailers;
+                    } else if body.len() + size > max_body_len {
+                        return Err(ParseError::BodyTooLarge);
+                    } else {
+                        *self = BodyState::ChunkData(size);
+                    }
+                }
+                BodyState::ChunkDataEnd => {
+                    if available.len() < 2 {
+                        break false;
+                    }
+                    if &available[..2] != b"\r\n" {
+                        return Err(ParseError::InvalidChunk);
+                    }
+                    pos += 2;
+                    *self = BodyState::ChunkSize;
+                }
+                BodyState::Trailers => {
+                    // Trailer fields are dropped
+                    let Some(line_end) = find_crlf(available) else {
+                        if available.len() > MAX_LINE_LEN {
+                            return Err(ParseError::InvalidChunk);
+                        }
+                        break false;
+                    };
+                    pos += line_end + 2;
+                    if line_end == 0 {
+                        break true;
+                    }
+                }
+                BodyState::UntilClose => {
+                    if body.len() + available.len() > max_body_len {
+                        return Err(ParseError::BodyTooLarge);
+                    }
+                    body.extend_from_slice(available);
+                    pos = buf.len();
+                    break false;
+                }
+            }
+        };
+
+        buf.drain(..pos);
+        Ok(done)
+    }
+}
+
+/// Parses requests from a byte stream that arrives in arbitrary pieces. Input after the end of
+/// a request is kept for the next one, so pipelined requests work
+#[derive(Default)]
+pub struct RequestParser {
+    buf: Vec<u8>,
+    // Request whose head has been parsed but whose body is still arriving
+    pending: Option<(Request, Option<BodyState>)>,
+}
+
+impl RequestParser {
+    pub fn new() -> RequestParser {
+        Default::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// None until all of the next request has been pushed. Errors are not recoverable as the
+    /// start of the next request is unknown
+    pub fn next_request(&mut self) -> Result<Option<Request>, ParseError> {
+        if self.pending.is_none() {
+            // Servers should ignore empty lines before the request line, RFC 9112 section 2.2
+            let leading_newlines = self
+                .buf
+                .iter()
+                .take_while(|b| **b == b'\r' || **b == b'\n')
+                .count();
+            self.buf.drain(..leading_newlines);
+
+            let Some(head_end) = find_head_end(&self.buf)? else {
+                return Ok(None);
+            };
+
+            // Requests without a length have no body
+            let request = parse_request_head(&self.buf[..head_end])?;
+            let body = BodyState::from_headers(&request.headers, MAX_REQUEST_BODY_LEN)?;
+            self.buf.drain(..head_end + 4);
+            self.pending = Some((request, body));
+        }
+
+        let (request, body) = self.pending.as_mut().expect("Pending request was just set");
+        if let Some(body) = body {
+            if !body.advance(&mut self.buf, &mut request.body, MAX_REQUEST_BODY_LEN)? {
+                return Ok(None);
+            }
+        }
+
+        Ok(self.pending.take().map(|(request, _)| request))
+    }
+}
+
+/// Parses a response from a byte stream like RequestParser does requests. Interim 1xx
+/// responses are skipped. Responses to HEAD requests are not supported
+pub struct ResponseParser {
+    buf: Vec<u8>,
+    max_body_len: usize,
+    pending: Option<(Response, Option<BodyState>)>,
+}
+
+impl ResponseParser {
+    pub fn new(max_body_len: usize) -> ResponseParser {
+        ResponseParser {
+            buf: Vec::new(),
+            max_body_len,
+            pending: None,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn next_response(&mut self) -> Result<Option<Response>, ParseError> {
+        while self.pending.is_none() {
+            let Some(head_end) = find_head_end(&self.buf)? else {
+                return Ok(None);
+            };
+
+            let response = parse_response_head(&self.buf[..head_end])?;
+            self.buf.drain(..head_end + 4);
+            if response.status.0 / 100 == 1 {
+                continue;
+            }
+
+            let body = match response.status.0 {
+                204 | 304 => None,
+                _ => Some(
+                    BodyState::from_headers(&response.headers, self.max_body_len)?
+                        .unwrap_or(BodyState::UntilClose),
+                ),
+            };
+            self.pending = Some((response, body));
+        }
+
+        let (response, body) = self
+            .pending
+            .as_mut()
+            .expect("Pending response was just set");
+        if let Some(body) = body {
+            if !body.advance(&mut self.buf, &mut response.body, self.max_body_len)? {
+                return Ok(None);
+            }
+        }
+
+        Ok(self.pending.take().map(|(response, _)| response))
+    }
+
+    /// Completes a response that is delimited by the connection closing, call once the peer
+    /// has closed
+    pub fn finish(&mut self) -> Option<Response> {
+        match self.pending.take()? {
+            (response, Some(BodyState::UntilClose)) => Some(response),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::string::ToString;
+
+    create_test!(test_parse_incremental, {
+        let data = b"GET /form%2F1?data=hello+there&x=%41 HTTP/1.1\r\nHost: 192.168.2.2\r\n\
+                     Connection: close\r\n\r\n";
+
+        let mut parser = RequestParser::new();
+        for b in data {
+            test_true!(matches!(parser.next_request(), Ok(None)));
+            parser.push(&[*b]);
+        }
+
+        let request = parser.next_request().unwrap().unwrap();
+        test_eq!(request.method, Method::Get);
+        test_eq!(request.path, "/form/1");
+        test_eq!(request.param("data"), Some("hello there"));
+        test_eq!(request.param("x"), Some("A"));
+        test_eq!(request.headers.get("host"), Some("192.168.2.2"));
+        test_false!(request.keep_alive());
+        test_true!(request.body.is_empty());
+        test_true!(matches!(parser.next_request(), Ok(None)));
+
+        Ok(())
+    });
+
+    create_test!(test_parse_bodies, {
+        let mut parser = RequestParser::new();
+        // Pipelined requests, the second one has a chunked body split across pushes
+        parser.push(
+            b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+              PUT /b HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n6;ext=1\r\nhello \r\n",
+        );
+
+        let request = parser.next_request().unwrap().unwrap();
+        test_eq!(request.method, Method::Post);
+        test_eq!(request.body.as_slice(), b"hello");
+        test_true!(request.keep_alive());
+
+        test_true!(matches!(parser.next_request(), Ok(None)));
+        parser.push(b"5\r\nworld\r\n0\r\nTrailer: x\r\n");
+        test_true!(matches!(parser.next_request(), Ok(None)));
+        parser.push(b"\r\n");
+
+        let request = parser.next_request().unwrap().unwrap();
+        test_eq!(request.method, Method::Put);
+        test_eq!(request.path, "/b".to_string());
+        test_eq!(request.body.as_slice(), b"hello world");
+        test_false!(request.keep_alive());
+
+        Ok(())
+    });
+
+    create_test!(test_parse_responses, {
+        let mut parser = ResponseParser::new(16);
+        parser.push(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n");
+        test_true!(matches!(parser.next_response(), Ok(None)));
+        parser.push(b"Transfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n");
+        let response = parser.next_response().unwrap().unwrap();
+        test_eq!(response.status, StatusCode::OK);
+        test_eq!(response.body.as_slice(), b"abc");
+
+        // No length, the body ends with the connection
+        parser.push(b"HTTP/1.0 404 Not Found\r\n\r\nnot ");
+        test_true!(matches!(parser.next_response(), Ok(None)));
+        parser.push(b"here");
+        test_true!(matches!(parser.next_response(), Ok(None)));
+        let response = parser.finish().unwrap();
+        test_eq!(response.status, StatusCode::NOT_FOUND);
+        test_eq!(response.body.as_slice(), b"not here");
+
+        parser.push(b"HTTP/1.1 204 No Content\r\n\r\n");
+        test_true!(parser.next_response().unwrap().unwrap().body.is_empty());
+
+        parser.push(b"HTTP/1.1 200 OK\r\nContent-Length: 17\r\n\r\n");
+        test_eq!(
+            parser.next_response().unwrap_err(),
+            ParseError::BodyTooLarge
+        );
+
+        let mut parser = ResponseParser::new(16);
+        parser.push(b"HTTP/1.1 2000 OK\r\n\r\n");
+        test_eq!(
+            parser.next_response().unwrap_err(),
+            ParseError::InvalidStatusLine
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_parse_errors, {
+        fn parse(data: &[u8]) -> Result<Option<Request>, ParseError> {
+            let mut parser = RequestParser::new();
+            parser.push(data);
+            parser.next_request()
+        }
+
+        test_eq!(
+            parse(b"GET / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .unwrap_err(),
+            ParseError::ConflictingLength
+        );
+        test_eq!(
+            parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").unwrap_err(),
+            ParseError::InvalidChunk
+        );
+        test_eq!(
+            parse(b"POST / HTTP/1.1\r\nContent-Length: +1\r\n\r\n").unwrap_err(),
+            ParseError::InvalidContentLength
+        );
+        test_eq!(
+            parse(b"GET / HTTP/2.0\r\n\r\n").unwrap_err(),
+            ParseError::UnsupportedVersion
+        );
+        test_eq!(
+            parse(b"BREW / HTTP/1.1\r\n\r\n").unwrap_err().status(),
+            StatusCode::NOT_IMPLEMENTED
+        );
+        test_eq!(
+            parse(b"GET / HTTP/1.1\r\nHost : x\r\n\r\n").unwrap_err(),
+            ParseError::InvalidHeader
+        );
+        test_eq!(
+            parse(b"GET /%zz HTTP/1.1\r\n\r\n").unwrap_err(),
+            ParseError::InvalidPercentEncoding
+        );
+        test_eq!(
+            parse(&[b'a'; MAX_HEAD_LEN + 1]).unwrap_err(),
+            ParseError::HeadTooLarge
+        );
+
+        Ok(())
+    });
+}
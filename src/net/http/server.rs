@@ -0,0 +1,360 @@
+use crate::{
+    future::{self, Either},
+    net::{
+        http::{
+            parser::RequestParser,
+            websocket::{self, close_code, WebSocket, WebSocketHandler},
+            Method, Request, Response, StatusCode,
+        },
+        tcp::{self, TcpListener},
+    },
+    sleep::WakeupRequester,
+    time::MonotonicTime,
+    util::async_io::{AsyncRead, AsyncWrite, BoxFuture},
+};
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::future::Future;
+
+const MAX_CONNECTIONS: usize = 16;
+
+type Handler<'a> = Box<dyn Fn(Request) -> BoxFuture<'a, Response> + Send + Sync + 'a>;
+
+struct Route<'a> {
+    method: Method,
+    path: String,
+    handler: Handler<'a>,
+}
+
+/// Dispatches requests to handlers by method and exact path
+#[derive(Default)]
+pub struct Router<'a> {
+    routes: Vec<Route<'a>>,
+    websockets: Vec<(String, &'a dyn WebSocketHandler)>,
+}
+
+impl<'a> Router<'a> {
+    pub fn new() -> Router<'a> {
+        Default::default()
+    }
+
+    pub fn route<F, Fut>(mut self, method: Method, path: &str, handler: F) -> Router<'a>
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'a,
+        Fut: Future<Output = Response> + Send + 'a,
+    {
+        self.routes.push(Route {
+            method,
+            path: path.into(),
+            handler: Box::new(move |request| Box::pin(handler(request))),
+        });
+        self
+    }
+
+    pub fn get<F, Fut>(self, path: &str, handler: F) -> Router<'a>
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'a,
+        Fut: Future<Output = Response> + Send + 'a,
+    {
+        self.route(Method::Get, path, handler)
+    }
+
+    #[allow(unused)]
+    pub fn post<F, Fut>(self, path: &str, handler: F) -> Router<'a>
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'a,
+        Fut: Future<Output = Response> + Send + 'a,
+    {
+        self.route(Method::Post, path, handler)
+    }
+
+    /// GET requests to path are upgraded to websocket connections
+    pub fn websocket(mut self, path: &str, handler: &'a dyn WebSocketHandler) -> Router<'a> {
+        self.websockets.push((path.into(), handler));
+        self
+    }
+
+    fn websocket_handler(&self, request: &Request) -> Option<&'a dyn WebSocketHandler> {
+        if request.method != Method::Get {
+            return None;
+        }
+
+        self.websockets
+            .iter()
+            .find(|(path, _)| *path == request.path)
+            .map(|(_, handler)| *handler)
+    }
+
+    /// HEAD requests fall back to the GET handler. Unknown paths get a 404, known paths with
+    /// the wrong method a 405
+    pub async fn handle(&self, request: Request) -> Response {
+        let find = |method: Method| {
+            self.routes
+                .iter()
+                .find(|route| route.method == method && route.path == request.path)
+        };
+        let route = match request.method {
+            Method::Head => find(Method::Head).or_else(|| find(Method::Get)),
+            method => find(method),
+        };
+        if let Some(route) = route {
+            return (route.handler)(request).await;
+        }
+
+        let allowed = self
+            .routes
+            .iter()
+            .filter(|route| route.path == request.path)
+            .map(|route| route.method.as_str())
+            .collect::<Vec<_>>();
+        if allowed.is_empty() {
+            return Response::error(StatusCode::NOT_FOUND);
+        }
+
+        let mut response = Response::error(StatusCode::METHOD_NOT_ALLOWED);
+        response.headers.push("Allow", allowed.join(", "));
+        response
+    }
+}
+
+/// HTTP/1.1 server, connections are kept alive and served concurrently
+pub struct Server<'a> {
+    router: Router<'a>,
+    time: &'a MonotonicTime,
+    wakeup_list: &'a WakeupRequester,
+    /// Connections that send nothing for this long are closed
+    pub idle_timeout_s: f32,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(
+        router: Router<'a>,
+        time: &'a MonotonicTime,
+        wakeup_list: &'a WakeupRequester,
+    ) -> Server<'a> {
+        Server {
+            router,
+            time,
+            wakeup_list,
+            idle_timeout_s: 30.0,
+        }
+    }
+
+    pub async fn serve(&self, listener: &TcpListener) {
+        tcp::serve_connections(listener, MAX_CONNECTIONS, |connection| async move {
+            self.serve_connection(&connection).await;
+            connection.close().await;
+        })
+        .await
+    }
+
+    /// Serves requests until the peer closes, asks to close or sends something unparsable
+    pub async fn serve_connection<S: AsyncRead + AsyncWrite>(&self, stream: &S) {
+        let mut parser = RequestParser::new();
+        let mut buf = [0; 1024];
+        loop {
+            let request = match parser.next_request() {
+                Ok(Some(v)) => v,
+                Ok(None) => {
+                    let len = {
+                        let read = core::pin::pin!(stream.read(&mut buf));
+                        let idle = core::pin::pin!(crate::sleep::sleep(
+                            self.idle_timeout_s,
+                            self.time,
+                            self.wakeup_list
+                        ));
+                        match future::select(read, idle).await {
+                            Either::Left((len, _)) => len,
+                            Either::Right(_) => {
+                                debug!("Closing idle http connection");
+                                return;
+                            }
+                        }
+                    };
+                    if len == 0 {
+                        return;
+                    }
+                    parser.push(&buf[..len]);
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Invalid http request: {:?}", e);
+                    let response = Response::error(e.status()).serialize(false, true);
+                    if let Err(e) = stream.write_all(&response).await {
+                        warn!("Failed to write http response: {:?}", e);
+                    }
+                    return;
+                }
+            };
+
+            debug!("{} {}", request.method.as_str(), request.path);
+            if let Some(handler) = self.router.websocket_handler(&request) {
+                let keep_alive = request.keep_alive();
+                if !Self::upgrade(handler, request, stream).await && keep_alive {
+                    continue;
+                }
+                return;
+            }
+
+            let keep_alive = request.keep_alive();
+            let include_body = request.method != Method::Head;
+            let response = self.router.handle(request).await;
+            let response = response.serialize(keep_alive, include_body);
+            if let Err(e) = stream.write_all(&response).await {
+                warn!("Failed to write http response: {:?}", e);
+                return;
+            }
+
+            if !keep_alive {
+                return;
+            }
+        }
+    }
+
+    /// Hands the stream to handler if the handshake succeeds. Returns whether it was upgraded,
+    /// after which the stream can't be used for http anymore
+    async fn upgrade<S: AsyncRead + AsyncWrite>(
+        handler: &dyn WebSocketHandler,
+        request: Request,
+        stream: &S,
+    ) -> bool {
+        let response = websocket::handshake_response(&request);
+        let upgraded = response.status == StatusCode::SWITCHING_PROTOCOLS;
+        if let Err(e) = stream
+            .write_all(&response.serialize(request.keep_alive(), true))
+            .await
+        {
+            warn!("Failed to write http response: {:?}", e);
+            return true;
+        }
+        if !upgraded {
+            return false;
+        }
+
+        let socket = WebSocket::new(stream, stream);
+        handler.handle(request, &socket).await;
+        socket.close(close_code::NORMAL).await;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::http::test::MockStream;
+    use crate::testing::*;
+    use crate::util::async_io::IoError;
+    use alloc::{format, string::ToString};
+
+    /// A peer that connects and never sends anything
+    struct IdleStream;
+
+    impl AsyncRead for IdleStream {
+        fn read<'a>(&'a self, _buf: &'a mut [u8]) -> BoxFuture<'a, usize> {
+            Box::pin(core::future::pending())
+        }
+    }
+
+    impl AsyncWrite for IdleStream {
+        fn write<'a>(&'a self, buf: &'a [u8]) -> BoxFuture<'a, Result<usize, IoError>> {
+            Box::pin(async move { Ok(buf.len()) })
+        }
+    }
+
+    fn router<'a>() -> Router<'a> {
+        Router::new()
+            .get("/", |_| async { Response::html("index") })
+            .post("/echo", |request: Request| async move {
+                Response::text(request.body)
+            })
+            .get("/hello", |request: Request| async move {
+                let name = request.param("name").unwrap_or("world");
+                Response::text(format!("hello {}", name))
+            })
+    }
+
+    create_test!(test_router, {
+        let router = router();
+        let request = |data: &[u8]| {
+            let mut parser = RequestParser::new();
+            parser.push(data);
+            parser.next_request().unwrap().unwrap()
+        };
+
+        let response = router
+            .handle(request(b"GET /hello?name=os HTTP/1.1\r\n\r\n"))
+            .await;
+        test_eq!(response.status, StatusCode::OK);
+        test_eq!(response.body.as_slice(), b"hello os");
+
+        let response = router.handle(request(b"HEAD / HTTP/1.1\r\n\r\n")).await;
+        test_eq!(response.status, StatusCode::OK);
+
+        let response = router
+            .handle(request(b"GET /missing HTTP/1.1\r\n\r\n"))
+            .await;
+        test_eq!(response.status, StatusCode::NOT_FOUND);
+
+        let response = router
+            .handle(request(b"DELETE /echo HTTP/1.1\r\n\r\n"))
+            .await;
+        test_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+        test_eq!(response.headers.get("allow"), Some("POST"));
+
+        Ok(())
+    });
+
+    create_test!(test_keep_alive, {
+        let time = MonotonicTime::new(10.0);
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let server = Server::new(router(), &time, &wakeup_list);
+        // The request after the one asking to close is never answered
+        let stream = MockStream::new(
+            b"GET /hello HTTP/1.1\r\n\r\n\
+              POST /echo HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nping\
+              GET / HTTP/1.1\r\n\r\n",
+        );
+        server.serve_connection(&stream).await;
+
+        let output = String::from_utf8(stream.output.lock().clone()).unwrap();
+        let expected = "HTTP/1.1 200 OK\r\n\
+                        Content-Type: text/plain; charset=utf-8\r\n\
+                        Content-Length: 11\r\n\
+                        Connection: keep-alive\r\n\r\n\
+                        hello world\
+                        HTTP/1.1 200 OK\r\n\
+                        Content-Type: text/plain; charset=utf-8\r\n\
+                        Content-Length: 4\r\n\
+                        Connection: close\r\n\r\n\
+                        ping";
+        test_eq!(output, expected.to_string());
+
+        // Malformed requests are answered and the connection is dropped
+        let stream = MockStream::new(b"GET / HTTP/3\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        server.serve_connection(&stream).await;
+        let output = stream.output.lock().clone();
+        test_true!(output.starts_with(b"HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+        test_true!(output.ends_with(b"Connection: close\r\n\r\nHTTP Version Not Supported"));
+
+        Ok(())
+    });
+
+    create_test!(test_idle_timeout, {
+        let time = MonotonicTime::new(10.0);
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let server = Server::new(router(), &time, &wakeup_list);
+
+        let stream = IdleStream;
+        let mut connection = core::pin::pin!(server.serve_connection(&stream));
+        test_true!(crate::future::poll_immediate(connection.as_mut())
+            .await
+            .is_none());
+
+        time.set_tick((server.idle_timeout_s * time.tick_freq()) as usize);
+        test_true!(crate::future::poll_immediate(connection.as_mut())
+            .await
+            .is_some());
+
+        Ok(())
+    });
+}
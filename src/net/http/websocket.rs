@@ -0,0 +1,616 @@
+use crate::{
+    net::http::{Headers, Request, Response, StatusCode},
+    util::{
+        async_io::{AsyncRead, AsyncWrite, BoxFuture, IoError},
+        async_mutex::Mutex,
+        base64,
+        pubsub::PubSub,
+        sha1::sha1,
+    },
+};
+
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Appended to the client's key before hashing, RFC 6455 section 1.3
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Larger messages close the connection with MESSAGE_TOO_BIG
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(val: u8) -> Option<Opcode> {
+        let ret = match val {
+            0x0 => Opcode::Continuation,
+            0x1 => Opcode::Text,
+            0x2 => Opcode::Binary,
+            0x8 => Opcode::Close,
+            0x9 => Opcode::Ping,
+            0xa => Opcode::Pong,
+            _ => return None,
+        };
+        Some(ret)
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xa,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    /// Unmasked
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Clients have to mask every frame they send, servers must not mask any
+    pub fn serialize(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.payload.len() + 14);
+        let fin = if self.fin { 0x80 } else { 0 };
+        ret.push(fin | self.opcode.to_u8());
+
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            len @ 0..=125 => ret.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                ret.push(mask_bit | 126);
+                ret.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                ret.push(mask_bit | 127);
+                ret.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        let payload_start = ret.len() + mask.map_or(0, |key| key.len());
+        if let Some(key) = mask {
+            ret.extend_from_slice(&key);
+        }
+        ret.extend_from_slice(&self.payload);
+        if let Some(key) = mask {
+            apply_mask(&mut ret[payload_start..], key);
+        }
+        ret
+    }
+}
+
+/// Masking and unmasking are the same operation
+fn apply_mask(data: &mut [u8], key: [u8; 4]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= key[i % 4];
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum FrameError {
+    /// No extensions are negotiated, so the reserved bits must be 0
+    ReservedBits,
+    UnknownOpcode,
+    /// Frames sent by clients have to be masked
+    Unmasked,
+    /// Control frames can't be fragmented and carry at most 125 bytes
+    InvalidControlFrame,
+    TooLarge,
+}
+
+impl FrameError {
+    pub fn close_code(&self) -> u16 {
+        match self {
+            FrameError::TooLarge => close_code::MESSAGE_TOO_BIG,
+            _ => close_code::PROTOCOL_ERROR,
+        }
+    }
+}
+
+/// Incremental parser for frames sent by a client
+pub struct FrameParser {
+    buf: Vec<u8>,
+    max_payload_len: usize,
+}
+
+impl FrameParser {
+    pub fn new(max_payload_len: usize) -> FrameParser {
+        FrameParser {
+            buf: Vec::new(),
+            max_payload_len,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Ok(None) until a whole frame has been pushed
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = self.buf[0] & 0x80 != 0;
+        if self.buf[0] & 0x70 != 0 {
+            return Err(FrameError::ReservedBits);
+        }
+        let opcode = Opcode::from_u8(self.buf[0] & 0x0f).ok_or(FrameError::UnknownOpcode)?;
+        if self.buf[1] & 0x80 == 0 {
+            return Err(FrameError::Unmasked);
+        }
+
+        let (len, mut pos) = match self.buf[1] & 0x7f {
+            126 if self.buf.len() >= 4 => {
+                (u16::from_be_bytes([self.buf[2], self.buf[3]]) as u64, 4)
+            }
+            127 if self.buf.len() >= 10 => {
+                let len = u64::from_be_bytes(self.buf[2..10].try_into().expect("8 bytes"));
+                (len, 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+
+        if opcode.is_control() && (!fin || len > 125) {
+            return Err(FrameError::InvalidControlFrame);
+        }
+        if len > self.max_payload_len as u64 {
+            return Err(FrameError::TooLarge);
+        }
+        let len = len as usize;
+
+        if self.buf.len() < pos + 4 + len {
+            return Ok(None);
+        }
+        let key = self.buf[pos..pos + 4].try_into().expect("4 bytes");
+        pos += 4;
+
+        let mut payload = self.buf[pos..pos + len].to_vec();
+        apply_mask(&mut payload, key);
+        self.buf.drain(..pos + len);
+
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Checks the opening handshake, RFC 6455 section 4.2.1. The response either switches
+/// protocols or explains what was wrong with the request
+pub fn handshake_response(request: &Request) -> Response {
+    let headers = &request.headers;
+    if !headers.contains_token("Upgrade", "websocket")
+        || !headers.contains_token("Connection", "Upgrade")
+    {
+        let mut response = Response::error(StatusCode::UPGRADE_REQUIRED);
+        response.headers.push("Upgrade", "websocket");
+        return response;
+    }
+
+    if headers.get("Sec-WebSocket-Version") != Some("13") {
+        let mut response = Response::error(StatusCode::UPGRADE_REQUIRED);
+        response.headers.push("Sec-WebSocket-Version", "13");
+        return response;
+    }
+
+    // The key is 16 random bytes in base64
+    let key = match headers.get("Sec-WebSocket-Key") {
+        Some(key) if key.len() == 24 && key.ends_with("==") => key,
+        _ => return Response::error(StatusCode::BAD_REQUEST),
+    };
+
+    let mut headers = Headers::new();
+    headers.push("Upgrade", "websocket");
+    headers.push("Connection", "Upgrade");
+    headers.push("Sec-WebSocket-Accept", accept_key(key));
+    Response {
+        status: StatusCode::SWITCHING_PROTOCOLS,
+        headers,
+        body: Vec::new(),
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    base64::encode(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+struct ReadState {
+    parser: FrameParser,
+    /// Opcode and data of a fragmented message
+    fragments: Option<(Opcode, Vec<u8>)>,
+}
+
+/// Server side of an established connection. Sending and receiving can happen concurrently
+/// from different futures, pings are answered while receiving
+pub struct WebSocket<'a> {
+    reader: &'a dyn AsyncRead,
+    writer: Mutex<&'a dyn AsyncWrite>,
+    read_state: Mutex<ReadState>,
+    close_sent: AtomicBool,
+    close_received: AtomicBool,
+}
+
+impl<'a> WebSocket<'a> {
+    pub fn new(reader: &'a dyn AsyncRead, writer: &'a dyn AsyncWrite) -> WebSocket<'a> {
+        WebSocket {
+            reader,
+            writer: Mutex::new(writer),
+            read_state: Mutex::new(ReadState {
+                parser: FrameParser::new(MAX_MESSAGE_LEN),
+                fragments: None,
+            }),
+            close_sent: AtomicBool::new(false),
+            close_received: AtomicBool::new(false),
+        }
+    }
+
+    #[allow(unused)]
+    pub async fn send(&self, message: &Message) -> Result<(), IoError> {
+        let frame = match message {
+            Message::Text(text) => Frame {
+                fin: true,
+                opcode: Opcode::Text,
+                payload: text.as_bytes().to_vec(),
+            },
+            Message::Binary(data) => Frame {
+                fin: true,
+                opcode: Opcode::Binary,
+                payload: data.clone(),
+            },
+        };
+        self.send_frame(&frame).await
+    }
+
+    pub async fn send_text(&self, text: &str) -> Result<(), IoError> {
+        self.send_frame(&Frame {
+            fin: true,
+            opcode: Opcode::Text,
+            payload: text.as_bytes().to_vec(),
+        })
+        .await
+    }
+
+    #[allow(unused)]
+    pub async fn ping(&self, payload: &[u8]) -> Result<(), IoError> {
+        self.send_frame(&Frame {
+            fin: true,
+            opcode: Opcode::Ping,
+            payload: payload[..payload.len().min(125)].to_vec(),
+        })
+        .await
+    }
+
+    /// Starts the closing handshake, does nothing if it was already started
+    pub async fn close(&self, code: u16) {
+        if self.close_sent.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let frame = Frame {
+            fin: true,
+            opcode: Opcode::Close,
+            payload: code.to_be_bytes().to_vec(),
+        };
+        let writer = self.writer.lock().await;
+        if let Err(e) = writer.write_all(&frame.serialize(None)).await {
+            debug!("Failed to send websocket close: {:?}", e);
+        }
+    }
+
+    /// Nothing can be sent once the close frame went out
+    async fn send_frame(&self, frame: &Frame) -> Result<(), IoError> {
+        let writer = self.writer.lock().await;
+        if self.close_sent.load(Ordering::Acquire) {
+            return Err(IoError::Closed);
+        }
+        writer.write_all(&frame.serialize(None)).await
+    }
+
+    /// Waits for the next data message. None once the connection is closed, protocol errors
+    /// close it with the matching status code. Dropping the future before it resolves loses
+    /// nothing
+    pub async fn recv(&self) -> Option<Message> {
+        let mut state = self.read_state.lock().await;
+        let mut buf = [0; 1024];
+        loop {
+            if self.close_received.load(Ordering::Acquire) {
+                return None;
+            }
+
+            let frame = match state.parser.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    let len = self.reader.read(&mut buf).await;
+                    if len == 0 {
+                        self.close_received.store(true, Ordering::Release);
+                        return None;
+                    }
+                    state.parser.push(&buf[..len]);
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Invalid websocket frame: {:?}", e);
+                    self.fail(e.close_code()).await;
+                    return None;
+                }
+            };
+
+            match self.handle_frame(&mut state, frame).await {
+                Ok(Some(message)) => return Some(message),
+                Ok(None) => (),
+                Err(code) => {
+                    self.fail(code).await;
+                    return None;
+                }
+            }
+        }
+    }
+
+    async fn handle_frame(
+        &self,
+        state: &mut ReadState,
+        frame: Frame,
+    ) -> Result<Option<Message>, u16> {
+        match frame.opcode {
+            Opcode::Ping => {
+                let pong = Frame {
+                    fin: true,
+                    opcode: Opcode::Pong,
+                    payload: frame.payload,
+                };
+                // A failed pong shows up as a closed stream on the next read
+                let _ = self.send_frame(&pong).await;
+                return Ok(None);
+            }
+            Opcode::Pong => return Ok(None),
+            Opcode::Close => {
+                self.close_received.store(true, Ordering::Release);
+                // Echo the status code to complete the handshake
+                let code = match frame.payload.get(..2) {
+                    Some(code) => u16::from_be_bytes([code[0], code[1]]),
+                    None => close_code::NORMAL,
+                };
+                self.close(code).await;
+                return Ok(None);
+            }
+            Opcode::Continuation | Opcode::Text | Opcode::Binary => (),
+        }
+
+        let (opcode, data) = match (frame.opcode, state.fragments.take()) {
+            (Opcode::Continuation, Some((opcode, mut data))) => {
+                if data.len() + frame.payload.len() > MAX_MESSAGE_LEN {
+                    return Err(close_code::MESSAGE_TOO_BIG);
+                }
+                data.extend_from_slice(&frame.payload);
+                (opcode, data)
+            }
+            (Opcode::Continuation, None) | (_, Some(_)) => return Err(close_code::PROTOCOL_ERROR),
+            (opcode, None) => (opcode, frame.payload),
+        };
+
+        if !frame.fin {
+            state.fragments = Some((opcode, data));
+            return Ok(None);
+        }
+
+        match opcode {
+            Opcode::Text => String::from_utf8(data)
+                .map(|text| Some(Message::Text(text)))
+                .map_err(|_| close_code::INVALID_DATA),
+            _ => Ok(Some(Message::Binary(data))),
+        }
+    }
+
+    async fn fail(&self, code: u16) {
+        self.close_received.store(true, Ordering::Release);
+        self.close(code).await;
+    }
+}
+
+/// Takes over connections to a websocket route once the handshake succeeded. The connection is
+/// closed after the returned future resolves
+pub trait WebSocketHandler: Send + Sync {
+    fn handle<'s>(&'s self, request: Request, socket: &'s WebSocket<'s>) -> BoxFuture<'s, ()>;
+}
+
+/// Lets a page subscribe to channels with "subscribe <name>" and "unsubscribe <name>" text
+/// messages. Publications are sent as "<name> <message>"
+impl WebSocketHandler for PubSub {
+    fn handle<'s>(&'s self, _request: Request, socket: &'s WebSocket<'s>) -> BoxFuture<'s, ()> {
+        Box::pin(async move {
+            let subscription = self.subscribe();
+
+            let forward = async {
+                loop {
+                    let publication = subscription.recv().await;
+                    let text = format!("{} {}", publication.channel, publication.message);
+                    if socket.send_text(&text).await.is_err() {
+                        return;
+                    }
+                }
+            };
+
+            let commands = async {
+                while let Some(message) = socket.recv().await {
+                    let Message::Text(text) = message else {
+                        continue;
+                    };
+                    match text.split_once(' ') {
+                        Some(("subscribe", channel)) => subscription.add(channel),
+                        Some(("unsubscribe", channel)) => subscription.remove(channel),
+                        _ => debug!("Unknown websocket command: {}", text),
+                    }
+                }
+            };
+
+            let forward = core::pin::pin!(forward);
+            let commands = core::pin::pin!(commands);
+            crate::future::select(forward, commands).await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::http::{parser::RequestParser, test::MockStream};
+    use crate::testing::*;
+    use alloc::vec;
+
+    fn client_frame(opcode: Opcode, fin: bool, payload: &[u8]) -> Vec<u8> {
+        Frame {
+            fin,
+            opcode,
+            payload: payload.to_vec(),
+        }
+        .serialize(Some([0x12, 0x34, 0x56, 0x78]))
+    }
+
+    create_test!(test_handshake, {
+        // Example from RFC 6455 section 1.3
+        let mut parser = RequestParser::new();
+        parser.push(
+            b"GET /chat HTTP/1.1\r\n\
+              Host: server.example.com\r\n\
+              Upgrade: websocket\r\n\
+              Connection: keep-alive, Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        );
+        let request = parser.next_request().unwrap().unwrap();
+        let response = handshake_response(&request);
+        test_eq!(response.status, StatusCode::SWITCHING_PROTOCOLS);
+        test_eq!(
+            response.headers.get("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        test_eq!(
+            response.serialize(true, true),
+            b"HTTP/1.1 101 Switching Protocols\r\n\
+              Upgrade: websocket\r\n\
+              Connection: Upgrade\r\n\
+              Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"
+                .to_vec()
+        );
+
+        let mut parser = RequestParser::new();
+        parser.push(b"GET /chat HTTP/1.1\r\n\r\n");
+        let request = parser.next_request().unwrap().unwrap();
+        test_eq!(
+            handshake_response(&request).status,
+            StatusCode::UPGRADE_REQUIRED
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_frame_parser, {
+        // Masked "Hello" from RFC 6455 section 5.7
+        let mut parser = FrameParser::new(1024);
+        let data = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        parser.push(&data[..6]);
+        test_true!(matches!(parser.next_frame(), Ok(None)));
+        parser.push(&data[6..]);
+        let frame = parser.next_frame().ok().flatten();
+        test_eq!(
+            frame,
+            Some(Frame {
+                fin: true,
+                opcode: Opcode::Text,
+                payload: b"Hello".to_vec(),
+            })
+        );
+
+        // 16 bit length
+        let payload = vec![0xab; 300];
+        parser.push(&client_frame(Opcode::Binary, true, &payload));
+        let frame = parser
+            .next_frame()
+            .ok()
+            .flatten()
+            .map(|frame| frame.payload);
+        test_eq!(frame.as_deref(), Some(payload.as_slice()));
+
+        parser.push(&client_frame(Opcode::Ping, false, b""));
+        test_eq!(
+            parser.next_frame().err(),
+            Some(FrameError::InvalidControlFrame)
+        );
+
+        let mut parser = FrameParser::new(1024);
+        parser.push(&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+        test_eq!(parser.next_frame().err(), Some(FrameError::Unmasked));
+
+        let mut parser = FrameParser::new(4);
+        parser.push(&client_frame(Opcode::Text, true, b"Hello"));
+        test_eq!(parser.next_frame().err(), Some(FrameError::TooLarge));
+
+        Ok(())
+    });
+
+    create_test!(test_websocket_recv, {
+        let mut input = client_frame(Opcode::Text, false, b"frag");
+        input.extend(client_frame(Opcode::Ping, true, b"p"));
+        input.extend(client_frame(Opcode::Continuation, true, b"mented"));
+        input.extend(client_frame(Opcode::Close, true, &1001u16.to_be_bytes()));
+        let stream = MockStream::new(&input);
+        let socket = WebSocket::new(&stream, &stream);
+
+        test_eq!(
+            socket.recv().await,
+            Some(Message::Text("fragmented".into()))
+        );
+        test_true!(socket.recv().await.is_none());
+        test_err!(socket.send_text("too late").await);
+
+        // The ping is answered and the close echoed
+        let output = stream.output.lock().clone();
+        test_eq!(output, [0x8a, 0x01, b'p', 0x88, 0x02, 0x03, 0xe9].to_vec());
+
+        // Invalid utf-8 closes with 1007
+        let stream = MockStream::new(&client_frame(Opcode::Text, true, &[0xff]));
+        let socket = WebSocket::new(&stream, &stream);
+        test_true!(socket.recv().await.is_none());
+        let output = stream.output.lock().clone();
+        test_eq!(output, [0x88, 0x02, 0x03, 0xef].to_vec());
+
+        Ok(())
+    });
+}
@@ -0,0 +1,554 @@
+use crate::{
+    net::{self, Ipv4Frame, Ipv4Protocol},
+    sleep::WakeupRequester,
+    time::MonotonicTime,
+    util::{
+        async_channel::{self, Receiver, Sender},
+        bit_manipulation::GetBits,
+        oneshot,
+        spinlock::SpinLock,
+    },
+    Ipv4Addr,
+};
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
+use hashbrown::HashMap;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum IcmpType {
+    EchoReply,
+    DestinationUnreachable,
+    EchoRequest,
+    Unknown(u8),
+}
+
+impl From<u8> for IcmpType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => IcmpType::EchoReply,
+            3 => IcmpType::DestinationUnreachable,
+            8 => IcmpType::EchoRequest,
+            v => IcmpType::Unknown(v),
+        }
+    }
+}
+
+impl From<IcmpType> for u8 {
+    fn from(value: IcmpType) -> Self {
+        match value {
+            IcmpType::EchoReply => 0,
+            IcmpType::DestinationUnreachable => 3,
+            IcmpType::EchoRequest => 8,
+            IcmpType::Unknown(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[repr(u8)]
+#[allow(unused)]
+pub enum UnreachableCode {
+    Network = 0,
+    Host = 1,
+    Protocol = 2,
+    Port = 3,
+    FragmentationNeeded = 4,
+    SourceRouteFailed = 5,
+}
+
+#[derive(Debug)]
+pub struct InvalidIcmpFrame;
+
+/// Addressing of the datagram quoted in a destination unreachable message
+#[derive(Debug, Eq, PartialEq)]
+pub struct UnreachableDatagram {
+    pub protocol: Ipv4Protocol,
+    pub source_ip: Ipv4Addr,
+    pub dest_ip: Ipv4Addr,
+    pub source_port: u16,
+    pub dest_port: u16,
+    /// Start of the udp/tcp header, for tcp it ends with the sequence number
+    pub transport_header: [u8; 8],
+}
+
+pub struct IcmpFrame<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> IcmpFrame<'a> {
+    const HEADER_LENGTH: usize = 8;
+
+    pub(super) fn new(data: &[u8]) -> Result<IcmpFrame<'_>, InvalidIcmpFrame> {
+        if data.len() < Self::HEADER_LENGTH {
+            return Err(InvalidIcmpFrame);
+        }
+
+        Ok(IcmpFrame { data })
+    }
+
+    pub fn icmp_type(&self) -> IcmpType {
+        self.data[0].into()
+    }
+
+    pub fn code(&self) -> u8 {
+        self.data[1]
+    }
+
+    pub fn checksum(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[2..4]
+                .try_into()
+                .expect("icmp checksum length wrong"),
+        )
+    }
+
+    /// Only meaningful for echo request/reply
+    pub fn identifier(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[4..6]
+                .try_into()
+                .expect("icmp identifier length wrong"),
+        )
+    }
+
+    /// Only meaningful for echo request/reply
+    pub fn sequence_number(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[6..8]
+                .try_into()
+                .expect("icmp sequence number length wrong"),
+        )
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.data[Self::HEADER_LENGTH..]
+    }
+
+    pub fn checksum_valid(&self) -> bool {
+        calculate_icmp_checksum(self.data) == 0
+    }
+
+    /// Only meaningful for destination unreachable. The quoted datagram is truncated to its
+    /// header + 8 bytes, which is enough for the udp/tcp ports and the tcp sequence number
+    pub fn unreachable_datagram(&self) -> Option<UnreachableDatagram> {
+        const MIN_IPV4_HEADER_LEN: usize = 20;
+        const QUOTED_TRANSPORT_LEN: usize = 8;
+
+        let original = self.payload();
+        let header_length = original.first()?.get_bits(0, 4) as usize * 4;
+        if header_length < MIN_IPV4_HEADER_LEN
+            || original.len() < header_length + QUOTED_TRANSPORT_LEN
+        {
+            return None;
+        }
+
+        let ports = &original[header_length..];
+        Some(UnreachableDatagram {
+            protocol: original[9].into(),
+            source_ip: original[12..16]
+                .try_into()
+                .expect("Invalid length for quoted source ip"),
+            dest_ip: original[16..20]
+                .try_into()
+                .expect("Invalid length for quoted dest ip"),
+            source_port: u16::from_be_bytes(
+                ports[0..2]
+                    .try_into()
+                    .expect("Invalid length for quoted source port"),
+            ),
+            dest_port: u16::from_be_bytes(
+                ports[2..4]
+                    .try_into()
+                    .expect("Invalid length for quoted dest port"),
+            ),
+            transport_header: ports[..QUOTED_TRANSPORT_LEN]
+                .try_into()
+                .expect("Invalid length for quoted transport header"),
+        })
+    }
+}
+
+impl core::fmt::Debug for IcmpFrame<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "type: {:?}", self.icmp_type())?;
+        writeln!(f, "code: {}", self.code())?;
+        writeln!(f, "checksum: {:#06x}", self.checksum())?;
+        writeln!(f, "identifier: {}", self.identifier())?;
+        writeln!(f, "sequence_number: {}", self.sequence_number())?;
+        Ok(())
+    }
+}
+
+fn calculate_icmp_checksum(data: &[u8]) -> u16 {
+    if data.len().is_multiple_of(2) {
+        return net::calculate_ipv4_checksum(data);
+    }
+
+    let mut padded = data.to_vec();
+    padded.push(0);
+    net::calculate_ipv4_checksum(&padded)
+}
+
+pub struct IcmpFrameParams<'a> {
+    pub icmp_type: IcmpType,
+    pub code: u8,
+    /// Identifier + sequence number for echo, unused for destination unreachable
+    pub rest_of_header: [u8; 4],
+    pub payload: &'a [u8],
+}
+
+pub fn generate_icmp_frame(params: &IcmpFrameParams<'_>) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(IcmpFrame::HEADER_LENGTH + params.payload.len());
+    ret.push(params.icmp_type.into());
+    ret.push(params.code);
+    let checksum_idx = ret.len();
+    ret.extend_from_slice(&0u16.to_be_bytes());
+    ret.extend_from_slice(&params.rest_of_header);
+    ret.extend_from_slice(params.payload);
+
+    let checksum = calculate_icmp_checksum(&ret);
+    ret[checksum_idx..checksum_idx + 2].copy_from_slice(&checksum.to_be_bytes());
+    ret
+}
+
+fn echo_rest_of_header(identifier: u16, sequence_number: u16) -> [u8; 4] {
+    let mut ret = [0; 4];
+    ret[0..2].copy_from_slice(&identifier.to_be_bytes());
+    ret[2..4].copy_from_slice(&sequence_number.to_be_bytes());
+    ret
+}
+
+pub fn generate_echo_request(identifier: u16, sequence_number: u16, payload: &[u8]) -> Vec<u8> {
+    generate_icmp_frame(&IcmpFrameParams {
+        icmp_type: IcmpType::EchoRequest,
+        code: 0,
+        rest_of_header: echo_rest_of_header(identifier, sequence_number),
+        payload,
+    })
+}
+
+pub fn generate_echo_reply(request: &IcmpFrame<'_>) -> Vec<u8> {
+    generate_icmp_frame(&IcmpFrameParams {
+        icmp_type: IcmpType::EchoReply,
+        code: 0,
+        rest_of_header: echo_rest_of_header(request.identifier(), request.sequence_number()),
+        payload: request.payload(),
+    })
+}
+
+/// RFC 792: Echo back the offending IP header and the first 64 bits of its payload
+pub fn generate_destination_unreachable(
+    code: UnreachableCode,
+    original: &Ipv4Frame<'_>,
+) -> Vec<u8> {
+    const ORIGINAL_PAYLOAD_LEN: usize = 8;
+    let original_len =
+        (original.header_length() + ORIGINAL_PAYLOAD_LEN).min(original.total_length());
+
+    generate_icmp_frame(&IcmpFrameParams {
+        icmp_type: IcmpType::DestinationUnreachable,
+        code: code as u8,
+        rest_of_header: [0; 4],
+        payload: &original.packet[..original_len],
+    })
+}
+
+#[derive(Debug)]
+pub enum PingError {
+    Timeout,
+}
+
+pub struct OutgoingIcmpPacket {
+    pub remote_ip: Ipv4Addr,
+    pub payload: Vec<u8>,
+}
+
+struct PendingEcho {
+    remote_ip: Ipv4Addr,
+    tx: oneshot::Sender<usize>,
+}
+
+/// Forgets the echo request when the ping is done or dropped, so late replies are not matched
+struct PendingEchoGuard<'a> {
+    pending_echos: &'a SpinLock<HashMap<u16, PendingEcho>>,
+    sequence_number: u16,
+}
+
+impl Drop for PendingEchoGuard<'_> {
+    fn drop(&mut self) {
+        self.pending_echos.lock().remove(&self.sequence_number);
+    }
+}
+
+pub struct Icmp {
+    sequence_number: AtomicU16,
+    pending_echos: SpinLock<HashMap<u16, PendingEcho>>,
+    outgoing_tx: Sender<OutgoingIcmpPacket>,
+    outgoing_rx: Receiver<OutgoingIcmpPacket>,
+    time: Arc<MonotonicTime>,
+    wakeup_list: WakeupRequester,
+}
+
+impl Icmp {
+    const IDENTIFIER: u16 = 0x5053;
+
+    pub fn new(time: Arc<MonotonicTime>, wakeup_list: WakeupRequester) -> Icmp {
+        let (outgoing_tx, outgoing_rx) = async_channel::channel();
+        Icmp {
+            sequence_number: AtomicU16::new(0),
+            pending_echos: SpinLock::new(HashMap::new()),
+            outgoing_tx,
+            outgoing_rx,
+            time,
+            wakeup_list,
+        }
+    }
+
+    /// Returns the round trip time in seconds
+    pub async fn ping(&self, ip: Ipv4Addr, timeout_s: f32) -> Result<f32, PingError> {
+        let sequence_number = self.sequence_number.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending_echos
+            .lock()
+            .insert(sequence_number, PendingEcho { remote_ip: ip, tx });
+        let _guard = PendingEchoGuard {
+            pending_echos: &self.pending_echos,
+            sequence_number,
+        };
+
+        let start = self.time.get();
+        self.outgoing_tx
+            .send(OutgoingIcmpPacket {
+                remote_ip: ip,
+                payload: generate_echo_request(
+                    Self::IDENTIFIER,
+                    sequence_number,
+                    b"stream-os ping",
+                ),
+            })
+            .await;
+
+        match crate::sleep::timeout(timeout_s, &self.time, &self.wakeup_list, rx.recv()).await {
+            Some(Ok(end)) => Ok((end - start) as f32 / self.time.tick_freq()),
+            Some(Err(_)) | None => Err(PingError::Timeout),
+        }
+    }
+
+    /// Returns an ICMP response to be sent back to the source if one is needed
+    pub async fn handle_frame(
+        &self,
+        frame: &IcmpFrame<'_>,
+        source_ip: &Ipv4Addr,
+    ) -> Option<Vec<u8>> {
+        if !frame.checksum_valid() {
+            debug!("Dropping icmp frame with invalid checksum");
+            return None;
+        }
+
+        match frame.icmp_type() {
+            IcmpType::EchoRequest => Some(generate_echo_reply(frame)),
+            IcmpType::EchoReply => {
+                if frame.identifier() != Self::IDENTIFIER {
+                    return None;
+                }
+
+                let tx = {
+                    let mut pending_echos = self.pending_echos.lock();
+                    match pending_echos.get(&frame.sequence_number()) {
+                        Some(echo) if echo.remote_ip == *source_ip => pending_echos
+                            .remove(&frame.sequence_number())
+                            .map(|echo| echo.tx),
+                        _ => None,
+                    }
+                };
+                match tx {
+                    Some(tx) => tx.send(self.time.get()).await,
+                    None => debug!(
+                        "Unexpected echo reply {} from {:?}",
+                        frame.sequence_number(),
+                        source_ip
+                    ),
+                }
+                None
+            }
+            IcmpType::DestinationUnreachable => {
+                debug!("Received destination unreachable, code {}", frame.code());
+                None
+            }
+            IcmpType::Unknown(t) => {
+                debug!("Unknown icmp type {}", t);
+                None
+            }
+        }
+    }
+
+    pub async fn service(&self) -> OutgoingIcmpPacket {
+        self.outgoing_rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::string::{String, ToString};
+
+    // Echo request in the format sent by windows `ping`
+    const ECHO_REQUEST: &[u8] = &[
+        0x08, 0x00, 0x4d, 0x4d, 0x00, 0x01, 0x00, 0x0e, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67,
+        0x68, 0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0x73, 0x74, 0x75, 0x76,
+        0x77, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    ];
+
+    create_test!(test_icmp_frame_parsing, {
+        let frame = IcmpFrame::new(ECHO_REQUEST).map_err(|_| "Invalid icmp frame".to_string())?;
+        test_eq!(frame.icmp_type(), IcmpType::EchoRequest);
+        test_eq!(frame.code(), 0);
+        test_eq!(frame.identifier(), 1);
+        test_eq!(frame.sequence_number(), 14);
+        test_eq!(frame.payload(), b"abcdefghijklmnopqrstuvwabcdefghi");
+        test_true!(frame.checksum_valid());
+
+        test_err!(IcmpFrame::new(&ECHO_REQUEST[..7]));
+        Ok(())
+    });
+
+    create_test!(test_echo_reply_generation, {
+        let request = IcmpFrame::new(ECHO_REQUEST).map_err(|_| "Invalid icmp frame".to_string())?;
+        let reply = generate_echo_reply(&request);
+        let reply = IcmpFrame::new(&reply).map_err(|_| "Invalid icmp reply".to_string())?;
+
+        test_eq!(reply.icmp_type(), IcmpType::EchoReply);
+        test_eq!(reply.identifier(), request.identifier());
+        test_eq!(reply.sequence_number(), request.sequence_number());
+        test_eq!(reply.payload(), request.payload());
+        test_true!(reply.checksum_valid());
+        Ok(())
+    });
+
+    create_test!(test_port_unreachable, {
+        let source_ip = [192, 168, 2, 1];
+        let dest_ip = [192, 168, 2, 2];
+        let udp_frame = net::generate_udp_frame(
+            &net::IpAddr::V4(source_ip),
+            &net::IpAddr::V4(dest_ip),
+            6000,
+            6000,
+            b"test\n",
+        );
+        let ipv4_frame =
+            net::generate_ipv4_frame(&udp_frame, net::Ipv4Protocol::Udp, &source_ip, &dest_ip);
+        let ipv4_frame =
+            Ipv4Frame::new(&ipv4_frame).map_err(|_| "Invalid ipv4 frame".to_string())?;
+
+        let unreachable_buf = generate_destination_unreachable(UnreachableCode::Port, &ipv4_frame);
+        let unreachable =
+            IcmpFrame::new(&unreachable_buf).map_err(|_| "Invalid icmp frame".to_string())?;
+
+        test_eq!(unreachable.icmp_type(), IcmpType::DestinationUnreachable);
+        test_eq!(unreachable.code(), UnreachableCode::Port as u8);
+        test_true!(unreachable.checksum_valid());
+        // Ipv4 header + 8 bytes of udp header
+        test_eq!(unreachable.payload().len(), 28);
+        test_eq!(&unreachable.payload()[20..], &udp_frame[..8]);
+
+        let datagram = unreachable
+            .unreachable_datagram()
+            .ok_or("Quoted datagram not parsed".to_string())?;
+        test_eq!(
+            datagram,
+            UnreachableDatagram {
+                protocol: net::Ipv4Protocol::Udp,
+                source_ip,
+                dest_ip,
+                source_port: 6000,
+                dest_port: 6000,
+                transport_header: udp_frame[..8]
+                    .try_into()
+                    .map_err(|_| "Short udp frame".to_string())?,
+            }
+        );
+        // Not enough of the original datagram for its ports
+        test_true!(IcmpFrame::new(&unreachable_buf[..30])
+            .map_err(|_| "Invalid icmp frame".to_string())?
+            .unreachable_datagram()
+            .is_none());
+        Ok(())
+    });
+
+    create_test!(test_ping_round_trip, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let icmp = Icmp::new(time, wakeup_list);
+
+        let mut ping = core::pin::pin!(icmp.ping([127, 0, 0, 1], 1.0));
+        test_true!(crate::future::poll_immediate(ping.as_mut()).await.is_none());
+
+        let request = crate::future::poll_immediate(icmp.service())
+            .await
+            .ok_or("No echo request sent".to_string())?;
+        test_eq!(request.remote_ip, [127, 0, 0, 1]);
+
+        let request =
+            IcmpFrame::new(&request.payload).map_err(|_| "Invalid echo request".to_string())?;
+        let reply = icmp
+            .handle_frame(&request, &[127, 0, 0, 1])
+            .await
+            .ok_or("No reply to echo request".to_string())?;
+
+        let reply = IcmpFrame::new(&reply).map_err(|_| "Invalid echo reply".to_string())?;
+        test_true!(icmp.handle_frame(&reply, &[127, 0, 0, 1]).await.is_none());
+
+        let rtt: Result<f32, String> = crate::future::poll_immediate(ping.as_mut())
+            .await
+            .ok_or("Ping did not complete".to_string())?
+            .map_err(|e| alloc::format!("{:?}", e));
+        test_eq!(rtt, Ok::<_, String>(0.0));
+        Ok(())
+    });
+
+    create_test!(test_ping_reply_from_other_host, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let icmp = Icmp::new(time, wakeup_list);
+
+        let mut ping = core::pin::pin!(icmp.ping([10, 0, 2, 2], 1.0));
+        test_true!(crate::future::poll_immediate(ping.as_mut()).await.is_none());
+
+        let request = crate::future::poll_immediate(icmp.service())
+            .await
+            .ok_or("No echo request sent".to_string())?;
+        let request =
+            IcmpFrame::new(&request.payload).map_err(|_| "Invalid echo request".to_string())?;
+        let reply = icmp
+            .handle_frame(&request, &[10, 0, 2, 15])
+            .await
+            .ok_or("No reply to echo request".to_string())?;
+        let reply = IcmpFrame::new(&reply).map_err(|_| "Invalid echo reply".to_string())?;
+
+        test_true!(icmp.handle_frame(&reply, &[10, 0, 2, 3]).await.is_none());
+        test_true!(crate::future::poll_immediate(ping.as_mut()).await.is_none());
+
+        test_true!(icmp.handle_frame(&reply, &[10, 0, 2, 2]).await.is_none());
+        test_true!(crate::future::poll_immediate(ping.as_mut())
+            .await
+            .is_some_and(|rtt| rtt.is_ok()));
+        Ok(())
+    });
+
+    create_test!(test_dropped_ping, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let icmp = Icmp::new(time, wakeup_list);
+
+        {
+            let mut ping = core::pin::pin!(icmp.ping([10, 0, 2, 2], 1.0));
+            test_true!(crate::future::poll_immediate(ping.as_mut()).await.is_none());
+            test_eq!(icmp.pending_echos.lock().len(), 1);
+        }
+
+        test_true!(icmp.pending_echos.lock().is_empty());
+        Ok(())
+    });
+}
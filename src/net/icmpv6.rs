@@ -0,0 +1,641 @@
+use crate::{
+    net::{
+        self,
+        ipv6::{self, Ipv6Config},
+        IpAddr, Ipv4Protocol,
+    },
+    util::updated_val::UpdatedVal,
+    Ipv6Addr, MacAddr,
+};
+
+use alloc::vec::Vec;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Icmpv6Type {
+    EchoRequest,
+    EchoReply,
+    RouterSolicitation,
+    RouterAdvertisement,
+    NeighborSolicitation,
+    NeighborAdvertisement,
+    Unknown(u8),
+}
+
+impl From<u8> for Icmpv6Type {
+    fn from(value: u8) -> Self {
+        match value {
+            128 => Icmpv6Type::EchoRequest,
+            129 => Icmpv6Type::EchoReply,
+            133 => Icmpv6Type::RouterSolicitation,
+            134 => Icmpv6Type::RouterAdvertisement,
+            135 => Icmpv6Type::NeighborSolicitation,
+            136 => Icmpv6Type::NeighborAdvertisement,
+            v => Icmpv6Type::Unknown(v),
+        }
+    }
+}
+
+impl From<Icmpv6Type> for u8 {
+    fn from(value: Icmpv6Type) -> Self {
+        match value {
+            Icmpv6Type::EchoRequest => 128,
+            Icmpv6Type::EchoReply => 129,
+            Icmpv6Type::RouterSolicitation => 133,
+            Icmpv6Type::RouterAdvertisement => 134,
+            Icmpv6Type::NeighborSolicitation => 135,
+            Icmpv6Type::NeighborAdvertisement => 136,
+            Icmpv6Type::Unknown(v) => v,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidIcmpv6Frame;
+
+pub struct Icmpv6Frame<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Icmpv6Frame<'a> {
+    const HEADER_LENGTH: usize = 4;
+
+    pub fn new(data: &[u8]) -> Result<Icmpv6Frame<'_>, InvalidIcmpv6Frame> {
+        if data.len() < Self::HEADER_LENGTH {
+            return Err(InvalidIcmpv6Frame);
+        }
+
+        Ok(Icmpv6Frame { data })
+    }
+
+    pub fn icmp_type(&self) -> Icmpv6Type {
+        self.data[0].into()
+    }
+
+    pub fn code(&self) -> u8 {
+        self.data[1]
+    }
+
+    /// Unlike ICMP the checksum covers the ipv6 pseudo header
+    pub fn checksum_valid(&self, source_ip: &Ipv6Addr, dest_ip: &Ipv6Addr) -> bool {
+        net::calculate_pseudo_header_checksum(
+            &IpAddr::V6(*source_ip),
+            &IpAddr::V6(*dest_ip),
+            Ipv4Protocol::Icmpv6,
+            self.data,
+        ) == 0
+    }
+
+    /// Everything after the type, code and checksum
+    pub fn body(&self) -> &'a [u8] {
+        &self.data[Self::HEADER_LENGTH..]
+    }
+}
+
+impl core::fmt::Debug for Icmpv6Frame<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Icmpv6Frame")
+            .field("icmp_type", &self.icmp_type())
+            .field("code", &self.code())
+            .field("body_length", &self.body().len())
+            .finish()
+    }
+}
+
+pub fn generate_icmpv6_frame(
+    icmp_type: Icmpv6Type,
+    code: u8,
+    body: &[u8],
+    source_ip: &Ipv6Addr,
+    dest_ip: &Ipv6Addr,
+) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(Icmpv6Frame::HEADER_LENGTH + body.len());
+    ret.push(icmp_type.into());
+    ret.push(code);
+    ret.extend_from_slice(&[0, 0]);
+    ret.extend_from_slice(body);
+
+    let checksum = net::calculate_pseudo_header_checksum(
+        &IpAddr::V6(*source_ip),
+        &IpAddr::V6(*dest_ip),
+        Ipv4Protocol::Icmpv6,
+        &ret,
+    );
+    ret[2..4].copy_from_slice(&checksum.to_be_bytes());
+    ret
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct PrefixInformation {
+    pub prefix_length: u8,
+    pub autonomous: bool,
+    pub valid_lifetime: u32,
+    pub prefix: Ipv6Addr,
+}
+
+/// Neighbor discovery options (RFC 4861 section 4.6), unknown options are skipped
+#[derive(Debug, Eq, PartialEq)]
+pub enum NdpOption {
+    SourceLinkLayerAddress(MacAddr),
+    TargetLinkLayerAddress(MacAddr),
+    PrefixInformation(PrefixInformation),
+}
+
+impl NdpOption {
+    const SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+    const TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+    const PREFIX_INFORMATION: u8 = 3;
+
+    pub fn parse(data: &[u8]) -> Vec<NdpOption> {
+        let mut ret = Vec::new();
+
+        let mut i = 0;
+        while i < data.len() {
+            let kind = data[i];
+            // Length is in units of 8 bytes, 0 is invalid and would never advance
+            let len = match data.get(i + 1) {
+                Some(&len) if len != 0 && i + len as usize * 8 <= data.len() => len as usize * 8,
+                _ => {
+                    warn!("Malformed ndp option {}", kind);
+                    break;
+                }
+            };
+            let value = &data[i..i + len];
+
+            match (kind, len) {
+                (Self::SOURCE_LINK_LAYER_ADDRESS, 8) => ret.push(
+                    NdpOption::SourceLinkLayerAddress(value[2..8].try_into().expect("6 bytes")),
+                ),
+                (Self::TARGET_LINK_LAYER_ADDRESS, 8) => ret.push(
+                    NdpOption::TargetLinkLayerAddress(value[2..8].try_into().expect("6 bytes")),
+                ),
+                (Self::PREFIX_INFORMATION, 32) => {
+                    ret.push(NdpOption::PrefixInformation(PrefixInformation {
+                        prefix_length: value[2],
+                        autonomous: value[3] & 0x40 != 0,
+                        valid_lifetime: u32::from_be_bytes(
+                            value[4..8].try_into().expect("4 bytes"),
+                        ),
+                        prefix: value[16..32].try_into().expect("16 bytes"),
+                    }))
+                }
+                _ => debug!("Ignoring ndp option {} with length {}", kind, len),
+            }
+
+            i += len;
+        }
+
+        ret
+    }
+
+    fn write_link_layer_address(kind: u8, mac: &MacAddr, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[kind, 1]);
+        out.extend_from_slice(mac);
+    }
+}
+
+pub struct OutgoingIcmpv6Packet {
+    pub local_ip: Ipv6Addr,
+    pub remote_ip: Ipv6Addr,
+    pub hop_limit: u8,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct Icmpv6Response {
+    pub reply: Option<OutgoingIcmpv6Packet>,
+    /// Link layer address learned from neighbor discovery, for the neighbor cache
+    pub neighbor: Option<(Ipv6Addr, MacAddr)>,
+}
+
+/// Echo and neighbor discovery. Router advertisements configure the default router and SLAAC
+/// addresses. Lifetimes are not tracked, so both stay configured until reboot
+pub struct Icmpv6 {
+    mac: MacAddr,
+    ipv6_config: UpdatedVal<Ipv6Config>,
+}
+
+impl Icmpv6 {
+    // Neighbor discovery is link local, routers would have decremented this
+    pub const NDP_HOP_LIMIT: u8 = 255;
+
+    const SOLICITED_FLAG: u8 = 0x40;
+    const OVERRIDE_FLAG: u8 = 0x20;
+
+    pub fn new(mac: MacAddr, ipv6_config: UpdatedVal<Ipv6Config>) -> Icmpv6 {
+        Icmpv6 { mac, ipv6_config }
+    }
+
+    pub async fn handle_frame(
+        &self,
+        frame: &Icmpv6Frame<'_>,
+        source_ip: &Ipv6Addr,
+        dest_ip: &Ipv6Addr,
+        hop_limit: u8,
+    ) -> Icmpv6Response {
+        let is_ndp = matches!(
+            frame.icmp_type(),
+            Icmpv6Type::RouterSolicitation
+                | Icmpv6Type::RouterAdvertisement
+                | Icmpv6Type::NeighborSolicitation
+                | Icmpv6Type::NeighborAdvertisement
+        );
+        if is_ndp && (hop_limit != Self::NDP_HOP_LIMIT || frame.code() != 0) {
+            debug!("Dropping ndp message that did not originate on link");
+            return Default::default();
+        }
+
+        match frame.icmp_type() {
+            Icmpv6Type::EchoRequest => self.handle_echo_request(frame, source_ip, dest_ip).await,
+            Icmpv6Type::NeighborSolicitation => {
+                self.handle_neighbor_solicitation(frame, source_ip).await
+            }
+            Icmpv6Type::NeighborAdvertisement => Self::handle_neighbor_advertisement(frame),
+            Icmpv6Type::RouterAdvertisement => {
+                self.handle_router_advertisement(frame, source_ip).await
+            }
+            t => {
+                debug!("Ignoring icmpv6 message {:?}", t);
+                Default::default()
+            }
+        }
+    }
+
+    async fn handle_echo_request(
+        &self,
+        frame: &Icmpv6Frame<'_>,
+        source_ip: &Ipv6Addr,
+        dest_ip: &Ipv6Addr,
+    ) -> Icmpv6Response {
+        let local_ip = if ipv6::is_multicast(dest_ip) {
+            self.ipv6_config.read().await.local_ip_for(source_ip)
+        } else {
+            *dest_ip
+        };
+
+        let payload =
+            generate_icmpv6_frame(Icmpv6Type::EchoReply, 0, frame.body(), &local_ip, source_ip);
+
+        Icmpv6Response {
+            reply: Some(OutgoingIcmpv6Packet {
+                local_ip,
+                remote_ip: *source_ip,
+                hop_limit: ipv6::DEFAULT_HOP_LIMIT,
+                payload,
+            }),
+            neighbor: None,
+        }
+    }
+
+    async fn handle_neighbor_solicitation(
+        &self,
+        frame: &Icmpv6Frame<'_>,
+        source_ip: &Ipv6Addr,
+    ) -> Icmpv6Response {
+        // Reserved, then the target
+        let body = frame.body();
+        if body.len() < 20 {
+            return Default::default();
+        }
+        let target: Ipv6Addr = body[4..20].try_into().expect("16 bytes");
+
+        if !self.ipv6_config.read().await.is_local(&target) {
+            return Default::default();
+        }
+
+        // Duplicate address detection probes come from the unspecified address and are answered
+        // to everyone
+        let (remote_ip, flags, neighbor) = if *source_ip == ipv6::UNSPECIFIED {
+            (ipv6::ALL_NODES, Self::OVERRIDE_FLAG, None)
+        } else {
+            let neighbor =
+                NdpOption::parse(&body[20..])
+                    .into_iter()
+                    .find_map(|option| match option {
+                        NdpOption::SourceLinkLayerAddress(mac) => Some((*source_ip, mac)),
+                        _ => None,
+                    });
+            (
+                *source_ip,
+                Self::SOLICITED_FLAG | Self::OVERRIDE_FLAG,
+                neighbor,
+            )
+        };
+
+        let mut advertisement = Vec::with_capacity(28);
+        advertisement.extend_from_slice(&[flags, 0, 0, 0]);
+        advertisement.extend_from_slice(&target);
+        NdpOption::write_link_layer_address(
+            NdpOption::TARGET_LINK_LAYER_ADDRESS,
+            &self.mac,
+            &mut advertisement,
+        );
+
+        let payload = generate_icmpv6_frame(
+            Icmpv6Type::NeighborAdvertisement,
+            0,
+            &advertisement,
+            &target,
+            &remote_ip,
+        );
+
+        Icmpv6Response {
+            reply: Some(OutgoingIcmpv6Packet {
+                local_ip: target,
+                remote_ip,
+                hop_limit: Self::NDP_HOP_LIMIT,
+                payload,
+            }),
+            neighbor,
+        }
+    }
+
+    fn handle_neighbor_advertisement(frame: &Icmpv6Frame<'_>) -> Icmpv6Response {
+        let body = frame.body();
+        if body.len() < 20 {
+            return Default::default();
+        }
+        let target: Ipv6Addr = body[4..20].try_into().expect("16 bytes");
+
+        let neighbor = NdpOption::parse(&body[20..])
+            .into_iter()
+            .find_map(|option| match option {
+                NdpOption::TargetLinkLayerAddress(mac) => Some((target, mac)),
+                _ => None,
+            });
+
+        Icmpv6Response {
+            reply: None,
+            neighbor,
+        }
+    }
+
+    async fn handle_router_advertisement(
+        &self,
+        frame: &Icmpv6Frame<'_>,
+        source_ip: &Ipv6Addr,
+    ) -> Icmpv6Response {
+        // Hop limit, flags, router lifetime, reachable time and retransmit timer
+        let body = frame.body();
+        if body.len() < 12 || !ipv6::is_link_local(source_ip) {
+            return Default::default();
+        }
+        let router_lifetime = u16::from_be_bytes([body[2], body[3]]);
+
+        let mut config = self.ipv6_config.read().await;
+        let old_config = config;
+
+        if router_lifetime != 0 {
+            config.router = Some(*source_ip);
+        } else if config.router == Some(*source_ip) {
+            config.router = None;
+        }
+
+        let mut neighbor = None;
+        for option in NdpOption::parse(&body[12..]) {
+            match option {
+                NdpOption::SourceLinkLayerAddress(mac) => neighbor = Some((*source_ip, mac)),
+                // Interface identifiers are 64 bits, SLAAC only works for /64 prefixes
+                NdpOption::PrefixInformation(info)
+                    if info.autonomous
+                        && info.prefix_length == 64
+                        && info.valid_lifetime != 0
+                        && !ipv6::is_link_local(&info.prefix) =>
+                {
+                    let address = ipv6::address_from_prefix(&info.prefix, &self.mac);
+                    if config.add_address(address) {
+                        info!("SLAAC address {:02x?}", address);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if config != old_config {
+            self.ipv6_config.write(config).await;
+        }
+
+        Icmpv6Response {
+            reply: None,
+            neighbor,
+        }
+    }
+
+    pub fn generate_router_solicitation(&self, local_ip: &Ipv6Addr) -> OutgoingIcmpv6Packet {
+        let mut solicitation = Vec::with_capacity(12);
+        solicitation.extend_from_slice(&[0; 4]);
+        NdpOption::write_link_layer_address(
+            NdpOption::SOURCE_LINK_LAYER_ADDRESS,
+            &self.mac,
+            &mut solicitation,
+        );
+
+        OutgoingIcmpv6Packet {
+            local_ip: *local_ip,
+            remote_ip: ipv6::ALL_ROUTERS,
+            hop_limit: Self::NDP_HOP_LIMIT,
+            payload: generate_icmpv6_frame(
+                Icmpv6Type::RouterSolicitation,
+                0,
+                &solicitation,
+                local_ip,
+                &ipv6::ALL_ROUTERS,
+            ),
+        }
+    }
+
+    pub fn generate_neighbor_solicitation(
+        &self,
+        target: &Ipv6Addr,
+        local_ip: &Ipv6Addr,
+    ) -> OutgoingIcmpv6Packet {
+        let remote_ip = ipv6::solicited_node_address(target);
+
+        let mut solicitation = Vec::with_capacity(28);
+        solicitation.extend_from_slice(&[0; 4]);
+        solicitation.extend_from_slice(target);
+        NdpOption::write_link_layer_address(
+            NdpOption::SOURCE_LINK_LAYER_ADDRESS,
+            &self.mac,
+            &mut solicitation,
+        );
+
+        OutgoingIcmpv6Packet {
+            local_ip: *local_ip,
+            remote_ip,
+            hop_limit: Self::NDP_HOP_LIMIT,
+            payload: generate_icmpv6_frame(
+                Icmpv6Type::NeighborSolicitation,
+                0,
+                &solicitation,
+                local_ip,
+                &remote_ip,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{future::poll_immediate, testing::*};
+    use alloc::string::{String, ToString};
+
+    const MAC: MacAddr = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const ROUTER_MAC: MacAddr = [0x52, 0x54, 0x00, 0xab, 0xcd, 0xef];
+    const PREFIX: Ipv6Addr = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    fn new_icmpv6() -> (Icmpv6, UpdatedVal<Ipv6Config>) {
+        let config = UpdatedVal::new(Ipv6Config::new(ipv6::link_local_address(&MAC)));
+        (Icmpv6::new(MAC, config.clone()), config)
+    }
+
+    async fn handle(
+        icmpv6: &Icmpv6,
+        payload: &[u8],
+        source_ip: &Ipv6Addr,
+        dest_ip: &Ipv6Addr,
+        hop_limit: u8,
+    ) -> Result<Icmpv6Response, String> {
+        let frame = Icmpv6Frame::new(payload).map_err(|_| "Invalid icmpv6 frame".to_string())?;
+        if !frame.checksum_valid(source_ip, dest_ip) {
+            return Err("Invalid checksum".to_string());
+        }
+        poll_immediate(icmpv6.handle_frame(&frame, source_ip, dest_ip, hop_limit))
+            .await
+            .ok_or_else(|| "handle_frame did not complete".to_string())
+    }
+
+    create_test!(test_ndp_option_parsing, {
+        let mut options = Vec::new();
+        NdpOption::write_link_layer_address(
+            NdpOption::SOURCE_LINK_LAYER_ADDRESS,
+            &MAC,
+            &mut options,
+        );
+        // Unknown MTU option is skipped
+        options.extend_from_slice(&[5, 1, 0, 0, 0, 0, 0x05, 0xdc]);
+        options.extend_from_slice(&[
+            3, 4, 64, 0xc0, 0, 0, 0x0e, 0x10, 0, 0, 0x0e, 0x10, 0, 0, 0, 0,
+        ]);
+        options.extend_from_slice(&PREFIX);
+
+        test_eq!(
+            NdpOption::parse(&options),
+            [
+                NdpOption::SourceLinkLayerAddress(MAC),
+                NdpOption::PrefixInformation(PrefixInformation {
+                    prefix_length: 64,
+                    autonomous: true,
+                    valid_lifetime: 3600,
+                    prefix: PREFIX,
+                }),
+            ]
+        );
+
+        // Zero length options end parsing instead of looping forever
+        test_true!(NdpOption::parse(&[1, 0, 0, 0, 0, 0, 0, 0]).is_empty());
+
+        Ok(())
+    });
+
+    create_test!(test_echo_request, {
+        let (icmpv6, _) = new_icmpv6();
+        let local_ip = ipv6::link_local_address(&MAC);
+        let remote_ip = ipv6::link_local_address(&ROUTER_MAC);
+
+        let request = generate_icmpv6_frame(
+            Icmpv6Type::EchoRequest,
+            0,
+            &[0, 1, 0, 2, b'h', b'i'],
+            &remote_ip,
+            &local_ip,
+        );
+        let response = handle(&icmpv6, &request, &remote_ip, &local_ip, 64).await?;
+        let reply = response.reply.ok_or_else(|| "No echo reply".to_string())?;
+        test_eq!(reply.local_ip, local_ip);
+        test_eq!(reply.remote_ip, remote_ip);
+
+        let frame =
+            Icmpv6Frame::new(&reply.payload).map_err(|_| "Invalid icmpv6 frame".to_string())?;
+        test_eq!(frame.icmp_type(), Icmpv6Type::EchoReply);
+        test_eq!(frame.body(), &[0, 1, 0, 2, b'h', b'i']);
+        test_true!(frame.checksum_valid(&local_ip, &remote_ip));
+
+        Ok(())
+    });
+
+    create_test!(test_neighbor_solicitation, {
+        let (icmpv6, _) = new_icmpv6();
+        let local_ip = ipv6::link_local_address(&MAC);
+        let remote_ip = ipv6::link_local_address(&ROUTER_MAC);
+
+        let ns = Icmpv6::new(ROUTER_MAC, UpdatedVal::new(Ipv6Config::new(remote_ip)))
+            .generate_neighbor_solicitation(&local_ip, &remote_ip);
+        test_eq!(ns.remote_ip, ipv6::solicited_node_address(&local_ip));
+
+        // Off link solicitations are ignored
+        let response = handle(&icmpv6, &ns.payload, &remote_ip, &ns.remote_ip, 254).await?;
+        test_true!(response.reply.is_none());
+
+        let response = handle(&icmpv6, &ns.payload, &remote_ip, &ns.remote_ip, 255).await?;
+        test_eq!(response.neighbor, Some((remote_ip, ROUTER_MAC)));
+        let reply = response
+            .reply
+            .ok_or_else(|| "No advertisement".to_string())?;
+        test_eq!(reply.remote_ip, remote_ip);
+        test_eq!(reply.hop_limit, 255);
+
+        // Feeding our advertisement back teaches the other side our mac
+        let response = handle(&icmpv6, &reply.payload, &local_ip, &remote_ip, 255).await?;
+        test_true!(response.reply.is_none());
+        test_eq!(response.neighbor, Some((local_ip, MAC)));
+
+        // Not our address
+        let other = Icmpv6::new(ROUTER_MAC, UpdatedVal::new(Ipv6Config::new(remote_ip)))
+            .generate_neighbor_solicitation(&remote_ip, &remote_ip);
+        let response = handle(&icmpv6, &other.payload, &remote_ip, &other.remote_ip, 255).await?;
+        test_true!(response.reply.is_none());
+
+        Ok(())
+    });
+
+    create_test!(test_router_advertisement_slaac, {
+        let (icmpv6, config) = new_icmpv6();
+        let router_ip = ipv6::link_local_address(&ROUTER_MAC);
+
+        let mut ra = Vec::new();
+        // Hop limit, flags, lifetime of 1800s, reachable and retransmit timers
+        ra.extend_from_slice(&[64, 0, 0x07, 0x08, 0, 0, 0, 0, 0, 0, 0, 0]);
+        NdpOption::write_link_layer_address(
+            NdpOption::SOURCE_LINK_LAYER_ADDRESS,
+            &ROUTER_MAC,
+            &mut ra,
+        );
+        ra.extend_from_slice(&[
+            3, 4, 64, 0xc0, 0, 0, 0x0e, 0x10, 0, 0, 0x0e, 0x10, 0, 0, 0, 0,
+        ]);
+        ra.extend_from_slice(&PREFIX);
+        let payload = generate_icmpv6_frame(
+            Icmpv6Type::RouterAdvertisement,
+            0,
+            &ra,
+            &router_ip,
+            &ipv6::ALL_NODES,
+        );
+
+        let response = handle(&icmpv6, &payload, &router_ip, &ipv6::ALL_NODES, 255).await?;
+        test_eq!(response.neighbor, Some((router_ip, ROUTER_MAC)));
+
+        let config = poll_immediate(config.read())
+            .await
+            .ok_or_else(|| "Config locked".to_string())?;
+        test_eq!(config.router, Some(router_ip));
+        test_eq!(
+            config.addresses[0],
+            Some(ipv6::address_from_prefix(&PREFIX, &MAC))
+        );
+        test_true!(config.addresses[1].is_none());
+
+        Ok(())
+    });
+}
@@ -0,0 +1,508 @@
+use crate::{
+    net::{self, stats::increment, Ipv4Frame, Ipv4FrameParams, Ipv4Protocol},
+    time::MonotonicTime,
+    util::async_mutex::Mutex,
+    Ipv4Addr,
+};
+
+use alloc::{borrow::Cow, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use hashbrown::HashMap;
+
+/// Counters named after their RFC 4293 equivalents
+#[derive(Debug, Default)]
+pub struct Ipv4Stats {
+    pub in_receives: AtomicUsize,
+    /// Truncated frames, bad checksums and malformed headers
+    pub in_header_errors: AtomicUsize,
+    pub reassembly_required: AtomicUsize,
+    pub reassembly_ok: AtomicUsize,
+    /// Includes timeouts, invalid fragments and fragments dropped for the memory cap
+    pub reassembly_failures: AtomicUsize,
+    pub reassembly_timeouts: AtomicUsize,
+    pub fragments_created: AtomicUsize,
+    /// Datagrams over the MTU with DF set
+    pub fragmentation_failures: AtomicUsize,
+}
+
+/// The datagram does not fit the MTU and may not be fragmented
+#[derive(Debug)]
+pub struct NeedsFragmentation;
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct ReassemblyKey {
+    source_ip: Ipv4Addr,
+    dest_ip: Ipv4Addr,
+    protocol: u8,
+    identification: u16,
+}
+
+struct PartialDatagram {
+    // From the fragment at offset 0, reused for the reassembled datagram
+    header: Option<Vec<u8>>,
+    // Payload pieces by offset, duplicates and overlaps are resolved when assembling
+    fragments: Vec<(usize, Vec<u8>)>,
+    // Known once the fragment without MF has arrived
+    payload_length: Option<usize>,
+    expiry: usize,
+}
+
+impl PartialDatagram {
+    fn buffered(&self) -> usize {
+        let header_length = self.header.as_ref().map_or(0, Vec::len);
+        header_length
+            + self
+                .fragments
+                .iter()
+                .map(|(_, data)| data.len() + Ipv4::FRAGMENT_OVERHEAD)
+                .sum::<usize>()
+    }
+
+    /// The complete datagram with a header for the unfragmented version, if everything arrived
+    fn assemble(&mut self) -> Option<Vec<u8>> {
+        let payload_length = self.payload_length?;
+        let header = self.header.as_ref()?;
+
+        self.fragments.sort_by_key(|(offset, _)| *offset);
+        let mut covered = 0;
+        for (offset, data) in &self.fragments {
+            if *offset > covered {
+                return None;
+            }
+            covered = covered.max(offset + data.len());
+        }
+        if covered < payload_length {
+            return None;
+        }
+
+        let mut datagram = header.clone();
+        datagram.resize(header.len() + payload_length, 0);
+        for (offset, data) in &self.fragments {
+            let start = header.len() + offset;
+            datagram[start..start + data.len()].copy_from_slice(data);
+        }
+
+        let total_length = datagram.len() as u16;
+        datagram[2..4].copy_from_slice(&total_length.to_be_bytes());
+        // Flags and fragment offset
+        datagram[6..8].copy_from_slice(&[0, 0]);
+        datagram[10..12].copy_from_slice(&[0, 0]);
+        let checksum = net::calculate_ipv4_checksum(&datagram[..header.len()]);
+        datagram[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        Some(datagram)
+    }
+}
+
+#[derive(Default)]
+struct Reassembly {
+    partial: HashMap<ReassemblyKey, PartialDatagram>,
+    // Sum of PartialDatagram::buffered
+    buffered: usize,
+}
+
+impl Reassembly {
+    fn remove(&mut self, key: &ReassemblyKey) {
+        if let Some(partial) = self.partial.remove(key) {
+            self.buffered -= partial.buffered();
+        }
+    }
+
+    fn expire(&mut self, now: usize, stats: &Ipv4Stats) {
+        let buffered = &mut self.buffered;
+        self.partial.retain(|key, partial| {
+            let expired = now >= partial.expiry;
+            if expired {
+                debug!("Reassembly timed out: {:?}", key);
+                *buffered -= partial.buffered();
+                increment(&stats.reassembly_timeouts);
+                increment(&stats.reassembly_failures);
+            }
+            !expired
+        });
+    }
+}
+
+/// Receive side validation bookkeeping, fragment reassembly and fragmentation
+pub struct Ipv4 {
+    reassembly: Mutex<Reassembly>,
+    next_identification: AtomicU16,
+    time: Arc<MonotonicTime>,
+    pub stats: Ipv4Stats,
+}
+
+impl Ipv4 {
+    // Linux uses 30 seconds, RFC 791 recommends at least 15
+    const REASSEMBLY_TIMEOUT_S: f32 = 30.0;
+    // Shared by all datagrams being reassembled, fragments over it are dropped
+    const MAX_REASSEMBLY_BYTES: usize = 256 * 1024;
+    // Charged per buffered fragment on top of its payload, so floods of tiny fragments or
+    // datagrams hit the cap as well
+    const FRAGMENT_OVERHEAD: usize = 64;
+    const HEADER_LENGTH: usize = 20;
+
+    pub fn new(time: Arc<MonotonicTime>) -> Ipv4 {
+        Ipv4 {
+            reassembly: Mutex::new(Default::default()),
+            next_identification: AtomicU16::new(0),
+            time,
+            stats: Default::default(),
+        }
+    }
+
+    /// Accounts for frames rejected by Ipv4Frame::new
+    pub fn record_invalid_frame(&self) {
+        increment(&self.stats.in_receives);
+        increment(&self.stats.in_header_errors);
+    }
+
+    /// The datagram frame belongs to, once all of its fragments have arrived. Unfragmented
+    /// frames are passed through as is
+    pub async fn reassemble<'a>(&self, frame: &Ipv4Frame<'a>) -> Option<Cow<'a, [u8]>> {
+        increment(&self.stats.in_receives);
+        if !frame.is_fragment() {
+            return Some(Cow::Borrowed(frame.datagram()));
+        }
+        increment(&self.stats.reassembly_required);
+
+        let mut guard = self.reassembly.lock().await;
+        let reassembly = &mut *guard;
+        let now = self.time.get();
+        reassembly.expire(now, &self.stats);
+
+        let payload = frame.payload();
+        let header = &frame.datagram()[..frame.datagram().len() - payload.len()];
+        let offset = frame.fragment_offset();
+        let end = offset + payload.len();
+
+        // All but the last fragment carry a non-empty multiple of 8 bytes
+        let invalid_length =
+            frame.more_fragments() && (payload.is_empty() || !payload.len().is_multiple_of(8));
+        if invalid_length || header.len() + end > u16::MAX as usize {
+            debug!("Dropping invalid fragment at offset {}", offset);
+            increment(&self.stats.reassembly_failures);
+            return None;
+        }
+
+        let added =
+            payload.len() + Self::FRAGMENT_OVERHEAD + if offset == 0 { header.len() } else { 0 };
+        if reassembly.buffered + added > Self::MAX_REASSEMBLY_BYTES {
+            debug!("Reassembly memory exhausted, dropping fragment");
+            increment(&self.stats.reassembly_failures);
+            return None;
+        }
+
+        let key = ReassemblyKey {
+            source_ip: frame.source_ip(),
+            dest_ip: frame.dest_ip(),
+            protocol: frame.protocol().into(),
+            identification: frame.identification(),
+        };
+        let expiry = now + (Self::REASSEMBLY_TIMEOUT_S * self.time.tick_freq()) as usize;
+        let partial = reassembly
+            .partial
+            .entry(key.clone())
+            .or_insert_with(|| PartialDatagram {
+                header: None,
+                fragments: Vec::new(),
+                payload_length: None,
+                expiry,
+            });
+
+        let consistent = match partial.payload_length {
+            Some(payload_length) if frame.more_fragments() => end <= payload_length,
+            Some(payload_length) => end == payload_length,
+            None => {
+                frame.more_fragments()
+                    || partial
+                        .fragments
+                        .iter()
+                        .all(|(offset, data)| offset + data.len() <= end)
+            }
+        };
+        if !consistent {
+            debug!("Dropping datagram with inconsistent fragments: {:?}", key);
+            reassembly.remove(&key);
+            increment(&self.stats.reassembly_failures);
+            return None;
+        }
+
+        let before = partial.buffered();
+        if !frame.more_fragments() {
+            partial.payload_length = Some(end);
+        }
+        if offset == 0 {
+            partial.header = Some(header.to_vec());
+        }
+        partial.fragments.push((offset, payload.to_vec()));
+        reassembly.buffered += partial.buffered() - before;
+
+        let datagram = partial.assemble()?;
+        reassembly.remove(&key);
+        increment(&self.stats.reassembly_ok);
+
+        Some(Cow::Owned(datagram))
+    }
+
+    /// Frames carrying payload, split into fragments when it does not fit the MTU
+    pub fn generate_frames(
+        &self,
+        payload: &[u8],
+        protocol: Ipv4Protocol,
+        source_ip: &Ipv4Addr,
+        dest_ip: &Ipv4Addr,
+        mtu: usize,
+        dont_fragment: bool,
+    ) -> Result<Vec<Vec<u8>>, NeedsFragmentation> {
+        let identification = self.next_identification.fetch_add(1, Ordering::Relaxed);
+        let generate = |payload, fragment_offset, more_fragments| {
+            net::generate_ipv4_frame_with_params(&Ipv4FrameParams {
+                protocol,
+                source_ip: *source_ip,
+                dest_ip: *dest_ip,
+                identification,
+                ttl: net::DEFAULT_TTL,
+                dont_fragment,
+                more_fragments,
+                fragment_offset,
+                payload,
+            })
+        };
+
+        if Self::HEADER_LENGTH + payload.len() <= mtu {
+            return Ok(alloc::vec![generate(payload, 0, false)]);
+        }
+
+        if dont_fragment {
+            increment(&self.stats.fragmentation_failures);
+            return Err(NeedsFragmentation);
+        }
+
+        let fragment_length = (mtu - Self::HEADER_LENGTH) / 8 * 8;
+        let frames: Vec<_> = payload
+            .chunks(fragment_length)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let offset = i * fragment_length;
+                generate(chunk, offset, offset + chunk.len() < payload.len())
+            })
+            .collect();
+        self.stats
+            .fragments_created
+            .fetch_add(frames.len(), Ordering::Relaxed);
+
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::string::ToString;
+
+    const SOURCE_IP: Ipv4Addr = [192, 168, 2, 1];
+    const DEST_IP: Ipv4Addr = [192, 168, 2, 2];
+
+    fn test_payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    create_test!(test_fragment_round_trip, {
+        let ipv4 = Ipv4::new(Arc::new(MonotonicTime::new(10.0)));
+        let payload = test_payload(3000);
+
+        let frames = ipv4
+            .generate_frames(
+                &payload,
+                Ipv4Protocol::Udp,
+                &SOURCE_IP,
+                &DEST_IP,
+                1500,
+                false,
+            )
+            .map_err(|_| "Fragmentation failed".to_string())?;
+        test_eq!(frames.len(), 3);
+        test_eq!(ipv4.stats.fragments_created.load(Ordering::Relaxed), 3);
+        for frame in &frames {
+            test_true!(frame.len() <= 1500);
+        }
+
+        // Out of order and with a duplicate
+        let mut reassembled = None;
+        for frame in [&frames[2], &frames[0], &frames[0], &frames[1]] {
+            test_true!(reassembled.is_none());
+            let frame = Ipv4Frame::new(frame).map_err(|_| "Invalid fragment".to_string())?;
+            test_true!(frame.is_fragment());
+            reassembled = ipv4.reassemble(&frame).await.map(Cow::into_owned);
+        }
+
+        let reassembled = reassembled.ok_or("Datagram not reassembled".to_string())?;
+        let frame =
+            Ipv4Frame::new(&reassembled).map_err(|_| "Invalid reassembled frame".to_string())?;
+        test_false!(frame.is_fragment());
+        test_eq!(frame.protocol(), Ipv4Protocol::Udp);
+        test_eq!(frame.source_ip(), SOURCE_IP);
+        test_eq!(frame.payload(), payload.as_slice());
+        test_eq!(ipv4.stats.reassembly_ok.load(Ordering::Relaxed), 1);
+        test_eq!(ipv4.reassembly.lock().await.buffered, 0);
+
+        Ok(())
+    });
+
+    create_test!(test_dont_fragment, {
+        let ipv4 = Ipv4::new(Arc::new(MonotonicTime::new(10.0)));
+
+        let frames = ipv4
+            .generate_frames(
+                &[0; 1480],
+                Ipv4Protocol::Tcp,
+                &SOURCE_IP,
+                &DEST_IP,
+                1500,
+                true,
+            )
+            .map_err(|_| "Datagram fits the mtu".to_string())?;
+        let frame = Ipv4Frame::new(&frames[0]).map_err(|_| "Invalid frame".to_string())?;
+        test_true!(frame.dont_fragment());
+        test_false!(frame.is_fragment());
+
+        let result = ipv4.generate_frames(
+            &[0; 1481],
+            Ipv4Protocol::Tcp,
+            &SOURCE_IP,
+            &DEST_IP,
+            1500,
+            true,
+        );
+        test_true!(result.is_err());
+        test_eq!(ipv4.stats.fragmentation_failures.load(Ordering::Relaxed), 1);
+
+        Ok(())
+    });
+
+    create_test!(test_reassembly_timeout, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let ipv4 = Ipv4::new(Arc::clone(&time));
+        let frames = ipv4
+            .generate_frames(
+                &test_payload(2000),
+                Ipv4Protocol::Udp,
+                &SOURCE_IP,
+                &DEST_IP,
+                1500,
+                false,
+            )
+            .map_err(|_| "Fragmentation failed".to_string())?;
+
+        let first = Ipv4Frame::new(&frames[0]).map_err(|_| "Invalid fragment".to_string())?;
+        test_true!(ipv4.reassemble(&first).await.is_none());
+
+        // The rest arrives too late, only to start a new reassembly
+        time.set_tick((Ipv4::REASSEMBLY_TIMEOUT_S * time.tick_freq()) as usize);
+        let last = Ipv4Frame::new(&frames[1]).map_err(|_| "Invalid fragment".to_string())?;
+        test_true!(ipv4.reassemble(&last).await.is_none());
+        test_eq!(ipv4.stats.reassembly_timeouts.load(Ordering::Relaxed), 1);
+        test_eq!(ipv4.reassembly.lock().await.buffered, frames[1].len() - 20);
+
+        Ok(())
+    });
+
+    create_test!(test_reassembly_memory_cap, {
+        let ipv4 = Ipv4::new(Arc::new(MonotonicTime::new(10.0)));
+
+        // First fragments of datagrams that never complete
+        let mut accepted = 0;
+        for _ in 0..Ipv4::MAX_REASSEMBLY_BYTES / 1480 + 1 {
+            let frames = ipv4
+                .generate_frames(
+                    &[0; 2000],
+                    Ipv4Protocol::Udp,
+                    &SOURCE_IP,
+                    &DEST_IP,
+                    1500,
+                    false,
+                )
+                .map_err(|_| "Fragmentation failed".to_string())?;
+            let frame = Ipv4Frame::new(&frames[0]).map_err(|_| "Invalid fragment".to_string())?;
+            test_true!(ipv4.reassemble(&frame).await.is_none());
+            accepted = ipv4.reassembly.lock().await.partial.len();
+        }
+
+        test_eq!(
+            accepted,
+            Ipv4::MAX_REASSEMBLY_BYTES / (1500 + Ipv4::FRAGMENT_OVERHEAD)
+        );
+        test_true!(ipv4.reassembly.lock().await.buffered <= Ipv4::MAX_REASSEMBLY_BYTES);
+        test_ge!(ipv4.stats.reassembly_failures.load(Ordering::Relaxed), 1);
+
+        Ok(())
+    });
+
+    create_test!(test_reassembly_tiny_fragments_cap, {
+        let ipv4 = Ipv4::new(Arc::new(MonotonicTime::new(10.0)));
+
+        // Minimal fragments of distinct datagrams still count against the cap
+        let cost = 8 + Ipv4::FRAGMENT_OVERHEAD;
+        for identification in 0..(Ipv4::MAX_REASSEMBLY_BYTES / cost + 1) as u16 {
+            let frame = net::generate_ipv4_frame_with_params(&Ipv4FrameParams {
+                protocol: Ipv4Protocol::Udp,
+                source_ip: SOURCE_IP,
+                dest_ip: DEST_IP,
+                identification,
+                ttl: net::DEFAULT_TTL,
+                dont_fragment: false,
+                more_fragments: true,
+                fragment_offset: 8,
+                payload: &[0; 8],
+            });
+            let frame = Ipv4Frame::new(&frame).map_err(|_| "Invalid frame".to_string())?;
+            test_true!(ipv4.reassemble(&frame).await.is_none());
+        }
+
+        let reassembly = ipv4.reassembly.lock().await;
+        test_eq!(reassembly.partial.len(), Ipv4::MAX_REASSEMBLY_BYTES / cost);
+        test_true!(reassembly.buffered <= Ipv4::MAX_REASSEMBLY_BYTES);
+
+        Ok(())
+    });
+
+    create_test!(test_invalid_fragments, {
+        let ipv4 = Ipv4::new(Arc::new(MonotonicTime::new(10.0)));
+
+        // Non-final fragments must be a multiple of 8 bytes
+        let frame = net::generate_ipv4_frame_with_params(&Ipv4FrameParams {
+            protocol: Ipv4Protocol::Udp,
+            source_ip: SOURCE_IP,
+            dest_ip: DEST_IP,
+            identification: 1,
+            ttl: net::DEFAULT_TTL,
+            dont_fragment: false,
+            more_fragments: true,
+            fragment_offset: 0,
+            payload: &[0; 10],
+        });
+        let frame = Ipv4Frame::new(&frame).map_err(|_| "Invalid frame".to_string())?;
+        test_true!(ipv4.reassemble(&frame).await.is_none());
+
+        // Reaching past 65535 bytes
+        let frame = net::generate_ipv4_frame_with_params(&Ipv4FrameParams {
+            protocol: Ipv4Protocol::Udp,
+            source_ip: SOURCE_IP,
+            dest_ip: DEST_IP,
+            identification: 2,
+            ttl: net::DEFAULT_TTL,
+            dont_fragment: false,
+            more_fragments: false,
+            fragment_offset: 65528,
+            payload: &[0; 100],
+        });
+        let frame = Ipv4Frame::new(&frame).map_err(|_| "Invalid frame".to_string())?;
+        test_true!(ipv4.reassemble(&frame).await.is_none());
+
+        test_eq!(ipv4.stats.reassembly_failures.load(Ordering::Relaxed), 2);
+        test_true!(ipv4.reassembly.lock().await.partial.is_empty());
+
+        Ok(())
+    });
+}
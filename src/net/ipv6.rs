@@ -0,0 +1,323 @@
+use crate::{net::Ipv4Protocol, util::bit_manipulation::GetBits, Ipv6Addr, MacAddr};
+
+use alloc::vec::Vec;
+
+pub const UNSPECIFIED: Ipv6Addr = [0; 16];
+pub const LOOPBACK: Ipv6Addr = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+pub const ALL_NODES: Ipv6Addr = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+pub const ALL_ROUTERS: Ipv6Addr = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+pub const DEFAULT_HOP_LIMIT: u8 = 64;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum InvalidIpv6Frame {
+    Truncated,
+    InvalidHeader,
+}
+
+/// Extension headers are not supported, the next header is expected to be the upper layer
+/// protocol
+#[derive(Debug)]
+pub struct Ipv6Frame<'a> {
+    packet: &'a [u8],
+}
+
+impl<'a> Ipv6Frame<'a> {
+    const HEADER_LENGTH: usize = 40;
+
+    pub fn new(packet: &[u8]) -> Result<Ipv6Frame<'_>, InvalidIpv6Frame> {
+        let frame = Ipv6Frame { packet };
+
+        if packet.len() < Self::HEADER_LENGTH {
+            return Err(InvalidIpv6Frame::Truncated);
+        }
+
+        if frame.version() != 6 {
+            return Err(InvalidIpv6Frame::InvalidHeader);
+        }
+
+        if Self::HEADER_LENGTH + frame.payload_length() > packet.len() {
+            return Err(InvalidIpv6Frame::Truncated);
+        }
+
+        Ok(frame)
+    }
+
+    fn version(&self) -> u8 {
+        self.packet[0].get_bits(4, 4)
+    }
+
+    fn payload_length(&self) -> usize {
+        u16::from_be_bytes([self.packet[4], self.packet[5]]) as usize
+    }
+
+    pub fn next_header(&self) -> Ipv4Protocol {
+        self.packet[6].into()
+    }
+
+    pub fn hop_limit(&self) -> u8 {
+        self.packet[7]
+    }
+
+    pub fn source_ip(&self) -> Ipv6Addr {
+        self.packet[8..24]
+            .try_into()
+            .expect("Invalid length for ipv6 source ip")
+    }
+
+    pub fn dest_ip(&self) -> Ipv6Addr {
+        self.packet[24..40]
+            .try_into()
+            .expect("Invalid length for ipv6 dest ip")
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.packet[Self::HEADER_LENGTH..Self::HEADER_LENGTH + self.payload_length()]
+    }
+}
+
+pub fn generate_ipv6_frame(
+    payload: &[u8],
+    next_header: Ipv4Protocol,
+    source_ip: &Ipv6Addr,
+    dest_ip: &Ipv6Addr,
+    hop_limit: u8,
+) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(Ipv6Frame::HEADER_LENGTH + payload.len());
+
+    // Version, traffic class and flow label
+    ret.extend_from_slice(&[0x60, 0, 0, 0]);
+    // FIXME: usize -> u16 truncation
+    ret.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    ret.push(next_header.into());
+    ret.push(hop_limit);
+    ret.extend_from_slice(source_ip);
+    ret.extend_from_slice(dest_ip);
+    ret.extend_from_slice(payload);
+
+    ret
+}
+
+pub fn is_multicast(ip: &Ipv6Addr) -> bool {
+    ip[0] == 0xff
+}
+
+/// fe80::/10
+pub fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip[0] == 0xfe && ip[1] & 0xc0 == 0x80
+}
+
+/// Modified EUI-64 interface identifier (RFC 4291 appendix A)
+pub fn interface_id(mac: &MacAddr) -> [u8; 8] {
+    [
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ]
+}
+
+/// Address in the given /64 prefix using the interface identifier of mac
+pub fn address_from_prefix(prefix: &Ipv6Addr, mac: &MacAddr) -> Ipv6Addr {
+    let mut ret = *prefix;
+    ret[8..].copy_from_slice(&interface_id(mac));
+    ret
+}
+
+pub fn link_local_address(mac: &MacAddr) -> Ipv6Addr {
+    let mut prefix = UNSPECIFIED;
+    prefix[0..2].copy_from_slice(&[0xfe, 0x80]);
+    address_from_prefix(&prefix, mac)
+}
+
+/// Multicast group that neighbor solicitations for ip are sent to
+pub fn solicited_node_address(ip: &Ipv6Addr) -> Ipv6Addr {
+    let mut ret = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0, 0, 0];
+    ret[13..].copy_from_slice(&ip[13..]);
+    ret
+}
+
+/// Ethernet destination for a multicast address (RFC 2464)
+pub fn multicast_mac(ip: &Ipv6Addr) -> MacAddr {
+    [0x33, 0x33, ip[12], ip[13], ip[14], ip[15]]
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Ipv6Config {
+    pub link_local: Ipv6Addr,
+    /// Global addresses from SLAAC, all with a /64 prefix
+    pub addresses: [Option<Ipv6Addr>; Ipv6Config::MAX_ADDRESSES],
+    pub router: Option<Ipv6Addr>,
+}
+
+impl Ipv6Config {
+    pub const MAX_ADDRESSES: usize = 2;
+
+    pub fn new(link_local: Ipv6Addr) -> Ipv6Config {
+        Ipv6Config {
+            link_local,
+            addresses: [None; Self::MAX_ADDRESSES],
+            router: None,
+        }
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = Ipv6Addr> + '_ {
+        core::iter::once(self.link_local).chain(self.addresses.iter().flatten().copied())
+    }
+
+    pub fn is_local(&self, ip: &Ipv6Addr) -> bool {
+        self.addresses().any(|addr| addr == *ip)
+    }
+
+    /// Unicast addresses we own and the multicast groups we are a member of
+    pub fn accepts(&self, ip: &Ipv6Addr) -> bool {
+        *ip == ALL_NODES
+            || self
+                .addresses()
+                .any(|addr| addr == *ip || solicited_node_address(&addr) == *ip)
+    }
+
+    /// Returns false if the address is already configured or there is no room for it
+    pub fn add_address(&mut self, ip: Ipv6Addr) -> bool {
+        if self.is_local(&ip) {
+            return false;
+        }
+
+        match self.addresses.iter_mut().find(|addr| addr.is_none()) {
+            Some(slot) => {
+                *slot = Some(ip);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Link local scope destinations are reached from the link local address, everything else
+    /// from a global address if we have one
+    pub fn local_ip_for(&self, remote_ip: &Ipv6Addr) -> Ipv6Addr {
+        if *remote_ip == LOOPBACK {
+            return LOOPBACK;
+        }
+
+        if is_link_local(remote_ip) || is_multicast(remote_ip) {
+            return self.link_local;
+        }
+
+        self.addresses
+            .iter()
+            .flatten()
+            .copied()
+            .next()
+            .unwrap_or(self.link_local)
+    }
+
+    /// Link local destinations and ones sharing a prefix with us are on link, everything else
+    /// goes through the router
+    pub fn next_hop(&self, ip: &Ipv6Addr) -> Option<Ipv6Addr> {
+        let on_link = is_link_local(ip)
+            || self
+                .addresses
+                .iter()
+                .flatten()
+                .any(|addr| addr[..8] == ip[..8]);
+
+        if on_link {
+            Some(*ip)
+        } else {
+            self.router
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::string::ToString;
+
+    const MAC: MacAddr = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const LINK_LOCAL: Ipv6Addr = [
+        0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0x50, 0x54, 0x00, 0xff, 0xfe, 0x12, 0x34, 0x56,
+    ];
+    const PREFIX: Ipv6Addr = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    create_test!(test_ipv6_frame_round_trip, {
+        let frame = generate_ipv6_frame(b"hello", Ipv4Protocol::Udp, &LINK_LOCAL, &ALL_NODES, 1);
+        test_eq!(frame.len(), 45);
+
+        let parsed = Ipv6Frame::new(&frame).map_err(|_| "Invalid ipv6 frame".to_string())?;
+        test_eq!(parsed.next_header(), Ipv4Protocol::Udp);
+        test_eq!(parsed.hop_limit(), 1);
+        test_eq!(parsed.source_ip(), LINK_LOCAL);
+        test_eq!(parsed.dest_ip(), ALL_NODES);
+        test_eq!(parsed.payload(), b"hello");
+
+        // Ethernet padding is not part of the payload
+        let mut padded = frame.clone();
+        padded.extend_from_slice(&[0; 10]);
+        let parsed = Ipv6Frame::new(&padded).map_err(|_| "Invalid ipv6 frame".to_string())?;
+        test_eq!(parsed.payload(), b"hello");
+
+        test_eq!(
+            Ipv6Frame::new(&frame[..44]).err(),
+            Some(InvalidIpv6Frame::Truncated)
+        );
+        let mut ipv4 = frame.clone();
+        ipv4[0] = 0x45;
+        test_eq!(
+            Ipv6Frame::new(&ipv4).err(),
+            Some(InvalidIpv6Frame::InvalidHeader)
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_ipv6_address_helpers, {
+        test_eq!(link_local_address(&MAC), LINK_LOCAL);
+        test_true!(is_link_local(&LINK_LOCAL));
+        test_false!(is_link_local(&PREFIX));
+        test_true!(is_multicast(&ALL_NODES));
+
+        let solicited = solicited_node_address(&LINK_LOCAL);
+        test_eq!(
+            solicited,
+            [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0x12, 0x34, 0x56]
+        );
+        test_eq!(
+            multicast_mac(&solicited),
+            [0x33, 0x33, 0xff, 0x12, 0x34, 0x56]
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_ipv6_config, {
+        let mut config = Ipv6Config::new(LINK_LOCAL);
+        test_true!(config.accepts(&ALL_NODES));
+        test_true!(config.accepts(&solicited_node_address(&LINK_LOCAL)));
+        test_false!(config.accepts(&ALL_ROUTERS));
+
+        let global = address_from_prefix(&PREFIX, &MAC);
+        let remote = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1];
+        test_eq!(config.local_ip_for(&remote), LINK_LOCAL);
+        test_true!(config.next_hop(&remote).is_none());
+
+        test_true!(config.add_address(global));
+        test_false!(config.add_address(global));
+        test_true!(config.accepts(&global));
+        test_eq!(config.local_ip_for(&remote), global);
+        test_eq!(config.local_ip_for(&ALL_NODES), LINK_LOCAL);
+
+        // Same /64 is on link, other prefixes need the router
+        let neighbor = address_from_prefix(&PREFIX, &[0; 6]);
+        test_eq!(config.next_hop(&neighbor), Some(neighbor));
+        config.router = Some(LINK_LOCAL);
+        test_eq!(config.next_hop(&remote), Some(LINK_LOCAL));
+
+        Ok(())
+    });
+}
@@ -1,23 +1,196 @@
+pub mod capture;
+pub mod dhcp;
+pub mod dns;
+pub mod firewall;
+pub mod http;
+pub mod icmp;
+pub mod icmpv6;
+pub mod ipv4;
+pub mod ipv6;
+pub mod loopback;
+pub mod sntp;
+pub mod stats;
 pub mod tcp;
+pub mod tftp;
+pub mod udp;
+pub mod vlan;
 
 use alloc::vec::Vec;
+use icmp::{IcmpFrame, InvalidIcmpFrame};
+use icmpv6::{Icmpv6Frame, InvalidIcmpv6Frame};
+use ipv6::{InvalidIpv6Frame, Ipv6Frame};
+use loopback::Loopback;
 use tcp::TcpFrame;
+use vlan::VlanTag;
 
 use core::convert::From;
 
-use crate::{util::bit_manipulation::GetBits, IpAddr};
+use crate::{
+    rtl8139::{PacketTooShort, Rtl8139},
+    util::bit_manipulation::{GetBits, SetBits},
+    Ipv4Addr, Ipv6Addr, MacAddr,
+};
+
+/// Address of either family, what transport protocols key their sockets on
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum IpAddr {
+    V4(Ipv4Addr),
+    V6(Ipv6Addr),
+}
+
+impl IpAddr {
+    pub fn octets(&self) -> &[u8] {
+        match self {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => ip,
+        }
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        self.octets().iter().all(|b| *b == 0)
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        matches!(self, IpAddr::V4(_))
+    }
+}
+
+impl From<Ipv4Addr> for IpAddr {
+    fn from(value: Ipv4Addr) -> Self {
+        IpAddr::V4(value)
+    }
+}
+
+impl From<Ipv6Addr> for IpAddr {
+    fn from(value: Ipv6Addr) -> Self {
+        IpAddr::V6(value)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: [Option<Ipv4Addr>; Ipv4Config::MAX_DNS_SERVERS],
+}
+
+impl Ipv4Config {
+    pub const MAX_DNS_SERVERS: usize = 2;
+
+    pub fn new(address: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Config {
+        Ipv4Config {
+            address,
+            netmask,
+            router: None,
+            dns_servers: [None; Self::MAX_DNS_SERVERS],
+        }
+    }
+
+    pub fn dns_servers(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.dns_servers.iter().flatten().copied()
+    }
+
+    pub fn is_local(&self, ip: &Ipv4Addr) -> bool {
+        self.address
+            .iter()
+            .zip(ip)
+            .zip(self.netmask)
+            .all(|((a, b), mask)| a & mask == b & mask)
+    }
+
+    /// Our address as seen by the given remote
+    pub fn local_ip_for(&self, remote_ip: &Ipv4Addr) -> Ipv4Addr {
+        if loopback::is_loopback_ip(remote_ip) {
+            Loopback::IP
+        } else {
+            self.address
+        }
+    }
+
+    /// The address that should be ARP'd to reach the given ip
+    pub fn next_hop(&self, ip: &Ipv4Addr) -> Ipv4Addr {
+        match self.router {
+            Some(router) if !self.is_local(ip) && *ip != [255; 4] => router,
+            _ => *ip,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum NetDevice<'a> {
+    Rtl8139(&'a Rtl8139),
+    Loopback(&'a Loopback),
+    /// VLAN sub-interface of a physical device
+    Vlan(&'a Rtl8139, VlanTag),
+}
+
+impl<'a> NetDevice<'a> {
+    pub async fn write(&self, packet: &[u8]) -> Result<(), PacketTooShort> {
+        match self {
+            NetDevice::Rtl8139(rtl8139) => rtl8139.write(packet).await,
+            NetDevice::Loopback(loopback) => loopback.write(packet).await,
+            NetDevice::Vlan(rtl8139, _) => rtl8139.write(packet).await,
+        }
+    }
+
+    pub async fn read<F, Fut>(&self, on_read: F)
+    where
+        F: Fn(&[u8]) -> Fut,
+        Fut: core::future::Future<Output = ()>,
+    {
+        match self {
+            NetDevice::Rtl8139(rtl8139) => rtl8139.read(on_read).await,
+            NetDevice::Loopback(loopback) => loopback.read(on_read).await,
+            NetDevice::Vlan(rtl8139, _) => rtl8139.read(on_read).await,
+        }
+    }
+
+    pub fn get_mac(&self) -> MacAddr {
+        match self {
+            NetDevice::Rtl8139(rtl8139) => rtl8139.get_mac(),
+            NetDevice::Loopback(loopback) => loopback.get_mac(),
+            NetDevice::Vlan(rtl8139, _) => rtl8139.get_mac(),
+        }
+    }
+
+    pub fn mtu(&self) -> usize {
+        match self {
+            NetDevice::Rtl8139(_) | NetDevice::Vlan(_, _) => Rtl8139::MTU,
+            NetDevice::Loopback(_) => Loopback::MTU,
+        }
+    }
+
+    /// Tag that frames written to this device have to carry
+    pub fn vlan_tag(&self) -> Option<VlanTag> {
+        match self {
+            NetDevice::Vlan(_, tag) => Some(*tag),
+            _ => None,
+        }
+    }
+
+    /// Sub-interface for the given tag, VLANs can't be nested
+    pub fn with_vlan_tag(&self, tag: VlanTag) -> Option<NetDevice<'a>> {
+        match self {
+            NetDevice::Rtl8139(rtl8139) => Some(NetDevice::Vlan(rtl8139, tag)),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Copy, Clone)]
 #[repr(u16)]
 pub enum EtherType {
     Ipv4 = 0x0800,
     Arp = 0x0806,
+    Ipv6 = 0x86DD,
 }
 
 pub struct EthernetFrameParams<'a> {
     pub dest_mac: [u8; 6],
     pub source_mac: [u8; 6],
     pub ether_type: EtherType,
+    pub vlan: Option<VlanTag>,
     pub payload: &'a [u8],
 }
 
@@ -28,12 +201,17 @@ pub fn generate_ethernet_frame(params: &EthernetFrameParams<'_>) -> Vec<u8> {
     let length = core::mem::size_of_val(&params.dest_mac)
         + core::mem::size_of_val(&params.source_mac)
         + core::mem::size_of_val(&params.ether_type)
+        + params.vlan.map_or(0, |_| 4)
         + params.payload.len();
 
     let mut ret = Vec::with_capacity(length);
 
     ret.extend_from_slice(&params.dest_mac);
     ret.extend_from_slice(&params.source_mac);
+    if let Some(vlan) = params.vlan {
+        ret.extend_from_slice(&VlanTag::TPID.to_be_bytes());
+        ret.extend_from_slice(&vlan.tci().to_be_bytes());
+    }
     ret.extend_from_slice(&(params.ether_type as u16).to_be_bytes());
     ret.extend_from_slice(params.payload);
     if ret.len() < MIN_LENGTH {
@@ -81,6 +259,11 @@ impl<'a> EthernetFrame<'a> {
         }
     }
 
+    pub fn vlan_tag(&self) -> Option<VlanTag> {
+        self.tag()
+            .map(|tag| VlanTag::from_tci(u16::from_be_bytes([tag[2], tag[3]])))
+    }
+
     pub fn ether_type(&self) -> u16 {
         let start = self.ether_type_offset();
         let end = start + 2;
@@ -123,8 +306,7 @@ impl<'a> EthernetFrame<'a> {
                 .try_into()
                 .expect("Incorrect slice size"),
         );
-        const DOT1Q_ID: u16 = 0x8100;
-        tag == DOT1Q_ID
+        tag == VlanTag::TPID
     }
 }
 
@@ -346,8 +528,12 @@ impl core::fmt::Debug for ArpFrame<'_> {
     }
 }
 
-#[derive(Debug)]
-pub struct InvalidIpv4Frame;
+#[derive(Debug, Eq, PartialEq)]
+pub enum InvalidIpv4Frame {
+    Truncated,
+    InvalidHeader,
+    InvalidChecksum,
+}
 
 #[derive(Debug)]
 pub struct Ipv4Frame<'a> {
@@ -355,23 +541,77 @@ pub struct Ipv4Frame<'a> {
 }
 
 impl<'a> Ipv4Frame<'a> {
-    fn new(packet: &[u8]) -> Result<Ipv4Frame, InvalidIpv4Frame> {
+    const MIN_HEADER_LENGTH: usize = 20;
+
+    pub fn new(packet: &[u8]) -> Result<Ipv4Frame<'_>, InvalidIpv4Frame> {
         let frame = Ipv4Frame { packet };
 
-        if packet.is_empty()
+        if packet.len() < Self::MIN_HEADER_LENGTH
             || frame.header_length() > packet.len()
             || frame.total_length() > packet.len()
         {
-            return Err(InvalidIpv4Frame);
+            return Err(InvalidIpv4Frame::Truncated);
+        }
+
+        if frame.version() != 4
+            || frame.header_length() < Self::MIN_HEADER_LENGTH
+            || frame.total_length() < frame.header_length()
+        {
+            return Err(InvalidIpv4Frame::InvalidHeader);
+        }
+
+        // Summing a valid header including its checksum gives all ones
+        if calculate_ipv4_checksum(frame.header()) != 0 {
+            return Err(InvalidIpv4Frame::InvalidChecksum);
         }
 
         Ok(frame)
     }
 
+    fn version(&self) -> u8 {
+        self.packet[0].get_bits(4, 4)
+    }
+
     fn ihl(&self) -> u8 {
         self.packet[0].get_bits(0, 4)
     }
 
+    pub fn identification(&self) -> u16 {
+        u16::from_be_bytes([self.packet[4], self.packet[5]])
+    }
+
+    #[allow(unused)]
+    pub fn dont_fragment(&self) -> bool {
+        self.packet[6].get_bit(6)
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.packet[6].get_bit(5)
+    }
+
+    /// In bytes, the header stores it in units of 8
+    pub fn fragment_offset(&self) -> usize {
+        (u16::from_be_bytes([self.packet[6], self.packet[7]]) & 0x1fff) as usize * 8
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+
+    #[allow(unused)]
+    pub fn ttl(&self) -> u8 {
+        self.packet[8]
+    }
+
+    fn header(&self) -> &'a [u8] {
+        &self.packet[..self.header_length()]
+    }
+
+    /// Header and payload without any link layer padding
+    pub fn datagram(&self) -> &'a [u8] {
+        &self.packet[..self.total_length()]
+    }
+
     fn total_length(&self) -> usize {
         u16::from_be_bytes(
             self.packet[2..4]
@@ -380,25 +620,26 @@ impl<'a> Ipv4Frame<'a> {
         ) as usize
     }
 
-    fn protocol(&self) -> Ipv4Protocol {
-        match self.packet[9] {
-            0x06 => Ipv4Protocol::Tcp,
-            0x11 => Ipv4Protocol::Udp,
-            v => Ipv4Protocol::Unknown(v),
-        }
+    pub fn protocol(&self) -> Ipv4Protocol {
+        self.packet[9].into()
     }
 
-    fn payload(&self) -> &'a [u8] {
-        let ipv4_length = self.ihl() * 4;
-        &self.packet[ipv4_length as usize..self.total_length()]
+    pub fn payload(&self) -> &'a [u8] {
+        &self.packet[self.header_length()..self.total_length()]
     }
 
-    pub fn source_ip(&self) -> IpAddr {
+    pub fn source_ip(&self) -> Ipv4Addr {
         self.packet[12..16]
             .try_into()
             .expect("Invalid length for ipv4 source ip")
     }
 
+    pub fn dest_ip(&self) -> Ipv4Addr {
+        self.packet[16..20]
+            .try_into()
+            .expect("Invalid length for ipv4 dest ip")
+    }
+
     fn header_length(&self) -> usize {
         (self.ihl() as usize) * 4
     }
@@ -422,39 +663,72 @@ fn calculate_ipv4_checksum(data: &[u8]) -> u16 {
     !checksum
 }
 
+pub struct Ipv4FrameParams<'a> {
+    pub protocol: Ipv4Protocol,
+    pub source_ip: Ipv4Addr,
+    pub dest_ip: Ipv4Addr,
+    pub identification: u16,
+    pub ttl: u8,
+    pub dont_fragment: bool,
+    pub more_fragments: bool,
+    /// In bytes, must be a multiple of 8
+    pub fragment_offset: usize,
+    pub payload: &'a [u8],
+}
+
+pub const DEFAULT_TTL: u8 = 64;
+
+/// Unfragmented datagram without an identification
+#[allow(unused)]
 pub fn generate_ipv4_frame(
     payload: &[u8],
     protocol: Ipv4Protocol,
-    source_ip: &IpAddr,
-    dest_ip: &IpAddr,
+    source_ip: &Ipv4Addr,
+    dest_ip: &Ipv4Addr,
 ) -> Vec<u8> {
-    // FIXME: capacity?
-    let mut ret: Vec<u8> = Vec::new();
+    generate_ipv4_frame_with_params(&Ipv4FrameParams {
+        protocol,
+        source_ip: *source_ip,
+        dest_ip: *dest_ip,
+        identification: 0,
+        ttl: DEFAULT_TTL,
+        dont_fragment: false,
+        more_fragments: false,
+        fragment_offset: 0,
+        payload,
+    })
+}
+
+pub fn generate_ipv4_frame_with_params(params: &Ipv4FrameParams<'_>) -> Vec<u8> {
+    const HEADER_SIZE: usize = 20;
+    let mut ret: Vec<u8> = Vec::with_capacity(HEADER_SIZE + params.payload.len());
 
-    const HEADER_SIZE: u16 = 20;
     // Version + IHL
     ret.push(0x45);
     // DSCP ECN
     ret.push(0x0);
     // FIXME: usize -> u16 truncation
-    ret.extend_from_slice(&(HEADER_SIZE + payload.len() as u16).to_be_bytes());
-    // Identification
-    ret.extend_from_slice(&0u16.to_be_bytes());
-    // Flags + fragment offset
-    ret.extend_from_slice(&0u16.to_be_bytes());
-    // TTL (copied from wireshark incoming packet)
-    ret.push(64);
-    ret.push(protocol.into());
+    ret.extend_from_slice(&((HEADER_SIZE + params.payload.len()) as u16).to_be_bytes());
+    ret.extend_from_slice(&params.identification.to_be_bytes());
+
+    assert_eq!(params.fragment_offset % 8, 0);
+    let mut flags_and_offset = (params.fragment_offset / 8) as u16;
+    flags_and_offset.set_bit(14, params.dont_fragment);
+    flags_and_offset.set_bit(13, params.more_fragments);
+    ret.extend_from_slice(&flags_and_offset.to_be_bytes());
+
+    ret.push(params.ttl);
+    ret.push(params.protocol.into());
 
     let checksum_loc = ret.len();
     ret.extend_from_slice(&0u16.to_be_bytes());
-    ret.extend_from_slice(source_ip);
-    ret.extend_from_slice(dest_ip);
+    ret.extend_from_slice(&params.source_ip);
+    ret.extend_from_slice(&params.dest_ip);
 
     let checksum = calculate_ipv4_checksum(&ret);
     ret[checksum_loc..checksum_loc + 2].copy_from_slice(&checksum.to_be_bytes());
 
-    ret.extend_from_slice(payload);
+    ret.extend_from_slice(params.payload);
 
     ret
 }
@@ -480,6 +754,22 @@ impl UdpFrame<'_> {
         Ok(frame)
     }
 
+    pub fn source_port(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[0..2]
+                .try_into()
+                .expect("u16 packet size incorrect"),
+        )
+    }
+
+    pub fn dest_port(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[2..4]
+                .try_into()
+                .expect("u16 packet size incorrect"),
+        )
+    }
+
     fn length(&self) -> u16 {
         u16::from_be_bytes(
             self.packet[4..6]
@@ -488,22 +778,91 @@ impl UdpFrame<'_> {
         )
     }
 
+    pub fn checksum(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[6..8]
+                .try_into()
+                .expect("u16 packet size incorrect"),
+        )
+    }
+
+    /// The checksum is optional over ipv4, a checksum of 0 is always valid there. Ipv6 makes it
+    /// mandatory
+    pub fn checksum_valid(&self, source_ip: &IpAddr, dest_ip: &IpAddr) -> bool {
+        (self.checksum() == 0 && source_ip.is_ipv4())
+            || calculate_pseudo_header_checksum(
+                source_ip,
+                dest_ip,
+                Ipv4Protocol::Udp,
+                &self.packet[..self.length() as usize],
+            ) == 0
+    }
+
     pub fn data(&self) -> &[u8] {
         &self.packet[Self::HEADER_LENGTH..self.length() as usize]
     }
 }
-pub fn generate_udp_frame(dest_port: u16, payload: &[u8]) -> Vec<u8> {
+
+/// Checksum of an upper layer segment, which also covers the pseudo header of the address
+/// family carrying it
+pub fn calculate_pseudo_header_checksum(
+    source_ip: &IpAddr,
+    dest_ip: &IpAddr,
+    protocol: Ipv4Protocol,
+    segment: &[u8],
+) -> u16 {
+    let mut checksum_frame = Vec::with_capacity(40 + segment.len() + 1);
+    match (source_ip, dest_ip) {
+        (IpAddr::V4(source_ip), IpAddr::V4(dest_ip)) => {
+            checksum_frame.extend_from_slice(source_ip);
+            checksum_frame.extend_from_slice(dest_ip);
+            checksum_frame.push(0);
+            checksum_frame.push(protocol.into());
+            checksum_frame.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        }
+        (IpAddr::V6(source_ip), IpAddr::V6(dest_ip)) => {
+            checksum_frame.extend_from_slice(source_ip);
+            checksum_frame.extend_from_slice(dest_ip);
+            checksum_frame.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+            checksum_frame.extend_from_slice(&[0, 0, 0, protocol.into()]);
+        }
+        _ => panic!("Pseudo header with mixed address families"),
+    }
+    checksum_frame.extend_from_slice(segment);
+
+    if !checksum_frame.len().is_multiple_of(2) {
+        checksum_frame.push(0);
+    }
+
+    calculate_ipv4_checksum(&checksum_frame)
+}
+
+pub fn generate_udp_frame(
+    source_ip: &IpAddr,
+    dest_ip: &IpAddr,
+    source_port: u16,
+    dest_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
     let length: u16 = UdpFrame::HEADER_LENGTH as u16 + payload.len() as u16;
 
     let mut ret = Vec::with_capacity(length.into());
 
-    const SOURCE_PORT: &[u8] = &0u16.to_be_bytes();
-    ret.extend_from_slice(SOURCE_PORT);
+    ret.extend_from_slice(&source_port.to_be_bytes());
     ret.extend_from_slice(&dest_port.to_be_bytes());
     ret.extend_from_slice(&length.to_be_bytes());
-    const CHECKSUM: &[u8] = &0u16.to_be_bytes();
-    ret.extend_from_slice(CHECKSUM);
+    let checksum_idx = ret.len();
+    ret.extend_from_slice(&0u16.to_be_bytes());
     ret.extend_from_slice(payload);
+
+    let mut checksum =
+        calculate_pseudo_header_checksum(source_ip, dest_ip, Ipv4Protocol::Udp, &ret);
+    // A checksum of 0 means that no checksum was calculated, all ones is equivalent in ones
+    // compliment
+    if checksum == 0 {
+        checksum = 0xffff;
+    }
+    ret[checksum_idx..checksum_idx + 2].copy_from_slice(&checksum.to_be_bytes());
     ret
 }
 
@@ -512,11 +871,13 @@ pub enum ParsePacketError {
     Ethernet(InvalidEthernetFrame),
     Arp(InvalidArpFrame),
     Ipv4(InvalidIpv4Frame),
+    Ipv6(InvalidIpv6Frame),
 }
 
 pub enum ParsedPacket<'a> {
     Arp(ArpFrame<'a>),
     Ipv4(Ipv4Frame<'a>),
+    Ipv6(Ipv6Frame<'a>),
     Unknown(u16),
 }
 
@@ -538,6 +899,10 @@ pub fn parse_packet(data: &[u8]) -> Result<ParsedEthernetFrame, ParsePacketError
             let ipv4_frame = Ipv4Frame::new(payload).map_err(ParsePacketError::Ipv4)?;
             ParsedPacket::Ipv4(ipv4_frame)
         }
+        0x86DD => {
+            let ipv6_frame = Ipv6Frame::new(payload).map_err(ParsePacketError::Ipv6)?;
+            ParsedPacket::Ipv6(ipv6_frame)
+        }
         t => ParsedPacket::Unknown(t),
     };
 
@@ -547,43 +912,131 @@ pub fn parse_packet(data: &[u8]) -> Result<ParsedEthernetFrame, ParsePacketError
     })
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u8)]
 pub enum Ipv4Protocol {
+    Icmp,
     Tcp,
     Udp,
+    /// Only carried by ipv6
+    Icmpv6,
     Unknown(u8),
 }
 
+impl core::convert::From<u8> for Ipv4Protocol {
+    fn from(value: u8) -> Self {
+        match value {
+            0x01 => Ipv4Protocol::Icmp,
+            0x06 => Ipv4Protocol::Tcp,
+            0x11 => Ipv4Protocol::Udp,
+            0x3a => Ipv4Protocol::Icmpv6,
+            v => Ipv4Protocol::Unknown(v),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidIpv4Protocol;
+
+/// Names as used by iptables, anything else as the protocol number
+impl core::str::FromStr for Ipv4Protocol {
+    type Err = InvalidIpv4Protocol;
+
+    fn from_str(s: &str) -> Result<Ipv4Protocol, InvalidIpv4Protocol> {
+        let ret = match s {
+            "icmp" => Ipv4Protocol::Icmp,
+            "tcp" => Ipv4Protocol::Tcp,
+            "udp" => Ipv4Protocol::Udp,
+            "icmpv6" => Ipv4Protocol::Icmpv6,
+            s => s.parse::<u8>().map_err(|_| InvalidIpv4Protocol)?.into(),
+        };
+        Ok(ret)
+    }
+}
+
+impl core::fmt::Display for Ipv4Protocol {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Ipv4Protocol::Icmp => f.write_str("icmp"),
+            Ipv4Protocol::Tcp => f.write_str("tcp"),
+            Ipv4Protocol::Udp => f.write_str("udp"),
+            Ipv4Protocol::Icmpv6 => f.write_str("icmpv6"),
+            Ipv4Protocol::Unknown(v) => f.write_fmt(format_args!("{}", v)),
+        }?;
+        Ok(())
+    }
+}
+
 impl core::convert::From<Ipv4Protocol> for u8 {
     fn from(value: Ipv4Protocol) -> Self {
         match value {
+            Ipv4Protocol::Icmp => 0x01,
             Ipv4Protocol::Tcp => 0x06,
             Ipv4Protocol::Udp => 0x11,
+            Ipv4Protocol::Icmpv6 => 0x3a,
             Ipv4Protocol::Unknown(v) => v,
         }
     }
 }
 
 pub enum ParsedIpv4Frame<'a> {
+    Icmp(IcmpFrame<'a>),
     Udp(UdpFrame<'a>),
     Tcp(TcpFrame<'a>),
     Unknown(Ipv4Protocol),
 }
 
-pub fn parse_ipv4<'a>(frame: &Ipv4Frame<'a>) -> Result<ParsedIpv4Frame<'a>, InvalidUdpFrame> {
+#[derive(Debug)]
+pub enum ParseIpv4Error {
+    Icmp(InvalidIcmpFrame),
+    Udp(InvalidUdpFrame),
+}
+
+pub fn parse_ipv4<'a>(frame: &Ipv4Frame<'a>) -> Result<ParsedIpv4Frame<'a>, ParseIpv4Error> {
     debug!(
         "Parsing IPV4 packet with protocol {:#04x?}",
         frame.protocol()
     );
     let ret = match frame.protocol() {
-        Ipv4Protocol::Udp => ParsedIpv4Frame::Udp(UdpFrame::new(frame.payload())?),
+        Ipv4Protocol::Icmp => {
+            ParsedIpv4Frame::Icmp(IcmpFrame::new(frame.payload()).map_err(ParseIpv4Error::Icmp)?)
+        }
+        Ipv4Protocol::Udp => {
+            ParsedIpv4Frame::Udp(UdpFrame::new(frame.payload()).map_err(ParseIpv4Error::Udp)?)
+        }
         Ipv4Protocol::Tcp => ParsedIpv4Frame::Tcp(TcpFrame::new(frame.payload())),
         p => ParsedIpv4Frame::Unknown(p),
     };
     Ok(ret)
 }
 
+pub enum ParsedIpv6Frame<'a> {
+    Icmpv6(Icmpv6Frame<'a>),
+    Udp(UdpFrame<'a>),
+    Tcp(TcpFrame<'a>),
+    Unknown(Ipv4Protocol),
+}
+
+#[derive(Debug)]
+pub enum ParseIpv6Error {
+    Icmpv6(InvalidIcmpv6Frame),
+    Udp(InvalidUdpFrame),
+}
+
+pub fn parse_ipv6<'a>(frame: &Ipv6Frame<'a>) -> Result<ParsedIpv6Frame<'a>, ParseIpv6Error> {
+    let ret = match frame.next_header() {
+        Ipv4Protocol::Icmpv6 => ParsedIpv6Frame::Icmpv6(
+            Icmpv6Frame::new(frame.payload()).map_err(ParseIpv6Error::Icmpv6)?,
+        ),
+        Ipv4Protocol::Udp => {
+            ParsedIpv6Frame::Udp(UdpFrame::new(frame.payload()).map_err(ParseIpv6Error::Udp)?)
+        }
+        Ipv4Protocol::Tcp => ParsedIpv6Frame::Tcp(TcpFrame::new(frame.payload())),
+        p => ParsedIpv6Frame::Unknown(p),
+    };
+    Ok(ret)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -662,6 +1115,33 @@ mod test {
         Ok(())
     });
 
+    create_test!(test_vlan_tagged_frame, {
+        let tag = VlanTag {
+            id: 10,
+            priority: 3,
+        };
+        let mut tagged = generate_ethernet_frame(&EthernetFrameParams {
+            dest_mac: [0xff; 6],
+            source_mac: [82, 85, 10, 0, 2, 2],
+            ether_type: EtherType::Arp,
+            vlan: Some(tag),
+            payload: &ARP_REQUEST[14..42],
+        });
+        test_eq!(&tagged[12..16], &[0x81, 0x00, 0x60, 0x0a]);
+        // Pretend the CRC is still there like on a received frame
+        tagged.extend_from_slice(&[0; 4]);
+
+        let packet = parse_packet(&tagged).map_err(|_| "Invalid tagged packet".to_string())?;
+        test_eq!(packet.ethernet.vlan_tag(), Some(tag));
+        test_eq!(packet.ethernet.ether_type(), 0x0806);
+        test_true!(matches!(packet.inner, ParsedPacket::Arp(_)));
+
+        let packet = parse_packet(ARP_REQUEST).map_err(|_| "Invalid packet".to_string())?;
+        test_true!(packet.ethernet.vlan_tag().is_none());
+
+        Ok(())
+    });
+
     create_test!(test_arp_frame_validation, {
         let frame =
             EthernetFrame::new(ARP_REQUEST).map_err(|_| "Invalid ethernet frame".to_string())?;
@@ -718,6 +1198,20 @@ mod test {
         let ipv4_frame = Ipv4Frame::new(&[0xff]);
         test_err!(ipv4_frame);
 
+        let mut corrupted = frame.payload().to_vec();
+        corrupted[8] -= 1;
+        test_eq!(
+            Ipv4Frame::new(&corrupted).err(),
+            Some(InvalidIpv4Frame::InvalidChecksum)
+        );
+
+        // Shorter than its header
+        corrupted[2..4].copy_from_slice(&10u16.to_be_bytes());
+        test_eq!(
+            Ipv4Frame::new(&corrupted).err(),
+            Some(InvalidIpv4Frame::InvalidHeader)
+        );
+
         Ok(())
     });
 
@@ -729,6 +1223,33 @@ mod test {
         test_eq!(frame.ihl(), 5);
         test_eq!(frame.protocol(), Ipv4Protocol::Udp);
         test_eq!(frame.header_length(), 20);
+        test_eq!(frame.ttl(), 64);
+        test_false!(frame.is_fragment());
+        Ok(())
+    });
+
+    create_test!(test_ipv4_fragment_fields, {
+        let generated = generate_ipv4_frame_with_params(&Ipv4FrameParams {
+            protocol: Ipv4Protocol::Udp,
+            source_ip: [10, 0, 2, 15],
+            dest_ip: [10, 0, 2, 2],
+            identification: 0x1234,
+            ttl: 3,
+            dont_fragment: false,
+            more_fragments: true,
+            fragment_offset: 1480,
+            payload: &[1; 8],
+        });
+
+        let frame = Ipv4Frame::new(&generated).map_err(|_| "Invalid ipv4 frame".to_string())?;
+        test_eq!(frame.identification(), 0x1234);
+        test_eq!(frame.ttl(), 3);
+        test_false!(frame.dont_fragment());
+        test_true!(frame.more_fragments());
+        test_eq!(frame.fragment_offset(), 1480);
+        test_true!(frame.is_fragment());
+        test_eq!(frame.payload(), &[1; 8]);
+
         Ok(())
     });
 
@@ -768,4 +1289,52 @@ mod test {
 
         Ok(())
     });
+
+    create_test!(test_udp_checksum, {
+        let frame =
+            EthernetFrame::new(UDP_REQUEST).map_err(|_| "Invalid ethernet frame".to_string())?;
+        let ipv4_frame =
+            Ipv4Frame::new(frame.payload()).map_err(|_| "Invalid ipv4 frame".to_string())?;
+        let source_ip = IpAddr::V4(ipv4_frame.source_ip());
+        let dest_ip = IpAddr::V4(ipv4_frame.dest_ip());
+
+        let frame =
+            UdpFrame::new(ipv4_frame.payload()).map_err(|_| "Invalid UDP frame".to_string())?;
+        test_eq!(frame.checksum(), 0x198a);
+        test_true!(frame.checksum_valid(&source_ip, &dest_ip));
+        test_true!(!frame.checksum_valid(&source_ip, &IpAddr::V4([192, 168, 122, 56])));
+
+        let generated = generate_udp_frame(&source_ip, &dest_ip, 0x961e, 6000, b"test\n");
+        test_eq!(generated, &ipv4_frame.payload()[..13]);
+
+        let mut corrupted = generated.clone();
+        corrupted[8] ^= 1;
+        let frame = UdpFrame::new(&corrupted).map_err(|_| "Invalid UDP frame".to_string())?;
+        test_true!(!frame.checksum_valid(&source_ip, &dest_ip));
+
+        // Checksums are optional over ipv4
+        corrupted[6..8].copy_from_slice(&[0, 0]);
+        let frame = UdpFrame::new(&corrupted).map_err(|_| "Invalid UDP frame".to_string())?;
+        test_true!(frame.checksum_valid(&source_ip, &dest_ip));
+
+        Ok(())
+    });
+
+    create_test!(test_udp_checksum_ipv6, {
+        let source_ip = IpAddr::V6([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let dest_ip = IpAddr::V6([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+
+        let generated = generate_udp_frame(&source_ip, &dest_ip, 1000, 2000, b"test\n");
+        let frame = UdpFrame::new(&generated).map_err(|_| "Invalid UDP frame".to_string())?;
+        test_true!(frame.checksum_valid(&source_ip, &dest_ip));
+        test_true!(!frame.checksum_valid(&dest_ip, &dest_ip));
+
+        // Unlike ipv4 a missing checksum is not allowed
+        let mut corrupted = generated.clone();
+        corrupted[6..8].copy_from_slice(&[0, 0]);
+        let frame = UdpFrame::new(&corrupted).map_err(|_| "Invalid UDP frame".to_string())?;
+        test_true!(!frame.checksum_valid(&source_ip, &dest_ip));
+
+        Ok(())
+    });
 }
@@ -0,0 +1,403 @@
+use crate::{
+    net::{
+        udp::{BindError, Udp, UdpSocket},
+        IpAddr,
+    },
+    sleep::WakeupRequester,
+    time::{ClockAdjustment, MonotonicTime, WallClock},
+};
+
+use alloc::vec::Vec;
+
+pub const SERVER_PORT: u16 = 123;
+
+const PACKET_LENGTH: usize = 48;
+
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+const MAX_STRATUM: u8 = 15;
+
+/// Seconds from the start of the NTP era in 1900 to the unix epoch
+const NTP_UNIX_OFFSET_S: u64 = 2_208_988_800;
+const FRACTION_SCALE: f64 = (1u64 << 32) as f64;
+
+/// 32.32 fixed point seconds since 1900
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct NtpTimestamp(pub u64);
+
+impl NtpTimestamp {
+    pub fn from_unix_s(unix_s: f64) -> NtpTimestamp {
+        let seconds = unix_s as u64;
+        let fraction = ((unix_s - seconds as f64) * FRACTION_SCALE) as u64;
+        // Wraps in 2036, see to_unix_s
+        let seconds = (seconds + NTP_UNIX_OFFSET_S) & 0xffff_ffff;
+        NtpTimestamp(seconds << 32 | fraction)
+    }
+
+    pub fn to_unix_s(self) -> f64 {
+        // RFC 4330 section 3: with the high bit clear the timestamp is past the 2036 rollover
+        let seconds = self.0 >> 32;
+        let seconds = match seconds & 0x8000_0000 {
+            0 => seconds + (1 << 32),
+            _ => seconds,
+        };
+        let fraction = (self.0 & 0xffff_ffff) as f64 / FRACTION_SCALE;
+        seconds as f64 - NTP_UNIX_OFFSET_S as f64 + fraction
+    }
+}
+
+#[derive(Debug)]
+pub enum InvalidNtpPacket {
+    TooShort(usize),
+}
+
+impl core::fmt::Display for InvalidNtpPacket {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::TooShort(len) => f.write_fmt(format_args!("packet too short: {} bytes", len)),
+        }?;
+        Ok(())
+    }
+}
+
+pub struct NtpPacket<'a> {
+    data: &'a [u8],
+}
+
+impl NtpPacket<'_> {
+    pub fn new(data: &[u8]) -> Result<NtpPacket<'_>, InvalidNtpPacket> {
+        if data.len() < PACKET_LENGTH {
+            return Err(InvalidNtpPacket::TooShort(data.len()));
+        }
+
+        Ok(NtpPacket { data })
+    }
+
+    pub fn leap(&self) -> u8 {
+        self.data[0] >> 6
+    }
+
+    pub fn mode(&self) -> u8 {
+        self.data[0] & 0x7
+    }
+
+    pub fn stratum(&self) -> u8 {
+        self.data[1]
+    }
+
+    /// Kiss code when the stratum is 0, otherwise identifies the server's time source
+    pub fn reference_id(&self) -> [u8; 4] {
+        self.data[12..16]
+            .try_into()
+            .expect("reference id size incorrect")
+    }
+
+    fn timestamp(&self, offset: usize) -> NtpTimestamp {
+        let bytes = self.data[offset..offset + 8]
+            .try_into()
+            .expect("timestamp size incorrect");
+        NtpTimestamp(u64::from_be_bytes(bytes))
+    }
+
+    /// Copied from the transmit timestamp of the request
+    pub fn originate(&self) -> NtpTimestamp {
+        self.timestamp(24)
+    }
+
+    pub fn receive(&self) -> NtpTimestamp {
+        self.timestamp(32)
+    }
+
+    pub fn transmit(&self) -> NtpTimestamp {
+        self.timestamp(40)
+    }
+}
+
+/// The transmit timestamp is all a server needs from a client
+pub fn generate_request(transmit: NtpTimestamp) -> Vec<u8> {
+    let mut ret = alloc::vec![0; PACKET_LENGTH];
+    ret[0] = VERSION << 3 | MODE_CLIENT;
+    ret[40..48].copy_from_slice(&transmit.0.to_be_bytes());
+    ret
+}
+
+#[derive(Debug)]
+pub enum SntpError {
+    Bind(BindError),
+    Timeout,
+    /// The server has no usable time itself
+    Unsynchronized,
+    /// The server asks us to go away, e.g. with RATE or DENY
+    KissOfDeath([u8; 4]),
+}
+
+impl core::fmt::Display for SntpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Bind(e) => f.write_fmt(format_args!("failed to bind socket: {:?}", e)),
+            Self::Timeout => f.write_str("timed out"),
+            Self::Unsynchronized => f.write_str("server is unsynchronized"),
+            // Kiss codes are four ascii characters
+            Self::KissOfDeath(code) => f.write_fmt(format_args!(
+                "kiss of death: {}",
+                core::str::from_utf8(code).unwrap_or("?")
+            )),
+        }?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// How far the wall clock is behind the server
+    pub offset_s: f64,
+    /// Round trip time, without the time spent in the server
+    pub delay_s: f64,
+    pub stratum: u8,
+}
+
+impl Measurement {
+    /// From the client send (t1), server receive (t2), server send (t3) and client receive (t4)
+    /// times as in RFC 4330 section 5
+    pub fn new(t1: f64, t2: f64, t3: f64, t4: f64, stratum: u8) -> Measurement {
+        Measurement {
+            offset_s: ((t2 - t1) + (t3 - t4)) / 2.0,
+            delay_s: (t4 - t1) - (t3 - t2),
+            stratum,
+        }
+    }
+}
+
+/// SNTPv4 client (RFC 4330) that measures and corrects the wall clock against a single server
+pub struct Sntp<'a> {
+    udp: &'a Udp,
+    clock: &'a WallClock,
+    time: &'a MonotonicTime,
+    wakeup_list: &'a WakeupRequester,
+    pub timeout_s: f32,
+    pub attempts: usize,
+}
+
+impl<'a> Sntp<'a> {
+    pub fn new(
+        udp: &'a Udp,
+        clock: &'a WallClock,
+        time: &'a MonotonicTime,
+        wakeup_list: &'a WakeupRequester,
+    ) -> Sntp<'a> {
+        Sntp {
+            udp,
+            clock,
+            time,
+            wakeup_list,
+            timeout_s: 2.0,
+            attempts: 3,
+        }
+    }
+
+    /// Measures the clock offset without changing anything
+    pub async fn query(&self, server: impl Into<IpAddr>) -> Result<Measurement, SntpError> {
+        let server = server.into();
+        let socket = self.udp.bind(0).await.map_err(SntpError::Bind)?;
+
+        for _ in 0..self.attempts {
+            let t1 = self.clock.now();
+            // The server echoes this back, so it doubles as a check that the response is ours
+            let origin = NtpTimestamp::from_unix_s(t1);
+            socket
+                .send_to(&generate_request(origin), server, SERVER_PORT)
+                .await;
+
+            if let Some(response) = self.wait_for_response(&socket, server, origin).await {
+                let t4 = self.clock.now();
+                let packet =
+                    NtpPacket::new(&response).expect("Response validated in wait_for_response");
+
+                if packet.stratum() == 0 {
+                    return Err(SntpError::KissOfDeath(packet.reference_id()));
+                }
+                if packet.leap() == LEAP_UNSYNCHRONIZED
+                    || packet.stratum() > MAX_STRATUM
+                    || packet.transmit().0 == 0
+                {
+                    return Err(SntpError::Unsynchronized);
+                }
+
+                return Ok(Measurement::new(
+                    t1,
+                    packet.receive().to_unix_s(),
+                    packet.transmit().to_unix_s(),
+                    t4,
+                    packet.stratum(),
+                ));
+            }
+
+            debug!("SNTP query to {:?} timed out", server);
+        }
+
+        Err(SntpError::Timeout)
+    }
+
+    /// Measures the offset and steps or slews the wall clock to match the server
+    pub async fn sync(
+        &self,
+        server: impl Into<IpAddr>,
+    ) -> Result<(Measurement, ClockAdjustment), SntpError> {
+        let measurement = self.query(server).await?;
+        let adjustment = self.clock.adjust(measurement.offset_s);
+        Ok((measurement, adjustment))
+    }
+
+    async fn wait_for_response(
+        &self,
+        socket: &UdpSocket,
+        server: IpAddr,
+        origin: NtpTimestamp,
+    ) -> Option<Vec<u8>> {
+        let response = async {
+            loop {
+                let (data, remote_ip, remote_port) = socket.recv_from().await;
+                if remote_ip != server || remote_port != SERVER_PORT {
+                    debug!(
+                        "Ignoring SNTP response from unexpected source {:?}",
+                        remote_ip
+                    );
+                    continue;
+                }
+
+                match NtpPacket::new(&data) {
+                    Ok(packet) if packet.mode() == MODE_SERVER && packet.originate() == origin => {
+                        return data
+                    }
+                    Ok(_) => debug!("Ignoring unexpected SNTP packet"),
+                    Err(e) => debug!("Invalid SNTP packet: {}", e),
+                }
+            }
+        };
+        crate::sleep::timeout(self.timeout_s, self.time, self.wakeup_list, response).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::future::poll_immediate;
+    use crate::net::{self, UdpFrame};
+    use crate::testing::*;
+    use crate::Ipv4Addr;
+    use alloc::{
+        string::{String, ToString},
+        sync::Arc,
+    };
+
+    const LOCAL_IP: Ipv4Addr = [192, 168, 2, 2];
+    const SERVER_IP: Ipv4Addr = [192, 168, 2, 1];
+
+    fn response(origin: NtpTimestamp, receive_s: f64, transmit_s: f64, stratum: u8) -> Vec<u8> {
+        let mut ret = alloc::vec![0; PACKET_LENGTH];
+        ret[0] = VERSION << 3 | MODE_SERVER;
+        ret[1] = stratum;
+        ret[12..16].copy_from_slice(b"RATE");
+        ret[24..32].copy_from_slice(&origin.0.to_be_bytes());
+        ret[32..40].copy_from_slice(&NtpTimestamp::from_unix_s(receive_s).0.to_be_bytes());
+        ret[40..48].copy_from_slice(&NtpTimestamp::from_unix_s(transmit_s).0.to_be_bytes());
+        ret
+    }
+
+    async fn reply(udp: &Udp, local_port: u16, response: &[u8]) -> Result<(), String> {
+        let (server_ip, local_ip) = (IpAddr::V4(SERVER_IP), IpAddr::V4(LOCAL_IP));
+        let frame =
+            net::generate_udp_frame(&server_ip, &local_ip, SERVER_PORT, local_port, response);
+        let frame = UdpFrame::new(&frame).map_err(|_| "Invalid udp frame".to_string())?;
+        udp.handle_frame(&frame, &server_ip, &local_ip)
+            .await
+            .map_err(|_| "Failed to deliver sntp response".to_string())
+    }
+
+    create_test!(test_ntp_timestamps, {
+        test_eq!(NtpTimestamp::from_unix_s(0.0).0, NTP_UNIX_OFFSET_S << 32);
+        test_eq!(
+            NtpTimestamp::from_unix_s(1.5).0,
+            (NTP_UNIX_OFFSET_S + 1) << 32 | 0x8000_0000
+        );
+        test_eq!(
+            NtpTimestamp::from_unix_s(1709210096.25).to_unix_s(),
+            1709210096.25
+        );
+        // Past the 2036 rollover
+        test_eq!(NtpTimestamp::from_unix_s(2085978496.0).0, 0);
+        test_eq!(NtpTimestamp(0).to_unix_s(), 2085978496.0);
+
+        let request = generate_request(NtpTimestamp(0x0102030405060708));
+        test_eq!(request.len(), PACKET_LENGTH);
+        test_eq!(request[0], 0x23);
+        test_eq!(
+            request[40..48].to_vec(),
+            alloc::vec![1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        // Server 10s ahead, 250ms on the network and 125ms spent in the server
+        let measurement = Measurement::new(100.0, 110.125, 110.25, 100.375, 2);
+        test_eq!(measurement.offset_s, 10.0);
+        test_eq!(measurement.delay_s, 0.25);
+
+        Ok(())
+    });
+
+    create_test!(test_sntp_sync, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let udp = Udp::new();
+        let clock = WallClock::new(Arc::clone(&time), 1000.0);
+        let sntp = Sntp::new(&udp, &clock, &time, &wakeup_list);
+
+        let mut sync = core::pin::pin!(sntp.sync(SERVER_IP));
+        test_true!(poll_immediate(sync.as_mut()).await.is_none());
+        let request = poll_immediate(udp.service())
+            .await
+            .ok_or("No sntp request".to_string())?;
+        test_eq!(request.remote_port, SERVER_PORT);
+        let packet = NtpPacket::new(&request.data).map_err(|_| "Invalid request".to_string())?;
+        let origin = packet.transmit();
+        test_eq!(origin.to_unix_s(), 1000.0);
+
+        // Responses that don't echo our timestamp are not for us
+        let stale = response(NtpTimestamp(origin.0 - 1), 0.0, 0.0, 1);
+        reply(&udp, request.local_port, &stale).await?;
+        test_true!(poll_immediate(sync.as_mut()).await.is_none());
+
+        // The server is an hour ahead, the round trip takes no time
+        reply(
+            &udp,
+            request.local_port,
+            &response(origin, 4600.0, 4600.0, 1),
+        )
+        .await?;
+        let (measurement, adjustment) = poll_immediate(sync.as_mut())
+            .await
+            .ok_or("Sync did not complete".to_string())?
+            .map_err(|e| alloc::format!("Sync failed: {}", e))?;
+        test_eq!(measurement.offset_s, 3600.0);
+        test_eq!(measurement.delay_s, 0.0);
+        test_eq!(adjustment, ClockAdjustment::Stepped);
+        test_eq!(clock.now(), 4600.0);
+
+        let mut query = core::pin::pin!(sntp.query(SERVER_IP));
+        test_true!(poll_immediate(query.as_mut()).await.is_none());
+        let request = poll_immediate(udp.service())
+            .await
+            .ok_or("No sntp request".to_string())?;
+        let origin = NtpPacket::new(&request.data)
+            .map_err(|_| "Invalid request".to_string())?
+            .transmit();
+        reply(&udp, request.local_port, &response(origin, 0.0, 0.0, 0)).await?;
+        let result = poll_immediate(query.as_mut())
+            .await
+            .ok_or("Query did not complete".to_string())?;
+        test_true!(matches!(result, Err(SntpError::KissOfDeath(code)) if &code == b"RATE"));
+
+        Ok(())
+    });
+}
@@ -0,0 +1,383 @@
+use crate::net::{
+    ipv4::Ipv4Stats,
+    tcp::{TcpConnectionInfo, TcpStats},
+    IpAddr, Ipv4Protocol,
+};
+
+use core::{
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub fn increment(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+fn load(counter: &AtomicUsize) -> usize {
+    counter.load(Ordering::Relaxed)
+}
+
+/// Packet and byte counts in both directions, kept per interface and per protocol
+#[derive(Debug, Default)]
+pub struct TrafficStats {
+    pub rx_packets: AtomicUsize,
+    pub rx_bytes: AtomicUsize,
+    pub tx_packets: AtomicUsize,
+    pub tx_bytes: AtomicUsize,
+}
+
+impl TrafficStats {
+    pub fn record_rx(&self, len: usize) {
+        increment(&self.rx_packets);
+        self.rx_bytes.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn record_tx(&self, len: usize) {
+        increment(&self.tx_packets);
+        self.tx_bytes.fetch_add(len, Ordering::Relaxed);
+    }
+}
+
+impl Display for TrafficStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rx {} packets {} bytes, tx {} packets {} bytes",
+            load(&self.rx_packets),
+            load(&self.rx_bytes),
+            load(&self.tx_packets),
+            load(&self.tx_bytes)
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DropReason {
+    /// Frames that failed to parse
+    Malformed,
+    BadChecksum,
+    UnknownEtherType,
+    UnknownProtocol,
+    UnknownVlan,
+    /// Packets a firewall rule dropped
+    Filtered,
+    /// UDP datagrams to a port nobody is bound to
+    NoListener,
+    /// Outgoing packets whose next hop did not answer ARP or neighbor solicitations
+    ArpMiss,
+}
+
+#[derive(Debug, Default)]
+pub struct DropStats {
+    pub malformed: AtomicUsize,
+    pub bad_checksum: AtomicUsize,
+    pub unknown_ether_type: AtomicUsize,
+    pub unknown_protocol: AtomicUsize,
+    pub unknown_vlan: AtomicUsize,
+    pub filtered: AtomicUsize,
+    pub no_listener: AtomicUsize,
+    pub arp_miss: AtomicUsize,
+}
+
+impl DropStats {
+    pub fn record(&self, reason: DropReason) {
+        increment(match reason {
+            DropReason::Malformed => &self.malformed,
+            DropReason::BadChecksum => &self.bad_checksum,
+            DropReason::UnknownEtherType => &self.unknown_ether_type,
+            DropReason::UnknownProtocol => &self.unknown_protocol,
+            DropReason::UnknownVlan => &self.unknown_vlan,
+            DropReason::Filtered => &self.filtered,
+            DropReason::NoListener => &self.no_listener,
+            DropReason::ArpMiss => &self.arp_miss,
+        });
+    }
+}
+
+/// Counters shared by every interface, interfaces count their own traffic
+#[derive(Debug, Default)]
+pub struct NetStats {
+    pub arp: TrafficStats,
+    pub ipv4: TrafficStats,
+    pub ipv6: TrafficStats,
+    pub icmp: TrafficStats,
+    pub icmpv6: TrafficStats,
+    pub udp: TrafficStats,
+    pub tcp: TrafficStats,
+    pub drops: DropStats,
+}
+
+impl NetStats {
+    /// Counters for the transport protocol an ip packet carries
+    pub fn protocol(&self, protocol: Ipv4Protocol) -> Option<&TrafficStats> {
+        match protocol {
+            Ipv4Protocol::Icmp => Some(&self.icmp),
+            Ipv4Protocol::Icmpv6 => Some(&self.icmpv6),
+            Ipv4Protocol::Udp => Some(&self.udp),
+            Ipv4Protocol::Tcp => Some(&self.tcp),
+            Ipv4Protocol::Unknown(_) => None,
+        }
+    }
+
+    pub fn record_protocol_rx(&self, protocol: Ipv4Protocol, len: usize) {
+        if let Some(stats) = self.protocol(protocol) {
+            stats.record_rx(len);
+        }
+    }
+
+    pub fn record_protocol_tx(&self, protocol: Ipv4Protocol, len: usize) {
+        if let Some(stats) = self.protocol(protocol) {
+            stats.record_tx(len);
+        }
+    }
+}
+
+/// `netstat -s` style summary of every counter
+pub struct Netstat<'a> {
+    pub interfaces: &'a [(&'a str, &'a TrafficStats)],
+    pub net: &'a NetStats,
+    pub ipv4: &'a Ipv4Stats,
+    pub tcp: &'a TcpStats,
+    pub tcp_connections: usize,
+}
+
+impl Display for Netstat<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Interfaces:")?;
+        for (name, stats) in self.interfaces {
+            writeln!(f, "    {}: {}", name, stats)?;
+        }
+
+        let net = self.net;
+        for (name, stats) in [
+            ("Arp", &net.arp),
+            ("Ip", &net.ipv4),
+            ("Ip6", &net.ipv6),
+            ("Icmp", &net.icmp),
+            ("Icmp6", &net.icmpv6),
+            ("Udp", &net.udp),
+            ("Tcp", &net.tcp),
+        ] {
+            writeln!(f, "{}:", name)?;
+            writeln!(f, "    {}", stats)?;
+        }
+
+        let ipv4 = self.ipv4;
+        writeln!(f, "Ip reassembly:")?;
+        writeln!(f, "    {} incoming packets", load(&ipv4.in_receives))?;
+        writeln!(
+            f,
+            "    {} with invalid headers",
+            load(&ipv4.in_header_errors)
+        )?;
+        writeln!(
+            f,
+            "    {} fragments received",
+            load(&ipv4.reassembly_required)
+        )?;
+        writeln!(f, "    {} reassembled ok", load(&ipv4.reassembly_ok))?;
+        writeln!(
+            f,
+            "    {} reassemblies failed",
+            load(&ipv4.reassembly_failures)
+        )?;
+        writeln!(f, "    {} fragments created", load(&ipv4.fragments_created))?;
+
+        let tcp = self.tcp;
+        writeln!(f, "Tcp connections:")?;
+        writeln!(
+            f,
+            "    {} active connection openings",
+            load(&tcp.active_opens)
+        )?;
+        writeln!(
+            f,
+            "    {} passive connection openings",
+            load(&tcp.passive_opens)
+        )?;
+        writeln!(
+            f,
+            "    {} failed connection attempts",
+            load(&tcp.attempt_fails)
+        )?;
+        writeln!(
+            f,
+            "    {} connection resets received",
+            load(&tcp.estab_resets)
+        )?;
+        writeln!(f, "    {} connections established", self.tcp_connections)?;
+        writeln!(f, "    {} segments retransmitted", load(&tcp.retrans_segs))?;
+
+        let drops = &net.drops;
+        writeln!(f, "Drops:")?;
+        writeln!(f, "    {} malformed", load(&drops.malformed))?;
+        writeln!(f, "    {} bad checksum", load(&drops.bad_checksum))?;
+        writeln!(
+            f,
+            "    {} unknown ether type",
+            load(&drops.unknown_ether_type)
+        )?;
+        writeln!(f, "    {} unknown protocol", load(&drops.unknown_protocol))?;
+        writeln!(f, "    {} unknown vlan", load(&drops.unknown_vlan))?;
+        writeln!(f, "    {} filtered", load(&drops.filtered))?;
+        writeln!(
+            f,
+            "    {} no listener ({} udp, {} tcp)",
+            load(&drops.no_listener) + load(&tcp.no_listener),
+            load(&drops.no_listener),
+            load(&tcp.no_listener)
+        )?;
+        write!(f, "    {} arp miss", load(&drops.arp_miss))
+    }
+}
+
+/// `ss -ti` style listing of tcp connections
+pub struct SocketTable<'a>(pub &'a [TcpConnectionInfo]);
+
+impl Display for SocketTable<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "State       Local Address:Port    Peer Address:Port")?;
+        for connection in self.0 {
+            write!(
+                f,
+                "\n{:<11} {}:{} {}:{} cwnd:{} ssthresh:{} rto:{:.3}",
+                connection.state,
+                FmtIp(&connection.local_ip),
+                connection.local_port,
+                FmtIp(&connection.remote_ip),
+                connection.remote_port,
+                connection.cwnd,
+                connection.ssthresh,
+                connection.rto_s
+            )?;
+            if let Some(srtt_s) = connection.srtt_s {
+                write!(f, " rtt:{:.3}", srtt_s)?;
+            }
+            write!(f, " unacked:{}", connection.bytes_in_flight)?;
+        }
+        Ok(())
+    }
+}
+
+/// Dotted decimal for ipv4 and uncompressed groups for ipv6, in brackets like ss
+pub struct FmtIp<'a>(pub &'a IpAddr);
+
+impl Display for FmtIp<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            IpAddr::V4(ip) => write!(f, "{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]),
+            IpAddr::V6(ip) => {
+                write!(f, "[")?;
+                for (i, group) in ip.chunks_exact(2).enumerate() {
+                    if i != 0 {
+                        write!(f, ":")?;
+                    }
+                    write!(f, "{:x}", u16::from_be_bytes([group[0], group[1]]))?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::{format, string::ToString, vec};
+
+    create_test!(test_net_stats, {
+        let stats = NetStats::default();
+        stats.udp.record_rx(100);
+        stats.udp.record_rx(50);
+        stats.udp.record_tx(10);
+        test_eq!(
+            stats.udp.to_string(),
+            "rx 2 packets 150 bytes, tx 1 packets 10 bytes"
+        );
+
+        test_true!(core::ptr::eq(
+            stats.protocol(Ipv4Protocol::Udp).unwrap(),
+            &stats.udp
+        ));
+        test_true!(stats.protocol(Ipv4Protocol::Unknown(0x84)).is_none());
+
+        stats.drops.record(DropReason::ArpMiss);
+        stats.drops.record(DropReason::ArpMiss);
+        stats.drops.record(DropReason::BadChecksum);
+        test_eq!(load(&stats.drops.arp_miss), 2);
+        test_eq!(load(&stats.drops.bad_checksum), 1);
+
+        let eth0 = TrafficStats::default();
+        eth0.record_tx(60);
+        let tcp = TcpStats::default();
+        increment(&tcp.no_listener);
+        let netstat = Netstat {
+            interfaces: &[("eth0", &eth0)],
+            net: &stats,
+            ipv4: &Ipv4Stats::default(),
+            tcp: &tcp,
+            tcp_connections: 0,
+        }
+        .to_string();
+        test_true!(netstat.contains("    eth0: rx 0 packets 0 bytes, tx 1 packets 60 bytes\n"));
+        test_true!(netstat.contains("Udp:\n    rx 2 packets"));
+        test_true!(netstat.contains("    1 no listener (0 udp, 1 tcp)\n"));
+        test_true!(netstat.ends_with("    2 arp miss"));
+
+        Ok(())
+    });
+
+    create_test!(test_socket_table, {
+        let mut local_ip = [0; 16];
+        local_ip[0] = 0xfe;
+        local_ip[1] = 0x80;
+        local_ip[15] = 1;
+        let connections = vec![
+            TcpConnectionInfo {
+                state: "ESTAB",
+                local_ip: IpAddr::V4([192, 168, 2, 2]),
+                local_port: 80,
+                remote_ip: IpAddr::V4([192, 168, 2, 1]),
+                remote_port: 40000,
+                cwnd: 14600,
+                ssthresh: 65535,
+                srtt_s: Some(0.25),
+                rto_s: 1.0,
+                bytes_in_flight: 0,
+            },
+            TcpConnectionInfo {
+                state: "TIME-WAIT",
+                local_ip: IpAddr::V6(local_ip),
+                local_port: 80,
+                remote_ip: IpAddr::V6(local_ip),
+                remote_port: 40001,
+                cwnd: 14600,
+                ssthresh: 65535,
+                srtt_s: None,
+                rto_s: 1.0,
+                bytes_in_flight: 10,
+            },
+        ];
+
+        let table = format!("{}", SocketTable(&connections));
+        let mut lines = table.lines().skip(1);
+        test_eq!(
+            lines.next(),
+            Some(
+                "ESTAB       192.168.2.2:80 192.168.2.1:40000 cwnd:14600 ssthresh:65535 \
+                 rto:1.000 rtt:0.250 unacked:0"
+            )
+        );
+        test_eq!(
+            lines.next(),
+            Some(
+                "TIME-WAIT   [fe80:0:0:0:0:0:0:1]:80 [fe80:0:0:0:0:0:0:1]:40001 cwnd:14600 \
+                 ssthresh:65535 rto:1.000 unacked:10"
+            )
+        );
+        test_true!(lines.next().is_none());
+
+        Ok(())
+    });
+}
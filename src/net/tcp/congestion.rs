@@ -0,0 +1,131 @@
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LossKind {
+    /// Fast retransmit after duplicate acks
+    DupAcks,
+    RetransmitTimeout,
+}
+
+/// Congestion window management, all sizes are in bytes. Loss detection and the fast recovery
+/// state machine live in the connection, implementations only decide how the window reacts
+pub trait CongestionControl: Send + Sync {
+    fn cwnd(&self) -> usize;
+    fn ssthresh(&self) -> usize;
+    /// New data was acknowledged outside of fast recovery
+    fn on_ack(&mut self, acked: usize);
+    fn on_loss(&mut self, kind: LossKind, flight_size: usize);
+    /// Every further duplicate ack during fast recovery means a segment left the network
+    fn on_recovery_dup_ack(&mut self);
+    /// Ack for some but not all of the data outstanding when recovery started
+    fn on_partial_ack(&mut self, acked: usize);
+    fn on_recovery_exit(&mut self, flight_size: usize);
+}
+
+/// RFC 5681 slow start and congestion avoidance with RFC 6582 fast recovery
+pub struct NewReno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    // Appropriate byte counting (RFC 3465) for congestion avoidance
+    bytes_acked: usize,
+}
+
+impl NewReno {
+    pub fn new(mss: usize) -> NewReno {
+        NewReno {
+            mss,
+            // RFC 3390
+            cwnd: (4 * mss).min((2 * mss).max(4380)),
+            ssthresh: usize::MAX,
+            bytes_acked: 0,
+        }
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    fn on_ack(&mut self, acked: usize) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += acked.min(self.mss);
+            return;
+        }
+
+        self.bytes_acked += acked;
+        if self.bytes_acked >= self.cwnd {
+            self.bytes_acked -= self.cwnd;
+            self.cwnd += self.mss;
+        }
+    }
+
+    fn on_loss(&mut self, kind: LossKind, flight_size: usize) {
+        self.ssthresh = (flight_size / 2).max(2 * self.mss);
+        self.bytes_acked = 0;
+        self.cwnd = match kind {
+            // The three duplicate acks are segments that have left the network
+            LossKind::DupAcks => self.ssthresh + 3 * self.mss,
+            LossKind::RetransmitTimeout => self.mss,
+        };
+    }
+
+    fn on_recovery_dup_ack(&mut self) {
+        self.cwnd += self.mss;
+    }
+
+    fn on_partial_ack(&mut self, acked: usize) {
+        self.cwnd = self.cwnd.saturating_sub(acked);
+        if acked >= self.mss {
+            self.cwnd += self.mss;
+        }
+    }
+
+    fn on_recovery_exit(&mut self, flight_size: usize) {
+        self.cwnd = self.ssthresh.min(flight_size.max(self.mss) + self.mss);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    const MSS: usize = 536;
+
+    create_test!(test_new_reno, {
+        let mut cc = NewReno::new(MSS);
+        test_eq!(cc.cwnd(), 4 * MSS);
+        test_eq!(cc.ssthresh(), usize::MAX);
+
+        // Slow start grows by at most one segment per ack
+        cc.on_ack(4 * MSS);
+        test_eq!(cc.cwnd(), 5 * MSS);
+
+        cc.on_loss(LossKind::DupAcks, 4 * MSS);
+        test_eq!(cc.ssthresh(), 2 * MSS);
+        test_eq!(cc.cwnd(), 5 * MSS);
+
+        cc.on_recovery_dup_ack();
+        test_eq!(cc.cwnd(), 6 * MSS);
+        cc.on_partial_ack(MSS);
+        test_eq!(cc.cwnd(), 6 * MSS);
+        cc.on_recovery_exit(3 * MSS);
+        test_eq!(cc.cwnd(), 2 * MSS);
+
+        // Congestion avoidance, one segment per window
+        cc.on_ack(MSS);
+        test_eq!(cc.cwnd(), 2 * MSS);
+        cc.on_ack(MSS);
+        test_eq!(cc.cwnd(), 3 * MSS);
+
+        cc.on_loss(LossKind::RetransmitTimeout, 3 * MSS);
+        test_eq!(cc.ssthresh(), 2 * MSS);
+        test_eq!(cc.cwnd(), MSS);
+
+        Ok(())
+    });
+}
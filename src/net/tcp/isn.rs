@@ -0,0 +1,160 @@
+use super::TcpKey;
+use crate::{time::MonotonicTime, util::siphash::siphash24};
+
+/// RFC 6528 initial sequence numbers and RFC 4987 SYN cookies, both keyed with a boot secret
+pub struct IsnGenerator {
+    secret: [u64; 2],
+}
+
+impl IsnGenerator {
+    // RFC 6528 clock, one tick every 4 microseconds
+    const CLOCK_HZ: f64 = 250_000.0;
+    // Cookies are accepted for the slot they were generated in and the one after it
+    const COOKIE_SLOT_S: f64 = 64.0;
+    // 3 bits worth of MSS values a cookie can carry, rounded down to the nearest one
+    const COOKIE_MSS: [u16; 8] = [216, 536, 1024, 1200, 1300, 1400, 1440, 1460];
+
+    pub fn new(secret: [u64; 2]) -> IsnGenerator {
+        IsnGenerator { secret }
+    }
+
+    pub fn isn(&self, tcp_key: &TcpKey, time: &MonotonicTime) -> u32 {
+        let clock = (time.get() as f64 / time.tick_freq() as f64 * Self::CLOCK_HZ) as u64 as u32;
+        clock.wrapping_add(self.hash(tcp_key, [0; 3]) as u32)
+    }
+
+    /// Sequence number for a SYN-ACK that keeps no state. The top 5 bits are a time slot, the
+    /// next 3 the MSS and the rest a hash binding both to the connection and the peer's ISN
+    pub fn syn_cookie(
+        &self,
+        tcp_key: &TcpKey,
+        peer_isn: u32,
+        mss: u16,
+        time: &MonotonicTime,
+    ) -> u32 {
+        let slot = Self::cookie_slot(time);
+        let mss_idx = Self::COOKIE_MSS
+            .iter()
+            .rposition(|cookie_mss| *cookie_mss <= mss)
+            .unwrap_or(0) as u32;
+
+        (slot % 32) << 27 | mss_idx << 24 | self.cookie_hash(tcp_key, peer_isn, slot, mss_idx)
+    }
+
+    /// Returns the MSS of a valid cookie
+    pub fn check_syn_cookie(
+        &self,
+        tcp_key: &TcpKey,
+        peer_isn: u32,
+        cookie: u32,
+        time: &MonotonicTime,
+    ) -> Option<u16> {
+        let now = Self::cookie_slot(time);
+        let slot = [now, now.wrapping_sub(1)]
+            .into_iter()
+            .find(|slot| slot % 32 == cookie >> 27)?;
+        let mss_idx = (cookie >> 24) & 0x7;
+
+        let valid = self.cookie_hash(tcp_key, peer_isn, slot, mss_idx) == cookie & 0xffffff;
+        valid.then_some(Self::COOKIE_MSS[mss_idx as usize])
+    }
+
+    fn cookie_slot(time: &MonotonicTime) -> u32 {
+        (time.get() as f64 / time.tick_freq() as f64 / Self::COOKIE_SLOT_S) as u32
+    }
+
+    fn cookie_hash(&self, tcp_key: &TcpKey, peer_isn: u32, slot: u32, mss_idx: u32) -> u32 {
+        // Offset so a cookie never matches the ISN hash
+        self.hash(tcp_key, [peer_isn, slot.wrapping_add(1), mss_idx]) as u32 & 0xffffff
+    }
+
+    fn hash(&self, tcp_key: &TcpKey, extra: [u32; 3]) -> u64 {
+        // Large enough for two ipv6 addresses
+        let mut data = [0u8; 48];
+        let mut len = 0;
+        let fields: [&[u8]; 4] = [
+            tcp_key.local_ip.octets(),
+            tcp_key.remote_ip.octets(),
+            &tcp_key.local_port.to_be_bytes(),
+            &tcp_key.remote_port.to_be_bytes(),
+        ];
+        for field in fields {
+            data[len..len + field.len()].copy_from_slice(field);
+            len += field.len();
+        }
+        for val in extra {
+            data[len..len + 4].copy_from_slice(&val.to_be_bytes());
+            len += 4;
+        }
+        siphash24(self.secret, &data[..len])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{net::IpAddr, testing::*};
+
+    const KEY: TcpKey = TcpKey {
+        remote_ip: IpAddr::V4([192, 168, 2, 1]),
+        local_ip: IpAddr::V4([192, 168, 2, 2]),
+        remote_port: 40000,
+        local_port: 80,
+    };
+
+    create_test!(test_isn, {
+        let time = MonotonicTime::new(10.0);
+        let generator = IsnGenerator::new([1, 2]);
+
+        let isn = generator.isn(&KEY, &time);
+        let other_port = TcpKey {
+            remote_port: 40001,
+            ..KEY
+        };
+        test_ne!(generator.isn(&other_port, &time), isn);
+        test_ne!(IsnGenerator::new([3, 4]).isn(&KEY, &time), isn);
+
+        // Same connection later on moves forward with the clock
+        time.set_tick(10);
+        test_eq!(generator.isn(&KEY, &time), isn.wrapping_add(250_000));
+
+        Ok(())
+    });
+
+    create_test!(test_syn_cookie, {
+        let time = MonotonicTime::new(10.0);
+        let generator = IsnGenerator::new([1, 2]);
+
+        let cookie = generator.syn_cookie(&KEY, 1000, 1460, &time);
+        test_eq!(
+            generator.check_syn_cookie(&KEY, 1000, cookie, &time),
+            Some(1460)
+        );
+        test_true!(generator
+            .check_syn_cookie(&KEY, 1001, cookie, &time)
+            .is_none());
+        test_true!(generator
+            .check_syn_cookie(&KEY, 1000, cookie ^ 1, &time)
+            .is_none());
+
+        // MSS is rounded down to what fits in the cookie
+        let cookie = generator.syn_cookie(&KEY, 1000, 1380, &time);
+        test_eq!(
+            generator.check_syn_cookie(&KEY, 1000, cookie, &time),
+            Some(1300)
+        );
+
+        // Still valid in the next slot, expired after that
+        time.set_tick((IsnGenerator::COOKIE_SLOT_S * 10.0) as usize);
+        test_eq!(
+            generator.check_syn_cookie(&KEY, 1000, cookie, &time),
+            Some(1300)
+        );
+        time.set_tick((IsnGenerator::COOKIE_SLOT_S * 20.0) as usize);
+        test_true!(generator
+            .check_syn_cookie(&KEY, 1000, cookie, &time)
+            .is_none());
+
+        Ok(())
+    });
+}
@@ -0,0 +1,4600 @@
+pub mod congestion;
+mod isn;
+pub mod options;
+
+use crate::{
+    future::{Either, FutureSet},
+    net::{
+        self, icmp::UnreachableCode, ipv6::Ipv6Config, stats::increment, IpAddr, Ipv4Config,
+        Ipv4Protocol,
+    },
+    sleep::WakeupRequester,
+    time::MonotonicTime,
+    util::{
+        async_channel::{self, Receiver, Sender},
+        async_io::{AsyncRead, AsyncWrite, BoxFuture, IoError},
+        async_mutex::Mutex,
+        atomic_cell::AtomicCell,
+        bit_manipulation::{GetBits, SetBits},
+        oneshot,
+        updated_val::UpdatedVal,
+    },
+};
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+
+use congestion::{CongestionControl, LossKind, NewReno};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use hashbrown::HashMap;
+use isn::IsnGenerator;
+use options::{SackBlock, TcpOptions, Timestamps};
+
+pub struct TcpFlags(pub u8);
+
+#[allow(unused)]
+impl TcpFlags {
+    pub fn cwr(&self) -> bool {
+        self.0.get_bit(7)
+    }
+
+    pub fn ece(&self) -> bool {
+        self.0.get_bit(6)
+    }
+
+    pub fn urg(&self) -> bool {
+        self.0.get_bit(5)
+    }
+
+    pub fn ack(&self) -> bool {
+        self.0.get_bit(4)
+    }
+
+    pub fn psh(&self) -> bool {
+        self.0.get_bit(3)
+    }
+
+    pub fn rst(&self) -> bool {
+        self.0.get_bit(2)
+    }
+
+    pub fn syn(&self) -> bool {
+        self.0.get_bit(1)
+    }
+
+    pub fn fin(&self) -> bool {
+        self.0.get_bit(0)
+    }
+}
+
+impl core::fmt::Debug for TcpFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "cwr: {}", self.cwr())?;
+        write!(f, "ece: {}", self.ece())?;
+        write!(f, "urg: {}", self.urg())?;
+        write!(f, "ack: {}", self.ack())?;
+        write!(f, "psh: {}", self.psh())?;
+        write!(f, "rst: {}", self.rst())?;
+        write!(f, "syn: {}", self.syn())?;
+        write!(f, "fin: {}", self.fin())?;
+        Ok(())
+    }
+}
+
+pub struct TcpFlagsParams {
+    pub cwr: bool,
+    pub ece: bool,
+    pub urg: bool,
+    pub ack: bool,
+    pub psh: bool,
+    pub rst: bool,
+    pub syn: bool,
+    pub fin: bool,
+}
+
+pub fn generate_tcp_flags(params: &TcpFlagsParams) -> TcpFlags {
+    let mut ret = 0u8;
+
+    ret.set_bit(7, params.cwr);
+    ret.set_bit(6, params.ece);
+    ret.set_bit(5, params.urg);
+    ret.set_bit(4, params.ack);
+    ret.set_bit(3, params.psh);
+    ret.set_bit(2, params.rst);
+    ret.set_bit(1, params.syn);
+    ret.set_bit(0, params.fin);
+
+    TcpFlags(ret)
+}
+
+pub struct TcpFrame<'a> {
+    data: &'a [u8],
+}
+
+impl TcpFrame<'_> {
+    pub(super) fn new(data: &[u8]) -> TcpFrame<'_> {
+        TcpFrame { data }
+    }
+
+    pub fn source_port(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[0..2]
+                .try_into()
+                .expect("tcp source port length wrong"),
+        )
+    }
+
+    pub fn dest_port(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[2..4]
+                .try_into()
+                .expect("tcp dest port length wrong"),
+        )
+    }
+
+    pub fn seq_num(&self) -> u32 {
+        u32::from_be_bytes(
+            self.data[4..8]
+                .try_into()
+                .expect("tcp seq num length wrong"),
+        )
+    }
+
+    pub fn ack_num(&self) -> u32 {
+        u32::from_be_bytes(
+            self.data[8..12]
+                .try_into()
+                .expect("tcp ack num length wrong"),
+        )
+    }
+
+    pub fn data_offset_bytes(&self) -> usize {
+        let data_offset_words = self.data[12].get_bits(4, 4);
+        assert!(data_offset_words != 0);
+        (data_offset_words as usize) * 4
+    }
+
+    pub fn flags(&self) -> TcpFlags {
+        TcpFlags(self.data[13])
+    }
+
+    pub fn window_size(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[14..16]
+                .try_into()
+                .expect("tcp window size length wrong"),
+        )
+    }
+
+    pub fn checksum(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[16..18]
+                .try_into()
+                .expect("tcp checksum length wrong"),
+        )
+    }
+
+    pub fn urgent_ptr(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[18..20]
+                .try_into()
+                .expect("tcp checksum length wrong"),
+        )
+    }
+
+    pub fn options(&self) -> TcpOptions {
+        const HEADER_LEN: usize = 20;
+        match self.data.get(HEADER_LEN..self.data_offset_bytes()) {
+            Some(options) => TcpOptions::parse(options),
+            None => TcpOptions::default(),
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.data[self.data_offset_bytes()..]
+    }
+}
+
+impl core::fmt::Debug for TcpFrame<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "source_port: {}", self.source_port())?;
+        writeln!(f, "dest_port: {}", self.dest_port())?;
+        writeln!(f, "seq_num: {}", self.seq_num())?;
+        writeln!(f, "ack_num: {}", self.ack_num())?;
+        writeln!(f, "data_offset_bytes: {}", self.data_offset_bytes())?;
+        writeln!(f, "flags: {:?}", self.flags())?;
+        writeln!(f, "window_size: {}", self.window_size())?;
+        writeln!(f, "checksum: {:x}", self.checksum())?;
+        writeln!(f, "urgent_ptr: {}", self.urgent_ptr())?;
+        Ok(())
+    }
+}
+
+pub struct TcpFrameParams {
+    pub source_address: IpAddr,
+    pub dest_address: IpAddr,
+    pub source_port: u16,
+    pub dest_port: u16,
+    pub seq_num: u32,
+    pub ack_num: u32,
+    pub flags: TcpFlags,
+    pub window_size: u16,
+    pub urgent_ptr: u16,
+    pub options: TcpOptions,
+    pub payload: Arc<[u8]>,
+}
+
+pub fn generate_tcp_frame(params: &TcpFrameParams) -> Vec<u8> {
+    const HEADER_LEN: usize = 20;
+
+    let mut options = Vec::new();
+    params.options.write(&mut options);
+    let header_len = HEADER_LEN + options.len();
+
+    let mut ret = Vec::with_capacity(header_len + params.payload.len());
+
+    ret.extend_from_slice(&params.source_port.to_be_bytes());
+    ret.extend_from_slice(&params.dest_port.to_be_bytes());
+    ret.extend_from_slice(&params.seq_num.to_be_bytes());
+    ret.extend_from_slice(&params.ack_num.to_be_bytes());
+    ret.push(((header_len / 4) << 4) as u8);
+    ret.push(params.flags.0);
+    ret.extend_from_slice(&params.window_size.to_be_bytes());
+    const CHECKSUM: u16 = 0;
+    let checksum_idx = ret.len();
+    ret.extend_from_slice(&CHECKSUM.to_be_bytes());
+    ret.extend_from_slice(&params.urgent_ptr.to_be_bytes());
+    ret.extend_from_slice(&options);
+    ret.extend_from_slice(&params.payload);
+
+    let checksum = net::calculate_pseudo_header_checksum(
+        &params.source_address,
+        &params.dest_address,
+        Ipv4Protocol::Tcp,
+        &ret,
+    );
+    ret[checksum_idx..checksum_idx + 2].copy_from_slice(&checksum.to_be_bytes());
+
+    ret
+}
+
+fn generate_tcp_push(
+    tcp_key: &TcpKey,
+    state: &mut ConnectedState,
+    time: &MonotonicTime,
+    data: Arc<[u8]>,
+) -> TcpFrameParams {
+    let payload_length = data.len();
+    let ret = TcpFrameParams {
+        source_address: tcp_key.local_ip,
+        dest_address: tcp_key.remote_ip,
+        source_port: tcp_key.local_port,
+        dest_port: tcp_key.remote_port,
+        seq_num: state.seq_num,
+        ack_num: state.outgoing_ack_num,
+        flags: generate_tcp_flags(&TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: false,
+            syn: false,
+            fin: false,
+        }),
+        window_size: state.receive_buffer.window(),
+        urgent_ptr: 0,
+        options: segment_options(state, time, payload_length),
+        payload: data,
+    };
+
+    state.seq_num = state.seq_num.wrapping_add(payload_length as u32);
+
+    ret
+}
+
+fn generate_tcp_ack(
+    tcp_key: &TcpKey,
+    state: &ConnectedState,
+    time: &MonotonicTime,
+) -> TcpFrameParams {
+    TcpFrameParams {
+        source_address: tcp_key.local_ip,
+        dest_address: tcp_key.remote_ip,
+        source_port: tcp_key.local_port,
+        dest_port: tcp_key.remote_port,
+        seq_num: state.seq_num,
+        ack_num: state.outgoing_ack_num,
+        flags: generate_tcp_flags(&TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: false,
+            syn: false,
+            fin: false,
+        }),
+        window_size: state.receive_buffer.window(),
+        urgent_ptr: 0,
+        options: segment_options(state, time, 0),
+        payload: Arc::new([]),
+    }
+}
+
+fn generate_tcp_fin(
+    tcp_key: &TcpKey,
+    state: &mut ConnectedState,
+    time: &MonotonicTime,
+) -> TcpFrameParams {
+    let mut ret = generate_tcp_ack(tcp_key, state, time);
+    ret.flags = generate_tcp_flags(&TcpFlagsParams {
+        cwr: false,
+        ece: false,
+        urg: false,
+        ack: true,
+        psh: false,
+        rst: false,
+        syn: false,
+        fin: true,
+    });
+
+    state.fin_seq = Some(state.seq_num);
+    state.seq_num = state.seq_num.wrapping_add(1);
+    state.state = match state.state {
+        ConnectionState::Established => ConnectionState::FinWait1,
+        ConnectionState::CloseWait => ConnectionState::LastAck,
+        s => s,
+    };
+
+    ret
+}
+
+/// Options carried by every segment of an established connection. SACK blocks only go into
+/// the space the payload leaves below the MSS, full sized segments carry none
+fn segment_options(state: &ConnectedState, time: &MonotonicTime, payload_len: usize) -> TcpOptions {
+    let mut sack_blocks = match state.options.sack {
+        true => state.reassembly.sack_blocks(),
+        false => Vec::new(),
+    };
+    let sack_space = state
+        .options
+        .mss
+        .saturating_sub(payload_len)
+        .saturating_sub(SACK_HEADER_LEN);
+    sack_blocks.truncate(sack_space / SACK_BLOCK_LEN);
+
+    TcpOptions {
+        timestamps: state.options.timestamps.then(|| Timestamps {
+            value: timestamp_ms(time),
+            echo_reply: state.ts_recent,
+        }),
+        sack_blocks,
+        ..Default::default()
+    }
+}
+
+/// Options for our SYN, or for the SYN-ACK answering the peer's SYN. A SYN-ACK may only carry
+/// the options the peer offered
+fn syn_options(
+    peer_syn: Option<&TcpOptions>,
+    local_ip: &IpAddr,
+    time: &MonotonicTime,
+) -> TcpOptions {
+    let offered = |peer_offered: bool| peer_syn.is_none() || peer_offered;
+    let peer_timestamps = peer_syn.and_then(|options| options.timestamps);
+
+    TcpOptions {
+        mss: Some(local_mss(local_ip) as u16),
+        window_scale: offered(peer_syn.is_some_and(|options| options.window_scale.is_some()))
+            .then_some(ReceiveBuffer::WINDOW_SHIFT),
+        sack_permitted: offered(peer_syn.is_some_and(|options| options.sack_permitted)),
+        sack_blocks: Vec::new(),
+        timestamps: offered(peer_timestamps.is_some()).then(|| Timestamps {
+            value: timestamp_ms(time),
+            echo_reply: peer_timestamps.map_or(0, |timestamps| timestamps.value),
+        }),
+    }
+}
+
+/// RFC 7323 timestamp clock
+fn timestamp_ms(time: &MonotonicTime) -> u32 {
+    (time.get() as f64 * 1000.0 / time.tick_freq() as f64) as u64 as u32
+}
+
+/// SYN-ACK answering the SYN in frame
+fn generate_syn_ack(
+    frame: &TcpFrame<'_>,
+    source_ip: &IpAddr,
+    dest_ip: &IpAddr,
+    seq_num: u32,
+    options: TcpOptions,
+) -> Arc<[u8]> {
+    generate_tcp_frame(&TcpFrameParams {
+        source_address: *dest_ip,
+        dest_address: *source_ip,
+        ack_num: frame.seq_num().wrapping_add(1),
+        seq_num,
+        dest_port: frame.source_port(),
+        source_port: frame.dest_port(),
+        window_size: ReceiveBuffer::CAPACITY as u16,
+        flags: generate_tcp_flags(&TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: false,
+            syn: true,
+            fin: false,
+        }),
+        urgent_ptr: 0,
+        options,
+        payload: Arc::new([]),
+    })
+    .into()
+}
+
+/// RFC 793 reset generation for segments that do not belong to any connection
+fn generate_tcp_reset(
+    frame: &TcpFrame<'_>,
+    source_ip: &IpAddr,
+    dest_ip: &IpAddr,
+) -> Option<Arc<[u8]>> {
+    let flags = frame.flags();
+    if flags.rst() {
+        return None;
+    }
+
+    let (seq_num, ack_num, ack) = if flags.ack() {
+        (frame.ack_num(), 0, false)
+    } else {
+        let segment_length = frame.payload().len() as u32 + flags.syn() as u32 + flags.fin() as u32;
+        (0, frame.seq_num().wrapping_add(segment_length), true)
+    };
+
+    let ret = generate_tcp_frame(&TcpFrameParams {
+        source_address: *dest_ip,
+        dest_address: *source_ip,
+        source_port: frame.dest_port(),
+        dest_port: frame.source_port(),
+        seq_num,
+        ack_num,
+        flags: generate_tcp_flags(&TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack,
+            psh: false,
+            rst: true,
+            syn: false,
+            fin: false,
+        }),
+        window_size: 0,
+        urgent_ptr: 0,
+        options: TcpOptions::default(),
+        payload: Arc::new([]),
+    });
+
+    Some(ret.into())
+}
+
+/// Sequence space consumed by a segment, SYN and FIN count as one byte each
+fn segment_length(params: &TcpFrameParams) -> u32 {
+    params.payload.len() as u32 + params.flags.syn() as u32 + params.flags.fin() as u32
+}
+
+/// RFC 1122 default when the peer does not announce one
+const DEFAULT_MSS: usize = 536;
+/// Smaller announcements are raised to this, as Linux does. Tiny segments only cost overhead
+/// and an MSS of 0 would stall sending entirely
+const MIN_MSS: usize = 88;
+/// Ethernet MTU minus the IP and TCP headers
+fn local_mss(local_ip: &IpAddr) -> usize {
+    match local_ip {
+        IpAddr::V4(_) => 1460,
+        IpAddr::V6(_) => 1440,
+    }
+}
+/// RFC 7323 option overhead in every segment once timestamps are in use
+const TIMESTAMPS_LEN: usize = 12;
+/// RFC 2018 option overhead, the kind and length plus two NOPs for alignment
+const SACK_HEADER_LEN: usize = 4;
+const SACK_BLOCK_LEN: usize = 8;
+
+/// What is in use for a connection. We offer every option we support in our SYN and only
+/// echo the peer's in a SYN-ACK, so the outcome only depends on the peer's SYN
+#[derive(Debug, Clone, Copy)]
+struct NegotiatedOptions {
+    /// Largest payload of our segments
+    mss: usize,
+    /// Applied to the windows the peer advertises
+    send_window_shift: u8,
+    sack: bool,
+    timestamps: bool,
+}
+
+impl NegotiatedOptions {
+    fn from_syn(peer_syn: &TcpOptions, local_ip: &IpAddr) -> NegotiatedOptions {
+        let timestamps = peer_syn.timestamps.is_some();
+        // RFC 6691, the MSS does not account for options
+        let mss = peer_syn
+            .mss
+            .map_or(DEFAULT_MSS, usize::from)
+            .clamp(MIN_MSS, local_mss(local_ip))
+            .saturating_sub(if timestamps { TIMESTAMPS_LEN } else { 0 });
+
+        NegotiatedOptions {
+            mss,
+            send_window_shift: peer_syn.window_scale.unwrap_or(0),
+            sack: peer_syn.sack_permitted,
+            timestamps,
+        }
+    }
+}
+
+/// a <= b in sequence space, which wraps around
+fn seq_le(a: u32, b: u32) -> bool {
+    b.wrapping_sub(a) as i32 >= 0
+}
+
+/// a < b in sequence space
+fn seq_lt(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
+/// RFC 6298 round trip estimation
+struct RttEstimator {
+    srtt_s: Option<f32>,
+    rttvar_s: f32,
+    rto_s: f32,
+}
+
+impl RttEstimator {
+    const INITIAL_RTO_S: f32 = 1.0;
+    const MIN_RTO_S: f32 = 1.0;
+    const MAX_RTO_S: f32 = 60.0;
+
+    fn new() -> RttEstimator {
+        RttEstimator {
+            srtt_s: None,
+            rttvar_s: 0.0,
+            rto_s: Self::INITIAL_RTO_S,
+        }
+    }
+
+    fn sample(&mut self, rtt_s: f32) {
+        let srtt_s = match self.srtt_s {
+            None => {
+                self.rttvar_s = rtt_s / 2.0;
+                rtt_s
+            }
+            Some(srtt_s) => {
+                self.rttvar_s = 0.75 * self.rttvar_s + 0.25 * (srtt_s - rtt_s).abs();
+                0.875 * srtt_s + 0.125 * rtt_s
+            }
+        };
+        self.srtt_s = Some(srtt_s);
+        self.rto_s = (srtt_s + 4.0 * self.rttvar_s).clamp(Self::MIN_RTO_S, Self::MAX_RTO_S);
+    }
+
+    fn backoff(&mut self) {
+        self.rto_s = (self.rto_s * 2.0).min(Self::MAX_RTO_S);
+    }
+
+    fn deadline(&self, time: &MonotonicTime) -> usize {
+        time.get() + (self.rto_s * time.tick_freq()) as usize
+    }
+}
+
+#[derive(Debug, Hash, Eq, PartialEq)]
+struct TcpListenerKey {
+    ip: IpAddr,
+    port: u16,
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct TcpKey {
+    remote_ip: IpAddr,
+    local_ip: IpAddr,
+    remote_port: u16,
+    local_port: u16,
+}
+
+struct UnackedPacket {
+    timestamp: usize,
+    // Karn's algorithm, acks for retransmitted segments are not used as rtt samples
+    retransmitted: bool,
+    // Reported received by a SACK block, skipped when retransmitting holes
+    sacked: bool,
+    params: TcpFrameParams,
+}
+
+/// RFC 793 synchronized states, SYN-RECEIVED is represented by TcpState::SynAckSent
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ConnectionState {
+    Established,
+    FinWait1,
+    FinWait2 { expiry: usize },
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait { expiry: usize },
+    Closed,
+}
+
+impl ConnectionState {
+    fn can_receive(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Established
+                | ConnectionState::FinWait1
+                | ConnectionState::FinWait2 { .. }
+        )
+    }
+
+    /// As shown by ss
+    fn name(&self) -> &'static str {
+        match self {
+            ConnectionState::Established => "ESTAB",
+            ConnectionState::FinWait1 => "FIN-WAIT-1",
+            ConnectionState::FinWait2 { .. } => "FIN-WAIT-2",
+            ConnectionState::CloseWait => "CLOSE-WAIT",
+            ConnectionState::Closing => "CLOSING",
+            ConnectionState::LastAck => "LAST-ACK",
+            ConnectionState::TimeWait { .. } => "TIME-WAIT",
+            ConnectionState::Closed => "CLOSED",
+        }
+    }
+}
+
+enum WriteRequest {
+    Data(Arc<[u8]>),
+    Shutdown,
+    // Only wakes up the service, see ReceiveBuffer
+    WindowUpdate,
+}
+
+/// Data handed to the reader but not read yet, the free space is advertised as our window
+struct ReceiveBuffer {
+    buffered: AtomicUsize,
+    // The window grew back from (nearly) closed, the peer may be waiting for an update
+    window_update: AtomicBool,
+}
+
+impl ReceiveBuffer {
+    const CAPACITY: usize = 8192;
+    // The buffer fits an unscaled window, announcing a shift still lets the peer scale its own
+    const WINDOW_SHIFT: u8 = 0;
+    // RFC 1122 receiver side silly window avoidance, min(buffer / 2, default mss)
+    const WINDOW_UPDATE_THRESHOLD: usize = 536;
+
+    fn new() -> ReceiveBuffer {
+        ReceiveBuffer {
+            buffered: AtomicUsize::new(0),
+            window_update: AtomicBool::new(false),
+        }
+    }
+
+    fn free(&self) -> usize {
+        Self::CAPACITY.saturating_sub(self.buffered.load(Ordering::Relaxed))
+    }
+
+    fn window(&self) -> u16 {
+        self.free().min(u16::MAX as usize) as u16
+    }
+
+    fn push(&self, len: usize) {
+        self.buffered.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Returns true if a window update should be sent
+    fn pop(&self, len: usize) -> bool {
+        let buffered = self.buffered.fetch_sub(len, Ordering::Relaxed);
+        let free_before = Self::CAPACITY.saturating_sub(buffered);
+        let reopened = free_before < Self::WINDOW_UPDATE_THRESHOLD
+            && free_before + len >= Self::WINDOW_UPDATE_THRESHOLD;
+        if reopened {
+            self.window_update.store(true, Ordering::Relaxed);
+        }
+        reopened
+    }
+
+    fn take_window_update(&self) -> bool {
+        self.window_update.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Segments that arrived ahead of the next expected sequence number, sorted by sequence number
+struct ReassemblyQueue {
+    segments: VecDeque<(u32, Vec<u8>)>,
+    buffered: usize,
+}
+
+impl ReassemblyQueue {
+    fn new() -> ReassemblyQueue {
+        ReassemblyQueue {
+            segments: VecDeque::new(),
+            buffered: 0,
+        }
+    }
+
+    /// Data beyond the window is cut off, the peer retransmits it once the window moves
+    fn insert(&mut self, next_seq: u32, seq: u32, data: &[u8], window: usize) {
+        let offset = seq.wrapping_sub(next_seq) as usize;
+        let len = data
+            .len()
+            .min(window.saturating_sub(offset))
+            .min(ReceiveBuffer::CAPACITY.saturating_sub(self.buffered));
+        if len == 0 {
+            return;
+        }
+
+        let idx = self
+            .segments
+            .iter()
+            .position(|(other_seq, _)| !seq_le(*other_seq, seq))
+            .unwrap_or(self.segments.len());
+        if matches!(self.segments.get(idx.wrapping_sub(1)), Some((other_seq, other))
+            if *other_seq == seq && other.len() >= len)
+        {
+            return;
+        }
+
+        self.buffered += len;
+        self.segments.insert(idx, (seq, data[..len].to_vec()));
+    }
+
+    /// Next piece of data starting at next_seq, with anything already received trimmed off
+    fn pop(&mut self, next_seq: u32) -> Option<Vec<u8>> {
+        while let Some((seq, _)) = self.segments.front() {
+            if !seq_le(*seq, next_seq) {
+                return None;
+            }
+
+            let (seq, mut data) = self.segments.pop_front().expect("front exists");
+            self.buffered -= data.len();
+            let already_received = next_seq.wrapping_sub(seq) as usize;
+            if already_received < data.len() {
+                data.drain(..already_received);
+                return Some(data);
+            }
+        }
+
+        None
+    }
+
+    fn sack_blocks(&self) -> Vec<SackBlock> {
+        let mut ret: Vec<SackBlock> = Vec::new();
+        for (seq, data) in &self.segments {
+            let right = seq.wrapping_add(data.len() as u32);
+            match ret.last_mut() {
+                Some(block) if seq_le(*seq, block.right) => {
+                    if !seq_le(right, block.right) {
+                        block.right = right;
+                    }
+                }
+                _ => ret.push(SackBlock { left: *seq, right }),
+            }
+        }
+        ret
+    }
+}
+
+struct ConnectedState {
+    state: ConnectionState,
+    seq_num: u32,          // Incoming seq num
+    outgoing_ack_num: u32, // Outgoing ack num
+    incoming_ack_num: u32,
+    // Peer's receive window, already scaled
+    window_size: u32,
+    // RFC 793 SND.WL1 and SND.WL2, the segment that last updated window_size
+    window_update_seq: u32,
+    window_update_ack: u32,
+    options: NegotiatedOptions,
+    // RFC 7323 TS.Recent, echoed back in our timestamps
+    ts_recent: u32,
+    dup_ack_counter: u8,
+    congestion: Box<dyn CongestionControl>,
+    // RFC 6582 fast recovery, the highest sequence number sent when the loss was detected
+    recover: Option<u32>,
+    // Set by ack processing, the poller resends the first unacknowledged segment
+    fast_retransmit: bool,
+    unacknowledged: VecDeque<UnackedPacket>,
+    receive_buffer: Arc<ReceiveBuffer>,
+    reassembly: ReassemblyQueue,
+    // Zero window probing, running while the peer's window is closed and nothing is in flight
+    persist_deadline: Option<usize>,
+    persist_interval_s: f32,
+    rtt: RttEstimator,
+    // Running whenever there is unacknowledged data
+    retransmit_deadline: Option<usize>,
+    // Consecutive timeouts without progress, the connection is aborted after too many
+    retransmit_count: u8,
+    to_send: VecDeque<Arc<[u8]>>,
+    // The application will not write anymore, a FIN goes out once to_send is drained
+    write_closed: bool,
+    fin_seq: Option<u32>,
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<WriteRequest>,
+}
+
+type ConnectResult = Result<TcpConnection, ConnectError>;
+
+enum TcpState {
+    Uninit,
+    SynSent {
+        seq_num: u32,
+        // Set by connect() whenever the SYN should go out (again)
+        needs_transmit: bool,
+        sent_frame: OutgoingTcpPacket,
+        result_tx: Option<oneshot::Sender<ConnectResult>>,
+        // A soft ICMP error was received, reported instead of a timeout if no answer comes
+        unreachable: bool,
+        // connect() gives up well before this, the entry is only left behind if it was dropped
+        expiry: usize,
+    },
+    SynAckSent {
+        seq_num: u32,
+        ack_num: u32,
+        timeout: usize,
+        retries: u8,
+        sent_frame: OutgoingTcpPacket,
+        options: NegotiatedOptions,
+        ts_recent: u32,
+    },
+    Connected(Box<ConnectedState>),
+}
+
+impl TcpState {
+    fn is_dead(&self, now: usize) -> bool {
+        match self {
+            TcpState::Uninit => true,
+            TcpState::SynSent { expiry, .. } => now >= *expiry,
+            TcpState::SynAckSent { .. } => false,
+            TcpState::Connected(state) => match state.state {
+                ConnectionState::Closed => true,
+                ConnectionState::TimeWait { expiry } => now >= expiry,
+                // A half closed connection may wait for the peer indefinitely, but nobody is
+                // left to read once the application dropped its handle
+                ConnectionState::FinWait2 { expiry } => {
+                    now >= expiry && Arc::strong_count(&state.receive_buffer) == 1
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+fn new_connected_state(
+    seq_num: u32,
+    outgoing_ack_num: u32,
+    incoming_ack_num: u32,
+    window_size: u32,
+    options: NegotiatedOptions,
+    ts_recent: u32,
+) -> (ConnectedState, TcpConnection) {
+    let (tx_in, rx_in) = async_channel::channel();
+    let (tx_out, rx_out) = async_channel::channel();
+    let receive_buffer = Arc::new(ReceiveBuffer::new());
+    let connection = TcpConnection {
+        rx: rx_in,
+        tx: tx_out,
+        receive_buffer: Arc::clone(&receive_buffer),
+        pending: Mutex::new(VecDeque::new()),
+        read_closed: AtomicBool::new(false),
+        write_closed: AtomicBool::new(false),
+    };
+
+    let state = ConnectedState {
+        state: ConnectionState::Established,
+        seq_num,
+        outgoing_ack_num,
+        incoming_ack_num,
+        window_size,
+        window_update_seq: outgoing_ack_num,
+        window_update_ack: incoming_ack_num,
+        options,
+        ts_recent,
+        dup_ack_counter: 0,
+        congestion: Box::new(NewReno::new(options.mss)),
+        recover: None,
+        fast_retransmit: false,
+        unacknowledged: VecDeque::new(),
+        receive_buffer,
+        reassembly: ReassemblyQueue::new(),
+        persist_deadline: None,
+        persist_interval_s: 0.0,
+        rtt: RttEstimator::new(),
+        retransmit_deadline: None,
+        retransmit_count: 0,
+        to_send: VecDeque::new(),
+        write_closed: false,
+        fin_seq: None,
+        tx: tx_in,
+        rx: rx_out,
+    };
+
+    (state, connection)
+}
+
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The peer answered with a reset, nothing is listening
+    Refused,
+    /// ICMP destination unreachable for our SYN
+    Unreachable,
+    TimedOut,
+    NoFreePorts,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[allow(unused)]
+pub enum Shutdown {
+    Read,
+    Write,
+    Both,
+}
+
+/// In-order byte stream, see AsyncRead and AsyncWrite
+pub struct TcpConnection {
+    rx: Receiver<Vec<u8>>,
+    tx: Sender<WriteRequest>,
+    receive_buffer: Arc<ReceiveBuffer>,
+    // Received data that did not fit into the reader's buffer yet
+    pending: Mutex<VecDeque<u8>>,
+    read_closed: AtomicBool,
+    write_closed: AtomicBool,
+}
+
+impl TcpConnection {
+    /// Shutting down writes sends a FIN once all previously written data has been sent
+    pub async fn shutdown(&self, how: Shutdown) {
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.read_closed.store(true, Ordering::Relaxed);
+        }
+
+        if matches!(how, Shutdown::Write | Shutdown::Both)
+            && !self.write_closed.swap(true, Ordering::Relaxed)
+        {
+            self.tx.send(WriteRequest::Shutdown).await;
+        }
+    }
+
+    pub async fn close(self) {
+        self.shutdown(Shutdown::Both).await;
+    }
+}
+
+impl AsyncRead for TcpConnection {
+    /// Returns 0 once the peer has closed or reset the connection
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, usize> {
+        Box::pin(async move {
+            if buf.is_empty() || self.read_closed.load(Ordering::Relaxed) {
+                return 0;
+            }
+
+            let mut pending = self.pending.lock().await;
+            if pending.is_empty() {
+                let data = self.rx.recv().await;
+                if data.is_empty() {
+                    self.read_closed.store(true, Ordering::Relaxed);
+                    return 0;
+                }
+                pending.extend(data);
+            }
+
+            let len = buf.len().min(pending.len());
+            for (dst, src) in buf.iter_mut().zip(pending.drain(..len)) {
+                *dst = src;
+            }
+            drop(pending);
+
+            if self.receive_buffer.pop(len) {
+                self.tx.send(WriteRequest::WindowUpdate).await;
+            }
+            len
+        })
+    }
+}
+
+impl AsyncWrite for TcpConnection {
+    /// Takes all of buf, segmentation happens when the data is sent
+    fn write<'a>(&'a self, buf: &'a [u8]) -> BoxFuture<'a, Result<usize, IoError>> {
+        Box::pin(async move {
+            if self.write_closed.load(Ordering::Relaxed) {
+                return Err(IoError::Closed);
+            }
+
+            self.tx.send(WriteRequest::Data(buf.into())).await;
+            Ok(buf.len())
+        })
+    }
+}
+
+pub struct TcpListener {
+    rx: Receiver<TcpConnection>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl TcpListener {
+    pub async fn connection(&self) -> TcpConnection {
+        let connection = self.rx.recv().await;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        connection
+    }
+}
+
+/// Accepts connections forever, running up to max_connections handlers at once. Further
+/// connections wait in the listener's backlog. The handler owns its connection and should close it
+pub async fn serve_connections<'a, F, Fut>(
+    listener: &TcpListener,
+    max_connections: usize,
+    handler: F,
+) where
+    F: Fn(TcpConnection) -> Fut,
+    Fut: Future<Output = ()> + Send + 'a,
+{
+    let mut connections = FutureSet::new();
+    loop {
+        if connections.len() >= max_connections {
+            connections.next().await;
+            continue;
+        }
+
+        let connection = {
+            let accept = core::pin::pin!(listener.connection());
+            let finished = core::pin::pin!(connections.next());
+            match crate::future::select(accept, finished).await {
+                Either::Left((connection, _)) => connection,
+                Either::Right(_) => continue,
+            }
+        };
+
+        connections.push(handler(connection));
+    }
+}
+
+#[derive(Clone)]
+struct ListenerEntry {
+    // The unspecified address for listeners on any address
+    ip: IpAddr,
+    tx: Sender<TcpConnection>,
+    backlog: usize,
+    // Established connections not yet taken by TcpListener::connection
+    queued: Arc<AtomicUsize>,
+}
+
+impl ListenerEntry {
+    fn is_full(&self) -> bool {
+        self.queued.load(Ordering::Relaxed) >= self.backlog
+    }
+
+    async fn accept(&self, connection: TcpConnection) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(connection).await;
+    }
+}
+
+/// Snapshot of an established connection for debugging
+#[derive(Debug)]
+#[allow(unused)]
+pub struct TcpConnectionInfo {
+    pub state: &'static str,
+    pub local_ip: IpAddr,
+    pub local_port: u16,
+    pub remote_ip: IpAddr,
+    pub remote_port: u16,
+    pub cwnd: usize,
+    pub ssthresh: usize,
+    pub srtt_s: Option<f32>,
+    pub rto_s: f32,
+    pub bytes_in_flight: usize,
+}
+
+/// Counters named after their RFC 4022 equivalents, segment counts are kept by the ip layer
+#[derive(Debug, Default)]
+pub struct TcpStats {
+    pub active_opens: AtomicUsize,
+    pub passive_opens: AtomicUsize,
+    /// Handshakes that were refused, timed out or reset
+    pub attempt_fails: AtomicUsize,
+    /// Resets received on synchronized connections and aborts after retransmissions ran out
+    pub estab_resets: AtomicUsize,
+    /// Includes SYN and SYN-ACK retransmissions
+    pub retrans_segs: AtomicUsize,
+    /// Segments without a connection or listener, answered with a reset
+    pub no_listener: AtomicUsize,
+}
+
+pub struct Tcp {
+    listeners: Mutex<HashMap<TcpListenerKey, ListenerEntry>>,
+    tcp_states: Mutex<HashMap<TcpKey, TcpState>>,
+    isn: IsnGenerator,
+    syn_cookies: AtomicBool,
+    next_ephemeral_port: Mutex<u16>,
+    ipv4_config: UpdatedVal<Ipv4Config>,
+    ipv6_config: UpdatedVal<Ipv6Config>,
+    time: Arc<MonotonicTime>,
+    service_waker: AtomicCell<Waker>,
+    // Earliest timer the service has asked to be woken up for
+    registered_wakeup: AtomicUsize,
+    wakeup_list: WakeupRequester,
+    pub stats: TcpStats,
+}
+
+impl Tcp {
+    // Linux uses a fixed 60 seconds instead of 2 * MSL
+    const TIME_WAIT_S: f32 = 60.0;
+    // Linux tcp_fin_timeout, for orphaned connections waiting on the peer's FIN
+    const FIN_WAIT_2_S: f32 = 60.0;
+    const EPHEMERAL_PORT_START: u16 = 49152;
+    // RFC 6298 initial RTO, doubled for every retry. The last retry gets one more doubling
+    // before giving up, 31 seconds in total
+    const SYN_RETRANSMIT_S: f32 = 1.0;
+    const SYN_RETRIES: usize = 4;
+    const SYN_SENT_EXPIRY_S: f32 = 60.0;
+    // With the rto doubling from 1s up to 60s this gives up after roughly 4 minutes
+    const MAX_RETRANSMITS: u8 = 8;
+    const DUP_ACK_THRESHOLD: u8 = 3;
+    const DEFAULT_BACKLOG: usize = 16;
+    // Handshakes in progress per listener, further SYNs get a cookie or are dropped
+    const MAX_HALF_OPEN: usize = 64;
+    // SYN-ACKs are resent every second, after this many the half-open connection is dropped
+    const SYN_ACK_RETRIES: u8 = 5;
+
+    /// isn_secret keys sequence number generation, it must not be guessable from outside
+    pub fn new(
+        ipv4_config: UpdatedVal<Ipv4Config>,
+        ipv6_config: UpdatedVal<Ipv6Config>,
+        time: Arc<MonotonicTime>,
+        wakeup_list: WakeupRequester,
+        isn_secret: [u64; 2],
+    ) -> Tcp {
+        Tcp {
+            listeners: Mutex::new(Default::default()),
+            tcp_states: Mutex::new(Default::default()),
+            isn: IsnGenerator::new(isn_secret),
+            syn_cookies: AtomicBool::new(true),
+            next_ephemeral_port: Mutex::new(Self::EPHEMERAL_PORT_START),
+            ipv4_config,
+            ipv6_config,
+            service_waker: AtomicCell::new(),
+            registered_wakeup: AtomicUsize::new(usize::MAX),
+            time,
+            wakeup_list,
+            stats: Default::default(),
+        }
+    }
+
+    /// Actively opens a connection from an ephemeral port. Resolves once the handshake
+    /// completes, or with the reason it could not
+    pub async fn connect(
+        &self,
+        remote_ip: impl Into<IpAddr>,
+        remote_port: u16,
+    ) -> Result<TcpConnection, ConnectError> {
+        let remote_ip = remote_ip.into();
+        let local_ip = match &remote_ip {
+            IpAddr::V4(ip) => IpAddr::V4(self.ipv4_config.read().await.local_ip_for(ip)),
+            IpAddr::V6(ip) => IpAddr::V6(self.ipv6_config.read().await.local_ip_for(ip)),
+        };
+        let (result_tx, result_rx) = oneshot::channel();
+
+        let tcp_key = {
+            let mut tcp_states = self.tcp_states.lock().await;
+            let local_port = self
+                .find_ephemeral_port(&tcp_states)
+                .await
+                .ok_or(ConnectError::NoFreePorts)?;
+
+            let tcp_key = TcpKey {
+                remote_ip,
+                local_ip,
+                remote_port,
+                local_port,
+            };
+
+            let seq_num = self.isn.isn(&tcp_key, &self.time);
+            let syn = generate_tcp_frame(&TcpFrameParams {
+                source_address: local_ip,
+                dest_address: remote_ip,
+                source_port: local_port,
+                dest_port: remote_port,
+                seq_num,
+                ack_num: 0,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: false,
+                    psh: false,
+                    rst: false,
+                    syn: true,
+                    fin: false,
+                }),
+                window_size: ReceiveBuffer::CAPACITY as u16,
+                urgent_ptr: 0,
+                options: syn_options(None, &local_ip, &self.time),
+                payload: Arc::new([]),
+            });
+
+            tcp_states.insert(
+                tcp_key.clone(),
+                TcpState::SynSent {
+                    seq_num,
+                    needs_transmit: true,
+                    sent_frame: OutgoingTcpPacket {
+                        local_ip,
+                        remote_ip,
+                        payload: syn.into(),
+                    },
+                    result_tx: Some(result_tx),
+                    unreachable: false,
+                    expiry: self.time.get()
+                        + (Self::SYN_SENT_EXPIRY_S * self.time.tick_freq()) as usize,
+                },
+            );
+
+            tcp_key
+        };
+        increment(&self.stats.active_opens);
+
+        let mut retransmit_interval_s = Self::SYN_RETRANSMIT_S;
+        let mut retries = 0;
+        loop {
+            self.wake_service();
+
+            let sleep_fut = core::pin::pin!(crate::sleep::sleep(
+                retransmit_interval_s,
+                &self.time,
+                &self.wakeup_list
+            ));
+            let result_fut = core::pin::pin!(result_rx.recv());
+
+            if let Either::Left((result, _)) = crate::future::select(result_fut, sleep_fut).await {
+                return result.expect("Connect result is only received once");
+            }
+
+            let mut tcp_states = self.tcp_states.lock().await;
+            match tcp_states.get_mut(&tcp_key) {
+                Some(TcpState::SynSent { needs_transmit, .. }) if retries < Self::SYN_RETRIES => {
+                    *needs_transmit = true;
+                    retries += 1;
+                    retransmit_interval_s *= 2.0;
+                    increment(&self.stats.retrans_segs);
+                }
+                Some(TcpState::SynSent { unreachable, .. }) => {
+                    let unreachable = *unreachable;
+                    tcp_states.remove(&tcp_key);
+                    increment(&self.stats.attempt_fails);
+                    return Err(if unreachable {
+                        ConnectError::Unreachable
+                    } else {
+                        ConnectError::TimedOut
+                    });
+                }
+                // The result has already been posted
+                _ => (),
+            }
+        }
+    }
+
+    #[allow(unused)]
+    pub async fn connections(&self) -> Vec<TcpConnectionInfo> {
+        let tcp_states = self.tcp_states.lock().await;
+        tcp_states
+            .iter()
+            .filter_map(|(key, state)| match state {
+                TcpState::Connected(state) => Some(TcpConnectionInfo {
+                    state: state.state.name(),
+                    local_ip: key.local_ip,
+                    local_port: key.local_port,
+                    remote_ip: key.remote_ip,
+                    remote_port: key.remote_port,
+                    cwnd: state.congestion.cwnd(),
+                    ssthresh: state.congestion.ssthresh(),
+                    srtt_s: state.rtt.srtt_s,
+                    rto_s: state.rtt.rto_s,
+                    bytes_in_flight: state.seq_num.wrapping_sub(state.incoming_ack_num) as usize,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Ports in use by either a listener or an existing connection are skipped
+    async fn find_ephemeral_port(&self, tcp_states: &HashMap<TcpKey, TcpState>) -> Option<u16> {
+        let listeners = self.listeners.lock().await;
+        let mut next_port = self.next_ephemeral_port.lock().await;
+        let num_ephemeral_ports = u16::MAX - Self::EPHEMERAL_PORT_START + 1;
+
+        for _ in 0..num_ephemeral_ports {
+            let port = *next_port;
+            *next_port = match port {
+                u16::MAX => Self::EPHEMERAL_PORT_START,
+                _ => port + 1,
+            };
+
+            let in_use = listeners.keys().any(|key| key.port == port)
+                || tcp_states.keys().any(|key| key.local_port == port);
+            if !in_use {
+                return Some(port);
+            }
+        }
+
+        None
+    }
+
+    /// Called for ICMP destination unreachable messages quoting one of our segments, along with
+    /// the quoted start of its tcp header. Only hard errors fail connection attempts, established
+    /// connections treat every error as soft
+    pub async fn handle_unreachable(
+        &self,
+        code: u8,
+        local_ip: &IpAddr,
+        local_port: u16,
+        remote_ip: &IpAddr,
+        remote_port: u16,
+        quoted_header: &[u8; 8],
+    ) {
+        let tcp_key = TcpKey {
+            remote_ip: *remote_ip,
+            local_ip: *local_ip,
+            remote_port,
+            local_port,
+        };
+
+        let mut tcp_states = self.tcp_states.lock().await;
+        let state = match tcp_states.get_mut(&tcp_key) {
+            Some(v) => v,
+            None => return,
+        };
+
+        if let TcpState::SynSent {
+            seq_num,
+            result_tx,
+            unreachable,
+            ..
+        } = state
+        {
+            // RFC 5927, anyone can forge an error for a known connection but only the
+            // receiver of our SYN knows its sequence number
+            let quoted_seq = u32::from_be_bytes(
+                quoted_header[4..8]
+                    .try_into()
+                    .expect("Invalid length for quoted seq num"),
+            );
+            if quoted_seq != *seq_num {
+                debug!(
+                    "Ignoring unreachable with unexpected seq num: {}",
+                    quoted_seq
+                );
+                return;
+            }
+
+            // RFC 1122 4.2.3.9, network, host and source route failures may be transient
+            let soft_codes = [
+                UnreachableCode::Network as u8,
+                UnreachableCode::Host as u8,
+                UnreachableCode::SourceRouteFailed as u8,
+            ];
+            if soft_codes.contains(&code) {
+                debug!("Soft error for connection attempt: {:?}", tcp_key);
+                *unreachable = true;
+                return;
+            }
+
+            debug!("Connection attempt unreachable: {:?}", tcp_key);
+            increment(&self.stats.attempt_fails);
+            if let Some(result_tx) = result_tx.take() {
+                result_tx.send(Err(ConnectError::Unreachable)).await;
+            }
+            *state = TcpState::Uninit;
+        }
+    }
+
+    fn wake_service(&self) {
+        if let Some(service_waker) = self.service_waker.get() {
+            service_waker.wake_by_ref();
+        }
+    }
+
+    /// Listening on 0.0.0.0 accepts connections to any of our ipv4 addresses, listening on [::]
+    /// to any of our addresses of either family
+    pub async fn listen(&self, ip: impl Into<IpAddr>, port: u16) -> TcpListener {
+        self.listen_with_backlog(ip, port, Self::DEFAULT_BACKLOG)
+            .await
+    }
+
+    /// Handshakes are not completed while backlog connections are waiting to be accepted
+    pub async fn listen_with_backlog(
+        &self,
+        ip: impl Into<IpAddr>,
+        port: u16,
+        backlog: usize,
+    ) -> TcpListener {
+        let ip = ip.into();
+        let (tx, rx) = async_channel::channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let entry = ListenerEntry {
+            ip,
+            tx,
+            backlog,
+            queued: Arc::clone(&queued),
+        };
+        self.listeners
+            .lock()
+            .await
+            .insert(TcpListenerKey { ip, port }, entry);
+        TcpListener { rx, queued }
+    }
+
+    /// With SYN cookies disabled, SYNs over the half-open limit are dropped instead of being
+    /// answered statelessly. Enabled by default
+    #[allow(unused)]
+    pub fn set_syn_cookies(&self, enabled: bool) {
+        self.syn_cookies.store(enabled, Ordering::Relaxed);
+    }
+
+    async fn find_listener(&self, ip: &IpAddr, port: u16) -> Option<ListenerEntry> {
+        let listeners = self.listeners.lock().await;
+        let listener_key = TcpListenerKey { ip: *ip, port };
+        let wildcard_key = TcpListenerKey {
+            ip: match ip {
+                IpAddr::V4(_) => IpAddr::V4([0; 4]),
+                IpAddr::V6(_) => IpAddr::V6([0; 16]),
+            },
+            port,
+        };
+        let dual_stack_key = TcpListenerKey {
+            ip: IpAddr::V6([0; 16]),
+            port,
+        };
+
+        listeners
+            .get(&listener_key)
+            .or_else(|| listeners.get(&wildcard_key))
+            .or_else(|| listeners.get(&dual_stack_key))
+            .cloned()
+    }
+
+    fn half_open_connections(
+        tcp_states: &HashMap<TcpKey, TcpState>,
+        listener: &ListenerEntry,
+        port: u16,
+    ) -> usize {
+        tcp_states
+            .iter()
+            .filter(|(key, state)| {
+                matches!(state, TcpState::SynAckSent { .. })
+                    && key.local_port == port
+                    && (listener.ip.is_unspecified() || key.local_ip == listener.ip)
+            })
+            .count()
+    }
+
+    /// Completes a handshake for which we only sent a cookie, the connection starts out with
+    /// the cookie's MSS and without any other options
+    async fn handle_syn_cookie_ack(
+        &self,
+        tcp_states: &mut HashMap<TcpKey, TcpState>,
+        tcp_key: TcpKey,
+        listener: ListenerEntry,
+        frame: &TcpFrame<'_>,
+        source_ip: &IpAddr,
+        dest_ip: &IpAddr,
+    ) -> Option<Arc<[u8]>> {
+        let peer_isn = frame.seq_num().wrapping_sub(1);
+        let cookie = frame.ack_num().wrapping_sub(1);
+        let mss = match self
+            .isn
+            .check_syn_cookie(&tcp_key, peer_isn, cookie, &self.time)
+        {
+            Some(v) => v,
+            None => {
+                debug!("Resetting segment for unknown connection {:?}", tcp_key);
+                increment(&self.stats.no_listener);
+                return generate_tcp_reset(frame, source_ip, dest_ip);
+            }
+        };
+
+        if listener.is_full() {
+            debug!("Listen backlog full, dropping syn cookie ack");
+            return None;
+        }
+
+        let options = TcpOptions {
+            mss: Some(mss),
+            ..Default::default()
+        };
+        let (mut connected_state, connection) = new_connected_state(
+            frame.ack_num(),
+            frame.seq_num(),
+            frame.ack_num(),
+            frame.window_size() as u32,
+            NegotiatedOptions::from_syn(&options, &tcp_key.local_ip),
+            0,
+        );
+        // Any data or FIN on the ack goes through the normal receive path
+        let response = self
+            .handle_connected_frame(&tcp_key, &mut connected_state, frame)
+            .await;
+        tcp_states.insert(tcp_key, TcpState::Connected(Box::new(connected_state)));
+        increment(&self.stats.passive_opens);
+        listener.accept(connection).await;
+        self.wake_service();
+
+        response
+    }
+
+    fn time_wait_expiry(&self) -> usize {
+        self.time.get() + (Self::TIME_WAIT_S * self.time.tick_freq()) as usize
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn handle_frame<'a>(
+        &'a self,
+        frame: &'a TcpFrame<'_>,
+        source_ip: &'a IpAddr,
+        dest_ip: &'a IpAddr,
+    ) -> Pin<Box<dyn Future<Output = Option<Arc<[u8]>>> + 'a + Send>> {
+        Box::pin(async move {
+            let tcp_key = TcpKey {
+                remote_ip: *source_ip,
+                local_ip: *dest_ip,
+                remote_port: frame.source_port(),
+                local_port: frame.dest_port(),
+            };
+
+            let mut tcp_states = self.tcp_states.lock().await;
+            let flags = frame.flags();
+
+            if let Some(state) = tcp_states.get(&tcp_key) {
+                if state.is_dead(self.time.get()) {
+                    tcp_states.remove(&tcp_key);
+                }
+            }
+
+            if !tcp_states.contains_key(&tcp_key) {
+                let is_connection_request = flags.syn() && !flags.ack() && !flags.rst();
+                let is_handshake_ack = flags.ack() && !flags.syn() && !flags.rst();
+                let syn_cookies = self.syn_cookies.load(Ordering::Relaxed);
+
+                match self.find_listener(dest_ip, frame.dest_port()).await {
+                    Some(listener) if is_connection_request => {
+                        if listener.is_full() {
+                            debug!("Listen backlog full, dropping syn");
+                            return None;
+                        }
+
+                        let half_open =
+                            Self::half_open_connections(&tcp_states, &listener, frame.dest_port());
+                        if half_open >= Self::MAX_HALF_OPEN {
+                            if !syn_cookies {
+                                debug!("Too many half-open connections, dropping syn");
+                                return None;
+                            }
+
+                            let peer_mss = frame.options().mss.unwrap_or(DEFAULT_MSS as u16);
+                            let cookie = self.isn.syn_cookie(
+                                &tcp_key,
+                                frame.seq_num(),
+                                peer_mss,
+                                &self.time,
+                            );
+                            let options = TcpOptions {
+                                mss: Some(local_mss(dest_ip) as u16),
+                                ..Default::default()
+                            };
+                            return Some(generate_syn_ack(
+                                frame, source_ip, dest_ip, cookie, options,
+                            ));
+                        }
+                    }
+                    Some(listener) if is_handshake_ack && syn_cookies => {
+                        return self
+                            .handle_syn_cookie_ack(
+                                &mut tcp_states,
+                                tcp_key,
+                                listener,
+                                frame,
+                                source_ip,
+                                dest_ip,
+                            )
+                            .await;
+                    }
+                    _ => {
+                        debug!("Resetting segment for unknown connection {:?}", tcp_key);
+                        increment(&self.stats.no_listener);
+                        return generate_tcp_reset(frame, source_ip, dest_ip);
+                    }
+                }
+            }
+
+            let state = tcp_states
+                .entry(tcp_key.clone())
+                .or_insert(TcpState::Uninit);
+
+            let ret = match state {
+                TcpState::Uninit => {
+                    if !flags.syn() {
+                        return None;
+                    }
+
+                    let seq_num = self.isn.isn(&tcp_key, &self.time);
+                    let ack_num = frame.seq_num().wrapping_add(1);
+                    let peer_options = frame.options();
+                    let response_frame = generate_syn_ack(
+                        frame,
+                        source_ip,
+                        dest_ip,
+                        seq_num,
+                        syn_options(Some(&peer_options), dest_ip, &self.time),
+                    );
+
+                    let sent_frame = OutgoingTcpPacket {
+                        local_ip: *dest_ip,
+                        remote_ip: *source_ip,
+                        payload: Arc::clone(&response_frame),
+                    };
+
+                    let timeout = (self.time.get() as f32 + 1.0 * self.time.tick_freq()) as usize;
+                    self.wakeup_list.register_wakeup_time(timeout).await;
+                    increment(&self.stats.passive_opens);
+                    *state = TcpState::SynAckSent {
+                        seq_num,
+                        ack_num,
+                        sent_frame,
+                        timeout,
+                        retries: 0,
+                        options: NegotiatedOptions::from_syn(&peer_options, dest_ip),
+                        ts_recent: peer_options
+                            .timestamps
+                            .map_or(0, |timestamps| timestamps.value),
+                    };
+
+                    Some(response_frame)
+                }
+                TcpState::SynSent {
+                    seq_num, result_tx, ..
+                } => {
+                    let expected_ack = seq_num.wrapping_add(1);
+                    if flags.ack() && frame.ack_num() != expected_ack {
+                        debug!("Unacceptable ack in syn sent: {}", frame.ack_num());
+                        return generate_tcp_reset(frame, source_ip, dest_ip);
+                    }
+
+                    if flags.rst() {
+                        // A reset without an ack cannot be matched to our SYN
+                        if flags.ack() {
+                            debug!("Connection refused: {:?}", tcp_key);
+                            increment(&self.stats.attempt_fails);
+                            if let Some(result_tx) = result_tx.take() {
+                                result_tx.send(Err(ConnectError::Refused)).await;
+                            }
+                            *state = TcpState::Uninit;
+                        }
+                        return None;
+                    }
+
+                    if !flags.syn() || !flags.ack() {
+                        // Simultaneous open is not supported, our SYN will be retransmitted
+                        debug!("Ignoring segment without syn ack in syn sent");
+                        return None;
+                    }
+
+                    // The window in a SYN is never scaled
+                    let peer_options = frame.options();
+                    let (connected_state, connection) = new_connected_state(
+                        expected_ack,
+                        frame.seq_num().wrapping_add(1),
+                        frame.ack_num(),
+                        frame.window_size() as u32,
+                        NegotiatedOptions::from_syn(&peer_options, &tcp_key.local_ip),
+                        peer_options
+                            .timestamps
+                            .map_or(0, |timestamps| timestamps.value),
+                    );
+                    let response = generate_tcp_frame(&generate_tcp_ack(
+                        &tcp_key,
+                        &connected_state,
+                        &self.time,
+                    ));
+
+                    if let Some(result_tx) = result_tx.take() {
+                        result_tx.send(Ok(connection)).await;
+                    }
+                    *state = TcpState::Connected(Box::new(connected_state));
+
+                    Some(response.into())
+                }
+                TcpState::SynAckSent {
+                    ack_num,
+                    seq_num,
+                    options,
+                    ts_recent,
+                    ..
+                } => {
+                    if flags.rst() {
+                        debug!("Connection reset before handshake completed");
+                        increment(&self.stats.attempt_fails);
+                        // Back to LISTEN, the entry is reaped as dead
+                        *state = TcpState::Uninit;
+                        return None;
+                    }
+
+                    if flags.syn() {
+                        debug!("Resetting connection, unexpected syn");
+                        *state = TcpState::Uninit;
+                        drop(tcp_states);
+                        return self.handle_frame(frame, source_ip, dest_ip).await;
+                    }
+
+                    if !flags.ack() {
+                        debug!("Ack unset, ignoring");
+                        return None;
+                    }
+
+                    if frame.seq_num() != *ack_num {
+                        error!(
+                            "Sequence number not expected in syn ack: ack_num: {}, seq_num: {}",
+                            ack_num,
+                            frame.seq_num()
+                        );
+                        return None;
+                    }
+
+                    // Only a peer that received our SYN-ACK knows the ISN, anything else may be
+                    // spoofed
+                    if frame.ack_num() != seq_num.wrapping_add(1) {
+                        debug!("Unacceptable ack in syn-ack sent: {}", frame.ack_num());
+                        return generate_tcp_reset(frame, source_ip, dest_ip);
+                    }
+
+                    let listener = match self.find_listener(dest_ip, frame.dest_port()).await {
+                        Some(x) => x,
+                        None => {
+                            error!("Syn ack ack for non existent listener");
+                            return None;
+                        }
+                    };
+
+                    // Stay half-open, the retransmitted SYN-ACK gets us another ack
+                    if listener.is_full() {
+                        debug!("Listen backlog full, not completing handshake");
+                        return None;
+                    }
+
+                    let (mut connected_state, connection) = new_connected_state(
+                        seq_num.wrapping_add(1),
+                        *ack_num,
+                        frame.ack_num(),
+                        (frame.window_size() as u32) << options.send_window_shift,
+                        *options,
+                        *ts_recent,
+                    );
+                    // The ack may already carry data or a FIN, handled as on any other segment
+                    let response = self
+                        .handle_connected_frame(&tcp_key, &mut connected_state, frame)
+                        .await;
+                    *state = TcpState::Connected(Box::new(connected_state));
+
+                    listener.accept(connection).await;
+
+                    response
+                }
+                TcpState::Connected(ref mut state) => {
+                    self.handle_connected_frame(&tcp_key, state, frame).await
+                }
+            };
+
+            self.wake_service();
+
+            ret
+        })
+    }
+
+    async fn handle_connected_frame(
+        &self,
+        tcp_key: &TcpKey,
+        state: &mut ConnectedState,
+        frame: &TcpFrame<'_>,
+    ) -> Option<Arc<[u8]>> {
+        let flags = frame.flags();
+
+        if flags.rst() {
+            // RFC 5961, only trust resets at exactly the next expected sequence number
+            if frame.seq_num() == state.outgoing_ack_num {
+                debug!("Connection reset by peer: {:?}", tcp_key);
+                increment(&self.stats.estab_resets);
+                state.tx.send(Vec::new()).await;
+                state.state = ConnectionState::Closed;
+            }
+            return None;
+        }
+
+        if flags.syn() {
+            // RFC 5961 challenge ack, a peer that lost its state will answer with a reset
+            return Some(generate_tcp_frame(&generate_tcp_ack(tcp_key, state, &self.time)).into());
+        }
+
+        let ahead = frame.seq_num().wrapping_sub(state.outgoing_ack_num) as i32 > 0;
+        if ahead && !frame.payload().is_empty() && state.state.can_receive() {
+            // Held until the gap is filled, the duplicate ack tells the peer what is missing
+            state.reassembly.insert(
+                state.outgoing_ack_num,
+                frame.seq_num(),
+                frame.payload(),
+                state.receive_buffer.free(),
+            );
+            return Some(generate_tcp_frame(&generate_tcp_ack(tcp_key, state, &self.time)).into());
+        }
+
+        if state.outgoing_ack_num != frame.seq_num() {
+            debug!(
+                "ack num did not match seq num: {} {}",
+                state.outgoing_ack_num,
+                frame.seq_num()
+            );
+
+            // Retransmitted data or FIN (e.g. our ack was lost while in TIME_WAIT), or a zero
+            // window probe one byte before the window
+            let is_probe = frame.seq_num() == state.outgoing_ack_num.wrapping_sub(1);
+            if !frame.payload().is_empty() || flags.fin() || is_probe {
+                return Some(
+                    generate_tcp_frame(&generate_tcp_ack(tcp_key, state, &self.time)).into(),
+                );
+            }
+            return None;
+        }
+
+        let options = frame.options();
+        if let Some(timestamps) = options.timestamps {
+            state.ts_recent = timestamps.value;
+        }
+
+        let window_size = (frame.window_size() as u32) << state.options.send_window_shift;
+
+        // RFC 5681 duplicate ack, window updates and segments carrying data do not count
+        let is_dup_ack = frame.ack_num() == state.incoming_ack_num
+            && !state.unacknowledged.is_empty()
+            && frame.payload().is_empty()
+            && !flags.fin()
+            && window_size == state.window_size;
+        let flight_size = state.seq_num.wrapping_sub(state.incoming_ack_num) as usize;
+        if is_dup_ack {
+            state.dup_ack_counter = state.dup_ack_counter.saturating_add(1);
+            if state.recover.is_some() {
+                state.congestion.on_recovery_dup_ack();
+            } else if state.dup_ack_counter == Tcp::DUP_ACK_THRESHOLD {
+                debug!("Fast retransmit for {:?}", tcp_key);
+                state.congestion.on_loss(LossKind::DupAcks, flight_size);
+                state.recover = Some(state.seq_num);
+                state.fast_retransmit = true;
+            }
+        }
+
+        // Older acks can arrive reordered, they must neither move the window back nor count
+        // towards congestion control
+        let ack_acceptable = seq_le(state.incoming_ack_num, frame.ack_num())
+            && seq_le(frame.ack_num(), state.seq_num);
+        let newly_acked = frame.ack_num().wrapping_sub(state.incoming_ack_num) as usize;
+        let acks_new_data = ack_acceptable && seq_lt(state.incoming_ack_num, frame.ack_num());
+        if acks_new_data {
+            state.dup_ack_counter = 0;
+            match state.recover {
+                Some(recover) if seq_le(recover, frame.ack_num()) => {
+                    state.congestion.on_recovery_exit(flight_size - newly_acked);
+                    state.recover = None;
+                }
+                Some(_) => {
+                    state.congestion.on_partial_ack(newly_acked);
+                    state.fast_retransmit = true;
+                }
+                None => state.congestion.on_ack(newly_acked),
+            }
+            state.incoming_ack_num = frame.ack_num();
+        }
+
+        let mut newest_acked = None;
+        while let Some(unacked_packet) = state.unacknowledged.front() {
+            let end = unacked_packet
+                .params
+                .seq_num
+                .wrapping_add(segment_length(&unacked_packet.params));
+            if !seq_le(end, frame.ack_num()) {
+                break;
+            }
+            newest_acked = state.unacknowledged.pop_front();
+        }
+
+        if let Some(acked_packet) = newest_acked {
+            // RFC 7323 rtt measurement, echoed timestamps are unambiguous for retransmissions
+            let echo_reply = options
+                .timestamps
+                .filter(|timestamps| state.options.timestamps && timestamps.echo_reply != 0)
+                .map(|timestamps| timestamps.echo_reply);
+            match echo_reply {
+                Some(echo_reply) => {
+                    let rtt_ms = timestamp_ms(&self.time).wrapping_sub(echo_reply);
+                    state.rtt.sample(rtt_ms as f32 / 1000.0);
+                }
+                None if !acked_packet.retransmitted => {
+                    let rtt_ticks = self.time.get().saturating_sub(acked_packet.timestamp);
+                    state.rtt.sample(rtt_ticks as f32 / self.time.tick_freq());
+                }
+                None => (),
+            }
+
+            state.retransmit_count = 0;
+            state.retransmit_deadline = if state.unacknowledged.is_empty() {
+                None
+            } else {
+                Some(state.rtt.deadline(&self.time))
+            };
+        }
+
+        if state.options.sack {
+            for block in &options.sack_blocks {
+                for packet in &mut state.unacknowledged {
+                    let end = packet
+                        .params
+                        .seq_num
+                        .wrapping_add(segment_length(&packet.params));
+                    if seq_le(block.left, packet.params.seq_num) && seq_le(end, block.right) {
+                        packet.sacked = true;
+                    }
+                }
+            }
+        }
+
+        // RFC 793 only takes the window from segments at least as new as the last update
+        let window_update = seq_lt(state.window_update_seq, frame.seq_num())
+            || (state.window_update_seq == frame.seq_num()
+                && seq_le(state.window_update_ack, frame.ack_num()));
+        if ack_acceptable && window_update {
+            state.window_size = window_size;
+            state.window_update_seq = frame.seq_num();
+            state.window_update_ack = frame.ack_num();
+            if state.window_size > 0 {
+                state.persist_deadline = None;
+            }
+        }
+
+        let fin_acked = flags.ack()
+            && matches!(state.fin_seq, Some(seq) if frame.ack_num() == seq.wrapping_add(1));
+        if fin_acked {
+            state.state = match state.state {
+                ConnectionState::FinWait1 => ConnectionState::FinWait2 {
+                    expiry: self.time.get() + (Self::FIN_WAIT_2_S * self.time.tick_freq()) as usize,
+                },
+                ConnectionState::Closing => ConnectionState::TimeWait {
+                    expiry: self.time_wait_expiry(),
+                },
+                ConnectionState::LastAck => ConnectionState::Closed,
+                s => s,
+            };
+        }
+
+        let mut needs_ack = false;
+        let mut payload = frame.payload();
+        if !payload.is_empty() {
+            needs_ack = true;
+            if state.state.can_receive() {
+                // Trimmed to our window, the peer retransmits the rest
+                payload = &payload[..payload.len().min(state.receive_buffer.free())];
+                if !payload.is_empty() {
+                    state.receive_buffer.push(payload.len());
+                    state.tx.send(payload.to_vec()).await;
+                }
+            }
+            state.outgoing_ack_num = state.outgoing_ack_num.wrapping_add(payload.len() as u32);
+
+            while let Some(data) = state.reassembly.pop(state.outgoing_ack_num) {
+                let data = &data[..data.len().min(state.receive_buffer.free())];
+                if data.is_empty() {
+                    break;
+                }
+                state.receive_buffer.push(data.len());
+                state.tx.send(data.to_vec()).await;
+                state.outgoing_ack_num = state.outgoing_ack_num.wrapping_add(data.len() as u32);
+            }
+        }
+
+        // A FIN after trimmed data is outside the window, one before buffered data is bogus
+        let fin_seq = frame.seq_num().wrapping_add(frame.payload().len() as u32);
+        if flags.fin() && state.outgoing_ack_num == fin_seq {
+            needs_ack = true;
+            state.outgoing_ack_num = state.outgoing_ack_num.wrapping_add(1);
+            // EOF for the reader
+            state.tx.send(Vec::new()).await;
+            state.state = match state.state {
+                ConnectionState::Established => ConnectionState::CloseWait,
+                ConnectionState::FinWait1 => ConnectionState::Closing,
+                ConnectionState::FinWait2 { .. } => ConnectionState::TimeWait {
+                    expiry: self.time_wait_expiry(),
+                },
+                s => s,
+            };
+        }
+
+        if !needs_ack {
+            return None;
+        }
+
+        Some(generate_tcp_frame(&generate_tcp_ack(tcp_key, state, &self.time)).into())
+    }
+
+    pub async fn service(&self) -> OutgoingTcpPacket {
+        loop {
+            let event = OutgoingPoller {
+                tcp_states: &self.tcp_states,
+                time: &self.time,
+                waker: &self.service_waker,
+                registered_wakeup: &self.registered_wakeup,
+                stats: &self.stats,
+            }
+            .await;
+
+            match event {
+                ServiceEvent::Packet(packet) => return packet,
+                ServiceEvent::Wakeup(tick) => self.wakeup_list.register_wakeup_time(tick).await,
+                ServiceEvent::Abort(tx) => tx.send(Vec::new()).await,
+            }
+        }
+    }
+}
+
+enum ServiceEvent {
+    Packet(OutgoingTcpPacket),
+    // Wakeups are registered outside of the poller as registration is async
+    Wakeup(usize),
+    // Retransmissions were exhausted, the reader gets EOF
+    Abort(Sender<Vec<u8>>),
+}
+
+struct OutgoingPoller<'a> {
+    tcp_states: &'a Mutex<HashMap<TcpKey, TcpState>>,
+    time: &'a MonotonicTime,
+    waker: &'a AtomicCell<Waker>,
+    registered_wakeup: &'a AtomicUsize,
+    stats: &'a TcpStats,
+}
+
+impl Future for OutgoingPoller<'_> {
+    type Output = ServiceEvent;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.waker.store(cx.waker().clone());
+
+        let guard = core::pin::pin!(self.tcp_states.lock()).poll(cx);
+
+        let mut guard = match guard {
+            Poll::Ready(v) => v,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let now = self.time.get();
+        guard.retain(|_, tcp_state| !tcp_state.is_dead(now));
+
+        for (tcp_key, tcp_state) in &mut *guard {
+            match tcp_state {
+                TcpState::Connected(connection) => {
+                    if matches!(connection.retransmit_deadline, Some(deadline) if now >= deadline) {
+                        if connection.retransmit_count >= Tcp::MAX_RETRANSMITS {
+                            debug!("Retransmissions exhausted, aborting {:?}", tcp_key);
+                            increment(&self.stats.estab_resets);
+                            connection.state = ConnectionState::Closed;
+                            return Poll::Ready(ServiceEvent::Abort(connection.tx.clone()));
+                        }
+
+                        if connection.unacknowledged.is_empty() {
+                            connection.retransmit_deadline = None;
+                        } else {
+                            if connection.retransmit_count == 0 {
+                                let flight_size =
+                                    connection.seq_num.wrapping_sub(connection.incoming_ack_num)
+                                        as usize;
+                                connection
+                                    .congestion
+                                    .on_loss(LossKind::RetransmitTimeout, flight_size);
+                            }
+                            connection.recover = None;
+                            connection.dup_ack_counter = 0;
+                            connection.retransmit_count += 1;
+                            connection.rtt.backoff();
+                            connection.retransmit_deadline =
+                                Some(connection.rtt.deadline(self.time));
+
+                            if let Some(packet) = retransmit_front(tcp_key, connection, self.time) {
+                                increment(&self.stats.retrans_segs);
+                                return Poll::Ready(ServiceEvent::Packet(packet));
+                            }
+                        }
+                    }
+
+                    if connection.fast_retransmit {
+                        connection.fast_retransmit = false;
+                        if let Some(packet) = retransmit_front(tcp_key, connection, self.time) {
+                            increment(&self.stats.retrans_segs);
+                            return Poll::Ready(ServiceEvent::Packet(packet));
+                        }
+                    }
+
+                    if connection.recover.is_some() {
+                        if let Some(packet) = retransmit_sack_hole(tcp_key, connection, self.time) {
+                            increment(&self.stats.retrans_segs);
+                            return Poll::Ready(ServiceEvent::Packet(packet));
+                        }
+                    }
+
+                    if connection.receive_buffer.take_window_update() {
+                        let params = generate_tcp_ack(tcp_key, connection, self.time);
+                        return Poll::Ready(ServiceEvent::Packet(OutgoingTcpPacket {
+                            local_ip: tcp_key.local_ip,
+                            remote_ip: tcp_key.remote_ip,
+                            payload: generate_tcp_frame(&params).into(),
+                        }));
+                    }
+
+                    if matches!(connection.persist_deadline, Some(deadline) if now >= deadline) {
+                        connection.persist_interval_s =
+                            (connection.persist_interval_s * 2.0).min(RttEstimator::MAX_RTO_S);
+                        connection.persist_deadline = Some(
+                            now + (connection.persist_interval_s * self.time.tick_freq()) as usize,
+                        );
+
+                        // Already acked sequence space, the peer answers with its current window
+                        let mut params = generate_tcp_ack(tcp_key, connection, self.time);
+                        params.seq_num = params.seq_num.wrapping_sub(1);
+                        return Poll::Ready(ServiceEvent::Packet(OutgoingTcpPacket {
+                            local_ip: tcp_key.local_ip,
+                            remote_ip: tcp_key.remote_ip,
+                            payload: generate_tcp_frame(&params).into(),
+                        }));
+                    }
+
+                    let request = if let Some(data) = connection.to_send.pop_front() {
+                        WriteRequest::Data(data)
+                    } else if connection.write_closed {
+                        if connection.fin_seq.is_some() {
+                            continue;
+                        }
+                        WriteRequest::Shutdown
+                    } else if let Some(request) = poll_write_request(&connection.rx, cx) {
+                        request
+                    } else {
+                        continue;
+                    };
+
+                    let params = match request {
+                        WriteRequest::Data(data) => {
+                            let in_flight =
+                                connection.seq_num.wrapping_sub(connection.incoming_ack_num);
+                            let send_window =
+                                (connection.window_size as usize).min(connection.congestion.cwnd());
+                            let usable_window = send_window.saturating_sub(in_flight as usize);
+
+                            if usable_window == 0 {
+                                connection.to_send.push_front(data);
+                                if connection.unacknowledged.is_empty()
+                                    && connection.persist_deadline.is_none()
+                                {
+                                    connection.persist_interval_s = connection.rtt.rto_s;
+                                    connection.persist_deadline =
+                                        Some(connection.rtt.deadline(self.time));
+                                }
+                                continue;
+                            }
+
+                            let max_len = usable_window.min(connection.options.mss);
+                            let data = if data.len() > max_len {
+                                connection.to_send.push_front(data[max_len..].into());
+                                data[..max_len].into()
+                            } else {
+                                data
+                            };
+                            generate_tcp_push(tcp_key, connection, self.time, data)
+                        }
+                        WriteRequest::Shutdown => {
+                            connection.write_closed = true;
+                            generate_tcp_fin(tcp_key, connection, self.time)
+                        }
+                        // Filtered out by poll_write_request
+                        WriteRequest::WindowUpdate => continue,
+                    };
+
+                    return Poll::Ready(ServiceEvent::Packet(queue_outgoing_packet(
+                        tcp_key, connection, self.time, params,
+                    )));
+                }
+                TcpState::SynSent {
+                    needs_transmit,
+                    sent_frame,
+                    ..
+                } if *needs_transmit => {
+                    *needs_transmit = false;
+                    return Poll::Ready(ServiceEvent::Packet(sent_frame.clone()));
+                }
+                TcpState::SynAckSent {
+                    timeout, retries, ..
+                } if self.time.get() > *timeout && *retries >= Tcp::SYN_ACK_RETRIES => {
+                    debug!("Handshake timed out: {:?}", tcp_key);
+                    increment(&self.stats.attempt_fails);
+                    *tcp_state = TcpState::Uninit;
+                }
+                TcpState::SynAckSent {
+                    ref mut timeout,
+                    ref mut retries,
+                    sent_frame,
+                    ..
+                } if self.time.get() > *timeout => {
+                    *timeout += (self.time.tick_freq() * 1.0) as usize;
+                    *retries += 1;
+                    increment(&self.stats.retrans_segs);
+                    return Poll::Ready(ServiceEvent::Packet(sent_frame.clone()));
+                }
+                _ => (),
+            }
+        }
+
+        let next_wakeup = guard
+            .values()
+            .flat_map(|tcp_state| match tcp_state {
+                TcpState::Connected(connection) => {
+                    [connection.retransmit_deadline, connection.persist_deadline]
+                }
+                TcpState::SynAckSent { timeout, .. } => [Some(*timeout + 1), None],
+                _ => [None, None],
+            })
+            .flatten()
+            .min();
+
+        if let Some(next_wakeup) = next_wakeup {
+            if self.registered_wakeup.swap(next_wakeup, Ordering::Relaxed) != next_wakeup {
+                return Poll::Ready(ServiceEvent::Wakeup(next_wakeup));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+fn retransmit_front(
+    tcp_key: &TcpKey,
+    connection: &mut ConnectedState,
+    time: &MonotonicTime,
+) -> Option<OutgoingTcpPacket> {
+    retransmit(tcp_key, connection, time, 0)
+}
+
+/// RFC 6675 style loss recovery, segments below the highest SACKed one that were neither
+/// SACKed nor retransmitted yet are resent once
+fn retransmit_sack_hole(
+    tcp_key: &TcpKey,
+    connection: &mut ConnectedState,
+    time: &MonotonicTime,
+) -> Option<OutgoingTcpPacket> {
+    let highest_sacked = connection
+        .unacknowledged
+        .iter()
+        .rposition(|packet| packet.sacked)?;
+    let hole = connection
+        .unacknowledged
+        .iter()
+        .take(highest_sacked)
+        .position(|packet| !packet.sacked && !packet.retransmitted)?;
+
+    retransmit(tcp_key, connection, time, hole)
+}
+
+fn retransmit(
+    tcp_key: &TcpKey,
+    connection: &mut ConnectedState,
+    time: &MonotonicTime,
+    idx: usize,
+) -> Option<OutgoingTcpPacket> {
+    let payload_len = connection.unacknowledged.get(idx)?.params.payload.len();
+    let options = segment_options(connection, time, payload_len);
+    let packet = connection.unacknowledged.get_mut(idx)?;
+    packet.retransmitted = true;
+    packet.params.ack_num = connection.outgoing_ack_num;
+    packet.params.window_size = connection.receive_buffer.window();
+    packet.params.options = options;
+
+    Some(OutgoingTcpPacket {
+        local_ip: tcp_key.local_ip,
+        remote_ip: tcp_key.remote_ip,
+        payload: generate_tcp_frame(&packet.params).into(),
+    })
+}
+
+/// Window updates have already been handled by the time they are dequeued, they only exist to
+/// wake the service up
+fn poll_write_request(rx: &Receiver<WriteRequest>, cx: &mut Context<'_>) -> Option<WriteRequest> {
+    loop {
+        match core::pin::pin!(rx.recv()).poll(cx) {
+            Poll::Ready(WriteRequest::WindowUpdate) => (),
+            Poll::Ready(request) => return Some(request),
+            Poll::Pending => return None,
+        }
+    }
+}
+
+fn queue_outgoing_packet(
+    tcp_key: &TcpKey,
+    connected_state: &mut ConnectedState,
+    time: &MonotonicTime,
+    params: TcpFrameParams,
+) -> OutgoingTcpPacket {
+    let payload = generate_tcp_frame(&params).into();
+    connected_state.unacknowledged.push_back(UnackedPacket {
+        timestamp: time.get(),
+        retransmitted: false,
+        sacked: false,
+        params,
+    });
+    if connected_state.retransmit_deadline.is_none() {
+        connected_state.retransmit_deadline = Some(connected_state.rtt.deadline(time));
+    }
+
+    OutgoingTcpPacket {
+        local_ip: tcp_key.local_ip,
+        remote_ip: tcp_key.remote_ip,
+        payload,
+    }
+}
+
+#[derive(Clone)]
+pub struct OutgoingTcpPacket {
+    pub local_ip: IpAddr,
+    pub remote_ip: IpAddr,
+    pub payload: Arc<[u8]>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::{
+        firewall::{Action, Firewall},
+        icmp::Icmp,
+        icmpv6::Icmpv6,
+        ipv4::Ipv4,
+        ipv6,
+        loopback::Loopback,
+        udp::Udp,
+        NetDevice,
+    };
+    use crate::testing::*;
+    use crate::MonotonicTime;
+    use alloc::{
+        string::{String, ToString},
+        vec,
+    };
+    use options::SackBlock;
+
+    const CLIENT_IP: IpAddr = IpAddr::V4([192, 168, 2, 1]);
+    const SERVER_IP: IpAddr = IpAddr::V4([192, 168, 2, 2]);
+    const LOOPBACK_IP: IpAddr = IpAddr::V4(Loopback::IP);
+    const LOOPBACK_IPV6: IpAddr = IpAddr::V6(ipv6::LOOPBACK);
+
+    struct TcpFixture {
+        time: Arc<MonotonicTime>,
+        tcp: Tcp,
+    }
+
+    fn gen_fixture() -> TcpFixture {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+
+        let ipv4_config = UpdatedVal::new(Ipv4Config::new([192, 168, 2, 2], [255, 255, 255, 0]));
+        let ipv6_config = UpdatedVal::new(Ipv6Config::new(ipv6::LOOPBACK));
+        let tcp = Tcp::new(
+            ipv4_config,
+            ipv6_config,
+            Arc::clone(&time),
+            wakeup_list,
+            [1, 2],
+        );
+
+        TcpFixture { time, tcp }
+    }
+
+    // Also acts as the server for connections we actively open, in which case client_ip/port
+    // are the mock's own address and server_ip/port ours
+    struct MockClient {
+        client_ip: IpAddr,
+        server_ip: IpAddr,
+        client_port: u16,
+        server_port: u16,
+        window_size: u16,
+        seq: u32,
+        ack: u32,
+    }
+
+    impl MockClient {
+        fn syn(&mut self) -> Arc<[u8]> {
+            self.syn_with_options(TcpOptions::default())
+        }
+
+        fn syn_with_options(&mut self, options: TcpOptions) -> Arc<[u8]> {
+            let ret = generate_tcp_frame(&TcpFrameParams {
+                source_address: self.client_ip,
+                dest_address: self.server_ip,
+                source_port: self.client_port,
+                dest_port: self.server_port,
+                seq_num: self.seq,
+                // Syn should always have ack 0 as we have nothing to ack
+                ack_num: 0,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: false,
+                    psh: false,
+                    rst: false,
+                    syn: true,
+                    fin: false,
+                }),
+                window_size: self.window_size,
+                urgent_ptr: 0,
+                options,
+                payload: Arc::new([]),
+            })
+            .into();
+            self.seq = self.seq.wrapping_add(1);
+            ret
+        }
+
+        fn syn_ack(&mut self) -> Arc<[u8]> {
+            let ret = generate_tcp_frame(&TcpFrameParams {
+                source_address: self.client_ip,
+                dest_address: self.server_ip,
+                source_port: self.client_port,
+                dest_port: self.server_port,
+                seq_num: self.seq,
+                ack_num: self.ack,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: true,
+                    psh: false,
+                    rst: false,
+                    syn: true,
+                    fin: false,
+                }),
+                window_size: self.window_size,
+                urgent_ptr: 0,
+                options: TcpOptions::default(),
+                payload: Arc::new([]),
+            })
+            .into();
+            self.seq = self.seq.wrapping_add(1);
+            ret
+        }
+
+        fn ack(&self) -> Arc<[u8]> {
+            self.ack_with_options(TcpOptions::default())
+        }
+
+        fn ack_with_options(&self, options: TcpOptions) -> Arc<[u8]> {
+            generate_tcp_frame(&TcpFrameParams {
+                source_address: self.client_ip,
+                dest_address: self.server_ip,
+                source_port: self.client_port,
+                dest_port: self.server_port,
+                seq_num: self.seq,
+                ack_num: self.ack,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: true,
+                    psh: false,
+                    rst: false,
+                    syn: false,
+                    fin: false,
+                }),
+                window_size: self.window_size,
+                urgent_ptr: 0,
+                options,
+                payload: Arc::new([]),
+            })
+            .into()
+        }
+
+        fn push(&mut self, data: &[u8]) -> Arc<[u8]> {
+            let ret = generate_tcp_frame(&TcpFrameParams {
+                source_address: self.client_ip,
+                dest_address: self.server_ip,
+                source_port: self.client_port,
+                dest_port: self.server_port,
+                seq_num: self.seq,
+                ack_num: self.ack,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: true,
+                    psh: true,
+                    rst: false,
+                    syn: false,
+                    fin: false,
+                }),
+                window_size: self.window_size,
+                urgent_ptr: 0,
+                options: TcpOptions::default(),
+                payload: data.into(),
+            })
+            .into();
+            self.seq = self.seq.wrapping_add(data.len() as u32);
+            ret
+        }
+
+        async fn handshake(&mut self, fixture: &TcpFixture) -> Result<(), String> {
+            let syn = self.syn();
+
+            let syn_ack = match fixture
+                .tcp
+                .handle_frame(&TcpFrame::new(&syn), &self.client_ip, &self.server_ip)
+                .await
+            {
+                Some(v) => v,
+                None => {
+                    return Err("No syn ack for syn".into());
+                }
+            };
+
+            self.handle_frame(&syn_ack);
+
+            let ack = self.ack();
+
+            let response = fixture
+                .tcp
+                .handle_frame(&TcpFrame::new(&ack), &self.client_ip, &self.server_ip)
+                .await;
+
+            test_true!(response.is_none());
+
+            Ok(())
+        }
+
+        fn fin(&mut self) -> Arc<[u8]> {
+            let ret = generate_tcp_frame(&TcpFrameParams {
+                source_address: self.client_ip,
+                dest_address: self.server_ip,
+                source_port: self.client_port,
+                dest_port: self.server_port,
+                seq_num: self.seq,
+                ack_num: self.ack,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: true,
+                    psh: false,
+                    rst: false,
+                    syn: false,
+                    fin: true,
+                }),
+                window_size: self.window_size,
+                urgent_ptr: 0,
+                options: TcpOptions::default(),
+                payload: Arc::new([]),
+            })
+            .into();
+            self.seq = self.seq.wrapping_add(1);
+            ret
+        }
+
+        fn rst(&self) -> Arc<[u8]> {
+            generate_tcp_frame(&TcpFrameParams {
+                source_address: self.client_ip,
+                dest_address: self.server_ip,
+                source_port: self.client_port,
+                dest_port: self.server_port,
+                seq_num: self.seq,
+                ack_num: 0,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: false,
+                    psh: false,
+                    rst: true,
+                    syn: false,
+                    fin: false,
+                }),
+                window_size: self.window_size,
+                urgent_ptr: 0,
+                options: TcpOptions::default(),
+                payload: Arc::new([]),
+            })
+            .into()
+        }
+
+        /// How a closed port answers a SYN
+        fn rst_ack(&self) -> Arc<[u8]> {
+            generate_tcp_frame(&TcpFrameParams {
+                source_address: self.client_ip,
+                dest_address: self.server_ip,
+                source_port: self.client_port,
+                dest_port: self.server_port,
+                seq_num: 0,
+                ack_num: self.ack,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: true,
+                    psh: false,
+                    rst: true,
+                    syn: false,
+                    fin: false,
+                }),
+                window_size: 0,
+                urgent_ptr: 0,
+                options: TcpOptions::default(),
+                payload: Arc::new([]),
+            })
+            .into()
+        }
+
+        async fn send(&self, fixture: &TcpFixture, frame: &[u8]) -> Option<Arc<[u8]>> {
+            fixture
+                .tcp
+                .handle_frame(&TcpFrame::new(frame), &self.client_ip, &self.server_ip)
+                .await
+        }
+
+        fn handle_frame(&mut self, buf: &[u8]) {
+            let frame = TcpFrame::new(buf);
+            let seq = frame.seq_num();
+            let flags = frame.flags();
+
+            if flags.syn() {
+                self.ack = seq.wrapping_add(1);
+            } else if self.ack == seq {
+                let segment_length = frame.payload().len() as u32 + flags.fin() as u32;
+                self.ack = seq.wrapping_add(segment_length);
+            }
+        }
+    }
+
+    /// Whatever is available without blocking
+    async fn try_read(connection: &TcpConnection) -> Option<Vec<u8>> {
+        let mut buf = vec![0; ReceiveBuffer::CAPACITY];
+        let len = crate::future::poll_immediate(connection.read(&mut buf)).await?;
+        buf.truncate(len);
+        Some(buf)
+    }
+
+    fn new_mock_client(client_port: u16, server_port: u16) -> MockClient {
+        MockClient {
+            client_ip: CLIENT_IP,
+            server_ip: SERVER_IP,
+            client_port,
+            server_port,
+            window_size: 5000,
+            seq: 150,
+            ack: 0,
+        }
+    }
+
+    async fn connect_mock_client(
+        fixture: &TcpFixture,
+        server_port: u16,
+    ) -> Result<(MockClient, TcpConnection), String> {
+        let listener = fixture.tcp.listen(SERVER_IP, server_port).await;
+
+        let mut mock_client = MockClient {
+            client_ip: CLIENT_IP,
+            server_ip: SERVER_IP,
+            client_port: 1234,
+            server_port,
+            window_size: 5000,
+            seq: 150,
+            ack: 0,
+        };
+
+        mock_client.handshake(fixture).await?;
+
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        Ok((mock_client, connection))
+    }
+
+    /// Returns the outgoing SYN along with a mock server ready to answer it
+    async fn start_connect(
+        fixture: &TcpFixture,
+        connect: Pin<&mut impl Future<Output = Result<TcpConnection, ConnectError>>>,
+        server_port: u16,
+    ) -> Result<(MockClient, OutgoingTcpPacket), String> {
+        let pending = crate::future::poll_immediate(connect).await.is_none();
+        test_true!(pending);
+
+        let syn = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Syn not sent".to_string())?;
+        test_eq!(syn.local_ip, SERVER_IP);
+        test_eq!(syn.remote_ip, CLIENT_IP);
+
+        let syn_frame = TcpFrame::new(&syn.payload);
+        test_true!(syn_frame.flags().syn());
+        test_true!(!syn_frame.flags().ack());
+        test_eq!(syn_frame.dest_port(), server_port);
+
+        let mut mock_server = MockClient {
+            client_ip: CLIENT_IP,
+            server_ip: SERVER_IP,
+            client_port: server_port,
+            server_port: syn_frame.source_port(),
+            window_size: 5000,
+            seq: 300,
+            ack: 0,
+        };
+        mock_server.handle_frame(&syn.payload);
+
+        Ok((mock_server, syn))
+    }
+
+    create_test!(test_tcp_frame_parsing, {
+        const TCP_SYN: &[u8] = &[
+            0x80, 0xd8, 0x17, 0x70, 0x5a, 0x5b, 0x14, 0x47, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x02,
+            0xfa, 0xf0, 0x7e, 0xa4, 0x00, 0x00, 0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a,
+            0x41, 0xcf, 0x00, 0x5d, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+        ];
+
+        let frame = TcpFrame::new(TCP_SYN);
+        test_eq!(frame.source_port(), 32984);
+        test_eq!(frame.dest_port(), 6000);
+        test_eq!(frame.seq_num(), 1515918407);
+        test_eq!(frame.ack_num(), 0);
+        test_eq!(frame.data_offset_bytes(), 40);
+        test_eq!(frame.flags().0, 2);
+        test_eq!(frame.window_size(), 64240);
+        test_eq!(frame.checksum(), 0x7ea4);
+        test_eq!(frame.urgent_ptr(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_flags_back_and_forth, {
+        let flags = generate_tcp_flags(&TcpFlagsParams {
+            cwr: true,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: true,
+            syn: true,
+            fin: true,
+        });
+
+        test_eq!(flags.cwr(), true);
+        test_eq!(flags.ece(), false);
+        test_eq!(flags.urg(), false);
+        test_eq!(flags.ack(), true);
+        test_eq!(flags.psh(), false);
+        test_eq!(flags.rst(), true);
+        test_eq!(flags.syn(), true);
+        test_eq!(flags.fin(), true);
+
+        Ok(())
+    });
+
+    create_test!(test_dropped_syn_ack_ack, {
+        const TCP_SYN: &[u8] = b"\x89\x06\x27\x0f\xcc\x6b\x38\x32\x00\x00\x00\x00\xa0\x02\xfa\xf0\x22\xb5\x00\x00\x02\x04\x05\xb4\x04\x02\x08\x0a\xc3\x8b\x2c\xc7\x00\x00\x00\x00\x01\x03\x03\x07";
+        const TCP_ACK: &[u8] =
+            b"\x89\x06\x27\x0f\xcc\x6b\x38\x33\x00\x39\x84\x21\x50\x10\xfa\xf0\xf6\x80\x00\x00";
+        const SOURCE_IP: IpAddr = IpAddr::V4([192, 168, 2, 1]);
+        const DEST_IP: IpAddr = IpAddr::V4([192, 168, 2, 2]);
+
+        let fixture = gen_fixture();
+
+        let listener = fixture.tcp.listen(DEST_IP, 9999).await;
+
+        let frame = TcpFrame::new(TCP_SYN);
+        fixture.tcp.handle_frame(&frame, &SOURCE_IP, &DEST_IP).await;
+
+        // We should get a syn-ack response from the initial syn
+        if crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_some()
+        {
+            return Err("Unexpected serivce response".into());
+        }
+
+        // After 2 seconds we should have waited enough to trigger a syn-ack resend
+        fixture
+            .time
+            .set_tick((fixture.time.tick_freq() * 2.0) as usize);
+
+        let syn_ack = match crate::future::poll_immediate(fixture.tcp.service()).await {
+            Some(v) => v,
+            None => return Err("Syn ack retransmit missing".into()),
+        };
+
+        let syn_ack = TcpFrame::new(&syn_ack.payload);
+
+        test_true!(syn_ack.flags().syn());
+        test_true!(syn_ack.flags().ack());
+
+        // The captured ack with our sequence number patched in
+        let mut ack = TCP_ACK.to_vec();
+        ack[8..12].copy_from_slice(&syn_ack.seq_num().wrapping_add(1).to_be_bytes());
+        let frame = TcpFrame::new(&ack);
+        fixture.tcp.handle_frame(&frame, &SOURCE_IP, &DEST_IP).await;
+
+        if crate::future::poll_immediate(listener.connection())
+            .await
+            .is_none()
+        {
+            return Err("Connection not ready".into());
+        }
+
+        Ok(())
+    });
+
+    create_test!(test_syn_ack_ack_validated, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen(SERVER_IP, 80).await;
+
+        let mut client = new_mock_client(1000, 80);
+        let syn_ack = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&client.syn()), &CLIENT_IP, &SERVER_IP)
+            .await
+            .ok_or("No syn ack".to_string())?;
+        client.handle_frame(&syn_ack);
+
+        // Someone who never saw the SYN-ACK has to guess our ISN
+        let expected_ack = client.ack;
+        client.ack = expected_ack.wrapping_add(1000);
+        let response = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&client.ack()), &CLIENT_IP, &SERVER_IP)
+            .await;
+        test_true!(response
+            .as_ref()
+            .is_some_and(|frame| TcpFrame::new(frame).flags().rst()));
+        test_true!(crate::future::poll_immediate(listener.connection())
+            .await
+            .is_none());
+
+        client.ack = expected_ack;
+        let response = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&client.ack()), &CLIENT_IP, &SERVER_IP)
+            .await;
+        test_true!(response.is_none());
+        test_true!(crate::future::poll_immediate(listener.connection())
+            .await
+            .is_some());
+
+        Ok(())
+    });
+
+    create_test!(test_handshake_ack_with_data, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen(SERVER_IP, 80).await;
+
+        let mut client = new_mock_client(1000, 80);
+        let syn_ack = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&client.syn()), &CLIENT_IP, &SERVER_IP)
+            .await
+            .ok_or("No syn ack".to_string())?;
+        client.handle_frame(&syn_ack);
+
+        // The SYN-ACK ack was lost, the first data segment completes the handshake
+        let push = client.push(b"hello");
+        let ack = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&push), &CLIENT_IP, &SERVER_IP)
+            .await
+            .ok_or("Data not acked".to_string())?;
+        test_eq!(TcpFrame::new(&ack).ack_num(), client.seq);
+
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+        test_eq!(try_read(&connection).await, Some(b"hello".to_vec()));
+
+        Ok(())
+    });
+
+    create_test!(test_handshake_seq_wraps, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen(SERVER_IP, 80).await;
+
+        let mut client = new_mock_client(1000, 80);
+        client.seq = u32::MAX;
+        let syn_ack = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&client.syn()), &CLIENT_IP, &SERVER_IP)
+            .await
+            .ok_or("No syn ack".to_string())?;
+        test_eq!(TcpFrame::new(&syn_ack).ack_num(), 0);
+        client.handle_frame(&syn_ack);
+
+        fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&client.ack()), &CLIENT_IP, &SERVER_IP)
+            .await;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        let push = client.push(b"hello");
+        fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&push), &CLIENT_IP, &SERVER_IP)
+            .await;
+        test_eq!(try_read(&connection).await, Some(b"hello".to_vec()));
+
+        Ok(())
+    });
+
+    create_test!(test_syn_ack_retries, {
+        let fixture = gen_fixture();
+        let _listener = fixture.tcp.listen(SERVER_IP, 80).await;
+
+        let syn = new_mock_client(1000, 80).syn();
+        let response = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&syn), &CLIENT_IP, &SERVER_IP)
+            .await;
+        test_true!(response.is_some());
+
+        // Resent once a second
+        for retry in 1..=Tcp::SYN_ACK_RETRIES as usize {
+            fixture
+                .time
+                .set_tick(retry * fixture.time.tick_freq() as usize + 1);
+            let syn_ack = crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or("Syn ack retransmit missing".to_string())?;
+            test_true!(TcpFrame::new(&syn_ack.payload).flags().syn());
+        }
+
+        // Then the half-open connection is given up on
+        let last_retry = Tcp::SYN_ACK_RETRIES as usize + 1;
+        fixture
+            .time
+            .set_tick(last_retry * fixture.time.tick_freq() as usize + 1);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_true!(fixture.tcp.tcp_states.lock().await.is_empty());
+
+        Ok(())
+    });
+
+    create_test!(test_syn_flood_cookies, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen(SERVER_IP, 80).await;
+
+        for client_port in 1000..1000 + Tcp::MAX_HALF_OPEN as u16 {
+            let syn = new_mock_client(client_port, 80).syn();
+            let response = fixture
+                .tcp
+                .handle_frame(&TcpFrame::new(&syn), &CLIENT_IP, &SERVER_IP)
+                .await;
+            test_true!(response.is_some());
+        }
+        test_eq!(
+            fixture.tcp.tcp_states.lock().await.len(),
+            Tcp::MAX_HALF_OPEN
+        );
+
+        // Past the limit SYNs are still answered, but without allocating anything
+        let mut client = new_mock_client(2000, 80);
+        let syn = client.syn_with_options(TcpOptions {
+            mss: Some(1200),
+            ..Default::default()
+        });
+        let syn_ack = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&syn), &CLIENT_IP, &SERVER_IP)
+            .await
+            .ok_or("No syn ack with cookie".to_string())?;
+        test_eq!(
+            fixture.tcp.tcp_states.lock().await.len(),
+            Tcp::MAX_HALF_OPEN
+        );
+        client.handle_frame(&syn_ack);
+
+        // The cookie is bound to the connection it was sent on
+        let mut forged = new_mock_client(2001, 80);
+        forged.seq = client.seq;
+        forged.ack = client.ack;
+        let response = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&forged.ack()), &CLIENT_IP, &SERVER_IP)
+            .await;
+        test_true!(response
+            .as_ref()
+            .is_some_and(|frame| TcpFrame::new(frame).flags().rst()));
+
+        let response = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&client.ack()), &CLIENT_IP, &SERVER_IP)
+            .await;
+        test_true!(response.is_none());
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection with cookie not accepted".to_string())?;
+
+        let tcp_key = TcpKey {
+            remote_ip: CLIENT_IP,
+            local_ip: SERVER_IP,
+            remote_port: 2000,
+            local_port: 80,
+        };
+        match fixture.tcp.tcp_states.lock().await.get(&tcp_key) {
+            Some(TcpState::Connected(state)) => test_eq!(state.options.mss, 1200),
+            _ => return Err("Connection with cookie has no state".into()),
+        }
+
+        let push = client.push(b"hello");
+        fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&push), &CLIENT_IP, &SERVER_IP)
+            .await;
+        test_eq!(try_read(&connection).await, Some(b"hello".to_vec()));
+
+        // Without cookies they are dropped instead
+        fixture.tcp.set_syn_cookies(false);
+        let syn = new_mock_client(2002, 80).syn();
+        let response = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&syn), &CLIENT_IP, &SERVER_IP)
+            .await;
+        test_true!(response.is_none());
+
+        Ok(())
+    });
+
+    create_test!(test_syn_cookie_ack_with_data, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen(SERVER_IP, 80).await;
+
+        for client_port in 1000..1000 + Tcp::MAX_HALF_OPEN as u16 {
+            let syn = new_mock_client(client_port, 80).syn();
+            fixture
+                .tcp
+                .handle_frame(&TcpFrame::new(&syn), &CLIENT_IP, &SERVER_IP)
+                .await;
+        }
+
+        let mut client = new_mock_client(2000, 80);
+        let syn_ack = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&client.syn()), &CLIENT_IP, &SERVER_IP)
+            .await
+            .ok_or("No syn ack with cookie".to_string())?;
+        client.handle_frame(&syn_ack);
+
+        // A pushed segment can be the first to echo the cookie
+        let push = client.push(b"hello");
+        let ack = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&push), &CLIENT_IP, &SERVER_IP)
+            .await
+            .ok_or("Data not acked".to_string())?;
+        test_false!(TcpFrame::new(&ack).flags().rst());
+        test_eq!(TcpFrame::new(&ack).ack_num(), client.seq);
+
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection with cookie not accepted".to_string())?;
+        test_eq!(try_read(&connection).await, Some(b"hello".to_vec()));
+
+        Ok(())
+    });
+
+    create_test!(test_listen_backlog, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen_with_backlog(SERVER_IP, 80, 1).await;
+
+        new_mock_client(1000, 80).handshake(&fixture).await?;
+
+        // Nothing new is accepted until the queued connection is taken
+        let syn = new_mock_client(1001, 80).syn();
+        let response = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&syn), &CLIENT_IP, &SERVER_IP)
+            .await;
+        test_true!(response.is_none());
+
+        test_true!(crate::future::poll_immediate(listener.connection())
+            .await
+            .is_some());
+        new_mock_client(1001, 80).handshake(&fixture).await?;
+        test_true!(crate::future::poll_immediate(listener.connection())
+            .await
+            .is_some());
+
+        Ok(())
+    });
+
+    create_test!(test_dup_ack_retransmission, {
+        const CLIENT_PORT: u16 = 1234;
+        const SERVER_PORT: u16 = 5678;
+
+        let fixture = gen_fixture();
+
+        let listener = fixture.tcp.listen(SERVER_IP, SERVER_PORT).await;
+
+        let mut mock_client = MockClient {
+            client_ip: CLIENT_IP,
+            server_ip: SERVER_IP,
+            client_port: CLIENT_PORT,
+            server_port: SERVER_PORT,
+            window_size: 5000,
+            seq: 150,
+            ack: 0,
+        };
+
+        mock_client.handshake(&fixture).await?;
+
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        test_ok!(connection.write_all(b"hello world").await);
+        test_ok!(connection.write_all(b"hello world 2").await);
+
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("tcp service did not return a value".to_string())?;
+
+        mock_client.handle_frame(&frame.payload);
+
+        let frame = TcpFrame::new(&frame.payload);
+        test_eq!(frame.payload(), b"hello world");
+
+        let data1_ack = mock_client.ack();
+
+        let response = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(&data1_ack), &CLIENT_IP, &SERVER_IP)
+            .await;
+
+        test_true!(response.is_none());
+
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("tcp service did not return a value".to_string())?;
+
+        let frame = TcpFrame::new(&frame.payload);
+        test_eq!(frame.payload(), b"hello world 2");
+
+        // Intentionally do not inform the mock of the second frame
+
+        // After we've sent first two packets, nothing to do
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        for _ in 0..3 {
+            // ACK first segment 3 more times
+            let response = fixture
+                .tcp
+                .handle_frame(&TcpFrame::new(&data1_ack), &CLIENT_IP, &SERVER_IP)
+                .await;
+            test_true!(response.is_none());
+        }
+
+        // 3 duplicate acks, retransmission please
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("No retransmission".to_string())?;
+        test_eq!(TcpFrame::new(&frame.payload).payload(), b"hello world 2");
+
+        let info = fixture.tcp.connections().await;
+        test_eq!(info.len(), 1);
+        test_eq!(info[0].ssthresh, 2 * DEFAULT_MSS);
+        test_eq!(info[0].cwnd, 5 * DEFAULT_MSS);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_send_window, {
+        let fixture = gen_fixture();
+        let (mut mock_client, connection) = connect_mock_client(&fixture, 80).await?;
+
+        mock_client.window_size = 5;
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+
+        test_ok!(connection.write_all(b"hello world").await);
+        let push = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Data not sent".to_string())?;
+        test_eq!(TcpFrame::new(&push.payload).payload(), b"hello");
+        mock_client.handle_frame(&push.payload);
+
+        // Window is full until the peer acks
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+        let push = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Data not sent after ack".to_string())?;
+        test_eq!(TcpFrame::new(&push.payload).payload(), b" worl");
+        mock_client.handle_frame(&push.payload);
+
+        // Peer's window closes, the remaining byte has to wait for a probe to find it open
+        mock_client.window_size = 0;
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        fixture
+            .time
+            .set_tick((RttEstimator::MAX_RTO_S * fixture.time.tick_freq()) as usize);
+        let probe = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("No zero window probe".to_string())?;
+        let probe = TcpFrame::new(&probe.payload);
+        test_true!(probe.payload().is_empty());
+        test_eq!(probe.seq_num(), mock_client.ack.wrapping_sub(1));
+
+        // Our probe handling answers with the current window
+        let probe_ack = MockClient {
+            seq: mock_client.seq.wrapping_sub(1),
+            ..mock_client
+        }
+        .ack();
+        let probe_response = mock_client
+            .send(&fixture, &probe_ack)
+            .await
+            .ok_or("Zero window probe not answered".to_string())?;
+        test_eq!(TcpFrame::new(&probe_response).seq_num(), mock_client.ack);
+
+        mock_client.window_size = 5;
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+        let push = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Data not sent after window opened".to_string())?;
+        test_eq!(TcpFrame::new(&push.payload).payload(), b"d");
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_stale_ack, {
+        let fixture = gen_fixture();
+        let (mut mock_client, connection) = connect_mock_client(&fixture, 80).await?;
+
+        mock_client.window_size = u16::MAX;
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+
+        let segment = [0u8; DEFAULT_MSS];
+        test_ok!(connection.write_all(&segment).await);
+        test_ok!(connection.write_all(&segment).await);
+        let push = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Data not sent".to_string())?;
+        mock_client.handle_frame(&push.payload);
+        // Delayed in the network, overtaken by the ack below
+        let stale_ack = MockClient {
+            window_size: 0,
+            ..mock_client
+        }
+        .ack();
+
+        let push = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Data not sent".to_string())?;
+        mock_client.handle_frame(&push.payload);
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+
+        let snapshot = |tcp_states: &HashMap<TcpKey, TcpState>| {
+            tcp_states.values().find_map(|state| match state {
+                TcpState::Connected(state) => Some((
+                    state.incoming_ack_num,
+                    state.window_size,
+                    state.congestion.cwnd(),
+                )),
+                _ => None,
+            })
+        };
+        let before = snapshot(&*fixture.tcp.tcp_states.lock().await);
+        test_true!(mock_client.send(&fixture, &stale_ack).await.is_none());
+        let after = snapshot(&*fixture.tcp.tcp_states.lock().await);
+        test_true!(before.is_some());
+        test_eq!(before, after);
+
+        // The window did not close, new data goes out straight away
+        test_ok!(connection.write_all(b"more").await);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_some());
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_congestion_window, {
+        let fixture = gen_fixture();
+        let (mut mock_client, connection) = connect_mock_client(&fixture, 80).await?;
+
+        mock_client.window_size = u16::MAX;
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+
+        let segment = [0u8; DEFAULT_MSS];
+        for _ in 0..10 {
+            test_ok!(connection.write_all(&segment).await);
+        }
+
+        // Initial window is 4 segments even though the peer advertises more
+        for _ in 0..4 {
+            let push = crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or("Data not sent".to_string())?;
+            mock_client.handle_frame(&push.payload);
+        }
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        // A cumulative ack opens up the window by one segment in slow start
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+        for _ in 0..5 {
+            crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or("Data not sent after ack".to_string())?;
+        }
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_option_negotiation, {
+        // Linux SYN offering MSS 1460, SACK, timestamps and a window shift of 7
+        const TCP_SYN: &[u8] = &[
+            0x80, 0xd8, 0x17, 0x70, 0x5a, 0x5b, 0x14, 0x47, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x02,
+            0xfa, 0xf0, 0x7e, 0xa4, 0x00, 0x00, 0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a,
+            0x41, 0xcf, 0x00, 0x5d, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+        ];
+        const PEER_TS: u32 = 0x41cf005d;
+
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen(SERVER_IP, 6000).await;
+
+        let syn_ack = fixture
+            .tcp
+            .handle_frame(&TcpFrame::new(TCP_SYN), &CLIENT_IP, &SERVER_IP)
+            .await
+            .ok_or("No syn ack".to_string())?;
+        let syn_ack_options = TcpFrame::new(&syn_ack).options();
+        test_eq!(syn_ack_options.mss, Some(local_mss(&SERVER_IP) as u16));
+        test_eq!(
+            syn_ack_options.window_scale,
+            Some(ReceiveBuffer::WINDOW_SHIFT)
+        );
+        test_true!(syn_ack_options.sack_permitted);
+        test_eq!(
+            syn_ack_options.timestamps.map(|ts| ts.echo_reply),
+            Some(PEER_TS)
+        );
+
+        let mut mock_client = MockClient {
+            client_ip: CLIENT_IP,
+            server_ip: SERVER_IP,
+            client_port: 32984,
+            server_port: 6000,
+            window_size: 5000,
+            seq: 1515918408,
+            ack: 0,
+        };
+        mock_client.handle_frame(&syn_ack);
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        match fixture.tcp.tcp_states.lock().await.values().next() {
+            Some(TcpState::Connected(state)) => {
+                test_eq!(state.window_size, 5000 << 7);
+                test_eq!(state.options.mss, local_mss(&SERVER_IP) - TIMESTAMPS_LEN);
+            }
+            _ => return Err("Not connected".into()),
+        }
+
+        // Writes are split at the MSS, which leaves room for the timestamps
+        test_ok!(connection.write_all(&[0u8; 2000]).await);
+        let push = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Data not sent".to_string())?;
+        let push = TcpFrame::new(&push.payload);
+        test_eq!(push.payload().len(), local_mss(&SERVER_IP) - TIMESTAMPS_LEN);
+        test_eq!(
+            push.options().timestamps.map(|ts| ts.echo_reply),
+            Some(PEER_TS)
+        );
+
+        let push = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Remainder not sent".to_string())?;
+        test_eq!(
+            TcpFrame::new(&push.payload).payload().len(),
+            2000 - (local_mss(&SERVER_IP) - TIMESTAMPS_LEN)
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_peer_mss_clamped, {
+        let timestamps = Some(Timestamps {
+            value: 1,
+            echo_reply: 0,
+        });
+        for (mss, timestamps, expected) in [
+            (0, None, MIN_MSS),
+            (4, timestamps, MIN_MSS - TIMESTAMPS_LEN),
+            (9000, None, local_mss(&SERVER_IP)),
+        ] {
+            let peer_syn = TcpOptions {
+                mss: Some(mss),
+                timestamps,
+                ..Default::default()
+            };
+            let options = NegotiatedOptions::from_syn(&peer_syn, &SERVER_IP);
+            test_eq!(options.mss, expected);
+        }
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_mss_segmentation, {
+        let fixture = gen_fixture();
+        let (_, connection) = connect_mock_client(&fixture, 80).await?;
+
+        // Peer announced no MSS, the RFC 1122 default applies
+        test_ok!(connection.write_all(&[0u8; 1200]).await);
+        for expected_len in [DEFAULT_MSS, DEFAULT_MSS, 1200 - 2 * DEFAULT_MSS] {
+            let push = crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or("Data not sent".to_string())?;
+            test_eq!(TcpFrame::new(&push.payload).payload().len(), expected_len);
+        }
+
+        Ok(())
+    });
+
+    create_test!(test_sack_retransmits_holes, {
+        const MSS: u32 = 100;
+
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen(SERVER_IP, 80).await;
+        let mut mock_client = MockClient {
+            client_ip: CLIENT_IP,
+            server_ip: SERVER_IP,
+            client_port: 1234,
+            server_port: 80,
+            window_size: 5000,
+            seq: 150,
+            ack: 0,
+        };
+
+        let syn = mock_client.syn_with_options(TcpOptions {
+            mss: Some(MSS as u16),
+            sack_permitted: true,
+            ..Default::default()
+        });
+        let syn_ack = mock_client
+            .send(&fixture, &syn)
+            .await
+            .ok_or("No syn ack".to_string())?;
+        test_true!(TcpFrame::new(&syn_ack).options().sack_permitted);
+        mock_client.handle_frame(&syn_ack);
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        let base = mock_client.ack;
+        test_ok!(connection.write_all(&[0u8; 6 * MSS as usize]).await);
+
+        // Initial window of 4 segments, the ack for the first one lets 2 more out
+        for _ in 0..4 {
+            crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or("Data not sent".to_string())?;
+        }
+        mock_client.ack = base + MSS;
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+        for _ in 0..2 {
+            crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or("Data not sent after ack".to_string())?;
+        }
+
+        // Segments 2 and 4 are lost, 3, 5 and 6 arrive and each triggers a duplicate ack
+        let segment = |n: u32| SackBlock {
+            left: base + (n - 1) * MSS,
+            right: base + n * MSS,
+        };
+        let sack_blocks = [
+            vec![segment(3)],
+            vec![segment(5), segment(3)],
+            vec![
+                SackBlock {
+                    left: segment(5).left,
+                    right: segment(6).right,
+                },
+                segment(3),
+            ],
+        ];
+        for sack_blocks in sack_blocks {
+            let dup_ack = mock_client.ack_with_options(TcpOptions {
+                sack_blocks,
+                ..Default::default()
+            });
+            test_true!(mock_client.send(&fixture, &dup_ack).await.is_none());
+        }
+
+        for lost in [2, 4] {
+            let retransmit = crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or("Missing retransmission".to_string())?;
+            test_eq!(
+                TcpFrame::new(&retransmit.payload).seq_num(),
+                segment(lost).left
+            );
+        }
+
+        // Everything else was SACKed
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_receive_window, {
+        const HALF: usize = ReceiveBuffer::CAPACITY / 2;
+
+        let fixture = gen_fixture();
+        let (mut mock_client, connection) = connect_mock_client(&fixture, 80).await?;
+
+        let push = mock_client.push(&[1; HALF]);
+        let ack = mock_client
+            .send(&fixture, &push)
+            .await
+            .ok_or("No ack for data".to_string())?;
+        test_eq!(TcpFrame::new(&ack).window_size() as usize, HALF);
+
+        // Only what fits in the window is accepted
+        let push = mock_client.push(&[2; ReceiveBuffer::CAPACITY]);
+        let ack = mock_client
+            .send(&fixture, &push)
+            .await
+            .ok_or("No ack for data".to_string())?;
+        let ack = TcpFrame::new(&ack);
+        test_eq!(ack.window_size(), 0);
+        test_eq!(
+            ack.ack_num(),
+            mock_client
+                .seq
+                .wrapping_sub((ReceiveBuffer::CAPACITY - HALF) as u32)
+        );
+
+        // Reading frees up space, the peer is told about it
+        let data = try_read(&connection).await.ok_or("No data".to_string())?;
+        test_eq!(data.len(), HALF);
+        let update = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("No window update".to_string())?;
+        let update = TcpFrame::new(&update.payload);
+        test_true!(update.payload().is_empty());
+        test_eq!(update.window_size() as usize, HALF);
+
+        let data = try_read(&connection).await.ok_or("No data".to_string())?;
+        test_eq!(data.as_slice(), &[2; HALF]);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_out_of_order_reassembly, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen(SERVER_IP, 80).await;
+        let mut mock_client = MockClient {
+            client_ip: CLIENT_IP,
+            server_ip: SERVER_IP,
+            client_port: 1234,
+            server_port: 80,
+            window_size: 5000,
+            seq: 150,
+            ack: 0,
+        };
+
+        let syn = mock_client.syn_with_options(TcpOptions {
+            sack_permitted: true,
+            ..Default::default()
+        });
+        let syn_ack = mock_client
+            .send(&fixture, &syn)
+            .await
+            .ok_or("No syn ack".to_string())?;
+        mock_client.handle_frame(&syn_ack);
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        let expected_seq = mock_client.seq;
+        let first = mock_client.push(b"hello ");
+        let second = mock_client.push(b"byte ");
+        let third = mock_client.push(b"stream");
+
+        // Held back, the duplicate ack reports what we have
+        let dup_ack = mock_client
+            .send(&fixture, &third)
+            .await
+            .ok_or("No ack for out of order data".to_string())?;
+        let dup_ack = TcpFrame::new(&dup_ack);
+        test_eq!(dup_ack.ack_num(), expected_seq);
+        test_eq!(
+            dup_ack.options().sack_blocks,
+            vec![SackBlock {
+                left: expected_seq + 11,
+                right: mock_client.seq,
+            }]
+        );
+        test_true!(try_read(&connection).await.is_none());
+
+        // Filling the gap delivers everything up to the buffered segment
+        let ack = mock_client
+            .send(&fixture, &first)
+            .await
+            .ok_or("No ack for data".to_string())?;
+        test_eq!(TcpFrame::new(&ack).ack_num(), expected_seq + 6);
+        let ack = mock_client
+            .send(&fixture, &second)
+            .await
+            .ok_or("No ack for data".to_string())?;
+        let ack = TcpFrame::new(&ack);
+        test_eq!(ack.ack_num(), mock_client.seq);
+        test_true!(ack.options().sack_blocks.is_empty());
+
+        let mut received = [0; 17];
+        test_ok!(connection.read_exact(&mut received).await);
+        test_eq!(&received, b"hello byte stream");
+
+        Ok(())
+    });
+
+    create_test!(test_sack_blocks_fit_mss, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen(SERVER_IP, 80).await;
+        let mut mock_client = MockClient {
+            client_ip: CLIENT_IP,
+            server_ip: SERVER_IP,
+            client_port: 1234,
+            server_port: 80,
+            window_size: 5000,
+            seq: 150,
+            ack: 0,
+        };
+
+        let syn = mock_client.syn_with_options(TcpOptions {
+            sack_permitted: true,
+            ..Default::default()
+        });
+        let syn_ack = mock_client
+            .send(&fixture, &syn)
+            .await
+            .ok_or("No syn ack".to_string())?;
+        mock_client.handle_frame(&syn_ack);
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        // A gap in the received data, every segment we send has a block to report
+        mock_client.push(b"lost");
+        let held = mock_client.push(b"held back");
+        test_true!(mock_client.send(&fixture, &held).await.is_some());
+
+        // The full sized segment has no room left for the block, the remainder does
+        test_ok!(connection.write_all(&[0u8; DEFAULT_MSS + 10]).await);
+        let push = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Data not sent".to_string())?;
+        let push = TcpFrame::new(&push.payload);
+        test_eq!(push.payload().len(), DEFAULT_MSS);
+        test_true!(push.options().sack_blocks.is_empty());
+
+        let push = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Remainder not sent".to_string())?;
+        let push = TcpFrame::new(&push.payload);
+        test_eq!(push.payload().len(), 10);
+        test_eq!(push.options().sack_blocks.len(), 1);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_partial_reads, {
+        let fixture = gen_fixture();
+        let (mut mock_client, connection) = connect_mock_client(&fixture, 80).await?;
+
+        let push = mock_client.push(b"GET / HTTP/1.1\r\nHost: kernel\r\n\r\n");
+        test_true!(mock_client.send(&fixture, &push).await.is_some());
+
+        // A segment is consumed across reads
+        let mut line = Vec::new();
+        test_eq!(connection.read_until(b'\n', &mut line).await, 16);
+        test_eq!(line.as_slice(), b"GET / HTTP/1.1\r\n");
+
+        let mut buf = [0; 4];
+        test_eq!(connection.read(&mut buf).await, 4);
+        test_eq!(&buf, b"Host");
+
+        let rest = try_read(&connection)
+            .await
+            .ok_or("Rest of the segment missing".to_string())?;
+        test_eq!(rest.as_slice(), b": kernel\r\n\r\n");
+
+        // Writes fail once our side is shut down
+        connection.shutdown(Shutdown::Write).await;
+        test_eq!(
+            connection.write(b"late").await,
+            Err::<usize, _>(IoError::Closed)
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_passive_close, {
+        let fixture = gen_fixture();
+        let (mut mock_client, connection) = connect_mock_client(&fixture, 80).await?;
+
+        let fin = mock_client.fin();
+        let fin_ack = mock_client
+            .send(&fixture, &fin)
+            .await
+            .ok_or("No ack for fin".to_string())?;
+        let fin_ack = TcpFrame::new(&fin_ack);
+        test_true!(fin_ack.flags().ack());
+        test_eq!(fin_ack.ack_num(), mock_client.seq);
+
+        // EOF, and it stays that way
+        for _ in 0..2 {
+            let data = try_read(&connection)
+                .await
+                .ok_or("Read did not return EOF".to_string())?;
+            test_true!(data.is_empty());
+        }
+
+        // We can still write in CLOSE_WAIT, the FIN follows the data
+        test_ok!(connection.write_all(b"bye").await);
+        connection.close().await;
+
+        let data = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Data not sent".to_string())?;
+        mock_client.handle_frame(&data.payload);
+        test_eq!(TcpFrame::new(&data.payload).payload(), b"bye");
+
+        let fin = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Fin not sent".to_string())?;
+        mock_client.handle_frame(&fin.payload);
+        test_true!(TcpFrame::new(&fin.payload).flags().fin());
+
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+
+        // LAST_ACK -> CLOSED, anything else for this connection is reset
+        let reset = mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .ok_or("No reset for closed connection".to_string())?;
+        test_true!(TcpFrame::new(&reset).flags().rst());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_active_close_time_wait, {
+        let fixture = gen_fixture();
+        let (mut mock_client, connection) = connect_mock_client(&fixture, 80).await?;
+
+        connection.shutdown(Shutdown::Write).await;
+        let fin = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Fin not sent".to_string())?;
+        test_true!(TcpFrame::new(&fin.payload).flags().fin());
+        mock_client.handle_frame(&fin.payload);
+
+        // FIN_WAIT_1 -> FIN_WAIT_2
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+
+        // Peer can keep sending until it closes its side
+        let push = mock_client.push(b"late data");
+        test_true!(mock_client.send(&fixture, &push).await.is_some());
+        let data = try_read(&connection)
+            .await
+            .ok_or("No data in FIN_WAIT_2".to_string())?;
+        test_eq!(data.as_slice(), b"late data");
+
+        let fin = mock_client.fin();
+        let fin_ack = mock_client
+            .send(&fixture, &fin)
+            .await
+            .ok_or("No ack for fin".to_string())?;
+        test_eq!(TcpFrame::new(&fin_ack).ack_num(), mock_client.seq);
+        let data = try_read(&connection)
+            .await
+            .ok_or("Read did not return EOF".to_string())?;
+        test_true!(data.is_empty());
+
+        // A retransmitted FIN in TIME_WAIT is acked again
+        test_true!(mock_client.send(&fixture, &fin).await.is_some());
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 1);
+
+        fixture
+            .time
+            .set_tick(((Tcp::TIME_WAIT_S + 1.0) * fixture.time.tick_freq()) as usize);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_orphaned_fin_wait_2, {
+        let fixture = gen_fixture();
+        let (mut mock_client, connection) = connect_mock_client(&fixture, 80).await?;
+
+        connection.shutdown(Shutdown::Write).await;
+        let fin = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Fin not sent".to_string())?;
+        mock_client.handle_frame(&fin.payload);
+        test_true!(mock_client
+            .send(&fixture, &mock_client.ack())
+            .await
+            .is_none());
+
+        // The peer never sends its FIN, but the application can still read
+        fixture
+            .time
+            .set_tick(((Tcp::FIN_WAIT_2_S + 1.0) * fixture.time.tick_freq()) as usize);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 1);
+
+        drop(connection);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_reset, {
+        let fixture = gen_fixture();
+
+        // Nobody listening
+        let mut closed_port_client = MockClient {
+            client_ip: CLIENT_IP,
+            server_ip: SERVER_IP,
+            client_port: 1234,
+            server_port: 81,
+            window_size: 5000,
+            seq: 150,
+            ack: 0,
+        };
+        let syn = closed_port_client.syn();
+        let reset = closed_port_client
+            .send(&fixture, &syn)
+            .await
+            .ok_or("No reset for syn to closed port".to_string())?;
+        let reset = TcpFrame::new(&reset);
+        test_true!(reset.flags().rst());
+        test_true!(reset.flags().ack());
+        test_eq!(reset.ack_num(), closed_port_client.seq);
+
+        let (mut mock_client, connection) = connect_mock_client(&fixture, 80).await?;
+
+        // Segment for a connection we know nothing about
+        let unknown_client = MockClient {
+            client_port: 4321,
+            ack: 1000,
+            ..mock_client
+        };
+        let reset = unknown_client
+            .send(&fixture, &unknown_client.ack())
+            .await
+            .ok_or("No reset for unknown connection".to_string())?;
+        let reset = TcpFrame::new(&reset);
+        test_true!(reset.flags().rst());
+        test_true!(!reset.flags().ack());
+        test_eq!(reset.seq_num(), 1000);
+        // Resets are never answered
+        test_true!(unknown_client
+            .send(&fixture, &unknown_client.rst())
+            .await
+            .is_none());
+
+        // Out of window resets are ignored
+        mock_client.seq += 100;
+        test_true!(mock_client
+            .send(&fixture, &mock_client.rst())
+            .await
+            .is_none());
+        mock_client.seq -= 100;
+        test_true!(try_read(&connection).await.is_none());
+
+        test_true!(mock_client
+            .send(&fixture, &mock_client.rst())
+            .await
+            .is_none());
+        let data = try_read(&connection)
+            .await
+            .ok_or("Read did not return EOF after reset".to_string())?;
+        test_true!(data.is_empty());
+
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_connect, {
+        let fixture = gen_fixture();
+        let mut connect = core::pin::pin!(fixture.tcp.connect(CLIENT_IP, 6000));
+        let (mut mock_server, syn) = start_connect(&fixture, connect.as_mut(), 6000).await?;
+        test_eq!(
+            TcpFrame::new(&syn.payload).source_port(),
+            Tcp::EPHEMERAL_PORT_START
+        );
+
+        let syn_ack = mock_server.syn_ack();
+        let ack = mock_server
+            .send(&fixture, &syn_ack)
+            .await
+            .ok_or("No ack for syn ack".to_string())?;
+        let ack = TcpFrame::new(&ack);
+        test_true!(ack.flags().ack());
+        test_true!(!ack.flags().syn());
+        test_eq!(ack.ack_num(), mock_server.seq);
+        test_eq!(ack.seq_num(), mock_server.ack);
+
+        let connection = crate::future::poll_immediate(connect.as_mut())
+            .await
+            .ok_or("Connect did not complete".to_string())?
+            .map_err(|e| alloc::format!("Connect failed: {:?}", e))?;
+
+        test_ok!(connection.write_all(b"request").await);
+        let push = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Data not sent".to_string())?;
+        test_eq!(TcpFrame::new(&push.payload).payload(), b"request");
+        mock_server.handle_frame(&push.payload);
+
+        let response = mock_server.push(b"response");
+        test_true!(mock_server.send(&fixture, &response).await.is_some());
+        let data = try_read(&connection)
+            .await
+            .ok_or("No response data".to_string())?;
+        test_eq!(data.as_slice(), b"response");
+
+        // The next connection picks another port
+        let mut connect = core::pin::pin!(fixture.tcp.connect(CLIENT_IP, 6000));
+        let (_, syn) = start_connect(&fixture, connect.as_mut(), 6000).await?;
+        test_eq!(
+            TcpFrame::new(&syn.payload).source_port(),
+            Tcp::EPHEMERAL_PORT_START + 1
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_connect_refused, {
+        let fixture = gen_fixture();
+        let mut connect = core::pin::pin!(fixture.tcp.connect(CLIENT_IP, 81));
+        let (mock_server, _) = start_connect(&fixture, connect.as_mut(), 81).await?;
+
+        // Acks for something we never sent are reset without failing the attempt
+        let bogus_ack = MockClient {
+            ack: mock_server.ack.wrapping_add(100),
+            ..mock_server
+        }
+        .ack();
+        let reset = mock_server
+            .send(&fixture, &bogus_ack)
+            .await
+            .ok_or("No reset for bogus ack".to_string())?;
+        test_true!(TcpFrame::new(&reset).flags().rst());
+        test_true!(crate::future::poll_immediate(connect.as_mut())
+            .await
+            .is_none());
+
+        test_true!(mock_server
+            .send(&fixture, &mock_server.rst_ack())
+            .await
+            .is_none());
+        let result = crate::future::poll_immediate(connect.as_mut())
+            .await
+            .ok_or("Connect did not complete".to_string())?;
+        test_true!(matches!(result, Err(ConnectError::Refused)));
+
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_connect_unreachable, {
+        let fixture = gen_fixture();
+        let mut connect = core::pin::pin!(fixture.tcp.connect(CLIENT_IP, 6000));
+        let (mock_server, syn) = start_connect(&fixture, connect.as_mut(), 6000).await?;
+        let quoted_header: [u8; 8] = syn.payload[..8]
+            .try_into()
+            .map_err(|_| "Short syn".to_string())?;
+        let tcp = &fixture.tcp;
+        let unreachable = |code: UnreachableCode, quoted_header: [u8; 8]| async move {
+            tcp.handle_unreachable(
+                code as u8,
+                &SERVER_IP,
+                mock_server.server_port,
+                &CLIENT_IP,
+                mock_server.client_port,
+                &quoted_header,
+            )
+            .await
+        };
+
+        // Quoting a segment we never sent
+        let mut forged_header = quoted_header;
+        forged_header[7] ^= 1;
+        unreachable(UnreachableCode::Port, forged_header).await;
+        test_true!(crate::future::poll_immediate(connect.as_mut())
+            .await
+            .is_none());
+
+        // Soft errors only replace the eventual timeout
+        unreachable(UnreachableCode::Host, quoted_header).await;
+        test_true!(crate::future::poll_immediate(connect.as_mut())
+            .await
+            .is_none());
+
+        unreachable(UnreachableCode::Port, quoted_header).await;
+        let result = crate::future::poll_immediate(connect.as_mut())
+            .await
+            .ok_or("Connect did not complete".to_string())?;
+        test_true!(matches!(result, Err(ConnectError::Unreachable)));
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_connect_soft_error_timeout, {
+        let fixture = gen_fixture();
+        let mut connect = core::pin::pin!(fixture.tcp.connect(CLIENT_IP, 6000));
+        let (mock_server, syn) = start_connect(&fixture, connect.as_mut(), 6000).await?;
+        let quoted_header: [u8; 8] = syn.payload[..8]
+            .try_into()
+            .map_err(|_| "Short syn".to_string())?;
+        fixture
+            .tcp
+            .handle_unreachable(
+                UnreachableCode::Network as u8,
+                &SERVER_IP,
+                mock_server.server_port,
+                &CLIENT_IP,
+                mock_server.client_port,
+                &quoted_header,
+            )
+            .await;
+
+        let mut elapsed_s = 0.0;
+        let mut interval_s = Tcp::SYN_RETRANSMIT_S;
+        for _ in 0..Tcp::SYN_RETRIES {
+            elapsed_s += interval_s;
+            interval_s *= 2.0;
+            fixture
+                .time
+                .set_tick((elapsed_s * fixture.time.tick_freq()) as usize);
+
+            test_true!(crate::future::poll_immediate(connect.as_mut())
+                .await
+                .is_none());
+            crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or("Syn not retransmitted".to_string())?;
+        }
+
+        // The soft error is reported instead of the timeout
+        elapsed_s += interval_s;
+        fixture
+            .time
+            .set_tick((elapsed_s * fixture.time.tick_freq()) as usize);
+        let result = crate::future::poll_immediate(connect.as_mut())
+            .await
+            .ok_or("Connect did not time out".to_string())?;
+        test_true!(matches!(result, Err(ConnectError::Unreachable)));
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_connect_timeout, {
+        let fixture = gen_fixture();
+        let mut connect = core::pin::pin!(fixture.tcp.connect(CLIENT_IP, 6000));
+        let (_, syn) = start_connect(&fixture, connect.as_mut(), 6000).await?;
+
+        let mut elapsed_s = 0.0;
+        let mut interval_s = Tcp::SYN_RETRANSMIT_S;
+        for _ in 0..Tcp::SYN_RETRIES {
+            elapsed_s += interval_s;
+            interval_s *= 2.0;
+            fixture
+                .time
+                .set_tick((elapsed_s * fixture.time.tick_freq()) as usize);
+
+            test_true!(crate::future::poll_immediate(connect.as_mut())
+                .await
+                .is_none());
+            let retransmit = crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or("Syn not retransmitted".to_string())?;
+            test_eq!(&retransmit.payload[..], &syn.payload[..]);
+        }
+
+        elapsed_s += interval_s;
+        fixture
+            .time
+            .set_tick((elapsed_s * fixture.time.tick_freq()) as usize);
+        let result = crate::future::poll_immediate(connect.as_mut())
+            .await
+            .ok_or("Connect did not time out".to_string())?;
+        test_true!(matches!(result, Err(ConnectError::TimedOut)));
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_connect_dropped, {
+        let fixture = gen_fixture();
+        {
+            let mut connect = core::pin::pin!(fixture.tcp.connect(CLIENT_IP, 6000));
+            start_connect(&fixture, connect.as_mut(), 6000).await?;
+        }
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 1);
+
+        fixture
+            .time
+            .set_tick((Tcp::SYN_SENT_EXPIRY_S * fixture.time.tick_freq()) as usize);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_rtt_estimator, {
+        let mut rtt = RttEstimator::new();
+        test_eq!(rtt.rto_s, RttEstimator::INITIAL_RTO_S);
+
+        rtt.sample(0.5);
+        test_eq!(rtt.srtt_s, Some(0.5));
+        test_eq!(rtt.rto_s, 1.5);
+        rtt.sample(0.5);
+        test_eq!(rtt.rttvar_s, 0.1875);
+        test_eq!(rtt.rto_s, 1.25);
+
+        for _ in 0..20 {
+            rtt.sample(0.01);
+        }
+        test_eq!(rtt.rto_s, RttEstimator::MIN_RTO_S);
+
+        rtt.backoff();
+        test_eq!(rtt.rto_s, 2.0);
+        for _ in 0..10 {
+            rtt.backoff();
+        }
+        test_eq!(rtt.rto_s, RttEstimator::MAX_RTO_S);
+
+        Ok(())
+    });
+
+    create_test!(test_retransmit_abort, {
+        let fixture = gen_fixture();
+        let (_mock_client, connection) = connect_mock_client(&fixture, 80).await?;
+        let stats = &fixture.tcp.stats;
+        test_eq!(stats.passive_opens.load(Ordering::Relaxed), 1);
+
+        test_ok!(connection.write_all(b"unanswered").await);
+        let sent = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("Data not sent".to_string())?;
+
+        let mut elapsed_s = 0.0;
+        let mut rto_s = RttEstimator::INITIAL_RTO_S;
+        for _ in 0..Tcp::MAX_RETRANSMITS {
+            elapsed_s += rto_s;
+            rto_s = (rto_s * 2.0).min(RttEstimator::MAX_RTO_S);
+            fixture
+                .time
+                .set_tick((elapsed_s * fixture.time.tick_freq()) as usize);
+
+            let retransmit = crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or("Segment not retransmitted".to_string())?;
+            test_eq!(&retransmit.payload[..], &sent.payload[..]);
+        }
+        test_eq!(
+            stats.retrans_segs.load(Ordering::Relaxed),
+            Tcp::MAX_RETRANSMITS as usize
+        );
+
+        elapsed_s += rto_s;
+        fixture
+            .time
+            .set_tick((elapsed_s * fixture.time.tick_freq()) as usize);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        let data = try_read(&connection)
+            .await
+            .ok_or("Read did not return EOF after abort".to_string())?;
+        test_true!(data.is_empty());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+        test_eq!(stats.estab_resets.load(Ordering::Relaxed), 1);
+
+        Ok(())
+    });
+
+    fn to_loopback_frame(tcp_frame: &[u8], source_ip: &IpAddr, dest_ip: &IpAddr) -> Vec<u8> {
+        let (ip_frame, ether_type) = match (source_ip, dest_ip) {
+            (IpAddr::V4(source_ip), IpAddr::V4(dest_ip)) => (
+                net::generate_ipv4_frame(tcp_frame, Ipv4Protocol::Tcp, source_ip, dest_ip),
+                net::EtherType::Ipv4,
+            ),
+            (IpAddr::V6(source_ip), IpAddr::V6(dest_ip)) => (
+                ipv6::generate_ipv6_frame(
+                    tcp_frame,
+                    Ipv4Protocol::Tcp,
+                    source_ip,
+                    dest_ip,
+                    ipv6::DEFAULT_HOP_LIMIT,
+                ),
+                net::EtherType::Ipv6,
+            ),
+            _ => panic!("Mixed address families"),
+        };
+        net::generate_ethernet_frame(&net::EthernetFrameParams {
+            dest_mac: Loopback::MAC,
+            source_mac: Loopback::MAC,
+            ether_type,
+            vlan: None,
+            payload: &ip_frame,
+        })
+    }
+
+    fn from_loopback_frame(frame: &[u8]) -> Result<Vec<u8>, String> {
+        let frame = net::parse_packet(frame).map_err(|_| "Invalid loopback frame".to_string())?;
+        match frame.inner {
+            net::ParsedPacket::Ipv4(ipv4_frame) => Ok(ipv4_frame.payload().to_vec()),
+            net::ParsedPacket::Ipv6(ipv6_frame) => Ok(ipv6_frame.payload().to_vec()),
+            _ => Err("Loopback frame was not ip".into()),
+        }
+    }
+
+    /// Moves frames between the tcp service and the loopback receive path until both are idle
+    async fn pump_loopback(
+        tcp: &Tcp,
+        loopback: &Loopback,
+        net_stack: &crate::NetStack<'_>,
+    ) -> Result<(), String> {
+        loop {
+            let mut idle = true;
+
+            while let Some(packet) = crate::future::poll_immediate(tcp.service()).await {
+                idle = false;
+                loopback
+                    .write(&to_loopback_frame(
+                        &packet.payload,
+                        &packet.local_ip,
+                        &packet.remote_ip,
+                    ))
+                    .await
+                    .map_err(|_| "Loopback write failed".to_string())?;
+            }
+
+            while let Some(frame) = crate::future::poll_immediate(loopback.recv()).await {
+                idle = false;
+                crate::handle_packet(
+                    frame,
+                    NetDevice::Loopback(loopback),
+                    &Loopback::IP,
+                    &Ipv6Config::new(ipv6::LOOPBACK),
+                    &[],
+                    net_stack,
+                )
+                .await;
+            }
+
+            if idle {
+                return Ok(());
+            }
+        }
+    }
+
+    create_test!(test_retransmit_timeout_recovers, {
+        const LOST: &[u8] = b"lost segment";
+
+        let fixture = gen_fixture();
+        let loopback = Loopback::new();
+        let arp_table = crate::ArpTable::new();
+        let ndp_table = crate::NdpTable::new();
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let icmp = Icmp::new(Arc::clone(&fixture.time), wakeup_list);
+        let icmpv6 = Icmpv6::new(
+            Loopback::MAC,
+            UpdatedVal::new(Ipv6Config::new(ipv6::LOOPBACK)),
+        );
+        let udp = Udp::new();
+        let ipv4 = Ipv4::new(Arc::clone(&fixture.time));
+        let stats = crate::net::stats::NetStats::default();
+        let firewall = Firewall::new(Vec::new(), Action::Accept);
+
+        // Only the first transmission goes missing
+        let dropped = AtomicBool::new(false);
+        let drop_lost_segment = |frame: &TcpFrame<'_>| {
+            frame.payload() == LOST && !dropped.swap(true, Ordering::Relaxed)
+        };
+        let net_stack = crate::NetStack {
+            arp_table: &arp_table,
+            ndp_table: &ndp_table,
+            ipv4: &ipv4,
+            tcp: &fixture.tcp,
+            icmp: &icmp,
+            icmpv6: &icmpv6,
+            udp: &udp,
+            tcp_drop_hook: Some(&drop_lost_segment),
+            capture: None,
+            stats: &stats,
+            firewall: &firewall,
+        };
+
+        let listener = fixture.tcp.listen(Loopback::IP, 80).await;
+        let mut connect = core::pin::pin!(fixture.tcp.connect(Loopback::IP, 80));
+        test_true!(crate::future::poll_immediate(connect.as_mut())
+            .await
+            .is_none());
+        pump_loopback(&fixture.tcp, &loopback, &net_stack).await?;
+
+        let client = crate::future::poll_immediate(connect.as_mut())
+            .await
+            .ok_or("Connect did not complete".to_string())?
+            .map_err(|e| alloc::format!("Connect failed: {:?}", e))?;
+        let server = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not accepted".to_string())?;
+
+        test_ok!(client.write_all(LOST).await);
+        pump_loopback(&fixture.tcp, &loopback, &net_stack).await?;
+        test_true!(dropped.load(Ordering::Relaxed));
+        test_true!(try_read(&server).await.is_none());
+
+        // Nothing goes out again before the rto expires
+        fixture
+            .time
+            .set_tick((RttEstimator::INITIAL_RTO_S / 2.0 * fixture.time.tick_freq()) as usize);
+        pump_loopback(&fixture.tcp, &loopback, &net_stack).await?;
+        test_true!(try_read(&server).await.is_none());
+
+        fixture
+            .time
+            .set_tick((RttEstimator::INITIAL_RTO_S * fixture.time.tick_freq()) as usize);
+        pump_loopback(&fixture.tcp, &loopback, &net_stack).await?;
+        let data = try_read(&server)
+            .await
+            .ok_or("Lost segment was not retransmitted".to_string())?;
+        test_eq!(data.as_slice(), LOST);
+
+        // The retransmission was acked, so the timer is stopped
+        for tcp_state in fixture.tcp.tcp_states.lock().await.values() {
+            if let TcpState::Connected(connection) = tcp_state {
+                test_true!(connection.unacknowledged.is_empty());
+                test_true!(connection.retransmit_deadline.is_none());
+            }
+        }
+
+        Ok(())
+    });
+
+    /// Handshake and a push over the loopback device, listening on listen_ip and using ip for
+    /// both ends
+    async fn loopback_round_trip(listen_ip: IpAddr, ip: IpAddr) -> Result<(), String> {
+        const CLIENT_PORT: u16 = 1234;
+        const SERVER_PORT: u16 = 80;
+
+        let fixture = gen_fixture();
+        let loopback = Loopback::new();
+        let arp_table = crate::ArpTable::new();
+        let ndp_table = crate::NdpTable::new();
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let icmp = Icmp::new(Arc::clone(&fixture.time), wakeup_list);
+        let icmpv6 = Icmpv6::new(
+            Loopback::MAC,
+            UpdatedVal::new(Ipv6Config::new(ipv6::LOOPBACK)),
+        );
+        let udp = Udp::new();
+        let ipv4 = Ipv4::new(Arc::clone(&fixture.time));
+        let stats = crate::net::stats::NetStats::default();
+        let firewall = Firewall::new(Vec::new(), Action::Accept);
+        let net_stack = crate::NetStack {
+            arp_table: &arp_table,
+            ndp_table: &ndp_table,
+            ipv4: &ipv4,
+            tcp: &fixture.tcp,
+            icmp: &icmp,
+            icmpv6: &icmpv6,
+            udp: &udp,
+            tcp_drop_hook: None,
+            capture: None,
+            stats: &stats,
+            firewall: &firewall,
+        };
+
+        let listener = fixture.tcp.listen(listen_ip, SERVER_PORT).await;
+
+        let mut mock_client = MockClient {
+            client_ip: ip,
+            server_ip: ip,
+            client_port: CLIENT_PORT,
+            server_port: SERVER_PORT,
+            window_size: 5000,
+            seq: 150,
+            ack: 0,
+        };
+
+        // Pull a single frame off the loopback device and run it through the normal receive path
+        let loopback_ipv6_config = Ipv6Config::new(ipv6::LOOPBACK);
+        let process_next_frame = || {
+            loopback.read(|packet| {
+                crate::handle_packet(
+                    packet.to_vec(),
+                    NetDevice::Loopback(&loopback),
+                    &Loopback::IP,
+                    &loopback_ipv6_config,
+                    &[],
+                    &net_stack,
+                )
+            })
+        };
+
+        let syn = mock_client.syn();
+        test_ok!(loopback.write(&to_loopback_frame(&syn, &ip, &ip)).await);
+        process_next_frame().await;
+
+        let syn_ack = crate::future::poll_immediate(loopback.recv())
+            .await
+            .ok_or("No syn ack on loopback".to_string())?;
+        let syn_ack = from_loopback_frame(&syn_ack)?;
+        let syn_ack_frame = TcpFrame::new(&syn_ack);
+        test_true!(syn_ack_frame.flags().syn());
+        test_true!(syn_ack_frame.flags().ack());
+        test_eq!(syn_ack_frame.source_port(), SERVER_PORT);
+        test_eq!(syn_ack_frame.dest_port(), CLIENT_PORT);
+        mock_client.handle_frame(&syn_ack);
+
+        let ack = mock_client.ack();
+        test_ok!(loopback.write(&to_loopback_frame(&ack, &ip, &ip)).await);
+        process_next_frame().await;
+
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        let push = mock_client.push(b"hello loopback");
+        test_ok!(loopback.write(&to_loopback_frame(&push, &ip, &ip)).await);
+        process_next_frame().await;
+
+        let data = try_read(&connection)
+            .await
+            .ok_or("No data on connection".to_string())?;
+        test_eq!(data.as_slice(), b"hello loopback");
+
+        let data_ack = crate::future::poll_immediate(loopback.recv())
+            .await
+            .ok_or("No ack for pushed data".to_string())?;
+        let data_ack = from_loopback_frame(&data_ack)?;
+        let data_ack = TcpFrame::new(&data_ack);
+        test_true!(data_ack.flags().ack());
+        test_eq!(data_ack.ack_num(), mock_client.seq);
+
+        Ok(())
+    }
+
+    create_test!(test_loopback_round_trip, {
+        loopback_round_trip(LOOPBACK_IP, LOOPBACK_IP).await
+    });
+
+    create_test!(test_loopback_round_trip_ipv6, {
+        loopback_round_trip(IpAddr::V6(ipv6::UNSPECIFIED), LOOPBACK_IPV6).await?;
+        // [::] also accepts ipv4 connections
+        loopback_round_trip(IpAddr::V6(ipv6::UNSPECIFIED), LOOPBACK_IP).await
+    });
+}
@@ -0,0 +1,215 @@
+use alloc::vec::Vec;
+
+/// Received range above the cumulative ack, right is exclusive
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SackBlock {
+    pub left: u32,
+    pub right: u32,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Timestamps {
+    pub value: u32,
+    pub echo_reply: u32,
+}
+
+/// TCP header options, unknown and malformed options are skipped when parsing
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct TcpOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    pub sack_blocks: Vec<SackBlock>,
+    pub timestamps: Option<Timestamps>,
+}
+
+impl TcpOptions {
+    const END: u8 = 0;
+    const NOP: u8 = 1;
+    const MSS: u8 = 2;
+    const WINDOW_SCALE: u8 = 3;
+    const SACK_PERMITTED: u8 = 4;
+    const SACK: u8 = 5;
+    const TIMESTAMPS: u8 = 8;
+
+    // RFC 7323, larger shifts are treated as 14
+    pub const MAX_WINDOW_SHIFT: u8 = 14;
+    // The data offset field limits options to 40 bytes, 3 blocks still fit next to timestamps
+    const MAX_SACK_BLOCKS: usize = 3;
+
+    pub fn parse(data: &[u8]) -> TcpOptions {
+        let mut ret = TcpOptions::default();
+
+        let mut i = 0;
+        while i < data.len() {
+            let kind = data[i];
+            match kind {
+                Self::END => break,
+                Self::NOP => {
+                    i += 1;
+                    continue;
+                }
+                _ => (),
+            }
+
+            let len = match data.get(i + 1) {
+                Some(&len) if len >= 2 && i + len as usize <= data.len() => len as usize,
+                _ => {
+                    warn!("Malformed tcp option {}", kind);
+                    break;
+                }
+            };
+            let value = &data[i + 2..i + len];
+
+            match (kind, value.len()) {
+                (Self::MSS, 2) => ret.mss = Some(u16::from_be_bytes([value[0], value[1]])),
+                (Self::WINDOW_SCALE, 1) => {
+                    ret.window_scale = Some(value[0].min(Self::MAX_WINDOW_SHIFT))
+                }
+                (Self::SACK_PERMITTED, 0) => ret.sack_permitted = true,
+                (Self::SACK, n) if n.is_multiple_of(8) => {
+                    ret.sack_blocks = value
+                        .chunks_exact(8)
+                        .map(|block| SackBlock {
+                            left: u32::from_be_bytes(block[0..4].try_into().expect("4 bytes")),
+                            right: u32::from_be_bytes(block[4..8].try_into().expect("4 bytes")),
+                        })
+                        .collect();
+                }
+                (Self::TIMESTAMPS, 8) => {
+                    ret.timestamps = Some(Timestamps {
+                        value: u32::from_be_bytes(value[0..4].try_into().expect("4 bytes")),
+                        echo_reply: u32::from_be_bytes(value[4..8].try_into().expect("4 bytes")),
+                    })
+                }
+                _ => debug!("Ignoring tcp option {} with length {}", kind, len),
+            }
+
+            i += len;
+        }
+
+        ret
+    }
+
+    /// Serialized options padded to a multiple of 4 bytes
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+
+        if let Some(mss) = self.mss {
+            out.extend_from_slice(&[Self::MSS, 4]);
+            out.extend_from_slice(&mss.to_be_bytes());
+        }
+
+        if let Some(window_scale) = self.window_scale {
+            out.extend_from_slice(&[Self::NOP, Self::WINDOW_SCALE, 3, window_scale]);
+        }
+
+        if self.sack_permitted {
+            out.extend_from_slice(&[Self::NOP, Self::NOP, Self::SACK_PERMITTED, 2]);
+        }
+
+        if let Some(timestamps) = self.timestamps {
+            out.extend_from_slice(&[Self::NOP, Self::NOP, Self::TIMESTAMPS, 10]);
+            out.extend_from_slice(&timestamps.value.to_be_bytes());
+            out.extend_from_slice(&timestamps.echo_reply.to_be_bytes());
+        }
+
+        if !self.sack_blocks.is_empty() {
+            let blocks = &self.sack_blocks[..self.sack_blocks.len().min(Self::MAX_SACK_BLOCKS)];
+            out.extend_from_slice(&[Self::NOP, Self::NOP, Self::SACK, 2 + 8 * blocks.len() as u8]);
+            for block in blocks {
+                out.extend_from_slice(&block.left.to_be_bytes());
+                out.extend_from_slice(&block.right.to_be_bytes());
+            }
+        }
+
+        while !(out.len() - start).is_multiple_of(4) {
+            out.push(Self::END);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::vec;
+
+    create_test!(test_parse_linux_syn_options, {
+        // Options of the captured syn in the tcp tests
+        const OPTIONS: &[u8] = &[
+            0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x41, 0xcf, 0x00, 0x5d, 0x00, 0x00,
+            0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+        ];
+
+        let options = TcpOptions::parse(OPTIONS);
+        test_eq!(options.mss, Some(1460));
+        test_eq!(options.window_scale, Some(7));
+        test_true!(options.sack_permitted);
+        test_eq!(
+            options.timestamps,
+            Some(Timestamps {
+                value: 0x41cf005d,
+                echo_reply: 0,
+            })
+        );
+        test_true!(options.sack_blocks.is_empty());
+
+        // Truncated option lengths are not read past the end
+        let truncated = TcpOptions::parse(&OPTIONS[..7]);
+        test_eq!(truncated.mss, Some(1460));
+        test_true!(truncated.timestamps.is_none());
+
+        Ok(())
+    });
+
+    create_test!(test_options_round_trip, {
+        let syn = TcpOptions {
+            mss: Some(1460),
+            window_scale: Some(2),
+            sack_permitted: true,
+            sack_blocks: Vec::new(),
+            timestamps: Some(Timestamps {
+                value: 1,
+                echo_reply: 0,
+            }),
+        };
+
+        // Largest option set of an established connection
+        let ack = TcpOptions {
+            sack_blocks: vec![
+                SackBlock {
+                    left: 100,
+                    right: 200,
+                },
+                SackBlock {
+                    left: 300,
+                    right: 400,
+                },
+                SackBlock {
+                    left: 500,
+                    right: 600,
+                },
+            ],
+            timestamps: Some(Timestamps {
+                value: 1,
+                echo_reply: 2,
+            }),
+            ..Default::default()
+        };
+
+        for options in [syn, ack] {
+            let mut buf = Vec::new();
+            options.write(&mut buf);
+            test_eq!(buf.len() % 4, 0);
+            test_true!(buf.len() <= 40);
+            test_eq!(TcpOptions::parse(&buf), options);
+        }
+
+        let mut buf = Vec::new();
+        TcpOptions::default().write(&mut buf);
+        test_true!(buf.is_empty());
+
+        Ok(())
+    });
+}
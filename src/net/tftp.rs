@@ -0,0 +1,575 @@
+use crate::{
+    net::{
+        udp::{BindError, Udp, UdpSocket},
+        IpAddr,
+    },
+    sleep::WakeupRequester,
+    time::MonotonicTime,
+};
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+pub const SERVER_PORT: u16 = 69;
+
+const OPCODE_READ_REQUEST: u16 = 1;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+const OPCODE_OPTION_ACK: u16 = 6;
+
+const ERROR_ALLOCATION_EXCEEDED: u16 = 3;
+const ERROR_UNKNOWN_TRANSFER_ID: u16 = 5;
+const ERROR_OPTION_NEGOTIATION: u16 = 8;
+
+/// Used when the server does not acknowledge the blksize option
+const DEFAULT_BLOCK_SIZE: u16 = 512;
+/// Limits from RFC 2348
+const MIN_BLOCK_SIZE: u16 = 8;
+const MAX_BLOCK_SIZE: u16 = 65464;
+
+#[derive(Debug)]
+pub enum InvalidTftpPacket {
+    TooShort(usize),
+    UnknownOpcode(u16),
+    Unterminated,
+}
+
+impl core::fmt::Display for InvalidTftpPacket {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::TooShort(len) => f.write_fmt(format_args!("packet too short: {} bytes", len)),
+            Self::UnknownOpcode(opcode) => f.write_fmt(format_args!("unknown opcode {}", opcode)),
+            Self::Unterminated => f.write_str("unterminated string"),
+        }?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum TftpPacket<'a> {
+    Data { block: u16, data: &'a [u8] },
+    Ack { block: u16 },
+    Error { code: u16, message: String },
+    OptionAck { options: Vec<(String, String)> },
+}
+
+impl TftpPacket<'_> {
+    /// Only packets a server sends are understood, requests are never received by a client
+    pub fn parse(data: &[u8]) -> Result<TftpPacket<'_>, InvalidTftpPacket> {
+        if data.len() < 2 {
+            return Err(InvalidTftpPacket::TooShort(data.len()));
+        }
+
+        // Option acks are the only packets without a block number or error code
+        let opcode = u16::from_be_bytes([data[0], data[1]]);
+        if opcode != OPCODE_OPTION_ACK && data.len() < 4 {
+            return Err(InvalidTftpPacket::TooShort(data.len()));
+        }
+
+        let ret = match opcode {
+            OPCODE_DATA => TftpPacket::Data {
+                block: u16::from_be_bytes([data[2], data[3]]),
+                data: &data[4..],
+            },
+            OPCODE_ACK => TftpPacket::Ack {
+                block: u16::from_be_bytes([data[2], data[3]]),
+            },
+            OPCODE_ERROR => {
+                let mut strings = parse_strings(&data[4..])?;
+                TftpPacket::Error {
+                    code: u16::from_be_bytes([data[2], data[3]]),
+                    message: strings.next().unwrap_or_default(),
+                }
+            }
+            OPCODE_OPTION_ACK => {
+                let mut strings = parse_strings(&data[2..])?;
+                let mut options = Vec::new();
+                while let Some(name) = strings.next() {
+                    let value = strings.next().ok_or(InvalidTftpPacket::Unterminated)?;
+                    options.push((name.to_ascii_lowercase(), value));
+                }
+                TftpPacket::OptionAck { options }
+            }
+            opcode => return Err(InvalidTftpPacket::UnknownOpcode(opcode)),
+        };
+
+        Ok(ret)
+    }
+}
+
+/// Zero terminated strings back to back, as used in requests, errors and option acks
+fn parse_strings(data: &[u8]) -> Result<impl Iterator<Item = String> + '_, InvalidTftpPacket> {
+    let data = match data.split_last() {
+        None => data,
+        Some((0, data)) => data,
+        Some(_) => return Err(InvalidTftpPacket::Unterminated),
+    };
+
+    let strings = (!data.is_empty()).then(|| {
+        data.split(|b| *b == 0)
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+    });
+    Ok(strings.into_iter().flatten())
+}
+
+/// Binary (octet mode) read request. The blksize option is left out for the default size
+pub fn generate_read_request(filename: &str, block_size: u16) -> Vec<u8> {
+    let mut ret = Vec::new();
+    ret.extend_from_slice(&OPCODE_READ_REQUEST.to_be_bytes());
+    for s in [filename, "octet"] {
+        ret.extend_from_slice(s.as_bytes());
+        ret.push(0);
+    }
+
+    if block_size != DEFAULT_BLOCK_SIZE {
+        for s in ["blksize", &block_size.to_string()] {
+            ret.extend_from_slice(s.as_bytes());
+            ret.push(0);
+        }
+    }
+    ret
+}
+
+pub fn generate_ack(block: u16) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(4);
+    ret.extend_from_slice(&OPCODE_ACK.to_be_bytes());
+    ret.extend_from_slice(&block.to_be_bytes());
+    ret
+}
+
+pub fn generate_error(code: u16, message: &str) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(5 + message.len());
+    ret.extend_from_slice(&OPCODE_ERROR.to_be_bytes());
+    ret.extend_from_slice(&code.to_be_bytes());
+    ret.extend_from_slice(message.as_bytes());
+    ret.push(0);
+    ret
+}
+
+#[derive(Debug)]
+pub enum TftpError {
+    Bind(BindError),
+    Timeout,
+    /// Error code and message sent by the server
+    Server(u16, String),
+    InvalidResponse(InvalidTftpPacket),
+    /// The option ack contained something we did not ask for or could not accept
+    InvalidOption,
+    BlockTooLarge(usize),
+    FileTooLarge,
+}
+
+impl core::fmt::Display for TftpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Bind(e) => f.write_fmt(format_args!("failed to bind socket: {:?}", e)),
+            Self::Timeout => f.write_str("timed out"),
+            Self::Server(code, message) => {
+                f.write_fmt(format_args!("server error {}: {}", code, message))
+            }
+            Self::InvalidResponse(e) => f.write_fmt(format_args!("invalid response: {}", e)),
+            Self::InvalidOption => f.write_str("invalid option ack"),
+            Self::BlockTooLarge(len) => f.write_fmt(format_args!("block too large: {} bytes", len)),
+            Self::FileTooLarge => f.write_str("file too large"),
+        }?;
+        Ok(())
+    }
+}
+
+/// State of a single read, the transfer id is the port the server answers from
+struct Transfer {
+    socket: UdpSocket,
+    server: IpAddr,
+    server_port: Option<u16>,
+    block_size: u16,
+    next_block: u16,
+    /// What to send again when the server goes quiet
+    last_sent: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Transfer {
+    async fn send(&mut self, packet: Vec<u8>) {
+        let port = self.server_port.unwrap_or(SERVER_PORT);
+        self.socket.send_to(&packet, self.server, port).await;
+        self.last_sent = packet;
+    }
+
+    async fn retransmit(&self) {
+        let port = self.server_port.unwrap_or(SERVER_PORT);
+        self.socket
+            .send_to(&self.last_sent, self.server, port)
+            .await;
+    }
+
+    async fn fail(&self, code: u16, message: &str) {
+        if let Some(port) = self.server_port {
+            let packet = generate_error(code, message);
+            self.socket.send_to(&packet, self.server, port).await;
+        }
+    }
+}
+
+/// RFC 1350 client with block size negotiation from RFC 2348. Only reads are supported, the
+/// kernel has nowhere to write files from
+pub struct Tftp<'a> {
+    udp: &'a Udp,
+    time: &'a MonotonicTime,
+    wakeup_list: &'a WakeupRequester,
+    /// Requested from the server, which may pick something smaller
+    pub block_size: u16,
+    /// Per packet, not for the whole transfer
+    pub timeout_s: f32,
+    pub max_retransmits: usize,
+    pub max_len: usize,
+}
+
+impl<'a> Tftp<'a> {
+    pub fn new(
+        udp: &'a Udp,
+        time: &'a MonotonicTime,
+        wakeup_list: &'a WakeupRequester,
+    ) -> Tftp<'a> {
+        Tftp {
+            udp,
+            time,
+            wakeup_list,
+            // Leaves room for IPv6 and tunnel headers in a 1500 byte MTU
+            block_size: 1428,
+            timeout_s: 1.0,
+            max_retransmits: 5,
+            max_len: 4 * 1024 * 1024,
+        }
+    }
+
+    /// Downloads a whole file. progress is called with the number of bytes received so far
+    /// after every block
+    pub async fn get(
+        &self,
+        server: impl Into<IpAddr>,
+        filename: &str,
+        mut progress: impl FnMut(usize),
+    ) -> Result<Vec<u8>, TftpError> {
+        let block_size = self.block_size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+        let mut transfer = Transfer {
+            socket: self.udp.bind(0).await.map_err(TftpError::Bind)?,
+            server: server.into(),
+            server_port: None,
+            block_size: DEFAULT_BLOCK_SIZE,
+            next_block: 1,
+            last_sent: Vec::new(),
+            data: Vec::new(),
+        };
+        transfer
+            .send(generate_read_request(filename, block_size))
+            .await;
+
+        let mut retransmits = 0;
+        loop {
+            let Some((packet, port)) = self.recv(&transfer).await else {
+                if retransmits == self.max_retransmits {
+                    return Err(TftpError::Timeout);
+                }
+                debug!("TFTP timeout for {}, retransmitting", filename);
+                retransmits += 1;
+                transfer.retransmit().await;
+                continue;
+            };
+
+            let packet = TftpPacket::parse(&packet).map_err(TftpError::InvalidResponse)?;
+            match packet {
+                TftpPacket::Error { code, message } => {
+                    return Err(TftpError::Server(code, message))
+                }
+                TftpPacket::OptionAck { options } if transfer.server_port.is_none() => {
+                    transfer.server_port = Some(port);
+                    match negotiated_block_size(&options, block_size) {
+                        Some(size) => transfer.block_size = size,
+                        None => {
+                            transfer
+                                .fail(ERROR_OPTION_NEGOTIATION, "Unexpected options")
+                                .await;
+                            return Err(TftpError::InvalidOption);
+                        }
+                    }
+                    transfer.send(generate_ack(0)).await;
+                }
+                TftpPacket::Data { block, data } if block == transfer.next_block => {
+                    transfer.server_port = Some(port);
+                    if data.len() > transfer.block_size as usize {
+                        return Err(TftpError::BlockTooLarge(data.len()));
+                    }
+                    if transfer.data.len() + data.len() > self.max_len {
+                        transfer
+                            .fail(ERROR_ALLOCATION_EXCEEDED, "File too large")
+                            .await;
+                        return Err(TftpError::FileTooLarge);
+                    }
+
+                    transfer.data.extend_from_slice(data);
+                    progress(transfer.data.len());
+                    transfer.send(generate_ack(block)).await;
+                    // Block numbers wrap around for files larger than 65535 blocks
+                    transfer.next_block = block.wrapping_add(1);
+
+                    // The last block is short, which may mean empty. A lost final ack leaves
+                    // the server retransmitting until it times out, which is harmless
+                    if data.len() < transfer.block_size as usize {
+                        return Ok(transfer.data);
+                    }
+                }
+                // The server repeats itself when our last ack got lost
+                TftpPacket::OptionAck { .. } | TftpPacket::Data { .. }
+                    if transfer.server_port.is_some() =>
+                {
+                    transfer.retransmit().await;
+                    continue;
+                }
+                packet => {
+                    debug!("Ignoring unexpected TFTP packet {:?}", packet);
+                    continue;
+                }
+            }
+
+            retransmits = 0;
+        }
+    }
+
+    /// Waits for the next packet of the transfer, returning the port it came from. Packets from
+    /// other ports are answered with an error as required by the RFC
+    async fn recv(&self, transfer: &Transfer) -> Option<(Vec<u8>, u16)> {
+        let packet = async {
+            loop {
+                let (data, remote_ip, remote_port) = transfer.socket.recv_from().await;
+                if remote_ip != transfer.server {
+                    debug!("Ignoring TFTP packet from unexpected host {:?}", remote_ip);
+                    continue;
+                }
+
+                match transfer.server_port {
+                    Some(port) if port != remote_port => {
+                        let packet =
+                            generate_error(ERROR_UNKNOWN_TRANSFER_ID, "Unknown transfer id");
+                        transfer
+                            .socket
+                            .send_to(&packet, remote_ip, remote_port)
+                            .await;
+                    }
+                    _ => return (data, remote_port),
+                }
+            }
+        };
+        crate::sleep::timeout(self.timeout_s, self.time, self.wakeup_list, packet).await
+    }
+}
+
+/// Servers may only lower the block size, and must not ack options that were not requested
+fn negotiated_block_size(options: &[(String, String)], requested: u16) -> Option<u16> {
+    let mut ret = DEFAULT_BLOCK_SIZE;
+    for (name, value) in options {
+        if name != "blksize" || requested == DEFAULT_BLOCK_SIZE {
+            return None;
+        }
+
+        ret = value.parse().ok()?;
+        if !(MIN_BLOCK_SIZE..=requested).contains(&ret) {
+            return None;
+        }
+    }
+    Some(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::future::poll_immediate;
+    use crate::net::{self, udp::OutgoingUdpPacket, UdpFrame};
+    use crate::testing::*;
+    use crate::Ipv4Addr;
+    use alloc::{format, vec};
+
+    const LOCAL_IP: Ipv4Addr = [192, 168, 2, 2];
+    const SERVER_IP: Ipv4Addr = [192, 168, 2, 1];
+    const TRANSFER_PORT: u16 = 40000;
+
+    fn data_packet(block: u16, data: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&OPCODE_DATA.to_be_bytes());
+        ret.extend_from_slice(&block.to_be_bytes());
+        ret.extend_from_slice(data);
+        ret
+    }
+
+    async fn sent(udp: &Udp) -> Result<OutgoingUdpPacket, String> {
+        poll_immediate(udp.service())
+            .await
+            .ok_or("Nothing was sent".to_string())
+    }
+
+    async fn reply(
+        udp: &Udp,
+        source_port: u16,
+        local_port: u16,
+        response: &[u8],
+    ) -> Result<(), String> {
+        let (server_ip, local_ip) = (IpAddr::V4(SERVER_IP), IpAddr::V4(LOCAL_IP));
+        let frame =
+            net::generate_udp_frame(&server_ip, &local_ip, source_port, local_port, response);
+        let frame = UdpFrame::new(&frame).map_err(|_| "Invalid udp frame".to_string())?;
+        udp.handle_frame(&frame, &server_ip, &local_ip)
+            .await
+            .map_err(|_| "Failed to deliver tftp response".to_string())
+    }
+
+    create_test!(test_tftp_packets, {
+        test_eq!(
+            generate_read_request("index.html", 1024),
+            b"\x00\x01index.html\x00octet\x00blksize\x001024\x00".to_vec()
+        );
+        test_eq!(
+            generate_read_request("a", DEFAULT_BLOCK_SIZE),
+            b"\x00\x01a\x00octet\x00".to_vec()
+        );
+
+        test_eq!(
+            TftpPacket::parse(b"\x00\x03\x00\x02abc").ok(),
+            Some(TftpPacket::Data {
+                block: 2,
+                data: b"abc"
+            })
+        );
+        test_eq!(
+            TftpPacket::parse(b"\x00\x05\x00\x01File not found\x00").ok(),
+            Some(TftpPacket::Error {
+                code: 1,
+                message: "File not found".to_string()
+            })
+        );
+        test_eq!(
+            TftpPacket::parse(b"\x00\x06BLKSIZE\x00512\x00").ok(),
+            Some(TftpPacket::OptionAck {
+                options: vec![("blksize".to_string(), "512".to_string())]
+            })
+        );
+        test_err!(TftpPacket::parse(b"\x00\x03\x00"));
+        test_err!(TftpPacket::parse(b"\x00\x09\x00\x01"));
+        test_err!(TftpPacket::parse(b"\x00\x06blksize\x00512"));
+        test_err!(TftpPacket::parse(b"\x00\x06blksize\x00"));
+
+        test_eq!(
+            negotiated_block_size(&[("blksize".into(), "1024".into())], 1428),
+            Some(1024)
+        );
+        test_eq!(negotiated_block_size(&[], 1428), Some(DEFAULT_BLOCK_SIZE));
+        test_true!(negotiated_block_size(&[("blksize".into(), "2048".into())], 1428).is_none());
+        test_true!(negotiated_block_size(&[("tsize".into(), "10".into())], 1428).is_none());
+
+        Ok(())
+    });
+
+    create_test!(test_tftp_get, {
+        let time = MonotonicTime::new(10.0);
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let udp = Udp::new();
+        let mut tftp = Tftp::new(&udp, &time, &wakeup_list);
+        tftp.block_size = 10;
+
+        let mut progress = Vec::new();
+        {
+            let mut get = core::pin::pin!(tftp.get(SERVER_IP, "file", |len| progress.push(len)));
+            test_true!(poll_immediate(get.as_mut()).await.is_none());
+            let request = sent(&udp).await?;
+            test_eq!(request.remote_port, SERVER_PORT);
+            test_eq!(request.data, generate_read_request("file", 10));
+            let port = request.local_port;
+
+            // The server answers from a new port and lowers the block size
+            reply(&udp, TRANSFER_PORT, port, b"\x00\x06blksize\x008\x00").await?;
+            test_true!(poll_immediate(get.as_mut()).await.is_none());
+            let ack = sent(&udp).await?;
+            test_eq!(ack.remote_port, TRANSFER_PORT);
+            test_eq!(ack.data, generate_ack(0));
+
+            reply(&udp, TRANSFER_PORT, port, &data_packet(1, b"01234567")).await?;
+            test_true!(poll_immediate(get.as_mut()).await.is_none());
+            test_eq!(sent(&udp).await?.data, generate_ack(1));
+
+            // Strangers get an error without disturbing the transfer
+            reply(&udp, TRANSFER_PORT + 1, port, &data_packet(2, b"x")).await?;
+            test_true!(poll_immediate(get.as_mut()).await.is_none());
+            let error = sent(&udp).await?;
+            test_eq!(error.remote_port, TRANSFER_PORT + 1);
+            test_eq!(error.data[..4].to_vec(), vec![0, 5, 0, 5]);
+
+            // Duplicates and timeouts both repeat the last ack
+            reply(&udp, TRANSFER_PORT, port, &data_packet(1, b"01234567")).await?;
+            test_true!(poll_immediate(get.as_mut()).await.is_none());
+            test_eq!(sent(&udp).await?.data, generate_ack(1));
+            time.set_tick(time.tick_freq() as usize);
+            test_true!(poll_immediate(get.as_mut()).await.is_none());
+            test_eq!(sent(&udp).await?.data, generate_ack(1));
+
+            reply(&udp, TRANSFER_PORT, port, &data_packet(2, b"89a")).await?;
+            let data = poll_immediate(get.as_mut())
+                .await
+                .ok_or("Transfer did not complete".to_string())?
+                .map_err(|e| format!("Transfer failed: {:?}", e))?;
+            test_eq!(data, b"0123456789a".to_vec());
+            test_eq!(sent(&udp).await?.data, generate_ack(2));
+        }
+        test_eq!(progress, vec![8, 11]);
+
+        Ok(())
+    });
+
+    create_test!(test_tftp_failures, {
+        let time = MonotonicTime::new(10.0);
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let udp = Udp::new();
+        let mut tftp = Tftp::new(&udp, &time, &wakeup_list);
+        tftp.max_retransmits = 1;
+
+        // Servers without option support send data right away with the default block size
+        let mut get = core::pin::pin!(tftp.get(SERVER_IP, "small", |_| ()));
+        test_true!(poll_immediate(get.as_mut()).await.is_none());
+        let port = sent(&udp).await?.local_port;
+        reply(&udp, TRANSFER_PORT, port, &data_packet(1, b"abc")).await?;
+        let data = poll_immediate(get.as_mut()).await.and_then(|r| r.ok());
+        test_eq!(data, Some(b"abc".to_vec()));
+        test_eq!(sent(&udp).await?.data, generate_ack(1));
+
+        let mut get = core::pin::pin!(tftp.get(SERVER_IP, "missing", |_| ()));
+        test_true!(poll_immediate(get.as_mut()).await.is_none());
+        let port = sent(&udp).await?.local_port;
+        reply(
+            &udp,
+            TRANSFER_PORT,
+            port,
+            b"\x00\x05\x00\x01File not found\x00",
+        )
+        .await?;
+        let result = poll_immediate(get.as_mut())
+            .await
+            .ok_or("Transfer did not fail".to_string())?;
+        test_true!(matches!(result, Err(TftpError::Server(1, _))));
+
+        let mut get = core::pin::pin!(tftp.get(SERVER_IP, "lost", |_| ()));
+        test_true!(poll_immediate(get.as_mut()).await.is_none());
+        let request = sent(&udp).await?;
+        time.set_tick(time.tick_freq() as usize);
+        test_true!(poll_immediate(get.as_mut()).await.is_none());
+        let retransmitted = sent(&udp).await?;
+        test_eq!(retransmitted.remote_port, SERVER_PORT);
+        test_eq!(retransmitted.data, request.data);
+        time.set_tick(2 * time.tick_freq() as usize);
+        let result = poll_immediate(get.as_mut())
+            .await
+            .ok_or("Transfer did not time out".to_string())?;
+        test_true!(matches!(result, Err(TftpError::Timeout)));
+
+        Ok(())
+    });
+}
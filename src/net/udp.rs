@@ -0,0 +1,314 @@
+use crate::{
+    net::{IpAddr, UdpFrame},
+    util::{
+        async_channel::{self, Receiver, Sender},
+        async_mutex::Mutex,
+    },
+};
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use hashbrown::HashMap;
+
+#[derive(Debug)]
+pub enum BindError {
+    AddrInUse,
+    NoFreePorts,
+}
+
+#[derive(Debug)]
+pub enum UdpDeliveryError {
+    InvalidChecksum,
+    PortUnreachable,
+}
+
+pub struct OutgoingUdpPacket {
+    pub local_port: u16,
+    pub remote_ip: IpAddr,
+    pub remote_port: u16,
+    pub data: Vec<u8>,
+}
+
+struct Datagram {
+    remote_ip: IpAddr,
+    remote_port: u16,
+    data: Vec<u8>,
+}
+
+struct BoundSocket {
+    // Sockets cannot take the async lock on drop, so the binding is released once this can no
+    // longer be upgraded
+    alive: Weak<()>,
+    tx: Sender<Datagram>,
+}
+
+impl BoundSocket {
+    fn is_alive(&self) -> bool {
+        self.alive.strong_count() > 0
+    }
+}
+
+pub struct UdpSocket {
+    port: u16,
+    rx: Receiver<Datagram>,
+    outgoing_tx: Sender<OutgoingUdpPacket>,
+    _alive: Arc<()>,
+}
+
+impl UdpSocket {
+    #[allow(unused)]
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the payload along with the address and port it was sent from
+    pub async fn recv_from(&self) -> (Vec<u8>, IpAddr, u16) {
+        let datagram = self.rx.recv().await;
+        (datagram.data, datagram.remote_ip, datagram.remote_port)
+    }
+
+    pub async fn send_to(&self, data: &[u8], remote_ip: impl Into<IpAddr>, remote_port: u16) {
+        self.outgoing_tx
+            .send(OutgoingUdpPacket {
+                local_port: self.port,
+                remote_ip: remote_ip.into(),
+                remote_port,
+                data: data.to_vec(),
+            })
+            .await;
+    }
+}
+
+pub struct Udp {
+    sockets: Mutex<HashMap<u16, BoundSocket>>,
+    next_ephemeral_port: Mutex<u16>,
+    outgoing_tx: Sender<OutgoingUdpPacket>,
+    outgoing_rx: Receiver<OutgoingUdpPacket>,
+}
+
+impl Udp {
+    const EPHEMERAL_PORT_START: u16 = 49152;
+
+    pub fn new() -> Udp {
+        let (outgoing_tx, outgoing_rx) = async_channel::channel();
+        Udp {
+            sockets: Mutex::new(Default::default()),
+            next_ephemeral_port: Mutex::new(Self::EPHEMERAL_PORT_START),
+            outgoing_tx,
+            outgoing_rx,
+        }
+    }
+
+    /// Binding to port 0 picks a free ephemeral port
+    pub async fn bind(&self, port: u16) -> Result<UdpSocket, BindError> {
+        let mut sockets = self.sockets.lock().await;
+
+        let port = match port {
+            0 => self.find_ephemeral_port(&sockets).await?,
+            port => {
+                if Self::is_bound(&sockets, port) {
+                    return Err(BindError::AddrInUse);
+                }
+                port
+            }
+        };
+
+        let (tx, rx) = async_channel::channel();
+        let alive = Arc::new(());
+        sockets.insert(
+            port,
+            BoundSocket {
+                alive: Arc::downgrade(&alive),
+                tx,
+            },
+        );
+
+        Ok(UdpSocket {
+            port,
+            rx,
+            outgoing_tx: self.outgoing_tx.clone(),
+            _alive: alive,
+        })
+    }
+
+    fn is_bound(sockets: &HashMap<u16, BoundSocket>, port: u16) -> bool {
+        matches!(sockets.get(&port), Some(socket) if socket.is_alive())
+    }
+
+    async fn find_ephemeral_port(
+        &self,
+        sockets: &HashMap<u16, BoundSocket>,
+    ) -> Result<u16, BindError> {
+        let mut next_port = self.next_ephemeral_port.lock().await;
+        let num_ephemeral_ports = u16::MAX - Self::EPHEMERAL_PORT_START + 1;
+
+        for _ in 0..num_ephemeral_ports {
+            let port = *next_port;
+            *next_port = match port {
+                u16::MAX => Self::EPHEMERAL_PORT_START,
+                _ => port + 1,
+            };
+
+            if !Self::is_bound(sockets, port) {
+                return Ok(port);
+            }
+        }
+
+        Err(BindError::NoFreePorts)
+    }
+
+    pub async fn handle_frame(
+        &self,
+        frame: &UdpFrame<'_>,
+        source_ip: &IpAddr,
+        dest_ip: &IpAddr,
+    ) -> Result<(), UdpDeliveryError> {
+        if !frame.checksum_valid(source_ip, dest_ip) {
+            return Err(UdpDeliveryError::InvalidChecksum);
+        }
+
+        let mut sockets = self.sockets.lock().await;
+        let port = frame.dest_port();
+        let socket = match sockets.get(&port) {
+            Some(v) if v.is_alive() => v,
+            Some(_) => {
+                sockets.remove(&port);
+                return Err(UdpDeliveryError::PortUnreachable);
+            }
+            None => return Err(UdpDeliveryError::PortUnreachable),
+        };
+
+        socket
+            .tx
+            .send(Datagram {
+                remote_ip: *source_ip,
+                remote_port: frame.source_port(),
+                data: frame.data().to_vec(),
+            })
+            .await;
+
+        Ok(())
+    }
+
+    pub async fn service(&self) -> OutgoingUdpPacket {
+        self.outgoing_rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::generate_udp_frame;
+    use crate::testing::*;
+    use alloc::string::{String, ToString};
+
+    const LOCAL_IP: IpAddr = IpAddr::V4([192, 168, 2, 2]);
+    const REMOTE_IP: IpAddr = IpAddr::V4([192, 168, 2, 1]);
+
+    async fn deliver(
+        udp: &Udp,
+        dest_port: u16,
+        data: &[u8],
+    ) -> Result<Result<(), UdpDeliveryError>, String> {
+        let frame = generate_udp_frame(&REMOTE_IP, &LOCAL_IP, 1234, dest_port, data);
+        let frame = UdpFrame::new(&frame).map_err(|_| "Invalid udp frame".to_string())?;
+        Ok(udp.handle_frame(&frame, &REMOTE_IP, &LOCAL_IP).await)
+    }
+
+    create_test!(test_udp_demux, {
+        let udp = Udp::new();
+        let socket_a = udp
+            .bind(5000)
+            .await
+            .map_err(|_| "bind failed".to_string())?;
+        let socket_b = udp
+            .bind(5001)
+            .await
+            .map_err(|_| "bind failed".to_string())?;
+        test_true!(matches!(udp.bind(5000).await, Err(BindError::AddrInUse)));
+
+        test_ok!(deliver(&udp, 5001, b"to b").await?);
+        test_ok!(deliver(&udp, 5000, b"to a").await?);
+        test_true!(matches!(
+            deliver(&udp, 5002, b"to nobody").await?,
+            Err(UdpDeliveryError::PortUnreachable)
+        ));
+
+        let (data, ip, port) = crate::future::poll_immediate(socket_a.recv_from())
+            .await
+            .ok_or_else(|| "No data for socket a".to_string())?;
+        test_eq!(data, b"to a");
+        test_eq!(ip, REMOTE_IP);
+        test_eq!(port, 1234);
+
+        let (data, _, _) = crate::future::poll_immediate(socket_b.recv_from())
+            .await
+            .ok_or_else(|| "No data for socket b".to_string())?;
+        test_eq!(data, b"to b");
+
+        // Sockets receive from either address family
+        let local_ip = IpAddr::V6([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        let remote_ip = IpAddr::V6([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let frame = generate_udp_frame(&remote_ip, &local_ip, 1234, 5001, b"over v6");
+        let frame = UdpFrame::new(&frame).map_err(|_| "Invalid udp frame".to_string())?;
+        test_ok!(udp.handle_frame(&frame, &remote_ip, &local_ip).await);
+        let (data, ip, _) = crate::future::poll_immediate(socket_b.recv_from())
+            .await
+            .ok_or_else(|| "No v6 data for socket b".to_string())?;
+        test_eq!(data, b"over v6");
+        test_eq!(ip, remote_ip);
+
+        // Port is released on drop
+        drop(socket_a);
+        test_true!(matches!(
+            deliver(&udp, 5000, b"to a").await?,
+            Err(UdpDeliveryError::PortUnreachable)
+        ));
+        test_true!(udp.bind(5000).await.is_ok());
+
+        Ok(())
+    });
+
+    create_test!(test_udp_invalid_checksum, {
+        let udp = Udp::new();
+        let socket = udp
+            .bind(5000)
+            .await
+            .map_err(|_| "bind failed".to_string())?;
+
+        let mut frame = generate_udp_frame(&REMOTE_IP, &LOCAL_IP, 1234, 5000, b"data");
+        frame[8] ^= 1;
+        let frame = UdpFrame::new(&frame).map_err(|_| "Invalid udp frame".to_string())?;
+        test_true!(matches!(
+            udp.handle_frame(&frame, &REMOTE_IP, &LOCAL_IP).await,
+            Err(UdpDeliveryError::InvalidChecksum)
+        ));
+        test_true!(crate::future::poll_immediate(socket.recv_from())
+            .await
+            .is_none());
+
+        Ok(())
+    });
+
+    create_test!(test_udp_send_to, {
+        let udp = Udp::new();
+        let socket = udp.bind(0).await.map_err(|_| "bind failed".to_string())?;
+        test_eq!(socket.local_port(), Udp::EPHEMERAL_PORT_START);
+
+        let other = udp.bind(0).await.map_err(|_| "bind failed".to_string())?;
+        test_true!(other.local_port() != socket.local_port());
+
+        socket.send_to(b"hello", REMOTE_IP, 6000).await;
+        let outgoing = crate::future::poll_immediate(udp.service())
+            .await
+            .ok_or_else(|| "No outgoing packet".to_string())?;
+        test_eq!(outgoing.local_port, socket.local_port());
+        test_eq!(outgoing.remote_ip, REMOTE_IP);
+        test_eq!(outgoing.remote_port, 6000);
+        test_eq!(outgoing.data, b"hello");
+
+        Ok(())
+    });
+}
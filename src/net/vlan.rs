@@ -0,0 +1,66 @@
+use crate::{net::Ipv4Config, util::updated_val::UpdatedVal};
+
+/// 802.1Q tag control information, the drop eligible bit is not used
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct VlanTag {
+    /// 12 bits, 0 and 0xfff are reserved
+    pub id: u16,
+    /// 3 bit priority code point
+    pub priority: u8,
+}
+
+impl VlanTag {
+    pub const TPID: u16 = 0x8100;
+
+    pub fn from_tci(tci: u16) -> VlanTag {
+        VlanTag {
+            id: tci & 0xfff,
+            priority: (tci >> 13) as u8,
+        }
+    }
+
+    pub fn tci(&self) -> u16 {
+        (self.priority as u16 & 0x7) << 13 | self.id & 0xfff
+    }
+}
+
+/// Sub-interface of a physical device that sends and receives frames tagged with its VLAN ID.
+/// Only ipv4 is configured on sub-interfaces
+pub struct VlanInterface {
+    pub tag: VlanTag,
+    pub ipv4_config: UpdatedVal<Ipv4Config>,
+}
+
+impl VlanInterface {
+    pub fn new(tag: VlanTag, ipv4_config: Ipv4Config) -> VlanInterface {
+        VlanInterface {
+            tag,
+            ipv4_config: UpdatedVal::new(ipv4_config),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_vlan_tci, {
+        let tag = VlanTag {
+            id: 100,
+            priority: 5,
+        };
+        test_eq!(tag.tci(), 0xa064);
+        test_eq!(VlanTag::from_tci(0xa064), tag);
+
+        // Drop eligible bit is ignored, out of range fields are truncated
+        test_eq!(VlanTag::from_tci(0xb064), tag);
+        let tag = VlanTag {
+            id: 0x1001,
+            priority: 9,
+        };
+        test_eq!(tag.tci(), 0x2001);
+
+        Ok(())
+    });
+}
@@ -30,3 +30,12 @@ impl Rng {
         val as f32 / u64::MAX as f32
     }
 }
+
+/// Cycle counter, its low bits are hard to guess from outside and make for a good seed mix
+pub fn tsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    (high as u64) << 32 | low as u64
+}
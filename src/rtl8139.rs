@@ -1,6 +1,10 @@
 use crate::{
     interrupts::{InterruptHandlerData, InterruptHandlerRegisterError},
     io::pci::{GeneralPciDevice, InvalidIrq, Pci},
+    net::{
+        capture::{Capture, CaptureInterface, Direction},
+        stats::TrafficStats,
+    },
     util::{
         async_mutex::Mutex,
         atomic_cell::AtomicCell,
@@ -29,6 +33,7 @@ const TRANSMIT_STATUS_OFFSET: usize = 0x10;
 const TRANSMIT_DATA_OFFSET: usize = 0x20;
 const CAPR_OFFSET: usize = 0x38;
 const CBR_OFFSET: usize = 0x3a;
+const MULTICAST_FILTER_OFFSET: usize = 0x08;
 
 unsafe fn reset_device(base: *mut u8) {
     let command_register = base.add(COMMAND_REGISTER_OFFSET);
@@ -251,11 +256,17 @@ unsafe fn init_receive_configuration(base: *mut u8) -> Result<(), ValueNotSet<u3
     global_receive_config.set_bits(0, 6, 0x00);
     // Physical match
     global_receive_config.set_bit(1, true);
-    // Multicast
+    // Multicast, needed for ipv6 neighbor discovery
+    global_receive_config.set_bit(2, true);
+    // Broadcast
     global_receive_config.set_bit(3, true);
     // Overwrite start of buffer when too much data (WRAP)
     global_receive_config.set_bit(7, true);
     global_receive_config_reg.write_volatile(global_receive_config);
+    // Accept every multicast group instead of tracking the ones we joined
+    let multicast_filter = base.add(MULTICAST_FILTER_OFFSET) as *mut u32;
+    multicast_filter.write_volatile(0xffffffff);
+    multicast_filter.add(1).write_volatile(0xffffffff);
     let retreived = global_receive_config_reg.read_volatile();
     if retreived != global_receive_config {
         Err(ValueNotSet {
@@ -574,10 +585,14 @@ pub struct Rtl8139 {
     inner: Mutex<Inner>,
     waker_list: Arc<SpinLock<HashMap<usize, Waker>>>,
     service_waker: Arc<AtomicCell<Waker>>,
+    capture: Option<Arc<Capture>>,
+    pub stats: TrafficStats,
 }
 
 impl Rtl8139 {
     pub const PCI_ID: (u16, u16) = (0x10ec, 0x8139);
+    // Ethernet payload, the transmit buffers would allow a little more
+    pub const MTU: usize = 1500;
 
     pub fn new(
         device: GeneralPciDevice,
@@ -601,12 +616,27 @@ impl Rtl8139 {
             inner,
             waker_list,
             service_waker,
+            capture: None,
+            stats: Default::default(),
         })
     }
 
+    /// Records every transmitted frame, received ones are recorded by whoever reads them
+    pub fn set_capture(&mut self, capture: Arc<Capture>) {
+        self.capture = Some(capture);
+    }
+
     pub async fn write(&self, packet: &[u8]) -> Result<(), PacketTooShort> {
+        if let Some(capture) = &self.capture {
+            capture
+                .record(CaptureInterface::Ethernet, Direction::Outbound, packet)
+                .await;
+        }
+
         let mut inner = self.inner.lock().await;
-        inner.write(packet).await
+        inner.write(packet).await?;
+        self.stats.record_tx(packet.len());
+        Ok(())
     }
 
     pub async fn read<F, Fut>(&self, on_read: F)
@@ -636,7 +666,12 @@ impl Rtl8139 {
                 }
                 .await;
                 if let Some(mut v) = self.inner.try_lock() {
-                    let fut = v.read(on_read).await;
+                    let fut = v
+                        .read(|packet| {
+                            self.stats.record_rx(packet.len());
+                            on_read(packet)
+                        })
+                        .await;
                     break fut;
                 };
             };
@@ -0,0 +1,366 @@
+use crate::{
+    allocator::ALLOC,
+    logger::{LogLevel, LOGGER},
+    net::tcp::{self, TcpListener},
+    util::async_io::{AsyncRead, AsyncWrite, BoxFuture},
+};
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::future::Future;
+use telnet::Telnet;
+
+const MAX_CONNECTIONS: usize = 4;
+
+// Longer lines are dropped instead of executed
+const MAX_LINE_LEN: usize = 1024;
+
+const PROMPT: &str = "> ";
+
+type Handler<'a> = Box<dyn Fn(Vec<String>) -> BoxFuture<'a, String> + Send + Sync + 'a>;
+
+struct Command<'a> {
+    name: String,
+    usage: String,
+    description: String,
+    handler: Handler<'a>,
+}
+
+/// Line oriented command interpreter. Subsystems add commands with register, help and exit are
+/// always available
+pub struct Shell<'a> {
+    commands: Vec<Command<'a>>,
+}
+
+impl<'a> Shell<'a> {
+    /// Comes with the commands that only need global state: mem, log and reboot
+    pub fn new() -> Shell<'a> {
+        let mut shell = Shell {
+            commands: Vec::new(),
+        };
+
+        shell.register("mem", "Heap usage", |_| async { mem() });
+        shell.register(
+            "log level <module> <level>",
+            "Set the log level of a module",
+            |args| async move { log(&args) },
+        );
+        shell.register("reboot", "Reset the machine", |_| async {
+            info!("Rebooting from the shell");
+            unsafe { crate::interrupts::reboot() }
+        });
+
+        shell
+    }
+
+    /// The first word of usage is the command name, the handler gets the words after it
+    pub fn register<F, Fut>(&mut self, usage: &str, description: &str, handler: F)
+    where
+        F: Fn(Vec<String>) -> Fut + Send + Sync + 'a,
+        Fut: Future<Output = String> + Send + 'a,
+    {
+        let name = usage.split_whitespace().next().unwrap_or(usage);
+        self.commands.push(Command {
+            name: name.into(),
+            usage: usage.into(),
+            description: description.into(),
+            handler: Box::new(move |args| Box::pin(handler(args))),
+        });
+    }
+
+    pub async fn execute(&self, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next() else {
+            return String::new();
+        };
+
+        if name == "help" {
+            return self.help();
+        }
+
+        match self.commands.iter().find(|command| command.name == name) {
+            Some(command) => (command.handler)(words.map(Into::into).collect()).await,
+            None => format!("Unknown command {}, try help\n", name),
+        }
+    }
+
+    fn help(&self) -> String {
+        let width = self
+            .commands
+            .iter()
+            .map(|command| command.usage.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut ret = String::new();
+        for command in &self.commands {
+            ret += &format!(
+                "{:width$}  {}\n",
+                command.usage,
+                command.description,
+                width = width
+            );
+        }
+        ret += &format!("{:width$}  {}\n", "help", "List commands", width = width);
+        ret += &format!(
+            "{:width$}  {}\n",
+            "exit",
+            "Close the session",
+            width = width
+        );
+        ret
+    }
+
+    pub async fn serve(&self, listener: &TcpListener) {
+        tcp::serve_connections(listener, MAX_CONNECTIONS, |connection| async move {
+            self.serve_connection(&connection).await;
+            connection.close().await;
+        })
+        .await
+    }
+
+    /// Runs commands until the client disconnects or asks to exit
+    pub async fn serve_connection<S: AsyncRead + AsyncWrite>(&self, stream: &S) {
+        let mut telnet = Telnet::new();
+        let greeting = format!("Type help for a list of commands\n{}", PROMPT);
+        if stream.write_all(&telnet::encode(&greeting)).await.is_err() {
+            return;
+        }
+
+        let mut line = Vec::new();
+        let mut overlong = false;
+        let mut buf = [0; 256];
+        loop {
+            let len = stream.read(&mut buf).await;
+            if len == 0 {
+                return;
+            }
+
+            let mut data = Vec::new();
+            let mut replies = Vec::new();
+            telnet.decode(&buf[..len], &mut data, &mut replies);
+            if !replies.is_empty() && stream.write_all(&replies).await.is_err() {
+                return;
+            }
+
+            for b in data {
+                if b != b'\n' {
+                    // The rest of an overlong line is discarded rather than buffered
+                    if line.len() < MAX_LINE_LEN {
+                        line.push(b);
+                    } else {
+                        overlong = true;
+                    }
+                    continue;
+                }
+
+                let received = core::mem::take(&mut line);
+                let mut output = if core::mem::take(&mut overlong) {
+                    "Line too long\n".to_string()
+                } else {
+                    let text = String::from_utf8_lossy(&received);
+                    let text = text.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+                    if text == "exit" || text == "quit" {
+                        return;
+                    }
+                    self.execute(text).await
+                };
+
+                output += PROMPT;
+                if stream.write_all(&telnet::encode(&output)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn mem() -> String {
+    let stats = ALLOC.stats();
+    format!(
+        "heap {} KiB, used {} KiB, free {} KiB in {} segments, largest free {} KiB\n",
+        stats.heap_size / 1024,
+        stats.heap_size.saturating_sub(stats.free) / 1024,
+        stats.free / 1024,
+        stats.free_segments,
+        stats.largest_free / 1024
+    )
+}
+
+fn log(args: &[String]) -> String {
+    let [subcommand, module, level] = args else {
+        return "Usage: log level <module> <level>\n".into();
+    };
+    if subcommand != "level" {
+        return format!("Unknown log subcommand {}\n", subcommand);
+    }
+    let Ok(level) = level.parse::<LogLevel>() else {
+        return format!(
+            "Invalid level {}, expected debug, info, warning or error\n",
+            level
+        );
+    };
+
+    // Modules can be given relative to the crate root
+    let root = module_path!()
+        .split("::")
+        .next()
+        .expect("Module path is not empty");
+    let module = if module == root || module.starts_with(&format!("{}::", root)) {
+        module.clone()
+    } else {
+        format!("{}::{}", root, module)
+    };
+
+    LOGGER.set_level(&module, level);
+    format!("{} now logs at {}\n", module, level)
+}
+
+/// Minimal telnet (RFC 854) support. Every option the client asks for is refused, so the
+/// session stays in the default line at a time mode with local echo. Clients that don't speak
+/// telnet, e.g. netcat, work the same
+mod telnet {
+    use alloc::vec::Vec;
+
+    const IAC: u8 = 255;
+    const DONT: u8 = 254;
+    const DO: u8 = 253;
+    const WONT: u8 = 252;
+    const WILL: u8 = 251;
+    const SB: u8 = 250;
+    const SE: u8 = 240;
+
+    enum State {
+        Data,
+        Iac,
+        /// Waiting for the option of a WILL, WONT, DO or DONT
+        Negotiation(u8),
+        Subnegotiation,
+        SubnegotiationIac,
+    }
+
+    pub struct Telnet {
+        state: State,
+    }
+
+    impl Telnet {
+        pub fn new() -> Telnet {
+            Telnet { state: State::Data }
+        }
+
+        /// Appends user data to data and the responses to option requests to replies
+        pub fn decode(&mut self, input: &[u8], data: &mut Vec<u8>, replies: &mut Vec<u8>) {
+            for &b in input {
+                self.state = match (&self.state, b) {
+                    (State::Data, IAC) => State::Iac,
+                    (State::Data, b) => {
+                        data.push(b);
+                        State::Data
+                    }
+                    // Escaped 255 data byte
+                    (State::Iac, IAC) => {
+                        data.push(IAC);
+                        State::Data
+                    }
+                    (State::Iac, WILL | WONT | DO | DONT) => State::Negotiation(b),
+                    (State::Iac, SB) => State::Subnegotiation,
+                    // Other commands, e.g. go ahead or interrupt process, are ignored
+                    (State::Iac, _) => State::Data,
+                    (State::Negotiation(verb), option) => {
+                        // Only requests to enable something need a refusal. Answering DONT and
+                        // WONT could loop, and they match our state anyway
+                        match *verb {
+                            DO => replies.extend_from_slice(&[IAC, WONT, option]),
+                            WILL => replies.extend_from_slice(&[IAC, DONT, option]),
+                            _ => (),
+                        }
+                        State::Data
+                    }
+                    (State::Subnegotiation, IAC) => State::SubnegotiationIac,
+                    (State::Subnegotiation, _) => State::Subnegotiation,
+                    (State::SubnegotiationIac, SE) => State::Data,
+                    (State::SubnegotiationIac, _) => State::Subnegotiation,
+                };
+            }
+        }
+    }
+
+    /// Network virtual terminal lines end in CR LF. Utf-8 never contains 255, so there is no
+    /// IAC to escape
+    pub fn encode(text: &str) -> Vec<u8> {
+        text.replace('\n', "\r\n").into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::http::test::MockStream;
+    use crate::testing::*;
+
+    create_test!(test_telnet_decode, {
+        let mut telnet = Telnet::new();
+        let mut data = Vec::new();
+        let mut replies = Vec::new();
+        // DO echo, WILL terminal type, a subnegotiation and an escaped 255, split between calls
+        let input = [
+            255, 253, 1, b'h', 255, 251, 24, 255, 250, 31, 0, 80, 255, 240, b'i', 255, 255, 255,
+            254, 3, b'\r', b'\n',
+        ];
+        telnet.decode(&input[..5], &mut data, &mut replies);
+        telnet.decode(&input[5..], &mut data, &mut replies);
+        test_eq!(data, b"hi\xff\r\n".to_vec());
+        test_eq!(replies, [255, 252, 1, 255, 254, 24].to_vec());
+
+        test_eq!(telnet::encode("a\nb\n"), b"a\r\nb\r\n".to_vec());
+
+        Ok(())
+    });
+
+    create_test!(test_shell_session, {
+        let mut shell = Shell::new();
+        shell.register("echo <words>", "Print the arguments", |args| async move {
+            args.join(" ") + "\n"
+        });
+
+        let output = shell.execute("  echo hello   world ").await;
+        test_eq!(output, "hello world\n");
+        test_eq!(shell.execute("").await, "");
+        test_true!(shell
+            .execute("missing")
+            .await
+            .starts_with("Unknown command missing"));
+        let help = shell.execute("help").await;
+        test_true!(help.contains("echo <words>  Print the arguments\n"));
+        test_true!(help.contains("log level <module> <level>"));
+
+        test_true!(shell
+            .execute("log level net::tcp loud")
+            .await
+            .starts_with("Invalid level"));
+        test_true!(shell.execute("log verbosity").await.starts_with("Usage"));
+
+        // Carriage returns and NULs from telnet line endings are dropped, nothing after exit
+        // is executed
+        let stream = MockStream::new(b"echo a\r\n\r\0echo b\r\nexit\r\necho c\r\n");
+        shell.serve_connection(&stream).await;
+        let output = String::from_utf8(stream.output.lock().clone()).unwrap();
+        let expected = "Type help for a list of commands\r\n> a\r\n> b\r\n> ";
+        test_eq!(output, expected.to_string());
+
+        // Only the first MAX_LINE_LEN bytes of an overlong line are ever held
+        let mut input = b"echo ".to_vec();
+        input.resize(MAX_LINE_LEN * 4, b'x');
+        input.extend_from_slice(b"\r\necho b\r\n");
+        let stream = MockStream::new(&input);
+        shell.serve_connection(&stream).await;
+        let output = String::from_utf8(stream.output.lock().clone()).unwrap();
+        test_true!(output.ends_with("> Line too long\r\n> b\r\n> "));
+
+        Ok(())
+    });
+}
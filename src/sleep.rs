@@ -1,4 +1,5 @@
 use crate::{
+    future::Either,
     time::MonotonicTime,
     util::async_mutex::Mutex,
     util::{atomic_cell::AtomicCell, interrupt_guard::InterruptGuarded},
@@ -147,7 +148,8 @@ impl SleepFuture<'_> {
         wakeup_list: &WakeupRequester,
     ) -> SleepFuture<'a> {
         let start = monotonic_time.get();
-        let end = start + (time_s * monotonic_time.tick_freq()) as usize;
+        // Saturates to a wakeup that never comes rather than wrapping into the past
+        let end = start.saturating_add((time_s * monotonic_time.tick_freq()) as usize);
         wakeup_list.register_wakeup_time(end).await;
 
         SleepFuture {
@@ -176,3 +178,19 @@ pub async fn sleep(time_s: f32, monotonic_time: &MonotonicTime, wakeup_list: &Wa
         .await
         .await
 }
+
+/// None if fut does not complete within time_s. Loops that skip unwanted messages belong inside
+/// fut, so that the deadline covers all of them
+pub async fn timeout<F: Future>(
+    time_s: f32,
+    monotonic_time: &MonotonicTime,
+    wakeup_list: &WakeupRequester,
+    fut: F,
+) -> Option<F::Output> {
+    let fut = core::pin::pin!(fut);
+    let sleep_fut = core::pin::pin!(sleep(time_s, monotonic_time, wakeup_list));
+    match crate::future::select(fut, sleep_fut).await {
+        Either::Left((v, _)) => Some(v),
+        Either::Right(_) => None,
+    }
+}
@@ -1,3 +1,6 @@
+use crate::util::spinlock::SpinLock;
+
+use alloc::sync::Arc;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct MonotonicTime {
@@ -31,3 +34,132 @@ impl MonotonicTime {
         self.tick_freq
     }
 }
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ClockAdjustment {
+    Stepped,
+    Slewed,
+}
+
+struct WallClockState {
+    /// Wall time at base_tick
+    base_s: f64,
+    base_tick: usize,
+    /// Correction that slewing has yet to apply, counted from base_tick
+    slew_s: f64,
+}
+
+/// Seconds since the unix epoch. Runs off the monotonic tick count, so it can be corrected
+/// without disturbing sleeps and timeouts
+pub struct WallClock {
+    time: Arc<MonotonicTime>,
+    state: SpinLock<WallClockState>,
+}
+
+impl WallClock {
+    /// Larger offsets are stepped, the same threshold as ntpd
+    pub const STEP_THRESHOLD_S: f64 = 0.128;
+    /// Slewing runs the clock at most this much faster or slower
+    pub const MAX_SLEW_RATE: f64 = 500e-6;
+
+    pub fn new(time: Arc<MonotonicTime>, now_s: f64) -> WallClock {
+        let base_tick = time.get();
+        WallClock {
+            time,
+            state: SpinLock::new(WallClockState {
+                base_s: now_s,
+                base_tick,
+                slew_s: 0.0,
+            }),
+        }
+    }
+
+    pub fn now(&self) -> f64 {
+        let state = self.state.lock();
+        self.at(&state, self.time.get()).0
+    }
+
+    /// Wall time at tick along with the part of the slew still outstanding
+    fn at(&self, state: &WallClockState, tick: usize) -> (f64, f64) {
+        let elapsed_s = tick.saturating_sub(state.base_tick) as f64 / self.time.tick_freq() as f64;
+        let max_slew_s = elapsed_s * Self::MAX_SLEW_RATE;
+        let slewed_s = state.slew_s.clamp(-max_slew_s, max_slew_s);
+        (state.base_s + elapsed_s + slewed_s, state.slew_s - slewed_s)
+    }
+
+    /// Jumps by offset_s, dropping any slew in progress
+    pub fn step(&self, offset_s: f64) {
+        let mut state = self.state.lock();
+        let tick = self.time.get();
+        let (now_s, _) = self.at(&state, tick);
+        *state = WallClockState {
+            base_s: now_s + offset_s,
+            base_tick: tick,
+            slew_s: 0.0,
+        };
+    }
+
+    /// Gradually applies offset_s, so the clock never jumps or runs backwards. Replaces any
+    /// slew in progress, as a new offset measurement already includes what was applied so far
+    pub fn slew(&self, offset_s: f64) {
+        let mut state = self.state.lock();
+        let tick = self.time.get();
+        let (now_s, _) = self.at(&state, tick);
+        *state = WallClockState {
+            base_s: now_s,
+            base_tick: tick,
+            slew_s: offset_s,
+        };
+    }
+
+    /// Steps or slews depending on the size of the offset
+    pub fn adjust(&self, offset_s: f64) -> ClockAdjustment {
+        if offset_s.abs() > Self::STEP_THRESHOLD_S {
+            self.step(offset_s);
+            ClockAdjustment::Stepped
+        } else {
+            self.slew(offset_s);
+            ClockAdjustment::Slewed
+        }
+    }
+
+    #[allow(unused)]
+    pub fn remaining_slew(&self) -> f64 {
+        let state = self.state.lock();
+        self.at(&state, self.time.get()).1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_wall_clock, {
+        let time = Arc::new(MonotonicTime::new(100.0));
+        let clock = WallClock::new(Arc::clone(&time), 1000.0);
+        time.set_tick(150);
+        test_eq!(clock.now(), 1001.5);
+
+        test_eq!(clock.adjust(-10.0), ClockAdjustment::Stepped);
+        test_eq!(clock.now(), 991.5);
+
+        // 50ms at 500ppm takes 100s to apply
+        test_eq!(clock.adjust(0.05), ClockAdjustment::Slewed);
+        time.set_tick(150 + 100 * 40);
+        let slewed = 40.0 * WallClock::MAX_SLEW_RATE;
+        test_eq!(clock.now(), 991.5 + 40.0 + slewed);
+        test_eq!(clock.remaining_slew(), 0.05 - slewed);
+        time.set_tick(150 + 100 * 200);
+        test_eq!(clock.now(), 991.5 + 200.0 + 0.05);
+        test_eq!(clock.remaining_slew(), 0.0);
+
+        // Slewing backwards only slows the clock down
+        clock.slew(-1.0);
+        let before = clock.now();
+        time.set_tick(150 + 100 * 201);
+        test_eq!(clock.now(), before + 1.0 - WallClock::MAX_SLEW_RATE);
+
+        Ok(())
+    });
+}
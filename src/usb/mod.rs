@@ -89,7 +89,6 @@ impl UsbServiceHandle {
         rx.recv().await.expect("Received oneshot twice")
     }
 
-    #[allow(unused)]
     pub async fn get_device_descriptor(&self, address: u8) -> UsbDeviceDescriptor<Vec<u8>> {
         const DESCRIPTOR_LENGTH: u16 = 18;
         let setup = UsbSetupRequestParams {
@@ -362,6 +361,36 @@ impl UsbSetupRequest<'_> {
 
 pub struct UsbDeviceDescriptor<T>(T);
 
+/// Summary of an enumerated device, formatted like `lsusb`
+#[derive(Debug, Clone)]
+pub struct UsbDeviceInfo {
+    pub address: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub class: u8,
+}
+
+impl UsbDeviceInfo {
+    pub fn new<T: AsRef<[u8]>>(address: u8, descriptor: &UsbDeviceDescriptor<T>) -> UsbDeviceInfo {
+        UsbDeviceInfo {
+            address,
+            vendor_id: descriptor.vendor_id(),
+            product_id: descriptor.product_id(),
+            class: descriptor.device_class(),
+        }
+    }
+}
+
+impl fmt::Display for UsbDeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Device {:03}: ID {:04x}:{:04x} class {:02x}",
+            self.address, self.vendor_id, self.product_id, self.class
+        )
+    }
+}
+
 impl<T> UsbDeviceDescriptor<T>
 where
     T: AsRef<[u8]>,
@@ -0,0 +1,143 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{future::Future, pin::Pin};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[allow(unused)]
+pub enum IoError {
+    /// The stream ended before the requested amount of data arrived
+    UnexpectedEof,
+    /// Writes after the stream was shut down
+    Closed,
+}
+
+/// Byte stream source. Methods return boxed futures so that protocols generic over the stream
+/// can still be spawned on the executor, which requires Send futures
+pub trait AsyncRead: Sync {
+    /// Waits for at least one byte, 0 means the stream has ended
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, usize>;
+
+    #[allow(unused)]
+    fn read_exact<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<(), IoError>> {
+        Box::pin(async move {
+            let mut filled = 0;
+            while filled < buf.len() {
+                match self.read(&mut buf[filled..]).await {
+                    0 => return Err(IoError::UnexpectedEof),
+                    n => filled += n,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    #[allow(unused)]
+    /// Appends to out up to and including delim, or until the end of the stream. Returns the
+    /// number of bytes appended. Reads a byte at a time to not consume past the delimiter
+    fn read_until<'a>(&'a self, delim: u8, out: &'a mut Vec<u8>) -> BoxFuture<'a, usize> {
+        Box::pin(async move {
+            let start = out.len();
+            let mut byte = [0];
+            while self.read(&mut byte).await != 0 {
+                out.push(byte[0]);
+                if byte[0] == delim {
+                    break;
+                }
+            }
+            out.len() - start
+        })
+    }
+}
+
+pub trait AsyncWrite: Sync {
+    /// Queues some of buf, returning how much was taken
+    fn write<'a>(&'a self, buf: &'a [u8]) -> BoxFuture<'a, Result<usize, IoError>>;
+
+    fn write_all<'a>(&'a self, mut buf: &'a [u8]) -> BoxFuture<'a, Result<(), IoError>> {
+        Box::pin(async move {
+            while !buf.is_empty() {
+                let written = self.write(buf).await?;
+                buf = &buf[written..];
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use crate::util::spinlock::SpinLock;
+    use alloc::collections::VecDeque;
+
+    /// Hands out at most 3 bytes per call to exercise the looping helpers
+    struct Trickle {
+        data: SpinLock<VecDeque<u8>>,
+    }
+
+    impl Trickle {
+        fn new(data: &[u8]) -> Trickle {
+            Trickle {
+                data: SpinLock::new(data.iter().copied().collect()),
+            }
+        }
+    }
+
+    impl AsyncRead for Trickle {
+        fn read<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, usize> {
+            Box::pin(async move {
+                let mut data = self.data.lock();
+                let len = buf.len().min(data.len()).min(3);
+                for (dst, src) in buf.iter_mut().zip(data.drain(..len)) {
+                    *dst = src;
+                }
+                len
+            })
+        }
+    }
+
+    impl AsyncWrite for Trickle {
+        fn write<'a>(&'a self, buf: &'a [u8]) -> BoxFuture<'a, Result<usize, IoError>> {
+            Box::pin(async move {
+                let len = buf.len().min(3);
+                self.data.lock().extend(&buf[..len]);
+                Ok(len)
+            })
+        }
+    }
+
+    create_test!(test_read_until, {
+        let stream = Trickle::new(b"GET / HTTP/1.1\r\nHost: x\r\n");
+
+        let mut line = Vec::new();
+        test_eq!(stream.read_until(b'\n', &mut line).await, 16);
+        test_eq!(line.as_slice(), b"GET / HTTP/1.1\r\n");
+
+        line.clear();
+        test_eq!(stream.read_until(b'\n', &mut line).await, 9);
+        test_eq!(line.as_slice(), b"Host: x\r\n");
+
+        // End of stream without a delimiter
+        line.clear();
+        test_eq!(stream.read_until(b'\n', &mut line).await, 0);
+
+        Ok(())
+    });
+
+    create_test!(test_read_exact_write_all, {
+        let stream = Trickle::new(b"");
+        test_ok!(stream.write_all(b"hello world").await);
+
+        let mut buf = [0; 5];
+        test_ok!(stream.read_exact(&mut buf).await);
+        test_eq!(&buf, b"hello");
+
+        let mut buf = [0; 8];
+        let result = stream.read_exact(&mut buf).await;
+        test_eq!(result, Err::<(), _>(IoError::UnexpectedEof));
+
+        Ok(())
+    });
+}
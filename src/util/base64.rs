@@ -0,0 +1,42 @@
+use alloc::string::String;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 from RFC 4648 with padding
+pub fn encode(data: &[u8]) -> String {
+    let mut ret = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let mut group = [0u8; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+
+        // n input bytes produce n + 1 output characters, the rest is padding
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3f;
+                ret.push(ALPHABET[index as usize] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_base64_encode, {
+        // RFC 4648 section 10
+        test_eq!(encode(b""), "");
+        test_eq!(encode(b"f"), "Zg==");
+        test_eq!(encode(b"fo"), "Zm8=");
+        test_eq!(encode(b"foo"), "Zm9v");
+        test_eq!(encode(b"foob"), "Zm9vYg==");
+        test_eq!(encode(b"foobar"), "Zm9vYmFy");
+
+        Ok(())
+    });
+}
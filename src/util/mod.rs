@@ -1,11 +1,16 @@
 pub mod async_channel;
+pub mod async_io;
 pub mod async_mutex;
 pub mod atomic_cell;
+pub mod base64;
 pub mod bit_manipulation;
 pub mod histogram;
 pub mod interrupt_guard;
 pub mod lock_free_queue;
 pub mod oneshot;
+pub mod pubsub;
+pub mod sha1;
+pub mod siphash;
 pub mod spinlock;
 pub mod updated_val;
 pub mod waker_list;
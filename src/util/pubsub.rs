@@ -0,0 +1,152 @@
+use crate::util::spinlock::SpinLock;
+
+use alloc::{
+    collections::VecDeque,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::task::{Poll, Waker};
+
+// Subscribers that fall further behind lose their oldest messages
+const MAX_QUEUED: usize = 256;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Publication {
+    pub channel: String,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct SubscriberState {
+    channels: Vec<String>,
+    queue: VecDeque<Publication>,
+    waker: Option<Waker>,
+}
+
+/// Named channels that any number of subscribers can listen to. Publishing never blocks so
+/// that it can be done from anywhere, including in the middle of other work
+pub struct PubSub {
+    subscribers: SpinLock<Vec<Weak<SpinLock<SubscriberState>>>>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            subscribers: SpinLock::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> Subscription {
+        let state = Arc::new(SpinLock::new(SubscriberState::default()));
+        self.subscribers.lock().push(Arc::downgrade(&state));
+        Subscription { state }
+    }
+
+    pub fn publish(&self, channel: &str, message: &str) {
+        let mut subscribers = self.subscribers.lock();
+        // Dropped subscriptions are cleaned up here instead of needing a Drop impl
+        subscribers.retain(|subscriber| subscriber.strong_count() > 0);
+
+        for subscriber in subscribers.iter().filter_map(Weak::upgrade) {
+            let mut subscriber = subscriber.lock();
+            if !subscriber.channels.iter().any(|name| name == channel) {
+                continue;
+            }
+
+            if subscriber.queue.len() >= MAX_QUEUED {
+                subscriber.queue.pop_front();
+            }
+            subscriber.queue.push_back(Publication {
+                channel: channel.into(),
+                message: message.into(),
+            });
+            if let Some(waker) = subscriber.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Starts out without any channels, unsubscribes when dropped
+pub struct Subscription {
+    state: Arc<SpinLock<SubscriberState>>,
+}
+
+impl Subscription {
+    pub fn add(&self, channel: &str) {
+        let mut state = self.state.lock();
+        if !state.channels.iter().any(|name| name == channel) {
+            state.channels.push(channel.into());
+        }
+    }
+
+    pub fn remove(&self, channel: &str) {
+        let mut state = self.state.lock();
+        state.channels.retain(|name| name != channel);
+        // Already queued messages for the channel are no longer wanted either
+        state
+            .queue
+            .retain(|publication| publication.channel != channel);
+    }
+
+    /// Waits for the next message on any of the subscribed channels. Nothing is lost if the
+    /// future is dropped before it resolves
+    pub async fn recv(&self) -> Publication {
+        crate::future::poll_fn(|cx| {
+            let mut state = self.state.lock();
+            match state.queue.pop_front() {
+                Some(publication) => Poll::Ready(publication),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::future::poll_immediate;
+    use crate::testing::*;
+
+    create_test!(test_pubsub, {
+        let pubsub = PubSub::new();
+        let logs = pubsub.subscribe();
+        let all = pubsub.subscribe();
+        logs.add("logs");
+        all.add("logs");
+        all.add("cursor");
+
+        pubsub.publish("cursor", "0.5 0.5");
+        pubsub.publish("logs", "hello");
+        pubsub.publish("unknown", "dropped");
+
+        let received = logs.recv().await;
+        test_eq!(received.channel, "logs");
+        test_eq!(received.message, "hello");
+        test_true!(poll_immediate(logs.recv()).await.is_none());
+        test_eq!(all.recv().await.channel, "cursor");
+        test_eq!(all.recv().await.channel, "logs");
+
+        all.remove("cursor");
+        pubsub.publish("cursor", "0 0");
+        test_true!(poll_immediate(all.recv()).await.is_none());
+
+        // Slow subscribers keep the newest messages
+        for i in 0..MAX_QUEUED + 1 {
+            pubsub.publish("logs", &alloc::format!("{}", i));
+        }
+        test_eq!(logs.recv().await.message, "1");
+
+        drop(logs);
+        drop(all);
+        pubsub.publish("logs", "nobody listening");
+        test_eq!(pubsub.subscribers.lock().len(), 0);
+
+        Ok(())
+    });
+}